@@ -209,7 +209,9 @@ impl EncodingSrategy {
             Self::Bitcoin => {
                 quote_spanned!(span => #import::bitcoin::consensus::encode::consensus_encode)
             }
-            Self::Lightning => unimplemented!(),
+            Self::Lightning => {
+                quote_spanned!(span => #import::lnp::presentation::lightning_encoding::lightning_encode)
+            }
         }
     }
 
@@ -217,7 +219,7 @@ impl EncodingSrategy {
         match self {
             Self::Strict => quote_spanned!(span => strict_decode),
             Self::Bitcoin => quote_spanned!(span => consensus_decode),
-            Self::Lightning => unimplemented!(),
+            Self::Lightning => quote_spanned!(span => lightning_decode),
         }
     }
 
@@ -231,7 +233,9 @@ impl EncodingSrategy {
                     consensus_encode, Encode,
                 };
             ),
-            Self::Lightning => unimplemented!(),
+            Self::Lightning => quote!(
+                use #import::lnp::presentation::lightning_encoding::lightning_encode;
+            ),
         }
     }
 
@@ -243,7 +247,9 @@ impl EncodingSrategy {
             Self::Bitcoin => quote!(
                 use #import::bitcoin::consensus::encode::Decode;
             ),
-            Self::Lightning => unimplemented!(),
+            Self::Lightning => quote!(
+                use #import::lnp::presentation::LightningDecode;
+            ),
         }
     }
 }
@@ -307,6 +313,8 @@ fn lnp_api_inner_enum(
         .lit,
     )?;
 
+    let decode_use = global_encoding.decode_use(&import);
+
     let example = "#[lnp_api(type=1000)]";
     let mut msg_const = vec![];
     let mut unmarshaller = vec![];
@@ -390,7 +398,7 @@ fn lnp_api_inner_enum(
 
                     unmarshall_fn.push(quote_spanned! { v.span() =>
                         fn #type_snake(mut reader: &mut dyn ::std::io::Read) -> Result<::std::sync::Arc<dyn ::core::any::Any>, #import::lnp::presentation::Error> {
-                            use #import::strict_encoding::StrictDecode;
+                            #decode_use
                             Ok(::std::sync::Arc::new(#payload_fisheye::#decode_fn(&mut reader)?))
                         }
                     });
@@ -452,7 +460,6 @@ fn lnp_api_inner_enum(
     let get_type = quote! { #( #get_type )* };
     let get_payload = quote! { #( #get_payload )* };
     let encode_use = global_encoding.encode_use(&import);
-    let decode_use = global_encoding.decode_use(&import);
 
     Ok(quote! {
         impl #import::lnp::CreateUnmarshaller for #ident_name {
@@ -843,6 +850,338 @@ fn strict_decode_inner_enum(
     })
 }
 
+// Lightning Encode/Decode Derives
+// ===============================
+
+#[proc_macro_derive(LightningEncode, attributes(lnpbp_crate, tlv))]
+pub fn derive_lightning_encode(input: TokenStream) -> TokenStream {
+    let derive_input = parse_macro_input!(input as DeriveInput);
+    lightning_encode_inner(derive_input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+#[proc_macro_derive(LightningDecode, attributes(lnpbp_crate, tlv))]
+pub fn derive_lightning_decode(input: TokenStream) -> TokenStream {
+    let derive_input = parse_macro_input!(input as DeriveInput);
+    lightning_decode_inner(derive_input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn lightning_encode_inner(input: DeriveInput) -> Result<TokenStream2> {
+    match input.data {
+        Data::Struct(ref data) => lightning_encode_inner_struct(&input, data),
+        Data::Enum(_) => Err(Error::new_spanned(
+            &input,
+            "Deriving LightningEncode is not supported in enums",
+        )),
+        Data::Union(_) => Err(Error::new_spanned(
+            &input,
+            "Deriving LightningEncode is not supported in unions",
+        )),
+    }
+}
+
+fn lightning_decode_inner(input: DeriveInput) -> Result<TokenStream2> {
+    match input.data {
+        Data::Struct(ref data) => lightning_decode_inner_struct(&input, data),
+        Data::Enum(_) => Err(Error::new_spanned(
+            &input,
+            "Deriving LightningDecode is not supported in enums",
+        )),
+        Data::Union(_) => Err(Error::new_spanned(
+            &input,
+            "Deriving LightningDecode is not supported in unions",
+        )),
+    }
+}
+
+fn lightning_encode_inner_struct(
+    input: &DeriveInput,
+    data: &DataStruct,
+) -> Result<TokenStream2> {
+    let (impl_generics, ty_generics, where_clause) =
+        input.generics.split_for_impl();
+    let ident_name = &input.ident;
+
+    let import = get_lnpbp_crate(input)?;
+
+    let mut recurse: Vec<TokenStream2> = vec![];
+    let mut tlvs: Vec<TokenStream2> = vec![];
+    let mut unknown_tlvs = None;
+    match data.fields {
+        Fields::Named(ref fields) => {
+            for (f, tlv) in lightning_fields(fields.named.iter())? {
+                let name = &f.ident;
+                let cfg = field_cfg_attrs(f);
+                match tlv {
+                    None => recurse.push(quote_spanned! { f.span() =>
+                        #( #cfg )*
+                        { len += self.#name.lightning_encode(&mut e)?; }
+                    }),
+                    Some(TlvField::Type(type_id)) => {
+                        tlvs.push(quote_spanned! { f.span() =>
+                            #( #cfg )*
+                            { tlvs.set_value(#type_id, &self.#name)?; }
+                        })
+                    }
+                    Some(TlvField::Unknown) => {
+                        unknown_tlvs = Some(quote_spanned! { f.span() =>
+                            #( #cfg )*
+                            { tlvs = self.#name.clone().into(); }
+                        })
+                    }
+                }
+            }
+        }
+        Fields::Unnamed(ref fields) => {
+            for (i, (f, _)) in lightning_fields(fields.unnamed.iter())?
+                .into_iter()
+                .enumerate()
+            {
+                let index = Index::from(i);
+                let cfg = field_cfg_attrs(f);
+                recurse.push(quote_spanned! { f.span() =>
+                    #( #cfg )*
+                    { len += self.#index.lightning_encode(&mut e)?; }
+                })
+            }
+        }
+        Fields::Unit => {
+            // Nothing to do here
+        }
+    };
+
+    if !tlvs.is_empty() || unknown_tlvs.is_some() {
+        recurse.push(quote! {
+            let mut tlvs = #import::lnp::presentation::tlv::RawStream::default();
+            #unknown_tlvs
+            #( #tlvs )*
+            len += tlvs.lightning_encode(&mut e)?;
+        });
+    }
+
+    let inner = match recurse.len() {
+        0 => quote! { Ok(0) },
+        _ => quote! {
+            let mut len = 0;
+            #( #recurse )*
+            Ok(len)
+        },
+    };
+
+    Ok(quote! {
+        #[allow(unused_qualifications)]
+        impl #impl_generics #import::lnp::presentation::LightningEncode for #ident_name #ty_generics #where_clause {
+            #[inline]
+            fn lightning_encode<E: ::std::io::Write>(&self, mut e: E) -> Result<usize, #import::lnp::presentation::Error> {
+                use #import::lnp::presentation::LightningEncode;
+
+                #inner
+            }
+        }
+    })
+}
+
+fn lightning_decode_inner_struct(
+    input: &DeriveInput,
+    data: &DataStruct,
+) -> Result<TokenStream2> {
+    let (impl_generics, ty_generics, where_clause) =
+        input.generics.split_for_impl();
+    let ident_name = &input.ident;
+
+    let import = get_lnpbp_crate(input)?;
+
+    let inner = match data.fields {
+        Fields::Named(ref fields) => {
+            let mut locals: Vec<TokenStream2> = vec![];
+            let mut tlvs: Vec<TokenStream2> = vec![];
+            let mut unknown_tlvs = None;
+            let mut fields_init: Vec<TokenStream2> = vec![];
+            for (f, tlv) in lightning_fields(fields.named.iter())? {
+                let name =
+                    f.ident.as_ref().expect("named fields are always named");
+                let local = Ident::new(&format!("__field_{}", name), f.span());
+                let cfg = field_cfg_attrs(f);
+                let code = quote_spanned! { f.span() =>
+                    #( #cfg )*
+                    let #local =
+                };
+                match tlv {
+                    None => locals.push(quote_spanned! { f.span() =>
+                        #code #import::lnp::presentation::LightningDecode::lightning_decode(&mut d)?;
+                    }),
+                    Some(TlvField::Type(type_id)) => {
+                        tlvs.push(quote_spanned! { f.span() =>
+                            #code tlvs.take_value(#type_id)?;
+                        })
+                    }
+                    Some(TlvField::Unknown) => {
+                        unknown_tlvs = Some(quote_spanned! { f.span() =>
+                            #code tlvs.into();
+                        })
+                    }
+                }
+                fields_init.push(quote_spanned! { f.span() =>
+                    #( #cfg )*
+                    #name: #local,
+                });
+            }
+            // TLV stream is always the last part of the data, so all of the
+            // ordinary fields are decoded first; known TLV records are taken
+            // out of the stream before checking it for unknown even records
+            let tlv_stream = if !tlvs.is_empty() || unknown_tlvs.is_some() {
+                quote! {
+                    let mut tlvs = #import::lnp::presentation::tlv::RawStream::lightning_decode(&mut d)?;
+                    #( #tlvs )*
+                    tlvs.ensure_odd()?;
+                    #unknown_tlvs
+                }
+            } else {
+                quote! {}
+            };
+            quote! {
+                {
+                    #( #locals )*
+                    #tlv_stream
+                    Self {
+                        #( #fields_init )*
+                    }
+                }
+            }
+        }
+        Fields::Unnamed(ref fields) => {
+            let recurse: Vec<TokenStream2> =
+                lightning_fields(fields.unnamed.iter())?
+                    .into_iter()
+                    .map(|(f, _)| {
+                        quote_spanned! { f.span() =>
+                            #import::lnp::presentation::LightningDecode::lightning_decode(&mut d)?,
+                        }
+                    })
+                    .collect();
+            quote! {
+                Self (
+                    #( #recurse )*
+                )
+            }
+        }
+        Fields::Unit => {
+            // Nothing to do here
+            quote! { Self() }
+        }
+    };
+
+    Ok(quote! {
+        #[allow(unused_qualifications)]
+        impl #impl_generics #import::lnp::presentation::LightningDecode for #ident_name #ty_generics #where_clause {
+            #[inline]
+            fn lightning_decode<D: ::std::io::Read>(mut d: D) -> Result<Self, #import::lnp::presentation::Error> {
+                use #import::lnp::presentation::LightningDecode;
+
+                Ok(#inner)
+            }
+        }
+    })
+}
+
+/// Kind of the TLV record a structure field is encoded as
+enum TlvField {
+    /// `#[tlv(type = N)]`: field is encoded as a TLV record of type `N`
+    Type(u64),
+    /// `#[tlv(unknown)]`: field keeps TLV records unknown to the structure
+    Unknown,
+}
+
+/// Parses `#[tlv(...)]` attributes of the structure fields, checking that TLV
+/// fields follow all of the ordinary fields (since TLV stream is always the
+/// last part of the message) and that there is not more than a single field
+/// for unknown TLV records
+fn lightning_fields<'a>(
+    fields: impl Iterator<Item = &'a Field>,
+) -> Result<Vec<(&'a Field, Option<TlvField>)>> {
+    let example = "#[tlv(type = 1)] or #[tlv(unknown)]";
+    let mut list = vec![];
+    let mut tlv_met = false;
+    let mut unknown_met = false;
+    for field in fields {
+        let tlv = match attr_list(&field.attrs, "tlv", example)? {
+            None if tlv_met => {
+                return proc_macro_err!(
+                    field,
+                    "TLV fields must follow all other structure fields",
+                    example
+                )
+            }
+            None => None,
+            Some(_) if field.ident.is_none() => return proc_macro_err!(
+                field,
+                "TLV fields are supported only in structures with named fields",
+                example
+            ),
+            Some(args) => match args.as_slice() {
+                [NestedMeta::Meta(Meta::Path(path))]
+                    if path.is_ident("unknown") =>
+                {
+                    if unknown_met {
+                        return proc_macro_err!(
+                            field,
+                            "only a single field may keep unknown TLV records",
+                            example
+                        );
+                    }
+                    unknown_met = true;
+                    Some(TlvField::Unknown)
+                }
+                [NestedMeta::Meta(Meta::NameValue(name_val))]
+                    if name_val.path.is_ident("type") =>
+                {
+                    match &name_val.lit {
+                        Lit::Int(i) => Some(TlvField::Type(
+                            i.base10_parse().or_else(|_| {
+                                proc_macro_err!(
+                                    i,
+                                    "`type` must be an integer",
+                                    example
+                                )
+                            })?,
+                        )),
+                        lit => {
+                            return proc_macro_err!(
+                                lit,
+                                "`type` must be an integer",
+                                example
+                            )
+                        }
+                    }
+                }
+                _ => {
+                    return proc_macro_err!(
+                        field,
+                        "unexpected TLV attribute arguments",
+                        example
+                    )
+                }
+            },
+        };
+        tlv_met |= tlv.is_some();
+        list.push((field, tlv));
+    }
+    Ok(list)
+}
+
+/// Collects `#[cfg(...)]` attributes of a field, so that feature-gated fields
+/// are encoded and decoded only when they are present in the structure
+fn field_cfg_attrs(field: &Field) -> Vec<&Attribute> {
+    field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("cfg"))
+        .collect()
+}
+
 fn get_strict_error(input: &DeriveInput) -> Result<TokenStream2> {
     let import = get_lnpbp_crate(input)?;
 
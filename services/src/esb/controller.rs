@@ -15,7 +15,7 @@ use std::collections::HashMap;
 
 use lnpbp::lnp::presentation::Encode;
 use lnpbp::lnp::rpc_connection::Request;
-use lnpbp::lnp::transport::zmqsocket;
+use lnpbp::lnp::transport::{self, zmqsocket};
 use lnpbp::lnp::{session, PlainTranscoder, Session, Unmarshall, Unmarshaller};
 
 use super::{BusId, Error, ServiceAddress};
@@ -202,13 +202,25 @@ where
                     &locator,
                     self.handler.identity()
                 );
-                let session = session::Raw::with_zmq_unencrypted(
-                    self.api_type,
-                    &locator,
-                    None,
-                    Some(&self.handler.identity().into()),
-                )?;
-                session
+                let identity: Vec<u8> = self.handler.identity().into();
+                match &config.curve {
+                    Some(curve) => session::Raw::with_zmq_curve(
+                        self.api_type,
+                        &locator,
+                        None,
+                        Some(identity.as_slice()),
+                        curve,
+                    )?,
+                    None => session::Raw::with_zmq_unencrypted(
+                        self.api_type,
+                        &locator,
+                        None,
+                        Some(identity.as_slice()),
+                    )?,
+                }
+            }
+            zmqsocket::Carrier::Socket(_) if config.curve.is_some() => {
+                Err(transport::Error::CurveNotApplicable)?
             }
             zmqsocket::Carrier::Socket(socket) => {
                 debug!("Creating ESB session for service {}", &id);
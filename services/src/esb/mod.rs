@@ -17,7 +17,7 @@ pub use controller::{Controller, Handler, SenderList};
 use std::fmt::{Debug, Display};
 use std::hash::Hash;
 
-use lnpbp::lnp::{presentation, transport, zmqsocket};
+use lnpbp::lnp::{presentation, session, transport, zmqsocket};
 
 /// Marker traits for service bus identifiers
 pub trait BusId: Copy + Eq + Hash + Display {}
@@ -31,6 +31,11 @@ where
     /// Indicates whether the messages must be queued, or the send function
     /// must fail immediatelly if the remote point is not avaliable
     pub queued: bool,
+    /// ZMQ CURVE security configuration, which may be used only with the
+    /// buses provided with [`zmqsocket::Carrier::Locator`]; sockets provided
+    /// with [`zmqsocket::Carrier::Socket`] are already bound or connected and
+    /// can't be secured
+    pub curve: Option<zmqsocket::Curve>,
 }
 
 impl<A> BusConfig<A>
@@ -45,6 +50,22 @@ where
             carrier: zmqsocket::Carrier::Locator(locator),
             router,
             queued: false,
+            curve: None,
+        }
+    }
+
+    /// Constructs configuration for the bus located at `locator` and secured
+    /// with ZMQ CURVE according to the `curve` configuration
+    pub fn with_curve(
+        locator: zmqsocket::ZmqSocketAddr,
+        router: Option<A>,
+        curve: zmqsocket::Curve,
+    ) -> Self {
+        Self {
+            carrier: zmqsocket::Carrier::Locator(locator),
+            router,
+            queued: false,
+            curve: Some(curve),
         }
     }
 
@@ -53,6 +74,7 @@ where
             carrier: zmqsocket::Carrier::Socket(socket),
             router,
             queued: false,
+            curve: None,
         }
     }
 }
@@ -98,3 +120,9 @@ impl From<presentation::Error> for Error {
         }
     }
 }
+
+impl From<session::Error> for Error {
+    fn from(err: session::Error) -> Self {
+        presentation::Error::from(err).into()
+    }
+}
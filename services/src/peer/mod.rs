@@ -11,7 +11,10 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use lnpbp::lnp::application::{Messages, PeerReceiver, RecvMessage};
+use lnpbp::lnp::application::supervisor::{KeepaliveConfig, Supervisor};
+use lnpbp::lnp::application::{
+    Messages, PeerConnection, PeerReceiver, RecvMessage,
+};
 use lnpbp::lnp::presentation;
 
 use crate::node::TryService;
@@ -24,6 +27,10 @@ pub trait Handler {
     fn handle(&mut self, message: Messages) -> Result<(), Self::Error>;
 
     fn handle_err(&mut self, error: Self::Error) -> Result<(), Self::Error>;
+
+    /// Function called once the remote peer supervised by [`Supervised`]
+    /// service is considered dead
+    fn peer_dead(&mut self, _reason: presentation::Error) {}
 }
 
 pub struct Listener<H>
@@ -75,3 +82,57 @@ where
         self.handler.handle(msg)
     }
 }
+
+/// Service processing messages from the peer connection under [`Supervisor`]
+/// control, which keeps the connection alive and reports peer death to the
+/// handler with [`Handler::peer_dead`]
+pub struct Supervised<H>
+where
+    H: Handler,
+{
+    supervisor: Supervisor,
+    handler: H,
+}
+
+impl<H> Supervised<H>
+where
+    H: Handler,
+{
+    pub fn with(
+        connection: PeerConnection,
+        config: KeepaliveConfig,
+        handler: H,
+    ) -> Self {
+        Self {
+            supervisor: Supervisor::with(connection, config),
+            handler,
+        }
+    }
+}
+
+impl<H> TryService for Supervised<H>
+where
+    H: Handler,
+{
+    type ErrorType = H::Error;
+
+    fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
+        trace!("Entering event loop of the supervised peer service");
+        loop {
+            let msg = match self.supervisor.recv_message() {
+                Ok(msg) => msg,
+                Err(err) => {
+                    debug!("Peer is dead: {}", err);
+                    self.handler.peer_dead(err);
+                    return Err(err.into());
+                }
+            };
+            debug!("Processing message {}", msg);
+            trace!("Message details: {:?}", msg);
+            if let Err(err) = self.handler.handle(msg) {
+                trace!("Peer message processing generated {:?}", err);
+                self.handler.handle_err(err)?;
+            }
+        }
+    }
+}
@@ -15,7 +15,7 @@ use std::collections::HashMap;
 
 use lnpbp::lnp::presentation::Encode;
 use lnpbp::lnp::rpc_connection::Api;
-use lnpbp::lnp::transport::zmqsocket::{ZmqSocketAddr, ZmqType};
+use lnpbp::lnp::transport::zmqsocket::{Curve, ZmqSocketAddr, ZmqType};
 use lnpbp::lnp::{
     session, transport, CreateUnmarshaller, PlainTranscoder, Session,
     Unmarshall, Unmarshaller,
@@ -62,6 +62,32 @@ where
         })
     }
 
+    /// Constructs RPC client connecting to each of the endpoints with ZMQ
+    /// CURVE encryption and authentication; each endpoint is provided with
+    /// its own CURVE configuration, which must use client role
+    pub fn with_curve(
+        endpoints: HashMap<E, (ZmqSocketAddr, Curve)>,
+    ) -> Result<Self, transport::Error> {
+        let mut sessions: HashMap<E, session::Raw<_, _>> = none!();
+        for (service, (endpoint, curve)) in endpoints {
+            sessions.insert(
+                service,
+                session::Raw::with_zmq_curve(
+                    ZmqType::Req,
+                    &endpoint,
+                    None,
+                    None,
+                    &curve,
+                )?,
+            );
+        }
+        let unmarshaller = A::Reply::create_unmarshaller();
+        Ok(Self {
+            sessions,
+            unmarshaller,
+        })
+    }
+
     pub fn request(
         &mut self,
         endpoint: E,
@@ -78,6 +78,12 @@ impl From<lnp::presentation::Error> for Error {
     }
 }
 
+impl From<lnp::session::Error> for Error {
+    fn from(err: lnp::session::Error) -> Self {
+        lnp::presentation::Error::from(err).into()
+    }
+}
+
 impl From<lnp::presentation::Error> for Failure {
     fn from(err: lnp::presentation::Error) -> Self {
         Failure {
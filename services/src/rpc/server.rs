@@ -74,6 +74,28 @@ where
     pub fn with(
         endpoints: HashMap<E, zmqsocket::Carrier>,
         handler: H,
+    ) -> Result<Self, transport::Error> {
+        Self::setup(endpoints, None, handler)
+    }
+
+    /// Constructs RPC server accepting only ZMQ CURVE-encrypted connections
+    /// according to the `curve` configuration, which must use server role.
+    /// All endpoints must be provided as [`zmqsocket::Carrier::Locator`]:
+    /// sockets provided as [`zmqsocket::Carrier::Socket`] are already bound
+    /// and can't be secured, so the function fails with
+    /// [`transport::Error::CurveNotApplicable`] for them.
+    pub fn with_curve(
+        endpoints: HashMap<E, zmqsocket::Carrier>,
+        curve: zmqsocket::Curve,
+        handler: H,
+    ) -> Result<Self, transport::Error> {
+        Self::setup(endpoints, Some(curve), handler)
+    }
+
+    fn setup(
+        endpoints: HashMap<E, zmqsocket::Carrier>,
+        curve: Option<zmqsocket::Curve>,
+        handler: H,
     ) -> Result<Self, transport::Error> {
         let mut sessions: HashMap<E, session::Raw<_, _>> = none!();
         for (endpoint, carrier) in endpoints {
@@ -86,12 +108,24 @@ where
                             &endpoint,
                             &locator
                         );
-                        session::Raw::with_zmq_unencrypted(
-                            zmqsocket::ZmqType::Rep,
-                            &locator,
-                            None,
-                            None,
-                        )?
+                        match &curve {
+                            Some(curve) => session::Raw::with_zmq_curve(
+                                zmqsocket::ZmqType::Rep,
+                                &locator,
+                                None,
+                                None,
+                                curve,
+                            )?,
+                            None => session::Raw::with_zmq_unencrypted(
+                                zmqsocket::ZmqType::Rep,
+                                &locator,
+                                None,
+                                None,
+                            )?,
+                        }
+                    }
+                    zmqsocket::Carrier::Socket(_) if curve.is_some() => {
+                        Err(transport::Error::CurveNotApplicable)?
                     }
                     zmqsocket::Carrier::Socket(socket) => {
                         debug!(
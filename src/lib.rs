@@ -36,6 +36,7 @@ extern crate lazy_static;
 #[macro_use]
 extern crate num_derive;
 
+extern crate chacha20;
 extern crate chacha20poly1305;
 
 // Support for node & node clients development (include API helpers)
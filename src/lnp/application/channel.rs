@@ -11,15 +11,21 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::collections::BTreeMap;
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::io;
+use std::rc::Rc;
 
+use amplify::DumbDefault;
 use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
-use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::{OutPoint, SigHashType, Transaction, TxIn, TxOut, Txid};
 
 use super::extension::{self, ChannelExtension, Extension};
 use super::Messages;
+use crate::strict_encoding::{self, StrictDecode, StrictEncode};
 
 #[derive(
     Clone,
@@ -109,6 +115,119 @@ where
             ),
         }
     }
+
+    /// Adds extender extension to the channel, replacing previously added
+    /// extender with the same identity. Allows to compose channel of
+    /// extenders of different types.
+    pub fn add_extender(
+        &mut self,
+        extender: impl ChannelExtension<Identity = N> + 'static,
+    ) {
+        self.extenders
+            .insert(extender.identity(), Box::new(extender));
+    }
+}
+
+impl<N> Channel<N>
+where
+    N: extension::Nomenclature,
+{
+    /// Strictly encodes extension data prefixed with the extension identity
+    fn strict_encode_entry(
+        extension: &dyn ChannelExtension<Identity = N>,
+        mut e: &mut dyn io::Write,
+    ) -> Result<usize, strict_encoding::Error> {
+        let id: u16 = extension.identity().into();
+        Ok(id.strict_encode(&mut e)? + extension.strict_encode_extension(e)?)
+    }
+
+    /// Strictly decodes extension data prefixed with the extension identity
+    fn strict_decode_entry(
+        mut d: &mut dyn io::Read,
+        shared: &mut N::Shared,
+    ) -> Result<Box<dyn ChannelExtension<Identity = N>>, strict_encoding::Error>
+    where
+        N: ExtensionDecoder,
+    {
+        let id = N::try_from(u16::strict_decode(&mut d)?)?;
+        id.strict_decode_extension(d, shared)
+    }
+}
+
+/// Extension nomenclature which is able to restore channel extensions from
+/// their strict encoding, as required for the [`Channel`] strict decoding
+pub trait ExtensionDecoder: extension::Nomenclature {
+    /// Handles to the restored extensions which are shared with other
+    /// extensions of the same channel
+    type Shared: Default;
+
+    /// Decodes data of the extension with this identity, keeping the handle
+    /// to it in `shared` if other extensions may read its state
+    fn strict_decode_extension(
+        self,
+        d: &mut dyn io::Read,
+        shared: &mut Self::Shared,
+    ) -> Result<
+        Box<dyn ChannelExtension<Identity = Self>>,
+        strict_encoding::Error,
+    >;
+
+    /// Connects restored extensions to the extensions they share state with.
+    /// Called once all channel extensions are decoded.
+    fn connect(shared: Self::Shared) -> Result<(), strict_encoding::Error>;
+}
+
+impl<N> StrictEncode for Channel<N>
+where
+    N: extension::Nomenclature,
+{
+    type Error = strict_encoding::Error;
+
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, Self::Error> {
+        let mut len =
+            Self::strict_encode_entry(self.constructor.as_ref(), &mut e)?;
+        for queue in &[&self.extenders, &self.modifiers] {
+            len += queue.len().strict_encode(&mut e)?;
+            for extension in queue.values() {
+                len += Self::strict_encode_entry(extension.as_ref(), &mut e)?;
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<N> StrictDecode for Channel<N>
+where
+    N: ExtensionDecoder,
+{
+    type Error = strict_encoding::Error;
+
+    fn strict_decode<D: io::Read>(mut d: D) -> Result<Self, Self::Error> {
+        let mut shared = N::Shared::default();
+        let constructor = Self::strict_decode_entry(&mut d, &mut shared)?;
+        let mut extenders = ExtensionQueue::<N>::new();
+        for _ in 0..usize::strict_decode(&mut d)? {
+            let extender = Self::strict_decode_entry(&mut d, &mut shared)?;
+            extenders.insert(extender.identity(), extender);
+        }
+        let mut modifiers = ExtensionQueue::<N>::new();
+        for _ in 0..usize::strict_decode(&mut d)? {
+            let modifier = Self::strict_decode_entry(&mut d, &mut shared)?;
+            modifiers.insert(modifier.identity(), modifier);
+        }
+
+        // Shared handles can be reconnected only once all extensions which
+        // may be shared are restored
+        N::connect(shared)?;
+        Ok(Self {
+            constructor,
+            extenders,
+            modifiers,
+        })
+    }
 }
 
 /// Channel is the extension to itself :) so it receives the same input as any
@@ -134,6 +253,17 @@ where
         Ok(())
     }
 
+    fn update_from_local(&mut self, data: &Messages) -> Result<(), Error> {
+        self.constructor.update_from_local(data)?;
+        self.extenders
+            .iter_mut()
+            .try_for_each(|(_, e)| e.update_from_local(data))?;
+        self.modifiers
+            .iter_mut()
+            .try_for_each(|(_, e)| e.update_from_local(data))?;
+        Ok(())
+    }
+
     fn extension_state(&self) -> Box<dyn State> {
         let mut data = IntegralState::<N>::new();
         data.insert(
@@ -179,8 +309,135 @@ where
         self.modifiers
             .iter_mut()
             .try_for_each(|(_, e)| e.apply(tx_graph))?;
+        tx_graph.link_cmt_outs();
         Ok(())
     }
+
+    fn strict_encode_extension(
+        &self,
+        e: &mut dyn io::Write,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.strict_encode(e)
+    }
+}
+
+/// Handle to the channel extension shared with other extensions, which read
+/// its state instead of maintaining their own copies of it.
+///
+/// The handle is added to the channel, which updates the extension and
+/// applies it to the transaction graph; other extensions keep clones of the
+/// handle referring to the same extension. Since the channel updates and
+/// applies its constructor before the extenders, and the extenders in the
+/// order of their identities, the shared state is always up to date when it
+/// is read by the extensions which follow it.
+///
+/// Strict encoding of the handle covers only the extension itself, and
+/// extensions keeping clones of the handle do not encode them. Strict decoding
+/// creates a new extension, which is not shared with any other one until
+/// [`Channel`] decoding reconnects them with [`ExtensionDecoder::connect`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Shared<E>(Rc<RefCell<E>>);
+
+impl<E> Shared<E> {
+    /// Constructs handle for the `extension`, which is not yet shared
+    #[inline]
+    pub fn new(extension: E) -> Self {
+        Shared(Rc::new(RefCell::new(extension)))
+    }
+
+    /// Immutably borrows the shared extension
+    ///
+    /// # Panics
+    ///
+    /// Panics if the extension is being updated or applied
+    #[inline]
+    pub fn borrow(&self) -> Ref<E> {
+        self.0.borrow()
+    }
+
+    /// Mutably borrows the shared extension
+    ///
+    /// # Panics
+    ///
+    /// Panics if the extension is currently borrowed
+    #[inline]
+    pub fn borrow_mut(&self) -> RefMut<E> {
+        self.0.borrow_mut()
+    }
+}
+
+impl<E> DumbDefault for Shared<E>
+where
+    E: DumbDefault,
+{
+    fn dumb_default() -> Self {
+        Shared::new(E::dumb_default())
+    }
+}
+
+impl<E> StrictEncode for Shared<E>
+where
+    E: StrictEncode,
+{
+    type Error = E::Error;
+
+    fn strict_encode<W: io::Write>(&self, e: W) -> Result<usize, Self::Error> {
+        self.borrow().strict_encode(e)
+    }
+}
+
+impl<E> StrictDecode for Shared<E>
+where
+    E: StrictDecode,
+{
+    type Error = E::Error;
+
+    fn strict_decode<D: io::Read>(d: D) -> Result<Self, Self::Error> {
+        Ok(Shared::new(E::strict_decode(d)?))
+    }
+}
+
+impl<E> Extension for Shared<E>
+where
+    E: Extension,
+{
+    type Identity = E::Identity;
+
+    fn identity(&self) -> Self::Identity {
+        self.borrow().identity()
+    }
+
+    fn update_from_peer(&mut self, data: &Messages) -> Result<(), Error> {
+        self.borrow_mut().update_from_peer(data)
+    }
+
+    fn update_from_local(&mut self, data: &Messages) -> Result<(), Error> {
+        self.borrow_mut().update_from_local(data)
+    }
+
+    fn extension_state(&self) -> Box<dyn State> {
+        self.borrow().extension_state()
+    }
+}
+
+impl<E> ChannelExtension for Shared<E>
+where
+    E: ChannelExtension,
+{
+    fn channel_state(&self) -> Box<dyn State> {
+        self.borrow().channel_state()
+    }
+
+    fn apply(&mut self, tx_graph: &mut TxGraph) -> Result<(), Error> {
+        self.borrow_mut().apply(tx_graph)
+    }
+
+    fn strict_encode_extension(
+        &self,
+        e: &mut dyn io::Write,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.borrow().strict_encode_extension(e)
+    }
 }
 
 pub trait TxRole: Clone + From<u16> + Into<u16> {}
@@ -200,11 +457,15 @@ pub struct TxGraph {
     funding_parties: u8,
     funding_threshold: u8,
     funding_tx: Psbt,
-    funding_outpoint: OutPoint,
+    pub funding_outpoint: OutPoint,
     pub cmt_version: i32,
     pub cmt_locktime: u32,
     pub cmt_sequence: u32,
     pub cmt_outs: Vec<TxOut>,
+    /// Consensus value of the signature hash type used by the remote party
+    /// for signing transactions spending commitment transaction outputs,
+    /// which may differ from the local one specified in the PSBT inputs
+    remote_sighash: u32,
     graph: BTreeMap<u16, BTreeMap<u64, Psbt>>,
 }
 
@@ -229,6 +490,89 @@ impl TxGraph {
             .and_then(|v| v.get_mut(&index.into()))
     }
 
+    /// Inserts transaction into the graph under the given `role` and `index`,
+    /// returning the transaction which was previously stored there, if any
+    pub fn insert_tx<R, I>(
+        &mut self,
+        role: R,
+        index: I,
+        psbt: Psbt,
+    ) -> Option<Psbt>
+    where
+        R: TxRole,
+        I: TxIndex,
+    {
+        self.graph
+            .entry(role.into())
+            .or_insert_with(BTreeMap::new)
+            .insert(index.into(), psbt)
+    }
+
+    /// Returns signature hash type used by the remote party for signing
+    /// transactions spending commitment transaction outputs
+    #[inline]
+    pub fn remote_sighash_type(&self) -> SigHashType {
+        SigHashType::from_u32(self.remote_sighash)
+    }
+
+    /// Sets signature hash type used by the remote party for signing
+    /// transactions spending commitment transaction outputs
+    #[inline]
+    pub fn set_remote_sighash_type(&mut self, sighash_type: SigHashType) {
+        self.remote_sighash = sighash_type.as_u32();
+    }
+
+    /// Returns number of transactions with the given `role` in the graph
+    pub fn role_count<R>(&self, role: R) -> usize
+    where
+        R: TxRole,
+    {
+        self.graph.get(&role.into()).map(BTreeMap::len).unwrap_or(0)
+    }
+
+    /// Removes all transactions with the given `role` from the graph
+    pub fn remove_role<R>(&mut self, role: R) -> Option<BTreeMap<u64, Psbt>>
+    where
+        R: TxRole,
+    {
+        self.graph.remove(&role.into())
+    }
+
+    /// Links inputs of the graph transactions spending commitment transaction
+    /// outputs to the final commitment transaction.
+    ///
+    /// Extensions adding such transactions to the graph do not know the final
+    /// commitment transaction id and output order, since they may be changed
+    /// by the extensions applied later. So they use [`Txid::default()`] as
+    /// the input transaction id and provide the spent output as the input
+    /// `witness_utxo`. This function replaces these inputs with the outpoints
+    /// of the matching commitment transaction outputs; it must be called
+    /// after all channel extensions were applied.
+    pub fn link_cmt_outs(&mut self) {
+        let cmt_txid = self.render_cmt().global.unsigned_tx.txid();
+        let mut used = BTreeSet::<u32>::new();
+        let cmt_outs = &self.cmt_outs;
+        for psbt in self.graph.values_mut().flat_map(|map| map.values_mut()) {
+            let Psbt { global, inputs, .. } = psbt;
+            for (txin, input) in
+                global.unsigned_tx.input.iter_mut().zip(inputs.iter())
+            {
+                if txin.previous_output.txid != Txid::default() {
+                    continue;
+                }
+                let vout = (0..cmt_outs.len() as u32).find(|vout| {
+                    !used.contains(vout)
+                        && Some(&cmt_outs[*vout as usize])
+                            == input.witness_utxo.as_ref()
+                });
+                if let Some(vout) = vout {
+                    used.insert(vout);
+                    txin.previous_output = OutPoint::new(cmt_txid, vout);
+                }
+            }
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.graph
             .iter()
@@ -294,6 +638,7 @@ impl Default for TxGraph {
             cmt_locktime: 0,
             cmt_sequence: 0,
             cmt_outs: none!(),
+            remote_sighash: SigHashType::All.as_u32(),
             graph: empty!(),
         }
     }
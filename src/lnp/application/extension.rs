@@ -14,6 +14,7 @@
 use std::convert::TryFrom;
 use std::fmt::{Debug, Display};
 use std::hash::Hash;
+use std::io;
 
 use super::channel;
 use crate::lnp::application::Messages;
@@ -46,13 +47,22 @@ pub trait Extension {
 
     fn identity(&self) -> Self::Identity;
 
-    /// Updates extension state from the data takend from the message received
+    /// Updates extension state from the data taken from the message received
     /// from the remote peer
     fn update_from_peer(
         &mut self,
         data: &Messages,
     ) -> Result<(), channel::Error>;
 
+    /// Updates extension state from the data taken from the message sent by
+    /// the local node to the remote peer
+    fn update_from_local(
+        &mut self,
+        _data: &Messages,
+    ) -> Result<(), channel::Error> {
+        Ok(())
+    }
+
     /// Returns extension state for persistence & backups
     ///
     /// These are extension configuration data, like the data that are the part
@@ -77,4 +87,13 @@ pub trait ChannelExtension: Extension {
         &mut self,
         tx_graph: &mut channel::TxGraph,
     ) -> Result<(), channel::Error>;
+
+    /// Strictly encodes extension data for the channel persistence. Handles
+    /// to the extensions shared with this one are not encoded: they are
+    /// reconnected by [`channel::ExtensionDecoder`] once the whole channel is
+    /// decoded
+    fn strict_encode_extension(
+        &self,
+        e: &mut dyn io::Write,
+    ) -> Result<usize, strict_encoding::Error>;
 }
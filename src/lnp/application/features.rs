@@ -14,8 +14,9 @@
 use std::collections::HashSet;
 use std::io;
 
+use crate::lnp::presentation::{self, LightningDecode, LightningEncode};
 use crate::paradigms::strict_encoding::{self, StrictDecode, StrictEncode};
-use crate::standards::features::FlagVec;
+use crate::standards::features::{FlagNo, FlagVec};
 
 /// Some features don't make sense on a per-channels or per-node basis, so each
 /// feature defines how it is presented in those contexts. Some features may be
@@ -25,7 +26,7 @@ use crate::standards::features::FlagVec;
 ///
 /// # Specification
 /// <https://github.com/lightningnetwork/lightning-rfc/blob/master/09-features.md#bolt-9-assigned-feature-flags>
-#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display)]
 pub enum FeatureContext {
     /// `I`: presented in the init message.
     // TODO: Add `alt = doc_comments` when `amplify` crate will support it
@@ -53,12 +54,147 @@ pub struct FeatureFlag {
     pub required: bool,
 }
 
+impl FeatureFlag {
+    fn with(info: &FeatureInfo, required: bool) -> Self {
+        Self {
+            context: info.contexts.iter().copied().collect(),
+            global: false,
+            required,
+        }
+    }
+}
+
+/// Errors in the feature flags set or feature negotiation
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum FeatureError {
+    /// feature `{0}` requires feature `{1}`, which is not set
+    MissingDependency(&'static str, &'static str),
+
+    /// unknown feature with even (required) bit {0}
+    UnknownRequired(FlagNo),
+
+    /// feature `{0}` can't be presented in `{1}` context
+    ContextMismatch(&'static str, FeatureContext),
+
+    /// remote peer requires feature `{0}`, which is not supported locally
+    RequiredByRemote(&'static str),
+
+    /// feature `{0}` is required locally, but is not supported by the remote
+    /// peer
+    NotSupportedByRemote(&'static str),
+}
+
+/// BOLT-9 metadata for the features known to the current implementation
+struct FeatureInfo {
+    name: &'static str,
+    /// Bit used if the feature is required; `None` for the features which can
+    /// be only optional
+    required_bit: Option<FlagNo>,
+    optional_bit: FlagNo,
+    contexts: &'static [FeatureContext],
+    requires: Option<&'static str>,
+}
+
+const IN: &[FeatureContext] =
+    &[FeatureContext::Init, FeatureContext::NodeAnnouncement];
+const IN9: &[FeatureContext] = &[
+    FeatureContext::Init,
+    FeatureContext::NodeAnnouncement,
+    FeatureContext::Bolt11Invoice,
+];
+
+/// Known features in the order of [`Features`] fields
+const KNOWN_FEATURES: [FeatureInfo; 11] = [
+    FeatureInfo {
+        name: "option_data_loss_protect",
+        required_bit: Some(0),
+        optional_bit: 1,
+        contexts: IN,
+        requires: None,
+    },
+    FeatureInfo {
+        name: "initial_routing_sync",
+        required_bit: None,
+        optional_bit: 3,
+        contexts: &[FeatureContext::Init],
+        requires: None,
+    },
+    FeatureInfo {
+        name: "option_upfront_shutdown_script",
+        required_bit: Some(4),
+        optional_bit: 5,
+        contexts: IN,
+        requires: None,
+    },
+    FeatureInfo {
+        name: "gossip_queries",
+        required_bit: Some(6),
+        optional_bit: 7,
+        contexts: IN,
+        requires: None,
+    },
+    FeatureInfo {
+        name: "var_onion_optin",
+        required_bit: Some(8),
+        optional_bit: 9,
+        contexts: IN9,
+        requires: None,
+    },
+    FeatureInfo {
+        name: "gossip_queries_ex",
+        required_bit: Some(10),
+        optional_bit: 11,
+        contexts: IN,
+        requires: Some("gossip_queries"),
+    },
+    FeatureInfo {
+        name: "option_static_remotekey",
+        required_bit: Some(12),
+        optional_bit: 13,
+        contexts: IN,
+        requires: None,
+    },
+    FeatureInfo {
+        name: "payment_secret",
+        required_bit: Some(14),
+        optional_bit: 15,
+        contexts: IN9,
+        requires: Some("var_onion_optin"),
+    },
+    FeatureInfo {
+        name: "basic_mpp",
+        required_bit: Some(16),
+        optional_bit: 17,
+        contexts: IN9,
+        requires: Some("payment_secret"),
+    },
+    FeatureInfo {
+        name: "option_support_large_channel",
+        required_bit: Some(18),
+        optional_bit: 19,
+        contexts: IN,
+        requires: None,
+    },
+    FeatureInfo {
+        name: "option_anchor_outputs",
+        required_bit: Some(20),
+        optional_bit: 21,
+        contexts: IN,
+        requires: Some("option_static_remotekey"),
+    },
+];
+
 /// Flags are numbered from the least-significant bit, at bit 0 (i.e. 0x1, an
 /// even bit). They are generally assigned in pairs so that features can be
 /// introduced as optional (odd bits) and later upgraded to be compulsory (even
 /// bits), which will be refused by outdated nodes: see BOLT #1: The init
 /// Message.
 ///
+/// Known features are represented by the fields set to `Some` value if the
+/// feature is supported; [`FeatureFlag::required`] tells whether the feature
+/// is presented with the even (required) bit.
+///
 /// # Specification
 /// <https://github.com/lightningnetwork/lightning-rfc/blob/master/09-features.md>
 #[derive(Clone, PartialEq, Eq, Debug, Display, Default)]
@@ -66,57 +202,214 @@ pub struct FeatureFlag {
 pub struct Features {
     /// Requires or supports extra `channel_reestablish` fields
     // #[lnpwp_feature(0, 1)]
-    pub option_data_loss_protect: FeatureFlag,
+    pub option_data_loss_protect: Option<FeatureFlag>,
 
     /// Sending node needs a complete routing information dump
     // #[lnpwp_feature(3)]
-    pub initial_routing_sync: FeatureFlag,
+    pub initial_routing_sync: Option<FeatureFlag>,
 
     /// Commits to a shutdown scriptpubkey when opening channel
     // #[lnpwp_feature(4, 5)]
-    pub option_upfront_sutdown_script: FeatureFlag,
+    pub option_upfront_sutdown_script: Option<FeatureFlag>,
 
     /// More sophisticated gossip control
     // #[lnpwp_feature(6, 7)]
-    pub gossip_queries: FeatureFlag,
+    pub gossip_queries: Option<FeatureFlag>,
 
     /// Requires/supports variable-length routing onion payloads
     // #[lnpwp_feature(8, 9)]
-    pub var_onion_optin: FeatureFlag,
+    pub var_onion_optin: Option<FeatureFlag>,
 
     /// Gossip queries can include additional information
     // #[lnpwp_feature(10, 11, requires(gossip_queries))]
-    pub gossip_queries_ex: FeatureFlag,
+    pub gossip_queries_ex: Option<FeatureFlag>,
 
     /// Static key for remote output
     // #[lnpwp_feature(12, 13)]
-    pub option_static_remotekey: FeatureFlag,
+    pub option_static_remotekey: Option<FeatureFlag>,
 
     /// Node supports `payment_secret` field
     // #[lnpwp_feature(14, 15, requires(var_onion_optin))]
-    pub payment_secret: FeatureFlag,
+    pub payment_secret: Option<FeatureFlag>,
 
     /// Node can receive basic multi-part payments
     // #[lnpwp_feature(16, 17, requires(payment_secret))]
-    pub basic_mpp: FeatureFlag,
+    pub basic_mpp: Option<FeatureFlag>,
 
     /// Can create large channels
     // #[lnpwp_feature(18, 19)]
-    pub option_support_large_channel: FeatureFlag,
+    pub option_support_large_channel: Option<FeatureFlag>,
 
     /// Anchor outputs
     // #[lnpwp_feature(20, 21, requires(option_static_remotekey))]
-    pub option_anchor_outputs: FeatureFlag,
+    pub option_anchor_outputs: Option<FeatureFlag>,
 
     /// Rest of feature flags which are unknown to the current implementation
     pub unknown: FlagVec,
 }
 
+impl Features {
+    /// Returns known feature fields in the order of [`KNOWN_FEATURES`]
+    fn known(&self) -> [&Option<FeatureFlag>; 11] {
+        [
+            &self.option_data_loss_protect,
+            &self.initial_routing_sync,
+            &self.option_upfront_sutdown_script,
+            &self.gossip_queries,
+            &self.var_onion_optin,
+            &self.gossip_queries_ex,
+            &self.option_static_remotekey,
+            &self.payment_secret,
+            &self.basic_mpp,
+            &self.option_support_large_channel,
+            &self.option_anchor_outputs,
+        ]
+    }
+
+    /// Returns mutable known feature fields in the order of
+    /// [`KNOWN_FEATURES`]
+    fn known_mut(&mut self) -> [&mut Option<FeatureFlag>; 11] {
+        [
+            &mut self.option_data_loss_protect,
+            &mut self.initial_routing_sync,
+            &mut self.option_upfront_sutdown_script,
+            &mut self.gossip_queries,
+            &mut self.var_onion_optin,
+            &mut self.gossip_queries_ex,
+            &mut self.option_static_remotekey,
+            &mut self.payment_secret,
+            &mut self.basic_mpp,
+            &mut self.option_support_large_channel,
+            &mut self.option_anchor_outputs,
+        ]
+    }
+
+    fn is_known_set(&self, name: &str) -> bool {
+        KNOWN_FEATURES
+            .iter()
+            .zip(self.known().iter())
+            .any(|(info, flag)| info.name == name && flag.is_some())
+    }
+
+    /// Checks feature set for the use in the given `context`, returning all
+    /// found issues: known features which can't be presented in the context,
+    /// features with missing dependencies and unknown even (required) bits
+    pub fn check(&self, context: FeatureContext) -> Vec<FeatureError> {
+        let mut errors = vec![];
+        for (info, flag) in KNOWN_FEATURES.iter().zip(self.known().iter()) {
+            if flag.is_none() {
+                continue;
+            }
+            if !info.contexts.contains(&context) {
+                errors.push(FeatureError::ContextMismatch(info.name, context));
+            }
+            if let Some(dependency) = info.requires {
+                if !self.is_known_set(dependency) {
+                    errors.push(FeatureError::MissingDependency(
+                        info.name, dependency,
+                    ));
+                }
+            }
+        }
+        errors.extend(
+            self.unknown
+                .iter()
+                .filter(|flag_no| flag_no % 2 == 0)
+                .map(FeatureError::UnknownRequired),
+        );
+        errors
+    }
+
+    /// Validates feature set for the use in the given `context`, returning
+    /// the first issue found by [`Features::check`]
+    pub fn validate(
+        &self,
+        context: FeatureContext,
+    ) -> Result<(), FeatureError> {
+        match self.check(context).into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Negotiates features with the remote peer, returning the features
+    /// supported by both sides. A negotiated feature is required if any of
+    /// the sides requires it. Fails if one of the sides requires a feature
+    /// which is not supported by the other side.
+    pub fn negotiate(
+        &self,
+        remote: &Features,
+    ) -> Result<Features, FeatureError> {
+        if let Some(flag_no) =
+            remote.unknown.iter().find(|flag_no| flag_no % 2 == 0)
+        {
+            Err(FeatureError::UnknownRequired(flag_no))?
+        }
+        let mut negotiated = Features::default();
+        for (index, info) in KNOWN_FEATURES.iter().enumerate() {
+            let flag = match (self.known()[index], remote.known()[index]) {
+                (Some(ours), Some(theirs)) => Some(FeatureFlag::with(
+                    info,
+                    ours.required || theirs.required,
+                )),
+                (None, Some(theirs)) if theirs.required => {
+                    Err(FeatureError::RequiredByRemote(info.name))?
+                }
+                (Some(ours), None) if ours.required => {
+                    Err(FeatureError::NotSupportedByRemote(info.name))?
+                }
+                _ => None,
+            };
+            *negotiated.known_mut()[index] = flag;
+        }
+        Ok(negotiated)
+    }
+}
+
+impl From<FlagVec> for Features {
+    fn from(mut flags: FlagVec) -> Self {
+        let mut features = Features::default();
+        for (info, field) in
+            KNOWN_FEATURES.iter().zip(features.known_mut().iter_mut())
+        {
+            let required = match info.required_bit {
+                Some(flag_no) if flags.is_set(flag_no) => flags.unset(flag_no),
+                _ => false,
+            };
+            let optional = flags.is_set(info.optional_bit)
+                && flags.unset(info.optional_bit);
+            if required || optional {
+                **field = Some(FeatureFlag::with(info, required));
+            }
+        }
+        flags.shrink();
+        features.unknown = flags;
+        features
+    }
+}
+
+impl From<&Features> for FlagVec {
+    fn from(features: &Features) -> Self {
+        let mut flags = features.unknown.clone();
+        for (info, flag) in KNOWN_FEATURES.iter().zip(features.known().iter()) {
+            match flag {
+                Some(flag) if flag.required => {
+                    flags.set(info.required_bit.unwrap_or(info.optional_bit))
+                }
+                Some(_) => flags.set(info.optional_bit),
+                None => false,
+            };
+        }
+        flags.shrink();
+        flags
+    }
+}
+
 impl StrictEncode for Features {
     type Error = strict_encoding::Error;
 
     fn strict_encode<E: io::Write>(&self, e: E) -> Result<usize, Self::Error> {
-        Ok(0)
+        FlagVec::from(self).strict_encode(e)
     }
 }
 
@@ -124,6 +417,137 @@ impl StrictDecode for Features {
     type Error = strict_encoding::Error;
 
     fn strict_decode<D: io::Read>(d: D) -> Result<Self, Self::Error> {
-        Ok(none!())
+        Ok(Features::from(FlagVec::strict_decode(d)?))
+    }
+}
+
+impl LightningEncode for Features {
+    fn lightning_encode<E: io::Write>(
+        &self,
+        e: E,
+    ) -> Result<usize, presentation::Error> {
+        FlagVec::from(self).lightning_encode(e)
+    }
+}
+
+impl LightningDecode for Features {
+    fn lightning_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, presentation::Error> {
+        Ok(Features::from(FlagVec::lightning_decode(d)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lnp::presentation::lightning_encoding::{
+        lightning_decode, lightning_encode,
+    };
+
+    fn flags(set: &[FlagNo]) -> FlagVec {
+        let mut flags = FlagVec::new();
+        for flag_no in set {
+            flags.set(*flag_no);
+        }
+        flags
+    }
+
+    #[test]
+    fn test_flag_vec_conversion() {
+        let features = Features::from(flags(&[0, 3, 9, 14, 23, 41]));
+        assert_eq!(
+            features
+                .option_data_loss_protect
+                .as_ref()
+                .map(|f| f.required),
+            Some(true)
+        );
+        assert_eq!(
+            features.initial_routing_sync.as_ref().map(|f| f.required),
+            Some(false)
+        );
+        assert_eq!(
+            features.var_onion_optin.as_ref().map(|f| f.required),
+            Some(false)
+        );
+        assert_eq!(
+            features.payment_secret.as_ref().map(|f| f.required),
+            Some(true)
+        );
+        assert_eq!(features.basic_mpp, None);
+        assert_eq!(features.unknown, flags(&[23, 41]));
+        assert_eq!(FlagVec::from(&features), flags(&[0, 3, 9, 14, 23, 41]));
+
+        // Feature set with both bits is treated as required
+        let features = Features::from(flags(&[12, 13]));
+        assert!(features.option_static_remotekey.unwrap().required);
+    }
+
+    #[test]
+    fn test_lightning_encoding() {
+        let features = Features::from(flags(&[1, 9, 17]));
+        let data = lightning_encode(&features).unwrap();
+        assert_eq!(data, vec![0x00, 0x03, 0x02, 0x02, 0x02]);
+        assert_eq!(lightning_decode::<Features>(&data).unwrap(), features);
+        let data = strict_encoding::strict_encode(&features).unwrap();
+        assert_eq!(
+            strict_encoding::strict_decode::<Features>(&data).unwrap(),
+            features
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let features = Features::from(flags(&[7, 11, 13, 21]));
+        assert_eq!(features.validate(FeatureContext::Init), Ok(()));
+
+        let features = Features::from(flags(&[3, 17, 20, 24, 25]));
+        assert_eq!(
+            features.check(FeatureContext::NodeAnnouncement),
+            vec![
+                FeatureError::ContextMismatch(
+                    "initial_routing_sync",
+                    FeatureContext::NodeAnnouncement
+                ),
+                FeatureError::MissingDependency("basic_mpp", "payment_secret"),
+                FeatureError::MissingDependency(
+                    "option_anchor_outputs",
+                    "option_static_remotekey"
+                ),
+                FeatureError::UnknownRequired(24),
+            ]
+        );
+        assert_eq!(
+            features.validate(FeatureContext::Init),
+            Err(FeatureError::MissingDependency(
+                "basic_mpp",
+                "payment_secret"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let local = Features::from(flags(&[1, 6, 9, 13, 15]));
+        let remote = Features::from(flags(&[0, 7, 9, 19, 35]));
+        let negotiated = local.negotiate(&remote).unwrap();
+        assert_eq!(FlagVec::from(&negotiated), flags(&[0, 6, 9]));
+        assert_eq!(negotiated.unknown, FlagVec::new());
+
+        assert_eq!(
+            local.negotiate(&Features::from(flags(&[1, 7, 18]))),
+            Err(FeatureError::RequiredByRemote(
+                "option_support_large_channel"
+            ))
+        );
+        assert_eq!(
+            local.negotiate(&Features::from(flags(&[1, 34]))),
+            Err(FeatureError::UnknownRequired(34))
+        );
+        assert_eq!(
+            local.negotiate(&Features::from(flags(&[1]))),
+            Err(FeatureError::NotSupportedByRemote("gossip_queries"))
+        );
     }
 }
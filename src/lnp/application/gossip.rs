@@ -0,0 +1,1117 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! BOLT-7 gossip messages used for P2P node and channel discovery, together
+//! with the verification of node and bitcoin key signatures over them.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+#[cfg(feature = "tor")]
+use std::str::FromStr;
+
+use amplify::internet::{InetAddr, InetSocketAddr};
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::secp256k1::{self, PublicKey, Signature};
+#[cfg(feature = "tor")]
+use torut::onion::{OnionAddressV3, TorPublicKeyV3};
+
+use super::payment::ChannelId;
+use super::Features;
+use crate::bp::chain::AssetId;
+use crate::bp::ShortId;
+use crate::lnp::presentation::lightning_encoding::lightning_encode;
+use crate::lnp::presentation::{self, LightningDecode, LightningEncode};
+use crate::lnp::session::{NodeAddr, RemoteNodeAddr};
+use crate::lnp::transport::RemoteSocketAddr;
+use crate::SECP256K1;
+
+/// Errors happening during verification of the gossip message signatures
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum VerificationError {
+    /// unable to serialize the message for signature verification: {0}
+    #[from]
+    Encoding(presentation::Error),
+
+    /// signature does not match the node public key
+    InvalidNodeSignature,
+
+    /// signature does not match the bitcoin public key of the channel
+    /// funding output
+    InvalidBitcoinSignature,
+
+    /// announcement signatures refer to a different channel
+    ChannelMismatch,
+}
+
+/// Verifies `signature` over the double-SHA256 `digest` with `pubkey`
+fn verify_digest(
+    digest: sha256d::Hash,
+    signature: &Signature,
+    pubkey: &PublicKey,
+) -> bool {
+    let msg = secp256k1::Message::from_slice(&digest[..])
+        .expect("double SHA256 hash is always a valid secp256k1 message");
+    SECP256K1.verify(&msg, signature, pubkey).is_ok()
+}
+
+/// This is a direct message between the two endpoints of a channel and serves
+/// as an opt-in mechanism to allow the announcement of the channel to the rest
+/// of the network. It contains the necessary signatures, by the sender, to
+/// construct the `channel_announcement` message.
+///
+/// # Specification
+/// <https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#the-announcement_signatures-message>
+#[derive(
+    Clone, PartialEq, Eq, Debug, Display, LightningEncode, LightningDecode,
+)]
+#[lnpbp_crate(crate)]
+#[display(Debug)]
+pub struct AnnouncementSignatures {
+    /// The channel ID
+    pub channel_id: ChannelId,
+
+    /// Short channel ID of the announced channel
+    pub short_channel_id: ShortId,
+
+    /// Signature of the channel announcement with the sender node key
+    pub node_signature: Signature,
+
+    /// Signature of the channel announcement with the sender funding key
+    pub bitcoin_signature: Signature,
+}
+
+impl AnnouncementSignatures {
+    /// Verifies that the signatures are valid for the given
+    /// `channel_announcement` made by the sender of this message, identified
+    /// by its `node_id` and channel funding `bitcoin_key`
+    pub fn verify(
+        &self,
+        announcement: &ChannelAnnouncement,
+        node_id: &PublicKey,
+        bitcoin_key: &PublicKey,
+    ) -> Result<(), VerificationError> {
+        if announcement.short_channel_id != self.short_channel_id {
+            Err(VerificationError::ChannelMismatch)?
+        }
+        let digest = announcement.signature_hash()?;
+        if !verify_digest(digest, &self.node_signature, node_id) {
+            Err(VerificationError::InvalidNodeSignature)?
+        }
+        if !verify_digest(digest, &self.bitcoin_signature, bitcoin_key) {
+            Err(VerificationError::InvalidBitcoinSignature)?
+        }
+        Ok(())
+    }
+}
+
+/// This gossip message contains ownership information regarding a channel. It
+/// ties each on-chain Bitcoin key to the associated Lightning node key, and
+/// vice-versa.
+///
+/// # Specification
+/// <https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#the-channel_announcement-message>
+#[derive(Clone, PartialEq, Eq, Debug, Display)]
+#[display(Debug)]
+pub struct ChannelAnnouncement {
+    /// Signature of the first node
+    pub node_signature_1: Signature,
+
+    /// Signature of the second node
+    pub node_signature_2: Signature,
+
+    /// Signature with the first node funding key
+    pub bitcoin_signature_1: Signature,
+
+    /// Signature with the second node funding key
+    pub bitcoin_signature_2: Signature,
+
+    /// Channel features
+    pub features: Features,
+
+    /// The genesis hash of the blockchain where the channel is opened
+    pub chain_hash: AssetId,
+
+    /// Short channel ID of the announced channel
+    pub short_channel_id: ShortId,
+
+    /// Public key of the first node
+    pub node_id_1: PublicKey,
+
+    /// Public key of the second node
+    pub node_id_2: PublicKey,
+
+    /// Funding public key of the first node
+    pub bitcoin_key_1: PublicKey,
+
+    /// Funding public key of the second node
+    pub bitcoin_key_2: PublicKey,
+
+    /// Message data covered by the signatures exactly as they were received
+    /// from the network, including the data unknown to this implementation.
+    /// If present, it is encoded instead of the message fields, so the
+    /// signatures are verified against the original data and the message is
+    /// relayed unchanged; locally constructed messages set it to `None`.
+    pub signed_data: Option<Vec<u8>>,
+}
+
+impl ChannelAnnouncement {
+    /// Size of the signatures prefixing the signed message data
+    const SIGNATURES_LEN: usize = 4 * 64;
+
+    /// Double-SHA256 hash of the message data following the signatures, which
+    /// is signed by both node keys and both bitcoin keys. For decoded
+    /// messages this is the hash of [`ChannelAnnouncement::signed_data`].
+    pub fn signature_hash(&self) -> Result<sha256d::Hash, presentation::Error> {
+        let data = lightning_encode(self)?;
+        Ok(sha256d::Hash::hash(&data[Self::SIGNATURES_LEN..]))
+    }
+
+    /// Verifies all four signatures of the channel announcement
+    pub fn verify(&self) -> Result<(), VerificationError> {
+        let digest = self.signature_hash()?;
+        if !verify_digest(digest, &self.node_signature_1, &self.node_id_1)
+            || !verify_digest(digest, &self.node_signature_2, &self.node_id_2)
+        {
+            Err(VerificationError::InvalidNodeSignature)?
+        }
+        if !verify_digest(
+            digest,
+            &self.bitcoin_signature_1,
+            &self.bitcoin_key_1,
+        ) || !verify_digest(
+            digest,
+            &self.bitcoin_signature_2,
+            &self.bitcoin_key_2,
+        ) {
+            Err(VerificationError::InvalidBitcoinSignature)?
+        }
+        Ok(())
+    }
+}
+
+impl LightningEncode for ChannelAnnouncement {
+    fn lightning_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, presentation::Error> {
+        let mut len = self.node_signature_1.lightning_encode(&mut e)?;
+        len += self.node_signature_2.lightning_encode(&mut e)?;
+        len += self.bitcoin_signature_1.lightning_encode(&mut e)?;
+        len += self.bitcoin_signature_2.lightning_encode(&mut e)?;
+        if let Some(data) = &self.signed_data {
+            e.write_all(data)?;
+            return Ok(len + data.len());
+        }
+        len += self.features.lightning_encode(&mut e)?;
+        len += self.chain_hash.lightning_encode(&mut e)?;
+        len += self.short_channel_id.lightning_encode(&mut e)?;
+        len += self.node_id_1.lightning_encode(&mut e)?;
+        len += self.node_id_2.lightning_encode(&mut e)?;
+        len += self.bitcoin_key_1.lightning_encode(&mut e)?;
+        len += self.bitcoin_key_2.lightning_encode(&mut e)?;
+        Ok(len)
+    }
+}
+
+impl LightningDecode for ChannelAnnouncement {
+    fn lightning_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, presentation::Error> {
+        let data = read_message(d)?;
+        let mut cursor = &data[..];
+        Ok(ChannelAnnouncement {
+            node_signature_1: Signature::lightning_decode(&mut cursor)?,
+            node_signature_2: Signature::lightning_decode(&mut cursor)?,
+            bitcoin_signature_1: Signature::lightning_decode(&mut cursor)?,
+            bitcoin_signature_2: Signature::lightning_decode(&mut cursor)?,
+            features: Features::lightning_decode(&mut cursor)?,
+            chain_hash: AssetId::lightning_decode(&mut cursor)?,
+            short_channel_id: ShortId::lightning_decode(&mut cursor)?,
+            node_id_1: PublicKey::lightning_decode(&mut cursor)?,
+            node_id_2: PublicKey::lightning_decode(&mut cursor)?,
+            bitcoin_key_1: PublicKey::lightning_decode(&mut cursor)?,
+            bitcoin_key_2: PublicKey::lightning_decode(&mut cursor)?,
+            signed_data: Some(data[Self::SIGNATURES_LEN..].to_vec()),
+        })
+    }
+}
+
+/// This gossip message allows a node to indicate extra data associated with
+/// it, in addition to its public key. To avoid trivial denial of service
+/// attacks, nodes not associated with an already known channel are ignored.
+///
+/// # Specification
+/// <https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#the-node_announcement-message>
+#[derive(Clone, PartialEq, Eq, Debug, Display)]
+#[display(Debug)]
+pub struct NodeAnnouncement {
+    /// Signature of the message with the node key
+    pub signature: Signature,
+
+    /// Node features
+    pub features: Features,
+
+    /// Timestamp allowing ordering in the case of multiple announcements
+    pub timestamp: u32,
+
+    /// Public key of the announced node
+    pub node_id: PublicKey,
+
+    /// Color of the node, may be used by clients for the node visualization
+    pub rgb_color: [u8; 3],
+
+    /// Node alias, which is not guaranteed to be unique
+    pub alias: [u8; 32],
+
+    /// Addresses the node accepts connections at. Only remote FTCP
+    /// addresses with IPv4, IPv6 or Tor v3 are allowed; the node id of the
+    /// addresses is always equal to the [`NodeAnnouncement::node_id`]
+    pub addresses: Vec<NodeAddr>,
+
+    /// Message data covered by the signature exactly as they were received
+    /// from the network, including the data unknown to this implementation.
+    /// If present, it is encoded instead of the message fields, so the
+    /// signature are verified against the original data and the message is
+    /// relayed unchanged; locally constructed messages set it to `None`.
+    pub signed_data: Option<Vec<u8>>,
+}
+
+impl NodeAnnouncement {
+    /// Size of the signature prefixing the signed message data
+    const SIGNATURE_LEN: usize = 64;
+
+    /// Double-SHA256 hash of the message data following the signature. For
+    /// decoded messages this is the hash of
+    /// [`NodeAnnouncement::signed_data`].
+    pub fn signature_hash(&self) -> Result<sha256d::Hash, presentation::Error> {
+        let data = lightning_encode(self)?;
+        Ok(sha256d::Hash::hash(&data[Self::SIGNATURE_LEN..]))
+    }
+
+    /// Verifies the node signature of the announcement
+    pub fn verify(&self) -> Result<(), VerificationError> {
+        if !verify_digest(
+            self.signature_hash()?,
+            &self.signature,
+            &self.node_id,
+        ) {
+            Err(VerificationError::InvalidNodeSignature)?
+        }
+        Ok(())
+    }
+}
+
+/// Address descriptor types defined by BOLT-7
+const ADDR_TYPE_IPV4: u8 = 1;
+const ADDR_TYPE_IPV6: u8 = 2;
+const ADDR_TYPE_TORV2: u8 = 3;
+const ADDR_TYPE_TORV3: u8 = 4;
+
+impl LightningEncode for NodeAnnouncement {
+    fn lightning_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, presentation::Error> {
+        let len = self.signature.lightning_encode(&mut e)?;
+        if let Some(data) = &self.signed_data {
+            e.write_all(data)?;
+            return Ok(len + data.len());
+        }
+
+        let mut addresses = vec![];
+        for addr in &self.addresses {
+            let inet = match addr {
+                NodeAddr::Remote(RemoteNodeAddr {
+                    remote_addr: RemoteSocketAddr::Ftcp(inet),
+                    ..
+                }) => inet,
+                _ => Err(presentation::Error::InvalidValue)?,
+            };
+            match inet.address {
+                InetAddr::IPv4(ip) => {
+                    addresses.push(ADDR_TYPE_IPV4);
+                    addresses.extend_from_slice(&ip.octets());
+                }
+                InetAddr::IPv6(ip) => {
+                    addresses.push(ADDR_TYPE_IPV6);
+                    addresses.extend_from_slice(&ip.octets());
+                }
+                #[cfg(feature = "tor")]
+                address => {
+                    let onion = address
+                        .to_onion()
+                        .ok_or(presentation::Error::InvalidValue)?;
+                    addresses.push(ADDR_TYPE_TORV3);
+                    addresses.extend_from_slice(&onion.get_raw_bytes());
+                }
+            }
+            addresses.extend_from_slice(&inet.port.to_be_bytes());
+        }
+
+        let mut len = len + self.features.lightning_encode(&mut e)?;
+        len += self.timestamp.lightning_encode(&mut e)?;
+        len += self.node_id.lightning_encode(&mut e)?;
+        len += self.rgb_color.lightning_encode(&mut e)?;
+        len += self.alias.lightning_encode(&mut e)?;
+        len += addresses.lightning_encode(&mut e)?;
+        Ok(len)
+    }
+}
+
+impl LightningDecode for NodeAnnouncement {
+    fn lightning_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, presentation::Error> {
+        let signed_data = read_message(d)?;
+        let mut d = &signed_data[..];
+        let signature = Signature::lightning_decode(&mut d)?;
+        let features = Features::lightning_decode(&mut d)?;
+        let timestamp = u32::lightning_decode(&mut d)?;
+        let node_id = PublicKey::lightning_decode(&mut d)?;
+        let rgb_color = <[u8; 3]>::lightning_decode(&mut d)?;
+        let alias = <[u8; 32]>::lightning_decode(&mut d)?;
+        let data = Vec::<u8>::lightning_decode(&mut d)?;
+
+        let mut addresses = vec![];
+        let mut cursor = &data[..];
+        while !cursor.is_empty() {
+            let address = match u8::lightning_decode(&mut cursor)? {
+                ADDR_TYPE_IPV4 => {
+                    let mut octets = [0u8; 4];
+                    io::Read::read_exact(&mut cursor, &mut octets)?;
+                    InetAddr::from(IpAddr::V4(Ipv4Addr::from(octets)))
+                }
+                ADDR_TYPE_IPV6 => {
+                    let mut octets = [0u8; 16];
+                    io::Read::read_exact(&mut cursor, &mut octets)?;
+                    InetAddr::from(IpAddr::V6(Ipv6Addr::from(octets)))
+                }
+                // Tor v2 addresses are deprecated, so we skip them; they are
+                // still kept in the signed data
+                ADDR_TYPE_TORV2 => {
+                    let mut skip = [0u8; 10 + 2];
+                    io::Read::read_exact(&mut cursor, &mut skip)?;
+                    continue;
+                }
+                #[cfg(feature = "tor")]
+                ADDR_TYPE_TORV3 => {
+                    let mut raw = [0u8; 35];
+                    io::Read::read_exact(&mut cursor, &mut raw)?;
+                    let mut key = [0u8; 32];
+                    key.copy_from_slice(&raw[..32]);
+                    let onion = TorPublicKeyV3::from_bytes(&key)
+                        .map(|key| OnionAddressV3::from(&key))
+                        .map_err(|_| presentation::Error::InvalidValue)?;
+                    // Checksum and version must match the public key
+                    if onion.get_raw_bytes()[..] != raw[..] {
+                        Err(presentation::Error::InvalidValue)?
+                    }
+                    InetAddr::from_str(&onion.get_address_without_dot_onion())
+                        .map_err(|_| presentation::Error::InvalidValue)?
+                }
+                #[cfg(not(feature = "tor"))]
+                ADDR_TYPE_TORV3 => {
+                    let mut skip = [0u8; 35 + 2];
+                    io::Read::read_exact(&mut cursor, &mut skip)?;
+                    continue;
+                }
+                // SHOULD ignore the first address descriptor that does NOT
+                // match the types defined above
+                _ => break,
+            };
+            let port = u16::lightning_decode(&mut cursor)?;
+            addresses.push(NodeAddr::Remote(RemoteNodeAddr {
+                node_id,
+                remote_addr: RemoteSocketAddr::Ftcp(InetSocketAddr::new(
+                    address, port,
+                )),
+            }));
+        }
+
+        Ok(NodeAnnouncement {
+            signature,
+            features,
+            timestamp,
+            node_id,
+            rgb_color,
+            alias,
+            addresses,
+            signed_data: Some(signed_data[Self::SIGNATURE_LEN..].to_vec()),
+        })
+    }
+}
+
+/// After a channel has been initially announced, each side independently
+/// announces the fees and minimum expiry delta it requires to relay HTLCs
+/// through this channel.
+///
+/// # Specification
+/// <https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#the-channel_update-message>
+#[derive(Clone, PartialEq, Eq, Debug, Display)]
+#[display(Debug)]
+pub struct ChannelUpdate {
+    /// Signature of the message with the key of the originating node
+    pub signature: Signature,
+
+    /// The genesis hash of the blockchain where the channel is opened
+    pub chain_hash: AssetId,
+
+    /// Short channel ID of the updated channel
+    pub short_channel_id: ShortId,
+
+    /// Timestamp allowing ordering in the case of multiple updates
+    pub timestamp: u32,
+
+    /// Message flags; the least-significant bit indicates presence of
+    /// `htlc_maximum_msat` field
+    pub message_flags: u8,
+
+    /// Channel flags; the least-significant bit indicates the direction of
+    /// the update (i.e. whether it originates from the first or the second
+    /// node of the channel announcement) and the next bit signals that the
+    /// channel is disabled
+    pub channel_flags: u8,
+
+    /// The number of blocks to subtract from incoming HTLCs' `cltv_expiry`
+    pub cltv_expiry_delta: u16,
+
+    /// The minimum HTLC value the channel peer will accept, in milli-satoshi
+    pub htlc_minimum_msat: u64,
+
+    /// The base fee charged for any HTLC, in milli-satoshi
+    pub fee_base_msat: u32,
+
+    /// The amount charged per transferred satoshi, in millionths of a satoshi
+    pub fee_proportional_millionths: u32,
+
+    /// The maximum value the node will send through this channel for a
+    /// single HTLC, in milli-satoshi
+    pub htlc_maximum_msat: Option<u64>,
+
+    /// Message data covered by the signature exactly as they were received
+    /// from the network, including the data unknown to this implementation.
+    /// If present, it is encoded instead of the message fields, so the
+    /// signature are verified against the original data and the message is
+    /// relayed unchanged; locally constructed messages set it to `None`.
+    pub signed_data: Option<Vec<u8>>,
+}
+
+impl ChannelUpdate {
+    /// Size of the signature prefixing the signed message data
+    const SIGNATURE_LEN: usize = 64;
+
+    /// Double-SHA256 hash of the message data following the signature. For
+    /// decoded messages this is the hash of [`ChannelUpdate::signed_data`].
+    pub fn signature_hash(&self) -> Result<sha256d::Hash, presentation::Error> {
+        let data = lightning_encode(self)?;
+        Ok(sha256d::Hash::hash(&data[Self::SIGNATURE_LEN..]))
+    }
+
+    /// Returns index of the channel announcement node (0 for `node_id_1` and 1
+    /// for `node_id_2`) which have originated the update
+    #[inline]
+    pub fn direction(&self) -> u8 {
+        self.channel_flags & 0x01
+    }
+
+    /// Detects whether the channel was disabled by the update originator
+    #[inline]
+    pub fn is_disabled(&self) -> bool {
+        self.channel_flags & 0x02 == 0x02
+    }
+
+    /// Verifies the update signature with the `node_id` of the originating
+    /// node
+    pub fn verify(&self, node_id: &PublicKey) -> Result<(), VerificationError> {
+        if !verify_digest(self.signature_hash()?, &self.signature, node_id) {
+            Err(VerificationError::InvalidNodeSignature)?
+        }
+        Ok(())
+    }
+
+    /// Verifies the update signature against the originating node picked out
+    /// of the channel `announcement` according to the update direction
+    pub fn verify_with(
+        &self,
+        announcement: &ChannelAnnouncement,
+    ) -> Result<(), VerificationError> {
+        if announcement.short_channel_id != self.short_channel_id {
+            Err(VerificationError::ChannelMismatch)?
+        }
+        match self.direction() {
+            0 => self.verify(&announcement.node_id_1),
+            _ => self.verify(&announcement.node_id_2),
+        }
+    }
+}
+
+impl LightningEncode for ChannelUpdate {
+    fn lightning_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, presentation::Error> {
+        let mut len = self.signature.lightning_encode(&mut e)?;
+        if let Some(data) = &self.signed_data {
+            e.write_all(data)?;
+            return Ok(len + data.len());
+        }
+        len += self.chain_hash.lightning_encode(&mut e)?;
+        len += self.short_channel_id.lightning_encode(&mut e)?;
+        len += self.timestamp.lightning_encode(&mut e)?;
+        len += self.message_flags.lightning_encode(&mut e)?;
+        len += self.channel_flags.lightning_encode(&mut e)?;
+        len += self.cltv_expiry_delta.lightning_encode(&mut e)?;
+        len += self.htlc_minimum_msat.lightning_encode(&mut e)?;
+        len += self.fee_base_msat.lightning_encode(&mut e)?;
+        len += self.fee_proportional_millionths.lightning_encode(&mut e)?;
+        len += self.htlc_maximum_msat.lightning_encode(&mut e)?;
+        Ok(len)
+    }
+}
+
+impl LightningDecode for ChannelUpdate {
+    fn lightning_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, presentation::Error> {
+        let data = read_message(d)?;
+        let mut cursor = &data[..];
+        let signature = Signature::lightning_decode(&mut cursor)?;
+        let chain_hash = AssetId::lightning_decode(&mut cursor)?;
+        let short_channel_id = ShortId::lightning_decode(&mut cursor)?;
+        let timestamp = u32::lightning_decode(&mut cursor)?;
+        let message_flags = u8::lightning_decode(&mut cursor)?;
+        let channel_flags = u8::lightning_decode(&mut cursor)?;
+        let cltv_expiry_delta = u16::lightning_decode(&mut cursor)?;
+        let htlc_minimum_msat = u64::lightning_decode(&mut cursor)?;
+        let fee_base_msat = u32::lightning_decode(&mut cursor)?;
+        let fee_proportional_millionths = u32::lightning_decode(&mut cursor)?;
+        // The field presence is signalled by the message flags, so the data
+        // following it is not mistaken for the field value
+        let htlc_maximum_msat = match message_flags & 0x01 {
+            0 => None,
+            _ => Some(u64::lightning_decode(&mut cursor)?),
+        };
+        Ok(ChannelUpdate {
+            signature,
+            chain_hash,
+            short_channel_id,
+            timestamp,
+            message_flags,
+            channel_flags,
+            cltv_expiry_delta,
+            htlc_minimum_msat,
+            fee_base_msat,
+            fee_proportional_millionths,
+            htlc_maximum_msat,
+            signed_data: Some(data[Self::SIGNATURE_LEN..].to_vec()),
+        })
+    }
+}
+
+/// Reads all data of the signed gossip message. Signed messages are always
+/// the last (and the only) data in the decoded stream, and the data following
+/// the fields known to this implementation are covered by the signature.
+fn read_message<D: io::Read>(mut d: D) -> Result<Vec<u8>, presentation::Error> {
+    let mut data = vec![];
+    d.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// List of short channel ids used in gossip queries. The list is prefixed
+/// with the encoding type byte; only the uncompressed encoding (type 0) is
+/// supported, since zlib encoding is deprecated by BOLT-7.
+#[derive(
+    Wrapper, Clone, PartialEq, Eq, Hash, Debug, Default, Display, From,
+)]
+#[display(Debug)]
+pub struct ShortChannelIds(Vec<ShortId>);
+
+impl ShortChannelIds {
+    /// Encoding type for an uncompressed array of short channel ids
+    const ENCODING_UNCOMPRESSED: u8 = 0;
+}
+
+impl LightningEncode for ShortChannelIds {
+    fn lightning_encode<E: io::Write>(
+        &self,
+        e: E,
+    ) -> Result<usize, presentation::Error> {
+        let mut data = vec![Self::ENCODING_UNCOMPRESSED];
+        for short_id in &self.0 {
+            short_id.lightning_encode(&mut data)?;
+        }
+        data.lightning_encode(e)
+    }
+}
+
+impl LightningDecode for ShortChannelIds {
+    fn lightning_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, presentation::Error> {
+        let data = Vec::<u8>::lightning_decode(d)?;
+        match data.split_first() {
+            None => Ok(ShortChannelIds::default()),
+            Some((&Self::ENCODING_UNCOMPRESSED, ids)) if ids.len() % 8 == 0 => {
+                ids.chunks(8)
+                    .map(ShortId::lightning_decode)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(ShortChannelIds)
+            }
+            // Wrong data length or unsupported (zlib) encoding
+            Some(_) => Err(presentation::Error::InvalidValue),
+        }
+    }
+}
+
+/// Requests information about specific channels, identified by their short
+/// channel ids
+///
+/// # Specification
+/// <https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#the-query_short_channel_idsreply_short_channel_ids_end-messages>
+#[derive(
+    Clone, PartialEq, Eq, Debug, Display, LightningEncode, LightningDecode,
+)]
+#[lnpbp_crate(crate)]
+#[display(Debug)]
+pub struct QueryShortChannelIds {
+    /// The genesis hash of the blockchain the channels belong to
+    pub chain_hash: AssetId,
+
+    /// Channels being queried
+    pub short_ids: ShortChannelIds,
+
+    #[tlv(unknown)]
+    pub unknown_tlvs: BTreeMap<u64, Vec<u8>>,
+}
+
+/// Concludes the reply to `query_short_channel_ids` request
+///
+/// # Specification
+/// <https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#the-query_short_channel_idsreply_short_channel_ids_end-messages>
+#[derive(
+    Clone, PartialEq, Eq, Debug, Display, LightningEncode, LightningDecode,
+)]
+#[lnpbp_crate(crate)]
+#[display(Debug)]
+pub struct ReplyShortChannelIdsEnd {
+    /// The genesis hash of the blockchain the channels belong to
+    pub chain_hash: AssetId,
+
+    /// Set to 0 if the replying node does not maintain up-to-date channel
+    /// information for the chain, and to 1 otherwise
+    pub full_information: u8,
+}
+
+/// Queries channels which funding transactions were mined within the given
+/// range of blocks
+///
+/// # Specification
+/// <https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#the-query_channel_range-and-reply_channel_range-messages>
+#[derive(
+    Clone, PartialEq, Eq, Debug, Display, LightningEncode, LightningDecode,
+)]
+#[lnpbp_crate(crate)]
+#[display(Debug)]
+pub struct QueryChannelRange {
+    /// The genesis hash of the blockchain the channels belong to
+    pub chain_hash: AssetId,
+
+    /// The first block of the queried range
+    pub first_blocknum: u32,
+
+    /// Number of blocks in the queried range
+    pub number_of_blocks: u32,
+
+    #[tlv(unknown)]
+    pub unknown_tlvs: BTreeMap<u64, Vec<u8>>,
+}
+
+/// Reply to the `query_channel_range` request; may be split into multiple
+/// messages
+///
+/// # Specification
+/// <https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#the-query_channel_range-and-reply_channel_range-messages>
+#[derive(
+    Clone, PartialEq, Eq, Debug, Display, LightningEncode, LightningDecode,
+)]
+#[lnpbp_crate(crate)]
+#[display(Debug)]
+pub struct ReplyChannelRange {
+    /// The genesis hash of the blockchain the channels belong to
+    pub chain_hash: AssetId,
+
+    /// The first block covered by the reply
+    pub first_blocknum: u32,
+
+    /// Number of blocks covered by the reply
+    pub number_of_blocks: u32,
+
+    /// Set to 1 in the last reply to the query
+    pub sync_complete: u8,
+
+    /// Channels within the block range
+    pub short_ids: ShortChannelIds,
+
+    #[tlv(unknown)]
+    pub unknown_tlvs: BTreeMap<u64, Vec<u8>>,
+}
+
+/// Allows a node to constrain future gossip messages to a specific range
+///
+/// # Specification
+/// <https://github.com/lightningnetwork/lightning-rfc/blob/master/07-routing-gossip.md#the-gossip_timestamp_filter-message>
+#[derive(
+    Clone, PartialEq, Eq, Debug, Display, LightningEncode, LightningDecode,
+)]
+#[lnpbp_crate(crate)]
+#[display(Debug)]
+pub struct GossipTimestampFilter {
+    /// The genesis hash of the blockchain the gossip is requested for
+    pub chain_hash: AssetId,
+
+    /// The first timestamp of the gossip messages to relay
+    pub first_timestamp: u32,
+
+    /// Duration of the timestamp range
+    pub timestamp_range: u32,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{Message, SecretKey};
+    use std::str::FromStr;
+
+    use crate::lnp::presentation::lightning_encoding::lightning_decode;
+    use crate::lnp::transport::LocalSocketAddr;
+
+    fn key(seed: u8) -> (SecretKey, PublicKey) {
+        let sk = SecretKey::from_slice(&[seed; 32]).unwrap();
+        (sk, PublicKey::from_secret_key(&SECP256K1, &sk))
+    }
+
+    fn sign(digest: sha256d::Hash, sk: &SecretKey) -> Signature {
+        SECP256K1.sign(&Message::from_slice(&digest[..]).unwrap(), sk)
+    }
+
+    fn dumb_signature() -> Signature {
+        sign(sha256d::Hash::hash(b""), &key(0xFF).0)
+    }
+
+    fn channel_announcement() -> (ChannelAnnouncement, [SecretKey; 4]) {
+        let (node_sk_1, node_id_1) = key(1);
+        let (node_sk_2, node_id_2) = key(2);
+        let (btc_sk_1, bitcoin_key_1) = key(3);
+        let (btc_sk_2, bitcoin_key_2) = key(4);
+        let announcement = ChannelAnnouncement {
+            node_signature_1: dumb_signature(),
+            node_signature_2: dumb_signature(),
+            bitcoin_signature_1: dumb_signature(),
+            bitcoin_signature_2: dumb_signature(),
+            features: Features::default(),
+            chain_hash: AssetId::default(),
+            short_channel_id: ShortId::from(0x0000_0100_0002_0003),
+            node_id_1,
+            node_id_2,
+            bitcoin_key_1,
+            bitcoin_key_2,
+            signed_data: None,
+        };
+        (announcement, [node_sk_1, node_sk_2, btc_sk_1, btc_sk_2])
+    }
+
+    #[test]
+    fn test_channel_announcement_verify() {
+        let (mut announcement, keys) = channel_announcement();
+        assert_eq!(
+            announcement.verify(),
+            Err(VerificationError::InvalidNodeSignature)
+        );
+
+        let digest = announcement.signature_hash().unwrap();
+        announcement.node_signature_1 = sign(digest, &keys[0]);
+        announcement.node_signature_2 = sign(digest, &keys[1]);
+        assert_eq!(
+            announcement.verify(),
+            Err(VerificationError::InvalidBitcoinSignature)
+        );
+        announcement.bitcoin_signature_1 = sign(digest, &keys[2]);
+        announcement.bitcoin_signature_2 = sign(digest, &keys[3]);
+        assert_eq!(announcement.verify(), Ok(()));
+
+        // Signatures are not a part of the signed data
+        let data = lightning_encode(&announcement).unwrap();
+        let mut decoded: ChannelAnnouncement = lightning_decode(&data).unwrap();
+        assert_eq!(decoded.signature_hash().unwrap(), digest);
+        assert_eq!(decoded.verify(), Ok(()));
+        assert_eq!(
+            decoded.signed_data.as_deref(),
+            Some(&data[ChannelAnnouncement::SIGNATURES_LEN..])
+        );
+        decoded.signed_data = None;
+        assert_eq!(decoded, announcement);
+
+        // Data unknown to us are covered by the signatures and relayed as is
+        let mut extended = data.clone();
+        extended.extend(&[0xAB, 0xCD]);
+        let decoded: ChannelAnnouncement = lightning_decode(&extended).unwrap();
+        assert_ne!(decoded.signature_hash().unwrap(), digest);
+        assert_eq!(
+            decoded.verify(),
+            Err(VerificationError::InvalidNodeSignature)
+        );
+        assert_eq!(lightning_encode(&decoded).unwrap(), extended);
+
+        let signatures = AnnouncementSignatures {
+            channel_id: ChannelId::default(),
+            short_channel_id: announcement.short_channel_id,
+            node_signature: announcement.node_signature_2,
+            bitcoin_signature: announcement.bitcoin_signature_2,
+        };
+        assert_eq!(
+            signatures.verify(
+                &announcement,
+                &announcement.node_id_2,
+                &announcement.bitcoin_key_2
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            signatures.verify(
+                &announcement,
+                &announcement.node_id_1,
+                &announcement.bitcoin_key_2
+            ),
+            Err(VerificationError::InvalidNodeSignature)
+        );
+
+        announcement.short_channel_id = ShortId::from(1);
+        assert_eq!(
+            announcement.verify(),
+            Err(VerificationError::InvalidNodeSignature)
+        );
+        assert_eq!(
+            signatures.verify(
+                &announcement,
+                &announcement.node_id_2,
+                &announcement.bitcoin_key_2
+            ),
+            Err(VerificationError::ChannelMismatch)
+        );
+    }
+
+    #[test]
+    fn test_node_announcement_addresses() {
+        let (sk, node_id) = key(1);
+        let remote = |s: &str| -> NodeAddr {
+            NodeAddr::Remote(RemoteNodeAddr {
+                node_id,
+                remote_addr: RemoteSocketAddr::Ftcp(
+                    InetSocketAddr::from_str(s).unwrap(),
+                ),
+            })
+        };
+        let mut announcement = NodeAnnouncement {
+            signature: dumb_signature(),
+            features: Features::default(),
+            timestamp: 1_600_000_000,
+            node_id,
+            rgb_color: [0xFF, 0x00, 0x80],
+            alias: [b'x'; 32],
+            addresses: vec![
+                remote("127.0.0.1:9735"),
+                remote("[2001:db8::1]:9735"),
+            ],
+            signed_data: None,
+        };
+        announcement.signature =
+            sign(announcement.signature_hash().unwrap(), &sk);
+        assert_eq!(announcement.verify(), Ok(()));
+
+        let data = lightning_encode(&announcement).unwrap();
+        // signature, features, timestamp, node_id, color, alias, addresses
+        assert_eq!(
+            data.len(),
+            64 + 2 + 4 + 33 + 3 + 32 + 2 + (1 + 4 + 2) + (1 + 16 + 2)
+        );
+        let mut decoded: NodeAnnouncement = lightning_decode(&data).unwrap();
+        assert_eq!(decoded.verify(), Ok(()));
+        decoded.signed_data = None;
+        assert_eq!(decoded, announcement);
+
+        // Tor v2 descriptors are skipped and unknown ones stop the parsing,
+        // but they are still covered by the signature, like the data
+        // following the addresses
+        let mut data = data[..data.len() - 2 - 26].to_vec();
+        data.extend(&[0x00, 0x18, 0x03]);
+        data.extend(&[0xAB; 12]);
+        data.extend(&[0x01, 0x7F, 0x00, 0x00, 0x01, 0x26, 0x07]);
+        data.extend(&[0x05, 0x00, 0x00, 0x00]);
+        data.extend(&[0xEF; 3]);
+        let digest =
+            sha256d::Hash::hash(&data[NodeAnnouncement::SIGNATURE_LEN..]);
+        data[..64].copy_from_slice(&sign(digest, &sk).serialize_compact());
+        let decoded: NodeAnnouncement = lightning_decode(&data).unwrap();
+        assert_eq!(decoded.addresses, vec![remote("127.0.0.1:9735")]);
+        assert_eq!(decoded.signature_hash().unwrap(), digest);
+        assert_eq!(decoded.verify(), Ok(()));
+        assert_eq!(lightning_encode(&decoded).unwrap(), data);
+
+        // Local addresses can't be announced
+        announcement.addresses =
+            vec![NodeAddr::Local(LocalSocketAddr::Posix(s!("/tmp/lnp.sock")))];
+        assert_eq!(
+            lightning_encode(&announcement),
+            Err(presentation::Error::InvalidValue)
+        );
+    }
+
+    #[cfg(feature = "tor")]
+    #[test]
+    fn test_node_announcement_tor() {
+        let (_, node_id) = key(1);
+        let onion = "32zzibxmqi2ybxpqyggwwuwz7a3lbvtzoloti7cxoevyvijexvgsfeid";
+        let addr = NodeAddr::Remote(RemoteNodeAddr {
+            node_id,
+            remote_addr: RemoteSocketAddr::Ftcp(InetSocketAddr::new(
+                InetAddr::from_str(onion).unwrap(),
+                9735,
+            )),
+        });
+        let announcement = NodeAnnouncement {
+            signature: dumb_signature(),
+            features: Features::default(),
+            timestamp: 0,
+            node_id,
+            rgb_color: [0u8; 3],
+            alias: [0u8; 32],
+            addresses: vec![addr],
+            signed_data: None,
+        };
+        let data = lightning_encode(&announcement).unwrap();
+        assert_eq!(data[data.len() - 38], ADDR_TYPE_TORV3);
+        let mut decoded: NodeAnnouncement = lightning_decode(&data).unwrap();
+        decoded.signed_data = None;
+        assert_eq!(decoded, announcement);
+
+        // Corrupted checksum
+        let mut data = data;
+        let pos = data.len() - 4;
+        data[pos] ^= 0xFF;
+        assert_eq!(
+            lightning_decode::<NodeAnnouncement>(&data),
+            Err(presentation::Error::InvalidValue)
+        );
+    }
+
+    #[test]
+    fn test_channel_update_verify() {
+        let (announcement, keys) = channel_announcement();
+        let mut update = ChannelUpdate {
+            signature: dumb_signature(),
+            chain_hash: AssetId::default(),
+            short_channel_id: announcement.short_channel_id,
+            timestamp: 1_600_000_000,
+            message_flags: 1,
+            channel_flags: 1,
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat: 1000,
+            fee_base_msat: 1000,
+            fee_proportional_millionths: 1,
+            htlc_maximum_msat: Some(1_000_000_000),
+            signed_data: None,
+        };
+        assert_eq!(update.direction(), 1);
+        assert!(!update.is_disabled());
+
+        update.signature = sign(update.signature_hash().unwrap(), &keys[1]);
+        assert_eq!(update.verify(&announcement.node_id_2), Ok(()));
+        assert_eq!(update.verify_with(&announcement), Ok(()));
+        assert_eq!(
+            update.verify(&announcement.node_id_1),
+            Err(VerificationError::InvalidNodeSignature)
+        );
+
+        let data = lightning_encode(&update).unwrap();
+        assert_eq!(data.len(), 64 + 32 + 8 + 4 + 1 + 1 + 2 + 8 + 4 + 4 + 8);
+        let mut decoded: ChannelUpdate = lightning_decode(&data).unwrap();
+        assert_eq!(decoded.verify_with(&announcement), Ok(()));
+        decoded.signed_data = None;
+        assert_eq!(decoded, update);
+
+        // Data following the known fields are kept in the signed data
+        let mut extended = data.clone();
+        extended.extend(&[0x01; 9]);
+        let decoded: ChannelUpdate = lightning_decode(&extended).unwrap();
+        assert_eq!(decoded.htlc_maximum_msat, update.htlc_maximum_msat);
+        assert_eq!(
+            decoded.verify_with(&announcement),
+            Err(VerificationError::InvalidNodeSignature)
+        );
+        assert_eq!(lightning_encode(&decoded).unwrap(), extended);
+
+        update.htlc_maximum_msat = None;
+        update.message_flags = 0;
+        let data = lightning_encode(&update).unwrap();
+        assert_eq!(data.len(), 64 + 32 + 8 + 4 + 1 + 1 + 2 + 8 + 4 + 4);
+        let mut decoded: ChannelUpdate = lightning_decode(&data).unwrap();
+        decoded.signed_data = None;
+        assert_eq!(decoded, update);
+        assert_eq!(
+            update.verify_with(&announcement),
+            Err(VerificationError::InvalidNodeSignature)
+        );
+    }
+
+    #[test]
+    fn test_short_channel_ids() {
+        let ids =
+            ShortChannelIds::from(vec![ShortId::from(1), ShortId::from(2)]);
+        let data = lightning_encode(&ids).unwrap();
+        assert_eq!(data[..3], [0x00, 0x11, 0x00]);
+        assert_eq!(data[3..11], [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(lightning_decode::<ShortChannelIds>(&data).unwrap(), ids);
+
+        let empty = lightning_encode(&ShortChannelIds::default()).unwrap();
+        assert_eq!(empty, vec![0x00, 0x01, 0x00]);
+        assert_eq!(
+            lightning_decode::<ShortChannelIds>(&empty).unwrap(),
+            ShortChannelIds::default()
+        );
+
+        // zlib-encoded data is not supported
+        assert_eq!(
+            lightning_decode::<ShortChannelIds>(&[0x00, 0x01, 0x01]),
+            Err(presentation::Error::InvalidValue)
+        );
+        // data length is not multiple of short id size
+        assert_eq!(
+            lightning_decode::<ShortChannelIds>(&[0x00, 0x02, 0x00, 0x01]),
+            Err(presentation::Error::InvalidValue)
+        );
+
+        let query = QueryShortChannelIds {
+            chain_hash: AssetId::default(),
+            short_ids: ids,
+            unknown_tlvs: bmap! { 1u64 => vec![0x00, 0x01, 0x02] },
+        };
+        let data = lightning_encode(&query).unwrap();
+        assert_eq!(data.len(), 32 + 2 + 1 + 16 + 1 + 1 + 3);
+        assert_eq!(
+            lightning_decode::<QueryShortChannelIds>(&data).unwrap(),
+            query
+        );
+    }
+}
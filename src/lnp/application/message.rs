@@ -20,13 +20,20 @@ use bitcoin::hashes::{sha256, Hmac};
 use bitcoin::secp256k1::{PublicKey, Signature};
 use bitcoin::{OutPoint, Script, Txid};
 
+use super::gossip::{
+    AnnouncementSignatures, ChannelAnnouncement, ChannelUpdate,
+    GossipTimestampFilter, NodeAnnouncement, QueryChannelRange,
+    QueryShortChannelIds, ReplyChannelRange, ReplyShortChannelIdsEnd,
+};
 use super::payment::{ChannelId, TempChannelId};
-use super::Features;
+use super::{FeatureError, Features};
 use crate::bp::chain::AssetId;
 use crate::bp::{HashLock, HashPreimage};
 use crate::lnp::presentation::{
-    CreateUnmarshaller, Encode, Unmarshall, Unmarshaller,
+    self, CreateUnmarshaller, Encode, LightningDecode, LightningEncode,
+    Unmarshall, Unmarshaller,
 };
+use crate::standards::features::FlagVec;
 use crate::strict_encoding::{self, StrictDecode, StrictEncode};
 use crate::SECP256K1_PUBKEY_DUMB;
 
@@ -39,7 +46,7 @@ lazy_static! {
 }
 
 #[derive(Clone, Debug, Display, LnpApi)]
-#[lnp_api(encoding = "strict")]
+#[lnp_api(encoding = "lightning")]
 #[lnpbp_crate(crate)]
 #[non_exhaustive]
 pub enum Messages {
@@ -147,6 +154,49 @@ pub enum Messages {
     #[lnp_api(type = 57156)]
     #[display("assign_funds(...)")]
     AssignFunds(AssignFunds),
+
+    // Part III: Gossip protocol
+    // =========================
+    //
+    // 1. Channel and node announcements
+    // ---------------------------------
+    #[lnp_api(type = 259)]
+    #[display("announcement_signatures(...)")]
+    AnnouncementSignatures(AnnouncementSignatures),
+
+    #[lnp_api(type = 256)]
+    #[display("channel_announcement(...)")]
+    ChannelAnnouncement(ChannelAnnouncement),
+
+    #[lnp_api(type = 257)]
+    #[display("node_announcement(...)")]
+    NodeAnnouncement(NodeAnnouncement),
+
+    #[lnp_api(type = 258)]
+    #[display("channel_update(...)")]
+    ChannelUpdate(ChannelUpdate),
+
+    // 2. Query messages
+    // -----------------
+    #[lnp_api(type = 261)]
+    #[display("query_short_channel_ids(...)")]
+    QueryShortChannelIds(QueryShortChannelIds),
+
+    #[lnp_api(type = 262)]
+    #[display("reply_short_channel_ids_end(...)")]
+    ReplyShortChannelIdsEnd(ReplyShortChannelIdsEnd),
+
+    #[lnp_api(type = 263)]
+    #[display("query_channel_range(...)")]
+    QueryChannelRange(QueryChannelRange),
+
+    #[lnp_api(type = 264)]
+    #[display("reply_channel_range(...)")]
+    ReplyChannelRange(ReplyChannelRange),
+
+    #[lnp_api(type = 265)]
+    #[display("gossip_timestamp_filter(...)")]
+    GossipTimestampFilter(GossipTimestampFilter),
 }
 
 /// Once authentication is complete, the first message reveals the features
@@ -154,7 +204,17 @@ pub enum Messages {
 ///
 /// # Specification
 /// <https://github.com/lightningnetwork/lightning-rfc/blob/master/01-messaging.md#the-init-message>
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(
     "init({global_features}, {local_features}, {assets:#?}), {unknown_tlvs:#?}"
@@ -162,12 +222,30 @@ pub enum Messages {
 pub struct Init {
     pub global_features: Features,
     pub local_features: Features,
-    // #[lnpwp(tlv=1)]
+    #[tlv(type = 1)]
     pub assets: HashSet<AssetId>,
-    // #[lpwpw(unknown_tlvs)]
+    #[tlv(unknown)]
     pub unknown_tlvs: BTreeMap<u64, Vec<u8>>,
 }
 
+impl Init {
+    /// Returns all features announced by the node. According to BOLT-1,
+    /// `global_features` field is deprecated and its flags must be treated
+    /// as if they were set in `local_features`.
+    pub fn features(&self) -> Features {
+        let flags = FlagVec::from(&self.global_features)
+            | FlagVec::from(&self.local_features);
+        Features::from(flags)
+    }
+
+    /// Negotiates features announced by the local node with this message
+    /// with the features announced by the `remote` node
+    #[inline]
+    pub fn negotiate(&self, remote: &Init) -> Result<Features, FeatureError> {
+        self.features().negotiate(&remote.features())
+    }
+}
+
 /// In order to allow for the existence of long-lived TCP connections, at
 /// times it may be required that both ends keep alive the TCP connection
 /// at the application level. Such messages also allow obfuscation of
@@ -175,12 +253,22 @@ pub struct Init {
 ///
 /// # Specification
 /// <https://github.com/lightningnetwork/lightning-rfc/blob/master/01-messaging.md#the-ping-and-pong-messages>
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct Ping {
-    pub ignored: Vec<u8>,
     pub pong_size: u16,
+    pub ignored: Vec<u8>,
 }
 
 /// For simplicity of diagnosis, it's often useful to tell a peer that something
@@ -217,7 +305,17 @@ impl Display for Error {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct OpenChannel {
@@ -285,14 +383,24 @@ pub struct OpenChannel {
 
     /// Optionally, a request to pre-set the to-sender output's scriptPubkey
     /// for when we collaboratively close
-    // #[lnpwp(tlv=0)]
+    #[tlv(type = 0)]
     pub shutdown_scriptpubkey: Option<Script>,
 
-    // #[lpwpw(unknown_tlvs)]
+    #[tlv(unknown)]
     pub unknown_tlvs: BTreeMap<u64, Vec<u8>>,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct AcceptChannel {
@@ -348,14 +456,24 @@ pub struct AcceptChannel {
 
     /// Optionally, a request to pre-set the to-sender output's scriptPubkey
     /// for when we collaboratively close
-    // #[lnpwp(tlv=0)]
+    #[tlv(type = 0)]
     pub shutdown_scriptpubkey: Option<Script>,
 
-    // #[lpwpw(unknown_tlvs)]
+    #[tlv(unknown)]
     pub unknown_tlvs: BTreeMap<u64, Vec<u8>>,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct FundingCreated {
@@ -373,7 +491,17 @@ pub struct FundingCreated {
     pub signature: Signature,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct FundingSigned {
@@ -384,7 +512,17 @@ pub struct FundingSigned {
     pub signature: Signature,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct FundingLocked {
@@ -395,7 +533,17 @@ pub struct FundingLocked {
     pub next_per_commitment_point: PublicKey,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct Shutdown {
@@ -407,7 +555,17 @@ pub struct Shutdown {
     pub scriptpubkey: Script,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct ClosingSigned {
@@ -421,7 +579,17 @@ pub struct ClosingSigned {
     pub signature: Signature,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct UpdateAddHtlc {
@@ -452,7 +620,17 @@ pub struct UpdateAddHtlc {
     pub asset_id: Option<AssetId>,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct UpdateFulfillHtlc {
@@ -466,7 +644,17 @@ pub struct UpdateFulfillHtlc {
     pub payment_preimage: HashPreimage,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct UpdateFailHtlc {
@@ -484,7 +672,17 @@ pub struct UpdateFailHtlc {
     pub reason: Vec<u8>,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct UpdateFailMalformedHtlc {
@@ -501,7 +699,17 @@ pub struct UpdateFailMalformedHtlc {
     pub failure_code: u16,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct CommitmentSigned {
@@ -515,7 +723,17 @@ pub struct CommitmentSigned {
     pub htlc_signatures: Vec<Signature>,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct RevokeAndAck {
@@ -529,7 +747,17 @@ pub struct RevokeAndAck {
     pub next_per_commitment_point: PublicKey,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct UpdateFee {
@@ -540,7 +768,17 @@ pub struct UpdateFee {
     pub feerate_per_kw: u32,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Display, StrictEncode, StrictDecode)]
+#[derive(
+    Clone,
+    PartialEq,
+    Eq,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+    LightningEncode,
+    LightningDecode,
+)]
 #[lnpbp_crate(crate)]
 #[display(Debug)]
 pub struct ChannelReestablish {
@@ -610,6 +848,66 @@ impl StrictDecode for Messages {
     }
 }
 
+/// Zero channel id is used to refer to all channels, so [`Error::channel_id`]
+/// set to `None` is encoded as 32 zero bytes
+impl LightningEncode for Error {
+    fn lightning_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, presentation::Error> {
+        let channel_id = self.channel_id.unwrap_or_default();
+        let mut len = channel_id.lightning_encode(&mut e)?;
+        len += self.data.lightning_encode(&mut e)?;
+        Ok(len)
+    }
+}
+
+impl LightningDecode for Error {
+    fn lightning_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, presentation::Error> {
+        let channel_id = ChannelId::lightning_decode(&mut d)?;
+        Ok(Error {
+            channel_id: if channel_id == ChannelId::default() {
+                None
+            } else {
+                Some(channel_id)
+            },
+            data: Vec::<u8>::lightning_decode(&mut d)?,
+        })
+    }
+}
+
+/// RGB consignment and outpoint have no representation in BOLT messages, so
+/// they are strict-encoded; the rest of the fields follow lightning encoding
+#[cfg(feature = "rgb")]
+impl LightningEncode for AssignFunds {
+    fn lightning_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, presentation::Error> {
+        let mut len = self.channel_id.lightning_encode(&mut e)?;
+        len += self.consignment.strict_encode(&mut e)?;
+        len += self.outpoint.strict_encode(&mut e)?;
+        len += self.blinding.lightning_encode(&mut e)?;
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "rgb")]
+impl LightningDecode for AssignFunds {
+    fn lightning_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, presentation::Error> {
+        Ok(AssignFunds {
+            channel_id: ChannelId::lightning_decode(&mut d)?,
+            consignment: Consignment::strict_decode(&mut d)?,
+            outpoint: OutPoint::strict_decode(&mut d)?,
+            blinding: u64::lightning_decode(&mut d)?,
+        })
+    }
+}
+
 impl DumbDefault for OpenChannel {
     fn dumb_default() -> Self {
         OpenChannel {
@@ -643,10 +941,77 @@ impl DumbDefault for OpenChannel {
 pub struct OnionPacket {
     pub version: u8,
     pub public_key: bitcoin::secp256k1::PublicKey,
-    pub hop_data: Vec<u8>, //[u8; 20 * 65],
+    /// Obfuscated routing information of [`OnionPacket::HOP_DATA_LEN`]
+    /// bytes; see [`super::onion`] for its construction and peeling
+    pub hop_data: Vec<u8>,
     pub hmac: Hmac<sha256::Hash>,
 }
 
+impl OnionPacket {
+    /// Size of the routing information in the onion packet
+    pub const HOP_DATA_LEN: usize = 20 * 65;
+}
+
+/// Onion packet is a fixed-size structure, so its `hop_data` is serialized as
+/// a raw byte array of [`OnionPacket::HOP_DATA_LEN`] bytes without any length
+/// prefix
+impl LightningEncode for OnionPacket {
+    fn lightning_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, presentation::Error> {
+        if self.hop_data.len() != Self::HOP_DATA_LEN {
+            Err(presentation::Error::InvalidValue)?
+        }
+        let mut len = self.version.lightning_encode(&mut e)?;
+        len += self.public_key.lightning_encode(&mut e)?;
+        e.write_all(&self.hop_data)?;
+        len += self.hop_data.len();
+        len += self.hmac.lightning_encode(&mut e)?;
+        Ok(len)
+    }
+}
+
+impl LightningDecode for OnionPacket {
+    fn lightning_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, presentation::Error> {
+        let version = u8::lightning_decode(&mut d)?;
+        let public_key = PublicKey::lightning_decode(&mut d)?;
+        let mut hop_data = vec![0u8; Self::HOP_DATA_LEN];
+        d.read_exact(&mut hop_data)?;
+        Ok(OnionPacket {
+            version,
+            public_key,
+            hop_data,
+            hmac: Hmac::lightning_decode(&mut d)?,
+        })
+    }
+}
+
+impl DumbDefault for AcceptChannel {
+    fn dumb_default() -> Self {
+        AcceptChannel {
+            temporary_channel_id: TempChannelId::dumb_default(),
+            dust_limit_satoshis: 0,
+            max_htlc_value_in_flight_msat: 0,
+            channel_reserve_satoshis: 0,
+            htlc_minimum_msat: 0,
+            minimum_depth: 0,
+            to_self_delay: 0,
+            max_accepted_htlcs: 0,
+            funding_pubkey: *SECP256K1_PUBKEY_DUMB,
+            revocation_basepoint: *SECP256K1_PUBKEY_DUMB,
+            payment_point: *SECP256K1_PUBKEY_DUMB,
+            delayed_payment_basepoint: *SECP256K1_PUBKEY_DUMB,
+            htlc_basepoint: *SECP256K1_PUBKEY_DUMB,
+            first_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+            shutdown_scriptpubkey: None,
+            unknown_tlvs: none!(),
+        }
+    }
+}
+
 impl DumbDefault for OnionPacket {
     fn dumb_default() -> Self {
         OnionPacket {
@@ -657,3 +1022,142 @@ impl DumbDefault for OnionPacket {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    use crate::lnp::presentation::lightning_encoding::lightning_decode;
+    use crate::lnp::TypedEnum;
+
+    #[test]
+    fn test_ping_bolt1_encoding() {
+        let ping = Messages::Ping(Ping {
+            pong_size: 4,
+            ignored: vec![0x00, 0x00],
+        });
+        let data = ping.encode().unwrap();
+        assert_eq!(data, vec![0x00, 0x12, 0x00, 0x04, 0x00, 0x02, 0x00, 0x00]);
+        let msg = LNPWP_UNMARSHALLER.unmarshall(&data).unwrap();
+        assert_eq!(msg.get_type(), ping.get_type());
+    }
+
+    #[test]
+    fn test_error_all_channels() {
+        let err = Error {
+            channel_id: None,
+            data: b"oops".to_vec(),
+        };
+        let data =
+            presentation::lightning_encoding::lightning_encode(&err).unwrap();
+        assert_eq!(data.len(), 32 + 2 + 4);
+        assert_eq!(&data[..32], &[0u8; 32]);
+        assert_eq!(lightning_decode::<Error>(&data).unwrap(), err);
+    }
+
+    #[test]
+    fn test_init_assets_tlv() {
+        let asset_id = AssetId::from_slice(&[0x11; 32]).unwrap();
+        let mut init = Init {
+            global_features: none!(),
+            local_features: none!(),
+            assets: none!(),
+            unknown_tlvs: none!(),
+        };
+        init.assets.insert(asset_id);
+        init.unknown_tlvs.insert(3, vec![0xAB]);
+        let data =
+            presentation::lightning_encoding::lightning_encode(&init).unwrap();
+        assert_eq!(&data[..4], &[0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(&data[4..6], &[0x01, 0x20]);
+        assert_eq!(&data[6..38], &[0x11; 32]);
+        assert_eq!(&data[38..], &[0x03, 0x01, 0xAB]);
+        assert_eq!(lightning_decode::<Init>(&data).unwrap(), init);
+
+        // Unknown even TLV records must fail the parsing
+        assert_eq!(
+            lightning_decode::<Init>(&[0x00, 0x00, 0x00, 0x00, 0x02, 0x00])
+                .unwrap_err(),
+            presentation::Error::TlvRecordEvenType
+        );
+        // Asset ids must be exactly 32 bytes each
+        assert_eq!(
+            lightning_decode::<Init>(&[
+                0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00
+            ])
+            .unwrap_err(),
+            presentation::Error::TlvRecordInvalidLen
+        );
+    }
+
+    #[test]
+    fn test_upfront_shutdown_script_tlv() {
+        let script = Script::from(vec![0x00, 0x14]);
+        let mut open_channel = OpenChannel::dumb_default();
+        let data =
+            presentation::lightning_encoding::lightning_encode(&open_channel)
+                .unwrap();
+        open_channel.shutdown_scriptpubkey = Some(script.clone());
+        let with_script =
+            presentation::lightning_encoding::lightning_encode(&open_channel)
+                .unwrap();
+        assert_eq!(&with_script[..data.len()], &data[..]);
+        assert_eq!(
+            &with_script[data.len()..],
+            &[0x00, 0x04, 0x00, 0x02, 0x00, 0x14]
+        );
+        assert_eq!(
+            lightning_decode::<OpenChannel>(&with_script).unwrap(),
+            open_channel
+        );
+        assert_eq!(
+            lightning_decode::<OpenChannel>(&data)
+                .unwrap()
+                .shutdown_scriptpubkey,
+            None
+        );
+
+        let mut accept_channel = AcceptChannel::dumb_default();
+        accept_channel.shutdown_scriptpubkey = Some(script);
+        accept_channel.unknown_tlvs.insert(1, vec![0xAB]);
+        let data =
+            presentation::lightning_encoding::lightning_encode(&accept_channel)
+                .unwrap();
+        assert_eq!(
+            &data[data.len() - 9..],
+            &[0x00, 0x04, 0x00, 0x02, 0x00, 0x14, 0x01, 0x01, 0xAB]
+        );
+        assert_eq!(
+            lightning_decode::<AcceptChannel>(&data).unwrap(),
+            accept_channel
+        );
+    }
+
+    #[test]
+    fn test_init_feature_negotiation() {
+        let init = |global: &str, local: &str| Init {
+            global_features: global.parse::<FlagVec>().unwrap().into(),
+            local_features: local.parse::<FlagVec>().unwrap().into(),
+            assets: none!(),
+            unknown_tlvs: none!(),
+        };
+        // Flags are written starting from bit 0
+        let local = init("", "01 0000 01 01");
+        let remote = init("00 0000 01", "10 0000 00 01");
+        assert_eq!(
+            local.features(),
+            Features::from("01 0000 01 01".parse::<FlagVec>().unwrap())
+        );
+        assert_eq!(
+            FlagVec::from(&local.negotiate(&remote).unwrap()),
+            "10 0000 01 01".parse::<FlagVec>().unwrap()
+        );
+        assert_eq!(
+            local.negotiate(&init("", "00 0010")),
+            Err(FeatureError::RequiredByRemote(
+                "option_upfront_shutdown_script"
+            ))
+        );
+    }
+}
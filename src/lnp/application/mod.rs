@@ -15,19 +15,23 @@ pub mod channel;
 pub mod extension;
 pub mod factories;
 mod features;
+pub mod gossip;
 pub mod message;
+pub mod onion;
 pub mod payment;
 pub mod peer_connection;
 pub mod prometheus;
 pub mod rpc_connection;
 pub mod storm;
+pub mod supervisor;
 
 pub use extension::{
     ChannelExtension, Extension, GossipExtension, RoutingExtension,
 };
-pub use features::{FeatureContext, FeatureFlag, Features};
+pub use features::{FeatureContext, FeatureError, FeatureFlag, Features};
 pub use message::{Messages, OnionPacket, LNPWP_UNMARSHALLER};
 pub use peer_connection::{
     PeerConnection, PeerReceiver, PeerSender, RecvMessage, SendMessage,
 };
 pub use rpc_connection::RpcConnection;
+pub use supervisor::{KeepaliveConfig, Supervisor};
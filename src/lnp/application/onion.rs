@@ -0,0 +1,625 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! BOLT-4 onion routing: construction and peeling of Sphinx onion packets
+//! carrying per-hop TLV payloads, and processing of the failure messages
+//! returned back to the payment origin.
+//!
+//! Specification: <https://github.com/lightningnetwork/lightning-rfc/blob/master/04-onion-routing.md>
+
+use bitcoin::hashes::{sha256, Hash, HashEngine, Hmac, HmacEngine};
+use bitcoin::secp256k1::rand::thread_rng;
+use bitcoin::secp256k1::{self, ecdh::SharedSecret, PublicKey, SecretKey};
+use chacha20::cipher::{NewStreamCipher, SyncStreamCipher};
+use chacha20::{ChaCha20, Key, Nonce};
+
+use super::OnionPacket;
+use crate::lnp::presentation::lightning_encoding::lightning_encode;
+use crate::lnp::presentation::{
+    self, tlv, BigSize, LightningDecode, LightningEncode,
+};
+use crate::lnp::session::LocalNode;
+use crate::SECP256K1;
+
+/// The only onion packet version defined by BOLT-4
+pub const ONION_VERSION: u8 = 0;
+
+/// Size of the `failuremsg` with its padding inside the failure message
+pub const FAILURE_LEN: usize = 256;
+
+const HMAC_LEN: usize = 32;
+
+/// Errors happening during onion packet and failure message processing
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum Error {
+    /// unsupported onion packet version {0}
+    UnknownVersion(u8),
+
+    /// onion packet HMAC check has failed: the packet is either corrupted or
+    /// is not intended for this node
+    InvalidHmac,
+
+    /// invalid onion ephemeral key or blinding factor: {0}
+    #[from]
+    Secp(secp256k1::Error),
+
+    /// the route must contain at least a single hop
+    EmptyRoute,
+
+    /// hop payloads do not fit into the onion packet
+    RouteTooLong,
+
+    /// hop payload is empty; legacy fixed-size hop data are not supported
+    EmptyPayload,
+
+    /// malformed hop payload: {0}
+    #[from]
+    Payload(presentation::Error),
+
+    /// failure message exceeds the maximum length of 256 bytes
+    FailureTooLong,
+
+    /// failure message can't be attributed to any of the route nodes
+    UnattributableFailure,
+}
+
+/// Route hop: the node the onion layer is encrypted for and the TLV payload
+/// the node will find inside it
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Hop {
+    /// Public key of the hop node
+    pub node_id: PublicKey,
+
+    /// TLV payload for the hop node
+    pub payload: tlv::RawStream,
+}
+
+/// Result of peeling a single layer of the onion packet
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PeeledOnion {
+    /// TLV payload for the current node
+    pub payload: tlv::RawStream,
+
+    /// Packet to be forwarded to the next node, or `None` if the current
+    /// node is the final one in the route
+    pub next_packet: Option<OnionPacket>,
+
+    /// Secret shared between the current node and the origin node, which
+    /// must be used for constructing failure messages with
+    /// [`build_failure`] and obfuscating them with [`wrap_failure`]
+    pub shared_secret: [u8; 32],
+}
+
+impl OnionPacket {
+    /// Constructs onion packet for the given `route` using fresh random
+    /// session key, as required by BOLT-4 for each packet. Returns the packet
+    /// and the secrets shared with each of the route nodes, which should be
+    /// kept by the origin to [`unwrap_failure`] messages.
+    pub fn new(
+        route: &[Hop],
+        associated_data: &[u8],
+    ) -> Result<(Self, Vec<[u8; 32]>), Error> {
+        let session_key = SecretKey::new(&mut thread_rng());
+        Self::with(&session_key, route, associated_data)
+    }
+
+    /// Constructs onion packet for the given `route` using provided
+    /// `session_key`, which must never be reused for other packets. Returns
+    /// the packet and the secrets shared with each of the route nodes.
+    pub fn with(
+        session_key: &SecretKey,
+        route: &[Hop],
+        associated_data: &[u8],
+    ) -> Result<(Self, Vec<[u8; 32]>), Error> {
+        if route.is_empty() {
+            Err(Error::EmptyRoute)?
+        }
+        let frames = route
+            .iter()
+            .map(|hop| hop_frame(&hop.payload))
+            .collect::<Result<Vec<_>, _>>()?;
+        let total_len: usize =
+            frames.iter().map(|frame| frame.len() + HMAC_LEN).sum();
+        if total_len > Self::HOP_DATA_LEN {
+            Err(Error::RouteTooLong)?
+        }
+
+        let node_ids: Vec<_> = route.iter().map(|hop| hop.node_id).collect();
+        let shared_secrets = shared_secrets(session_key, &node_ids)?;
+        let filler = generate_filler(&shared_secrets, &frames);
+
+        let pad_key = generate_key(b"pad", &session_key[..]);
+        let mut hop_data = generate_stream(&pad_key, Self::HOP_DATA_LEN);
+        let mut hmac = [0u8; HMAC_LEN];
+        for (index, (frame, secret)) in
+            frames.iter().zip(&shared_secrets).enumerate().rev()
+        {
+            let shift = frame.len() + HMAC_LEN;
+            hop_data.rotate_right(shift);
+            hop_data[..frame.len()].copy_from_slice(frame);
+            hop_data[frame.len()..shift].copy_from_slice(&hmac);
+            let rho = generate_key(b"rho", secret);
+            xor(&mut hop_data, &generate_stream(&rho, Self::HOP_DATA_LEN));
+            if index == frames.len() - 1 {
+                let start = Self::HOP_DATA_LEN - filler.len();
+                hop_data[start..].copy_from_slice(&filler);
+            }
+            hmac = packet_hmac(secret, &hop_data, associated_data);
+        }
+
+        let packet = OnionPacket {
+            version: ONION_VERSION,
+            public_key: PublicKey::from_secret_key(&SECP256K1, session_key),
+            hop_data,
+            hmac: Hmac::from_inner(hmac),
+        };
+        Ok((packet, shared_secrets))
+    }
+
+    /// Peels a single layer of the onion packet with the private key of the
+    /// local node
+    #[inline]
+    pub fn peel_with(
+        &self,
+        node: &LocalNode,
+        associated_data: &[u8],
+    ) -> Result<PeeledOnion, Error> {
+        self.peel(&node.private_key(), associated_data)
+    }
+
+    /// Peels a single layer of the onion packet with the node private key,
+    /// returning the hop payload and the packet for the next node
+    pub fn peel(
+        &self,
+        node_key: &SecretKey,
+        associated_data: &[u8],
+    ) -> Result<PeeledOnion, Error> {
+        if self.version != ONION_VERSION {
+            Err(Error::UnknownVersion(self.version))?
+        }
+        if self.hop_data.len() != Self::HOP_DATA_LEN {
+            Err(Error::Payload(presentation::Error::InvalidValue))?
+        }
+        let secret = shared_secret(&self.public_key, node_key);
+        if packet_hmac(&secret, &self.hop_data, associated_data)
+            != self.hmac.into_inner()
+        {
+            Err(Error::InvalidHmac)?
+        }
+
+        let mut data = self.hop_data.clone();
+        data.resize(2 * Self::HOP_DATA_LEN, 0);
+        let rho = generate_key(b"rho", &secret);
+        xor(&mut data, &generate_stream(&rho, 2 * Self::HOP_DATA_LEN));
+
+        let mut cursor = &data[..];
+        let len = BigSize::lightning_decode(&mut cursor)?.0 as usize;
+        if len == 0 {
+            Err(Error::EmptyPayload)?
+        }
+        let start = data.len() - cursor.len();
+        let end = start + len;
+        if end + HMAC_LEN > Self::HOP_DATA_LEN {
+            Err(Error::Payload(presentation::Error::BadLengthDescriptor))?
+        }
+        let payload = tlv::RawStream::lightning_decode(&data[start..end])?;
+
+        let mut hmac = [0u8; HMAC_LEN];
+        hmac.copy_from_slice(&data[end..end + HMAC_LEN]);
+        // Zero HMAC signals that the current node is the final one
+        let next_packet = if hmac == [0u8; HMAC_LEN] {
+            None
+        } else {
+            let mut public_key = self.public_key;
+            public_key.mul_assign(
+                &SECP256K1,
+                &blinding_factor(&self.public_key, &secret),
+            )?;
+            let next_start = end + HMAC_LEN;
+            Some(OnionPacket {
+                version: ONION_VERSION,
+                public_key,
+                hop_data: data[next_start..next_start + Self::HOP_DATA_LEN]
+                    .to_vec(),
+                hmac: Hmac::from_inner(hmac),
+            })
+        };
+
+        Ok(PeeledOnion {
+            payload,
+            next_packet,
+            shared_secret: secret,
+        })
+    }
+}
+
+/// Computes secrets shared by the origin node, using `session_key`, with
+/// each of the route nodes
+pub fn shared_secrets(
+    session_key: &SecretKey,
+    route: &[PublicKey],
+) -> Result<Vec<[u8; 32]>, Error> {
+    let mut ephemeral_key = *session_key;
+    let mut secrets = Vec::with_capacity(route.len());
+    for node_id in route {
+        let ephemeral_pubkey =
+            PublicKey::from_secret_key(&SECP256K1, &ephemeral_key);
+        let secret = shared_secret(node_id, &ephemeral_key);
+        ephemeral_key
+            .mul_assign(&blinding_factor(&ephemeral_pubkey, &secret))?;
+        secrets.push(secret);
+    }
+    Ok(secrets)
+}
+
+/// Constructs failure message on the node which has failed to process the
+/// HTLC. The `failure` data must start with the failure code, followed by
+/// the failure-specific data. The returned data are already obfuscated and
+/// can be used as `reason` of `update_fail_htlc` message.
+pub fn build_failure(
+    shared_secret: &[u8; 32],
+    failure: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if failure.len() > FAILURE_LEN {
+        Err(Error::FailureTooLong)?
+    }
+    let mut payload = Vec::with_capacity(2 + FAILURE_LEN + 2);
+    payload.extend_from_slice(&(failure.len() as u16).to_be_bytes());
+    payload.extend_from_slice(failure);
+    payload.extend_from_slice(
+        &((FAILURE_LEN - failure.len()) as u16).to_be_bytes(),
+    );
+    payload.resize(2 + FAILURE_LEN + 2, 0);
+
+    let um = generate_key(b"um", shared_secret);
+    let mut reason = hmac_sha256(&um, &[&payload]).to_vec();
+    reason.extend_from_slice(&payload);
+    Ok(wrap_failure(shared_secret, &reason))
+}
+
+/// Adds an obfuscation layer to the failure message `reason` received from
+/// the downstream node before returning it to the upstream node
+pub fn wrap_failure(shared_secret: &[u8; 32], reason: &[u8]) -> Vec<u8> {
+    let ammag = generate_key(b"ammag", shared_secret);
+    let mut data = reason.to_vec();
+    xor(&mut data, &generate_stream(&ammag, reason.len()));
+    data
+}
+
+/// Removes obfuscation layers from the failure message `reason` on the
+/// origin node and attributes it to one of the route nodes, identified by
+/// the `shared_secrets` returned during onion construction. Returns the
+/// index of the failing node in the route and the failure data.
+pub fn unwrap_failure(
+    shared_secrets: &[[u8; 32]],
+    reason: &[u8],
+) -> Result<(usize, Vec<u8>), Error> {
+    let mut data = reason.to_vec();
+    for (index, secret) in shared_secrets.iter().enumerate() {
+        data = wrap_failure(secret, &data);
+        if data.len() < HMAC_LEN + 2 {
+            break;
+        }
+        let (hmac, payload) = data.split_at(HMAC_LEN);
+        let um = generate_key(b"um", secret);
+        if hmac != hmac_sha256(&um, &[payload]) {
+            continue;
+        }
+        let len = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+        if 2 + len > payload.len() {
+            Err(Error::Payload(presentation::Error::BadLengthDescriptor))?
+        }
+        return Ok((index, payload[2..2 + len].to_vec()));
+    }
+    Err(Error::UnattributableFailure)
+}
+
+/// Serializes hop payload prefixed with its `BigSize` length
+fn hop_frame(payload: &tlv::RawStream) -> Result<Vec<u8>, Error> {
+    let data = lightning_encode(payload)?;
+    if data.is_empty() {
+        Err(Error::EmptyPayload)?
+    }
+    let mut frame = vec![];
+    BigSize(data.len() as u64).lightning_encode(&mut frame)?;
+    frame.extend_from_slice(&data);
+    Ok(frame)
+}
+
+/// Generates the filler which is appended by the final node to the routing
+/// information, so that HMACs of all intermediate nodes match their
+/// shifted-in (obfuscated) data
+fn generate_filler(shared_secrets: &[[u8; 32]], frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut filler = vec![];
+    for (secret, frame) in shared_secrets
+        .iter()
+        .zip(frames)
+        .take(frames.len().saturating_sub(1))
+    {
+        let start = OnionPacket::HOP_DATA_LEN - filler.len();
+        filler.resize(filler.len() + frame.len() + HMAC_LEN, 0);
+        let rho = generate_key(b"rho", secret);
+        let stream = generate_stream(&rho, 2 * OnionPacket::HOP_DATA_LEN);
+        xor(&mut filler, &stream[start..]);
+    }
+    filler
+}
+
+fn shared_secret(pubkey: &PublicKey, key: &SecretKey) -> [u8; 32] {
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(SharedSecret::new(pubkey, key).as_ref());
+    secret
+}
+
+fn blinding_factor(pubkey: &PublicKey, shared_secret: &[u8; 32]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&pubkey.serialize());
+    engine.input(shared_secret);
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+fn generate_key(key_type: &[u8], secret: &[u8]) -> [u8; 32] {
+    hmac_sha256(key_type, &[secret])
+}
+
+fn packet_hmac(
+    shared_secret: &[u8; 32],
+    hop_data: &[u8],
+    associated_data: &[u8],
+) -> [u8; 32] {
+    let mu = generate_key(b"mu", shared_secret);
+    hmac_sha256(&mu, &[hop_data, associated_data])
+}
+
+fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key);
+    for chunk in data {
+        engine.input(chunk);
+    }
+    Hmac::from_engine(engine).into_inner()
+}
+
+fn generate_stream(key: &[u8; 32], len: usize) -> Vec<u8> {
+    let mut stream = vec![0u8; len];
+    ChaCha20::new(Key::from_slice(key), Nonce::from_slice(&[0u8; 12]))
+        .apply_keystream(&mut stream);
+    stream
+}
+
+fn xor(data: &mut [u8], stream: &[u8]) {
+    data.iter_mut()
+        .zip(stream)
+        .for_each(|(byte, key)| *byte ^= key);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+
+    fn node_key(seed: u8) -> SecretKey {
+        SecretKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn route(len: u8) -> Vec<Hop> {
+        (0..len)
+            .map(|index| {
+                let mut payload = tlv::RawStream::default();
+                payload.set_value(2, &(1000u64 * index as u64)).unwrap();
+                payload.set_value(4, &(144u32 + index as u32)).unwrap();
+                Hop {
+                    node_id: PublicKey::from_secret_key(
+                        &SECP256K1,
+                        &node_key(0x41 + index),
+                    ),
+                    payload,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shared_secrets() {
+        // BOLT-4 test vectors
+        let node_ids: Vec<_> =
+            route(5).into_iter().map(|hop| hop.node_id).collect();
+        let secrets = shared_secrets(&node_key(0x41), &node_ids).unwrap();
+        assert_eq!(
+            secrets[0].to_hex(),
+            "53eb63ea8a3fec3b3cd433b85cd62a4b145e1dda09391b348c4e1cd36a03ea66"
+        );
+        assert_eq!(
+            secrets[1].to_hex(),
+            "a6519e98832a0b179f62123b3567c106db99ee37bef036e783263602f3488fae"
+        );
+        assert_eq!(
+            secrets[4].to_hex(),
+            "b5756b9b542727dbafc6765a49488b023a725d631af688fc031217e90770c328"
+        );
+    }
+
+    #[test]
+    fn test_onion_packet_vector() {
+        // BOLT-4 packet creation test vector
+        let payloads = [
+            "02023a98040205dc06080000000000000001",
+            "020236b00402057806080000000000000002fd02013c0102030405060708090a\
+             0b0c0d0e0f0102030405060708090a0b0c0d0e0f0102030405060708090a0b0c\
+             0d0e0f0102030405060708090a0b0c0d0e0f",
+            "020230d4040204e206080000000000000003",
+            "02022710040203e806080000000000000004",
+            "02022710040203e8082224a33562c54507a9334e79f0dc4f17d407e6d7c61f0e\
+             2f3d0d38599502f617042710fd012de0",
+        ];
+        let mut route = route(5);
+        for (index, hop) in route.iter_mut().enumerate() {
+            let mut data = Vec::<u8>::from_hex(payloads[index]).unwrap();
+            if index == 4 {
+                // Final hop has 224-byte record with 0x2a-filled value
+                data.extend(&[0x2a; 224]);
+            }
+            hop.payload = tlv::RawStream::lightning_decode(&data[..]).unwrap();
+        }
+        let assoc = [0x42u8; 32];
+        let (packet, _) =
+            OnionPacket::with(&node_key(0x41), &route, &assoc).unwrap();
+        assert_eq!(
+            lightning_encode(&packet).unwrap().to_hex(),
+            "0002eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f28368\
+             6619f7f3416a5aa36dc7eeb3ec6d421e9615471ab870a33ac07fa5d5a51df0a8\
+             823aabe3fea3f90d387529d4f72837f9e687230371ccd8d263072206dbed0234\
+             f6505e21e282abd8c0e4f5b9ff8042800bbab065036eadd0149b37f27dde6647\
+             25a49866e052e809d2b0198ab9610faa656bbf4ec516763a59f8f42c171b1791\
+             66ba38958d4f51b39b3e98706e2d14a2dafd6a5df808093abfca5aeaaca16ede\
+             d5db7d21fb0294dd1a163edf0fb445d5c8d7d688d6dd9c541762bf5a5123bf99\
+             39d957fe648416e88f1b0928bfa034982b22548e1a4d922690eecf546275afb2\
+             33acf4323974680779f1a964cfe687456035cc0fba8a5428430b390f0057b6d1\
+             fe9a8875bfa89693eeb838ce59f09d207a503ee6f6299c92d6361bc335fcbf9b\
+             5cd44747aadce2ce6069cfdc3d671daef9f8ae590cf93d957c9e873e9a1bc62d\
+             9640dc8fc39c14902d49a1c80239b6c5b7fd91d05878cbf5ffc7db2569f47c43\
+             d6c0d27c438abff276e87364deb8858a37e5a62c446af95d8b786eaf0b5fcf78\
+             d98b41496794f8dcaac4eef34b2acfb94c7e8c32a9e9866a8fa0b6f2a06f00a1\
+             ccde569f97eec05c803ba7500acc96691d8898d73d8e6a47b8f43c3d5de74458\
+             d20eda61474c426359677001fbd75a74d7d5db6cb4feb83122f133206203e4e2\
+             d293f838bf8c8b3a29acb321315100b87e80e0edb272ee80fda944e3fb6084ed\
+             4d7f7c7d21c69d9da43d31a90b70693f9b0cc3eac74c11ab8ff655905688916c\
+             fa4ef0bd04135f2e50b7c689a21d04e8e981e74c6058188b9b1f9dfc3eec6838\
+             e9ffbcf22ce738d8a177c19318dffef090cee67e12de1a3e2a39f61247547ba5\
+             257489cbc11d7d91ed34617fcc42f7a9da2e3cf31a94a210a1018143173913c3\
+             8f60e62b24bf0d7518f38b5bab3e6a1f8aeb35e31d6442c8abb5178efc892d2e\
+             787d79c6ad9e2fc271792983fa9955ac4d1d84a36c024071bc6e431b625519d5\
+             56af38185601f70e29035ea6a09c8b676c9d88cf7e05e0f17098b584c4168735\
+             940263f940033a220f40be4c85344128b14beb9e75696db37014107801a59b13\
+             e89cd9d2258c169d523be6d31552c44c82ff4bb18ec9f099f3bf0e5b1bb2ba9a\
+             87d7e26f98d294927b600b5529c47e04d98956677cbcee8fa2b60f49776d8b8c\
+             367465b7c626da53700684fb6c918ead0eab8360e4f60edd25b4f43816a75ecf\
+             70f909301825b512469f8389d79402311d8aecb7b3ef8599e79485a4388d8774\
+             4d899f7c47ee644361e17040a7958c8911be6f463ab6a9b2afacd688ec55ef51\
+             7b38f1339efc54487232798bb25522ff4572ff68567fe830f92f7b8113efce3e\
+             98c3fffbaedce4fd8b50e41da97c0c08e423a72689cc68e68f752a5e3a9003e6\
+             4e35c957ca2e1c48bb6f64b05f56b70b575ad2f278d57850a7ad568c24a4d32a\
+             3d74b29f03dc125488bc7c637da582357f40b0a52d16b3b40bb2c2315d03360b\
+             c24209e20972c200566bcf3bbe5c5b0aedd83132a8a4d5b4242ba370b6d67d9b\
+             67eb01052d132c7866b9cb502e44796d9d356e4e3cb47cc527322cd24976fe7c\
+             9257a2864151a38e568ef7a79f10d6ef27cc04ce382347a2488b1f404fdbf407\
+             fe1ca1c9d0d5649e34800e25e18951c98cae9f43555eef65fee1ea8f15828807\
+             366c3b612cd5753bf9fb8fced08855f742cddd6f765f74254f03186683d646e6\
+             f09ac2805586c7cf11998357cafc5df3f285329366f475130c928b2dceba4aa3\
+             83758e7a9d20705c4bb9db619e2992f608a1ba65db254bb389468741d0502e25\
+             88aeb54390ac600c19af5c8e61383fc1bebe0029e4474051e4ef908828db9cca\
+             13277ef65db3fd47ccc2179126aaefb627719f421e20"
+        );
+
+        let mut packet = Some(packet);
+        for (index, hop) in route.iter().enumerate() {
+            let peeled = packet
+                .take()
+                .unwrap()
+                .peel(&node_key(0x41 + index as u8), &assoc)
+                .unwrap();
+            assert_eq!(peeled.payload, hop.payload);
+            packet = peeled.next_packet;
+        }
+        assert_eq!(packet, None);
+    }
+
+    #[test]
+    fn test_onion_peeling() {
+        let route = route(3);
+        let assoc = [0x42u8; 32];
+        let (packet, secrets) =
+            OnionPacket::with(&node_key(0x01), &route, &assoc).unwrap();
+        assert_eq!(packet.hop_data.len(), OnionPacket::HOP_DATA_LEN);
+
+        let mut packet = Some(packet);
+        for (index, hop) in route.iter().enumerate() {
+            let current = packet.take().unwrap();
+            assert_eq!(
+                current.peel(&node_key(0x41 + index as u8 + 1), &assoc),
+                Err(Error::InvalidHmac)
+            );
+            assert_eq!(
+                current.peel(&node_key(0x41 + index as u8), &[]),
+                Err(Error::InvalidHmac)
+            );
+            let peeled =
+                current.peel(&node_key(0x41 + index as u8), &assoc).unwrap();
+            assert_eq!(peeled.payload, hop.payload);
+            assert_eq!(peeled.shared_secret, secrets[index]);
+            packet = peeled.next_packet;
+        }
+        assert_eq!(packet, None);
+    }
+
+    #[test]
+    fn test_onion_failures() {
+        let mut route = route(2);
+        route[1].payload = tlv::RawStream::default();
+        assert_eq!(
+            OnionPacket::with(&node_key(0x01), &route, &[]),
+            Err(Error::EmptyPayload)
+        );
+        assert_eq!(
+            OnionPacket::with(&node_key(0x01), &[], &[]),
+            Err(Error::EmptyRoute)
+        );
+
+        route[1]
+            .payload
+            .set_value(1, &vec![0u8; OnionPacket::HOP_DATA_LEN])
+            .unwrap();
+        assert_eq!(
+            OnionPacket::with(&node_key(0x01), &route, &[]),
+            Err(Error::RouteTooLong)
+        );
+
+        let (mut packet, _) =
+            OnionPacket::with(&node_key(0x01), &route[..1], &[]).unwrap();
+        packet.version = 1;
+        assert_eq!(
+            packet.peel(&node_key(0x41), &[]),
+            Err(Error::UnknownVersion(1))
+        );
+    }
+
+    #[test]
+    fn test_failure_attribution() {
+        let route = route(3);
+        let (_, secrets) =
+            OnionPacket::with(&node_key(0x01), &route, &[]).unwrap();
+
+        // temporary_node_failure returned by the second node
+        let failure = [0x20, 0x02];
+        let reason = build_failure(&secrets[1], &failure).unwrap();
+        assert_eq!(reason.len(), HMAC_LEN + 2 + FAILURE_LEN + 2);
+        let reason = wrap_failure(&secrets[0], &reason);
+        assert_eq!(
+            unwrap_failure(&secrets, &reason).unwrap(),
+            (1, failure.to_vec())
+        );
+
+        // Missed obfuscation layer makes failure unattributable
+        let reason = build_failure(&secrets[2], &failure).unwrap();
+        let reason = wrap_failure(&secrets[0], &reason);
+        assert_eq!(
+            unwrap_failure(&secrets, &reason),
+            Err(Error::UnattributableFailure)
+        );
+
+        assert_eq!(
+            build_failure(&secrets[0], &[0u8; FAILURE_LEN + 1]),
+            Err(Error::FailureTooLong)
+        );
+    }
+}
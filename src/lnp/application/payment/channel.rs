@@ -50,7 +50,7 @@ pub enum NegotiationError {
     LocalDustExceedsRemoteReserve(u64, u64),
 
     /// channel_reserve_satoshis from the open_channel message ({0}) is less
-    /// than dust_limit_satoshis ({1}; rejecting the channel according to
+    /// than dust_limit_satoshis ({1}); rejecting the channel according to
     /// BOLT-2
     RemoteDustExceedsLocalReserve(u64, u64),
 }
@@ -102,6 +102,12 @@ impl Params {
         })
     }
 
+    /// Updates parameters proposed in `open_channel` with the ones from
+    /// `accept_channel` message according to BOLT-2. Each party's channel
+    /// reserve must be at least the dust limit of the other party: the
+    /// reserve from `accept_channel` is checked against the dust limit from
+    /// `open_channel`, and the reserve from `open_channel` - against the
+    /// dust limit from `accept_channel`.
     pub fn updated(
         &self,
         accept_channel: &AcceptChannel,
@@ -136,7 +142,7 @@ impl Params {
         // than dust_limit_satoshis:
         //
         //     MUST reject the channel.
-        if self.dust_limit_satoshis < accept_channel.channel_reserve_satoshis {
+        if self.channel_reserve_satoshis < accept_channel.dust_limit_satoshis {
             return Err(NegotiationError::RemoteDustExceedsLocalReserve(
                 self.channel_reserve_satoshis,
                 accept_channel.dust_limit_satoshis,
@@ -221,3 +227,54 @@ impl DumbDefault for Keyset {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params() -> Params {
+        Params {
+            funding_satoshis: 10_000_000,
+            dust_limit_satoshis: 546,
+            channel_reserve_satoshis: 100_000,
+            ..Params::default()
+        }
+    }
+
+    fn accept_channel(
+        dust_limit_satoshis: u64,
+        channel_reserve_satoshis: u64,
+    ) -> AcceptChannel {
+        AcceptChannel {
+            dust_limit_satoshis,
+            channel_reserve_satoshis,
+            ..AcceptChannel::dumb_default()
+        }
+    }
+
+    #[test]
+    fn test_reserve_and_dust() {
+        // Each reserve exceeds dust limit of the other party
+        let updated = params()
+            .updated(&accept_channel(546, 100_000), None)
+            .unwrap();
+        assert_eq!(updated.dust_limit_satoshis, 546);
+        assert_eq!(updated.channel_reserve_satoshis, 100_000);
+        assert_eq!(updated.funding_satoshis, 10_000_000);
+
+        // Dust limit from accept_channel is not checked against the reserve
+        // from the same message
+        assert!(params().updated(&accept_channel(1000, 800), None).is_ok());
+
+        assert_eq!(
+            params().updated(&accept_channel(546, 500), None),
+            Err(NegotiationError::LocalDustExceedsRemoteReserve(500, 546))
+        );
+        assert_eq!(
+            params().updated(&accept_channel(200_000, 300_000), None),
+            Err(NegotiationError::RemoteDustExceedsLocalReserve(
+                100_000, 200_000
+            ))
+        );
+    }
+}
@@ -13,73 +13,367 @@
 
 use amplify::DumbDefault;
 use bitcoin::blockdata::{opcodes::all::*, script};
-use bitcoin::secp256k1::PublicKey;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
 use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+use std::io;
 
 use crate::bp::{
     IntoPk, LexOrder, LockScript, Psbt, PubkeyScript, WitnessScript,
 };
+use crate::lnp::application::payment::anchor_out::{
+    ANCHOR_OUTPUT_VALUE, COMMITMENT_TX_ANCHOR_WEIGHT,
+};
 use crate::lnp::application::payment::ExtensionId;
-use crate::lnp::application::{channel, ChannelExtension, Extension, Messages};
-use crate::SECP256K1_PUBKEY_DUMB;
+use crate::lnp::application::{
+    channel, ChannelExtension, Extension, Features, Messages,
+};
+use crate::lnp::message::{AcceptChannel, OpenChannel};
+use crate::strict_encoding::{self, StrictEncode};
+use crate::{SECP256K1, SECP256K1_PUBKEY_DUMB};
+
+/// Weight of the commitment transaction without HTLC outputs, used for the
+/// commitment fee computation according to BOLT-3
+pub const COMMITMENT_TX_BASE_WEIGHT: u64 = 724;
+
+/// Weight added to the commitment transaction by each untrimmed HTLC output
+pub const COMMITMENT_TX_HTLC_WEIGHT: u64 = 172;
 
+/// Channel basepoints of one of the channel parties, as they are provided in
+/// `open_channel` and `accept_channel` messages, together with the
+/// per-commitment point of the party's current commitment transaction
 #[derive(Copy, Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
 #[lnpbp_crate(crate)]
-struct Keyset {
+pub struct Keyset {
+    pub funding_pubkey: PublicKey,
     pub revocation_basepoint: PublicKey,
     pub payment_basepoint: PublicKey,
     pub delayed_payment_basepoint: PublicKey,
+    pub htlc_basepoint: PublicKey,
+    pub per_commitment_point: PublicKey,
 }
 
 impl DumbDefault for Keyset {
     fn dumb_default() -> Self {
         Self {
+            funding_pubkey: *SECP256K1_PUBKEY_DUMB,
             revocation_basepoint: *SECP256K1_PUBKEY_DUMB,
             payment_basepoint: *SECP256K1_PUBKEY_DUMB,
             delayed_payment_basepoint: *SECP256K1_PUBKEY_DUMB,
+            htlc_basepoint: *SECP256K1_PUBKEY_DUMB,
+            per_commitment_point: *SECP256K1_PUBKEY_DUMB,
         }
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode)]
+impl From<&OpenChannel> for Keyset {
+    fn from(msg: &OpenChannel) -> Self {
+        Self {
+            funding_pubkey: msg.funding_pubkey,
+            revocation_basepoint: msg.revocation_basepoint,
+            payment_basepoint: msg.payment_point,
+            delayed_payment_basepoint: msg.delayed_payment_basepoint,
+            htlc_basepoint: msg.htlc_basepoint,
+            per_commitment_point: msg.first_per_commitment_point,
+        }
+    }
+}
+
+impl From<&AcceptChannel> for Keyset {
+    fn from(msg: &AcceptChannel) -> Self {
+        Self {
+            funding_pubkey: msg.funding_pubkey,
+            revocation_basepoint: msg.revocation_basepoint,
+            payment_basepoint: msg.payment_point,
+            delayed_payment_basepoint: msg.delayed_payment_basepoint,
+            htlc_basepoint: msg.htlc_basepoint,
+            per_commitment_point: msg.first_per_commitment_point,
+        }
+    }
+}
+
+/// Channel features affecting construction of the commitment transaction
+#[derive(
+    Copy, Clone, PartialEq, Eq, Hash, Debug, Default, StrictEncode, StrictDecode,
+)]
+#[lnpbp_crate(crate)]
+pub struct CommitmentFeatures {
+    /// `to_remote` output pays directly to the remote `payment_basepoint`
+    pub static_remotekey: bool,
+    /// Commitment transaction has anchor outputs for CPFP fee bumping
+    pub anchor_outputs: bool,
+}
+
+impl From<&Features> for CommitmentFeatures {
+    fn from(features: &Features) -> Self {
+        Self {
+            static_remotekey: features.option_static_remotekey.is_some(),
+            anchor_outputs: features.option_anchor_outputs.is_some(),
+        }
+    }
+}
+
+impl CommitmentFeatures {
+    /// Returns features supported by both channel parties. Anchor outputs
+    /// imply static remote key according to BOLT-9.
+    pub fn intersection(&self, other: &CommitmentFeatures) -> Self {
+        let anchor_outputs = self.anchor_outputs && other.anchor_outputs;
+        Self {
+            static_remotekey: anchor_outputs
+                || (self.static_remotekey && other.static_remotekey),
+            anchor_outputs,
+        }
+    }
+}
+
+/// Public keys used by the outputs of a commitment transaction. They are
+/// derived according to BOLT-3 from the basepoints of both channel parties
+/// and the per-commitment point of the commitment transaction holder, which
+/// is called "local" here.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CommitmentKeys {
+    pub localpubkey: PublicKey,
+    pub remotepubkey: PublicKey,
+    pub local_htlcpubkey: PublicKey,
+    pub remote_htlcpubkey: PublicKey,
+    pub local_delayedpubkey: PublicKey,
+    pub revocationpubkey: PublicKey,
+}
+
+impl CommitmentKeys {
+    /// Derives keys for the current commitment transaction of the `local`
+    /// party. With `static_remotekey` the remote `payment_basepoint` is used
+    /// as `remotepubkey` without tweaking.
+    pub fn derive(
+        local: &Keyset,
+        remote: &Keyset,
+        static_remotekey: bool,
+    ) -> Self {
+        let point = local.per_commitment_point;
+        Self {
+            localpubkey: derive_pubkey(local.payment_basepoint, point),
+            remotepubkey: if static_remotekey {
+                remote.payment_basepoint
+            } else {
+                derive_pubkey(remote.payment_basepoint, point)
+            },
+            local_htlcpubkey: derive_pubkey(local.htlc_basepoint, point),
+            remote_htlcpubkey: derive_pubkey(remote.htlc_basepoint, point),
+            local_delayedpubkey: derive_pubkey(
+                local.delayed_payment_basepoint,
+                point,
+            ),
+            revocationpubkey: derive_revocation_pubkey(
+                remote.revocation_basepoint,
+                point,
+            ),
+        }
+    }
+}
+
+/// Constructor of the BOLT-3 commitment transaction held by the local node
+#[derive(
+    Getters, Copy, Clone, PartialEq, Eq, Debug, StrictEncode, StrictDecode,
+)]
 #[lnpbp_crate(crate)]
 pub struct Bolt3 {
-    local_amount: u64,
-    remote_amount: u64,
+    local_amount_msat: u64,
+    remote_amount_msat: u64,
     commitment_number: u64,
+    feerate_per_kw: u32,
+    /// Dust limit of the local node, below which outputs are trimmed from
+    /// the local commitment transaction
+    dust_limit_satoshis: u64,
+    /// Delay for the local `to_local` output requested by the remote peer
     to_self_delay: u16,
-    obscuring_factor: u64,
+    funding_outpoint: OutPoint,
 
     local_keys: Keyset,
+    /// Per-commitment point which will be used by the next local commitment
+    /// transaction, as it was sent to the remote peer
+    next_per_commitment_point: PublicKey,
     remote_keys: Keyset,
 
+    local_features: CommitmentFeatures,
+    remote_features: CommitmentFeatures,
+    /// Features are fixed once the channel is opened, so `init` messages
+    /// sent on reconnection do not change the commitment transaction
+    features_fixed: bool,
+
     is_originator: bool,
 }
 
+impl DumbDefault for Bolt3 {
+    fn dumb_default() -> Self {
+        Bolt3 {
+            local_amount_msat: 0,
+            remote_amount_msat: 0,
+            commitment_number: 0,
+            feerate_per_kw: 0,
+            dust_limit_satoshis: 0,
+            to_self_delay: 0,
+            funding_outpoint: OutPoint::null(),
+            local_keys: Keyset::dumb_default(),
+            next_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+            remote_keys: Keyset::dumb_default(),
+            local_features: CommitmentFeatures::default(),
+            remote_features: CommitmentFeatures::default(),
+            features_fixed: false,
+            is_originator: false,
+        }
+    }
+}
+
 impl Bolt3 {
+    /// Constructs commitment transaction constructor with the given channel
+    /// balances in satoshis. Keys and other channel parameters are set to
+    /// dumb values and are updated from the channel negotiation messages.
     pub fn new(
         is_originator: bool,
         local_amount: u64,
         remote_amount: u64,
         to_self_delay: u16,
     ) -> Self {
-        let dumb_keys = Keyset::dumb_default();
-        let obscuring_factor = compute_obscuring_factor(
-            is_originator,
-            dumb_keys.payment_basepoint,
-            dumb_keys.payment_basepoint,
-        );
         Bolt3 {
-            local_amount,
-            remote_amount,
-            commitment_number: 0,
+            local_amount_msat: local_amount * 1000,
+            remote_amount_msat: remote_amount * 1000,
             to_self_delay,
-            obscuring_factor,
-            local_keys: dumb_keys,
-            remote_keys: dumb_keys,
             is_originator,
+            ..Bolt3::dumb_default()
         }
     }
+
+    /// Returns obscuring factor for the commitment number, computed from the
+    /// payment basepoints of the channel funder and fundee
+    pub fn obscuring_factor(&self) -> u64 {
+        compute_obscuring_factor(
+            self.is_originator,
+            self.local_keys.payment_basepoint,
+            self.remote_keys.payment_basepoint,
+        )
+    }
+
+    /// Returns commitment features negotiated with the remote peer
+    #[inline]
+    pub fn features(&self) -> CommitmentFeatures {
+        self.local_features.intersection(&self.remote_features)
+    }
+
+    /// Returns keys for the current local commitment transaction
+    #[inline]
+    pub fn commitment_keys(&self) -> CommitmentKeys {
+        CommitmentKeys::derive(
+            &self.local_keys,
+            &self.remote_keys,
+            self.features().static_remotekey,
+        )
+    }
+
+    /// Returns fee for the local commitment transaction with `htlc_count`
+    /// untrimmed HTLC outputs. With negotiated `option_anchor_outputs` the
+    /// weight of the transaction with anchor outputs is used.
+    #[inline]
+    pub fn commitment_fee(&self, htlc_count: usize) -> u64 {
+        let base_weight = if self.features().anchor_outputs {
+            COMMITMENT_TX_ANCHOR_WEIGHT
+        } else {
+            COMMITMENT_TX_BASE_WEIGHT
+        };
+        let weight =
+            base_weight + COMMITMENT_TX_HTLC_WEIGHT * htlc_count as u64;
+        self.feerate_per_kw as u64 * weight / 1000
+    }
+
+    /// Returns amount paid by the channel funder for the local commitment
+    /// transaction with `htlc_count` untrimmed HTLC outputs: the commitment
+    /// fee and, with negotiated `option_anchor_outputs`, the amounts of both
+    /// anchor outputs
+    #[inline]
+    pub fn funder_fee(&self, htlc_count: usize) -> u64 {
+        let fee = self.commitment_fee(htlc_count);
+        if self.features().anchor_outputs {
+            fee + 2 * ANCHOR_OUTPUT_VALUE
+        } else {
+            fee
+        }
+    }
+
+    /// Constructs `to_local` and `to_remote` outputs of the local commitment
+    /// transaction for the given channel balances. Commitment transaction fee
+    /// for `htlc_count` untrimmed HTLC outputs and the anchor amounts (see
+    /// [`Bolt3::funder_fee`]) are paid by the channel funder; outputs below
+    /// the local dust limit are trimmed.
+    pub fn balance_outs(
+        &self,
+        local_amount_msat: u64,
+        remote_amount_msat: u64,
+        htlc_count: usize,
+    ) -> Vec<TxOut> {
+        let keys = self.commitment_keys();
+        let fee = self.funder_fee(htlc_count);
+        let mut to_local = local_amount_msat / 1000;
+        let mut to_remote = remote_amount_msat / 1000;
+        if self.is_originator {
+            to_local = to_local.saturating_sub(fee);
+        } else {
+            to_remote = to_remote.saturating_sub(fee);
+        }
+
+        let mut outs = vec![];
+        if to_local >= self.dust_limit_satoshis {
+            outs.push(TxOut::ln_to_local(
+                to_local,
+                keys.revocationpubkey,
+                keys.local_delayedpubkey,
+                self.to_self_delay,
+            ));
+        }
+        if to_remote >= self.dust_limit_satoshis {
+            outs.push(TxOut::ln_to_remote_v1(to_remote, keys.remotepubkey));
+        }
+        outs
+    }
+
+    /// Sets initial channel balances from the `open_channel` message. Fails
+    /// without modifying the channel state if the funding amount can't be
+    /// represented in millisatoshis or if it is less than `push_msat`.
+    fn open_channel(
+        &mut self,
+        open_channel: &OpenChannel,
+        is_originator: bool,
+    ) -> Result<(), channel::Error> {
+        let funding_msat = open_channel
+            .funding_satoshis
+            .checked_mul(1000)
+            .ok_or_else(|| {
+                channel::Error::Extension(format!(
+                    "funding amount of {} satoshis overflows millisatoshi \
+                     value",
+                    open_channel.funding_satoshis
+                ))
+            })?;
+        let funder_msat = funding_msat
+            .checked_sub(open_channel.push_msat)
+            .ok_or_else(|| {
+                channel::Error::Extension(format!(
+                    "pushed amount of {} msat exceeds funding amount of {} \
+                     msat",
+                    open_channel.push_msat, funding_msat
+                ))
+            })?;
+
+        self.is_originator = is_originator;
+        let (funder, fundee) = if is_originator {
+            (&mut self.local_amount_msat, &mut self.remote_amount_msat)
+        } else {
+            (&mut self.remote_amount_msat, &mut self.local_amount_msat)
+        };
+        *funder = funder_msat;
+        *fundee = open_channel.push_msat;
+        self.feerate_per_kw = open_channel.feerate_per_kw;
+        self.features_fixed = true;
+        Ok(())
+    }
 }
 
 impl channel::State for Bolt3 {}
@@ -96,33 +390,84 @@ impl Extension for Bolt3 {
         message: &Messages,
     ) -> Result<(), channel::Error> {
         match message {
+            Messages::Init(init) if !self.features_fixed => {
+                self.remote_features =
+                    CommitmentFeatures::from(&init.features());
+            }
+            Messages::OpenChannel(open_channel) => {
+                self.open_channel(open_channel, false)?;
+                self.remote_keys = Keyset::from(open_channel);
+                self.to_self_delay = open_channel.to_self_delay;
+            }
+            Messages::AcceptChannel(accept_channel) => {
+                self.remote_keys = Keyset::from(accept_channel);
+                self.to_self_delay = accept_channel.to_self_delay;
+                self.features_fixed = true;
+            }
+            Messages::FundingCreated(funding_created) => {
+                self.funding_outpoint = OutPoint::new(
+                    funding_created.funding_txid,
+                    funding_created.funding_output_index as u32,
+                );
+            }
+            Messages::FundingLocked(funding_locked) => {
+                self.remote_keys.per_commitment_point =
+                    funding_locked.next_per_commitment_point;
+            }
+            Messages::CommitmentSigned(_) => {
+                // Remote peer has signed our next commitment transaction
+                self.commitment_number += 1;
+                self.local_keys.per_commitment_point =
+                    self.next_per_commitment_point;
+            }
+            Messages::RevokeAndAck(revoke_and_ack) => {
+                self.remote_keys.per_commitment_point =
+                    revoke_and_ack.next_per_commitment_point;
+            }
+            Messages::UpdateFee(update_fee) => {
+                self.feerate_per_kw = update_fee.feerate_per_kw;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn update_from_local(
+        &mut self,
+        message: &Messages,
+    ) -> Result<(), channel::Error> {
+        match message {
+            Messages::Init(init) if !self.features_fixed => {
+                self.local_features =
+                    CommitmentFeatures::from(&init.features());
+            }
             Messages::OpenChannel(open_channel) => {
-                self.remote_keys.payment_basepoint = open_channel.payment_point;
-                self.remote_keys.revocation_basepoint =
-                    open_channel.revocation_basepoint;
-                self.remote_keys.delayed_payment_basepoint =
-                    open_channel.delayed_payment_basepoint;
+                self.open_channel(open_channel, true)?;
+                self.local_keys = Keyset::from(open_channel);
+                self.dust_limit_satoshis = open_channel.dust_limit_satoshis;
             }
             Messages::AcceptChannel(accept_channel) => {
-                self.remote_keys.payment_basepoint =
-                    accept_channel.payment_point;
-                self.remote_keys.revocation_basepoint =
-                    accept_channel.revocation_basepoint;
-                self.remote_keys.delayed_payment_basepoint =
-                    accept_channel.delayed_payment_basepoint;
+                self.local_keys = Keyset::from(accept_channel);
+                self.dust_limit_satoshis = accept_channel.dust_limit_satoshis;
+                self.features_fixed = true;
+            }
+            Messages::FundingCreated(funding_created) => {
+                self.funding_outpoint = OutPoint::new(
+                    funding_created.funding_txid,
+                    funding_created.funding_output_index as u32,
+                );
+            }
+            Messages::FundingLocked(funding_locked) => {
+                self.next_per_commitment_point =
+                    funding_locked.next_per_commitment_point;
+            }
+            Messages::RevokeAndAck(revoke_and_ack) => {
+                self.next_per_commitment_point =
+                    revoke_and_ack.next_per_commitment_point;
+            }
+            Messages::UpdateFee(update_fee) => {
+                self.feerate_per_kw = update_fee.feerate_per_kw;
             }
-            Messages::FundingCreated(_) => {}
-            Messages::FundingSigned(_) => {}
-            Messages::FundingLocked(_) => {}
-            Messages::Shutdown(_) => {}
-            Messages::ClosingSigned(_) => {}
-            Messages::UpdateAddHtlc(_) => {}
-            Messages::UpdateFulfillHtlc(_) => {}
-            Messages::UpdateFailHtlc(_) => {}
-            Messages::UpdateFailMalformedHtlc(_) => {}
-            Messages::CommitmentSigned(_) => {}
-            Messages::RevokeAndAck(_) => {}
-            Messages::ChannelReestablish(_) => {}
             _ => {}
         }
         Ok(())
@@ -142,33 +487,31 @@ impl ChannelExtension for Bolt3 {
         &mut self,
         tx_graph: &mut channel::TxGraph,
     ) -> Result<(), channel::Error> {
-        // The 48-bit commitment number is obscured by XOR with the lower
-        // 48 bits of `obscuring_factor`
-        let obscured_commitment = (self.commitment_number & 0xFFFFFF)
-            ^ (self.obscuring_factor & 0xFFFFFF);
-        let obscured_commitment = obscured_commitment as u32;
-        let lock_time = (0x20u32 << 24) | obscured_commitment;
-        let sequence = (0x80u32 << 24) | obscured_commitment;
-
+        let (lock_time, sequence) = obscured_commitment_number(
+            self.commitment_number,
+            self.obscuring_factor(),
+        );
+        tx_graph.funding_outpoint = self.funding_outpoint;
         tx_graph.cmt_version = 2;
         tx_graph.cmt_locktime = lock_time;
         tx_graph.cmt_sequence = sequence;
-        // We are doing counterparty's transaction!
-        tx_graph.cmt_outs = vec![
-            TxOut::ln_to_local(
-                self.remote_amount,
-                self.local_keys.revocation_basepoint,
-                self.remote_keys.delayed_payment_basepoint,
-                self.to_self_delay,
-            ),
-            TxOut::ln_to_remote_v1(
-                self.local_amount,
-                self.local_keys.payment_basepoint,
-            ),
-        ];
+        // Balance outputs always go first, so extenders may replace them
+        // by their position (see `Htlc`)
+        tx_graph.cmt_outs = self.balance_outs(
+            self.local_amount_msat,
+            self.remote_amount_msat,
+            0,
+        );
 
         Ok(())
     }
+
+    fn strict_encode_extension(
+        &self,
+        e: &mut dyn io::Write,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.strict_encode(e)
+    }
 }
 
 pub trait ScriptGenerators {
@@ -354,28 +697,256 @@ impl ScriptGenerators for TxOut {
     }
 }
 
+/// Derives public key from the `basepoint` and the `per_commitment_point`
+/// according to BOLT-3:
+/// `pubkey = basepoint + SHA256(per_commitment_point || basepoint) * G`.
+///
+/// Used for `localpubkey`, `remotepubkey`, `local_htlcpubkey`,
+/// `remote_htlcpubkey` and `local_delayedpubkey` derivation.
+pub fn derive_pubkey(
+    basepoint: PublicKey,
+    per_commitment_point: PublicKey,
+) -> PublicKey {
+    let tweak = tweak_hash(per_commitment_point, basepoint);
+    let mut pubkey = basepoint;
+    pubkey.add_exp_assign(&SECP256K1, &tweak[..]).expect(
+        "Tweaking with secret key can fail with negligible probability",
+    );
+    pubkey
+}
+
+/// Derives private key matching the public key from [`derive_pubkey`]:
+/// `privkey = basepoint_secret + SHA256(per_commitment_point || basepoint)`
+pub fn derive_privkey(
+    basepoint_secret: SecretKey,
+    per_commitment_point: PublicKey,
+) -> SecretKey {
+    let basepoint = PublicKey::from_secret_key(&SECP256K1, &basepoint_secret);
+    let tweak = tweak_hash(per_commitment_point, basepoint);
+    let mut privkey = basepoint_secret;
+    privkey.add_assign(&tweak[..]).expect(
+        "Tweaking with secret key can fail with negligible probability",
+    );
+    privkey
+}
+
+/// Derives `revocationpubkey` according to BOLT-3:
+/// `revocationpubkey = revocation_basepoint * SHA256(revocation_basepoint ||
+/// per_commitment_point) + per_commitment_point *
+/// SHA256(per_commitment_point || revocation_basepoint)`
+pub fn derive_revocation_pubkey(
+    revocation_basepoint: PublicKey,
+    per_commitment_point: PublicKey,
+) -> PublicKey {
+    let mut basepoint_part = revocation_basepoint;
+    basepoint_part
+        .mul_assign(
+            &SECP256K1,
+            &tweak_hash(revocation_basepoint, per_commitment_point)[..],
+        )
+        .expect(
+            "Tweaking with secret key can fail with negligible probability",
+        );
+    let mut point_part = per_commitment_point;
+    point_part
+        .mul_assign(
+            &SECP256K1,
+            &tweak_hash(per_commitment_point, revocation_basepoint)[..],
+        )
+        .expect(
+            "Tweaking with secret key can fail with negligible probability",
+        );
+    basepoint_part
+        .combine(&point_part)
+        .expect("Adding two tweaked keys can fail with negligible probability")
+}
+
+/// Derives private key matching `revocationpubkey` from
+/// [`derive_revocation_pubkey`]. It can be computed only by the owner of the
+/// revocation basepoint once the remote peer has revealed its
+/// `per_commitment_secret`.
+pub fn derive_revocation_privkey(
+    revocation_basepoint_secret: SecretKey,
+    per_commitment_secret: SecretKey,
+) -> SecretKey {
+    let revocation_basepoint =
+        PublicKey::from_secret_key(&SECP256K1, &revocation_basepoint_secret);
+    let per_commitment_point =
+        PublicKey::from_secret_key(&SECP256K1, &per_commitment_secret);
+
+    let mut basepoint_part = revocation_basepoint_secret;
+    basepoint_part
+        .mul_assign(&tweak_hash(revocation_basepoint, per_commitment_point)[..])
+        .expect(
+            "Tweaking with secret key can fail with negligible probability",
+        );
+    let mut point_part = per_commitment_secret;
+    point_part
+        .mul_assign(&tweak_hash(per_commitment_point, revocation_basepoint)[..])
+        .expect(
+            "Tweaking with secret key can fail with negligible probability",
+        );
+    basepoint_part
+        .add_assign(&point_part[..])
+        .expect("Adding two tweaked keys can fail with negligible probability");
+    basepoint_part
+}
+
+/// Generates per-commitment secret with the given 48-bit `index` from the
+/// `seed` according to BOLT-3 `generate_from_seed` algorithm. Commitment
+/// transaction number `n` uses index `2^48 - 1 - n`, so the secrets of the
+/// revoked commitments can be stored compactly by the remote peer.
+pub fn per_commitment_secret(seed: &[u8; 32], index: u64) -> [u8; 32] {
+    derive_secret(*seed, 48, index)
+}
+
+/// Derives secret with the given `index` from the `base` secret, flipping
+/// and hashing only the lower `bits` of the index, according to BOLT-3
+/// `derive_secret` algorithm
+fn derive_secret(base: [u8; 32], bits: usize, index: u64) -> [u8; 32] {
+    let mut secret = base;
+    for bit in (0..bits).rev() {
+        if index & (1 << bit) != 0 {
+            secret[bit / 8] ^= 1 << (bit % 8);
+            secret = sha256::Hash::hash(&secret).into_inner();
+        }
+    }
+    secret
+}
+
+/// Errors of the per-commitment secret storage
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum ShaChainError {
+    /// per-commitment secret with index {0} is received out of order
+    OutOfOrder(u64),
+
+    /// previously received per-commitment secret with index {0} can't be
+    /// derived from the new one
+    Underivable(u64),
+}
+
+/// Compact storage of the per-commitment secrets revealed by the remote peer,
+/// according to BOLT-3 "Efficient Per-commitment Secret Storage".
+///
+/// Secrets must be inserted in the order of decreasing indexes, i.e. of the
+/// increasing commitment numbers. Each secret is stored at the position
+/// equal to the number of trailing zeros of its index, replacing the secrets
+/// which can be derived from it, so at most 49 secrets are kept.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ShaChain {
+    known: Vec<Option<(u64, [u8; 32])>>,
+    last_index: Option<u64>,
+}
+
+impl Default for ShaChain {
+    fn default() -> Self {
+        ShaChain {
+            known: vec![None; 49],
+            last_index: None,
+        }
+    }
+}
+
+impl ShaChain {
+    /// Returns index of the last inserted secret
+    #[inline]
+    pub fn last_index(&self) -> Option<u64> {
+        self.last_index
+    }
+
+    /// Checks that the `secret` with the given `index` can be inserted: its
+    /// index must be less than the index of the last inserted secret and
+    /// all the stored secrets must be derivable from it
+    pub fn check(
+        &self,
+        index: u64,
+        secret: [u8; 32],
+    ) -> Result<(), ShaChainError> {
+        if let Some(last_index) = self.last_index {
+            if index >= last_index {
+                Err(ShaChainError::OutOfOrder(index))?
+            }
+        }
+        let pos = position(index);
+        for (known_index, known_secret) in self.known[..pos].iter().flatten() {
+            if derive_secret(secret, pos, *known_index) != *known_secret {
+                Err(ShaChainError::Underivable(*known_index))?
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts the `secret` with the given `index` if it passes
+    /// [`ShaChain::check`]
+    pub fn insert(
+        &mut self,
+        index: u64,
+        secret: [u8; 32],
+    ) -> Result<(), ShaChainError> {
+        self.check(index, secret)?;
+        self.known[position(index)] = Some((index, secret));
+        self.last_index = Some(index);
+        Ok(())
+    }
+
+    /// Returns previously inserted secret with the given `index`, if any
+    pub fn secret(&self, index: u64) -> Option<[u8; 32]> {
+        self.known.iter().enumerate().find_map(|(pos, known)| {
+            let (known_index, known_secret) = (*known)?;
+            // Stored secret allows to derive all secrets which indexes
+            // differ from its own index only in the lower `pos` bits
+            if index >> pos << pos == known_index {
+                Some(derive_secret(known_secret, pos, index))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Returns position of the secret with the given `index` in [`ShaChain`]
+fn position(index: u64) -> usize {
+    (index.trailing_zeros() as usize).min(48)
+}
+
+fn tweak_hash(first: PublicKey, second: PublicKey) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&first.serialize());
+    engine.input(&second.serialize());
+    sha256::Hash::from_engine(engine)
+}
+
 fn compute_obscuring_factor(
     is_originator: bool,
     local_payment_basepoint: PublicKey,
     remote_payment_basepoint: PublicKey,
 ) -> u64 {
-    use bitcoin::hashes::{sha256, Hash, HashEngine};
-
-    let mut engine = sha256::Hash::engine();
-    if is_originator {
-        engine.input(&local_payment_basepoint.serialize());
-        engine.input(&remote_payment_basepoint.serialize());
+    let obscuring_hash = if is_originator {
+        tweak_hash(local_payment_basepoint, remote_payment_basepoint)
     } else {
-        engine.input(&remote_payment_basepoint.serialize());
-        engine.input(&local_payment_basepoint.serialize());
-    }
-    let obscuring_hash = sha256::Hash::from_engine(engine);
+        tweak_hash(remote_payment_basepoint, local_payment_basepoint)
+    };
 
     let mut buf = [0u8; 8];
-    buf.copy_from_slice(&obscuring_hash[24..]);
+    buf[2..].copy_from_slice(&obscuring_hash[26..]);
     u64::from_be_bytes(buf)
 }
 
+/// Returns commitment transaction `lock_time` and input `sequence` values
+/// encoding 48-bit commitment number obscured by XOR with the lower 48 bits
+/// of `obscuring_factor`: upper 24 bits of the obscured number go to the
+/// sequence and lower 24 bits - to the lock time
+fn obscured_commitment_number(
+    commitment_number: u64,
+    obscuring_factor: u64,
+) -> (u32, u32) {
+    let obscured = (commitment_number ^ obscuring_factor) & 0xFFFF_FFFF_FFFF;
+    let lock_time = (0x20u32 << 24) | (obscured & 0xFF_FFFF) as u32;
+    let sequence = (0x80u32 << 24) | (obscured >> 24) as u32;
+    (lock_time, sequence)
+}
+
 // TODO: Remove TxGenerators since they are not needed
 pub trait TxGenerators {
     fn ln_cmt_base(
@@ -405,13 +976,8 @@ impl TxGenerators for Transaction {
         local_delayedpubkey: PublicKey,
         to_self_delay: u16,
     ) -> Self {
-        // The 48-bit commitment number is obscured by XOR with the lower
-        // 48 bits of `obscuring_factor`
-        let obscured_commitment =
-            (commitment_number & 0xFFFFFF) ^ (obscuring_factor & 0xFFFFFF);
-        let obscured_commitment = obscured_commitment as u32;
-        let lock_time = (0x20u32 << 24) | obscured_commitment;
-        let sequence = (0x80u32 << 24) | obscured_commitment;
+        let (lock_time, sequence) =
+            obscured_commitment_number(commitment_number, obscuring_factor);
         let tx = Transaction {
             version: 2,
             lock_time,
@@ -480,3 +1046,346 @@ impl TxGenerators for Psbt {
             .expect("Tx has empty sigs so PSBT creation does not faile")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use bitcoin::Txid;
+    use std::str::FromStr;
+
+    use crate::lnp::application::payment::bip96::Bip96;
+    use crate::lnp::test_helpers::{commitment_features, init};
+
+    fn secret(hex: &str) -> SecretKey {
+        SecretKey::from_str(hex).unwrap()
+    }
+
+    fn point(secret: SecretKey) -> PublicKey {
+        PublicKey::from_secret_key(&SECP256K1, &secret)
+    }
+
+    fn pubkey(hex: &str) -> PublicKey {
+        PublicKey::from_str(hex).unwrap()
+    }
+
+    // Test vectors from BOLT-3 Appendix E
+    #[test]
+    fn test_key_derivation() {
+        let base_secret = secret(
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        );
+        let per_commitment_secret = secret(
+            "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100",
+        );
+        let base_point = point(base_secret);
+        let per_commitment_point = point(per_commitment_secret);
+        assert_eq!(
+            base_point,
+            pubkey("036d6caac248af96f6afa7f904f550253a0f3ef3f5aa2fe6838a95b216691468e2")
+        );
+        assert_eq!(
+            per_commitment_point,
+            pubkey("025f7117a78150fe2ef97db7cfc83bd57b2e2c0d0dd25eaf467a4a1c2a45ce1486")
+        );
+
+        assert_eq!(
+            derive_pubkey(base_point, per_commitment_point),
+            pubkey("0235f2dbfaa89b57ec7b055afe29849ef7ddfeb1cefdb9ebdc43f5494984db29e5")
+        );
+        assert_eq!(
+            derive_privkey(base_secret, per_commitment_point),
+            secret("cbced912d3b21bf196a766651e436aff192362621ce317704ea2f75d87e7be0f")
+        );
+        assert_eq!(
+            derive_revocation_pubkey(base_point, per_commitment_point),
+            pubkey("02916e326636d19c33f13e8c0c3a03dd157f332f3e99c317c141dd865eb01f8ff0")
+        );
+        assert_eq!(
+            derive_revocation_privkey(base_secret, per_commitment_secret),
+            secret("d09ffff62ddb2297ab000cc85bcb4283fdeb6aa052affbc9dddcf33b61078110")
+        );
+        assert_eq!(
+            point(derive_revocation_privkey(
+                base_secret,
+                per_commitment_secret
+            )),
+            derive_revocation_pubkey(base_point, per_commitment_point)
+        );
+    }
+
+    // Test vectors from BOLT-3 Appendix D
+    #[test]
+    fn test_per_commitment_secret() {
+        let max_index = 0xFFFF_FFFF_FFFF;
+        for (seed, index, secret) in &[
+            (
+                [0x00u8; 32],
+                max_index,
+                "02a40c85b6f28da08dfdbe0926c53fab2de6d28c10301f8f7c4073d5e42e3148",
+            ),
+            (
+                [0xFF; 32],
+                max_index,
+                "7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc",
+            ),
+            (
+                [0xFF; 32],
+                0xaaaaaaaaaaa,
+                "56f4008fb007ca9acf0e15b054d5c9fd12ee06cea347914ddbaed70d1c13a528",
+            ),
+            (
+                [0xFF; 32],
+                0x555555555555,
+                "9015daaeb06dba4ccc05b91b2f73bd54405f2be9f217fbacd3c5ac2e62327d31",
+            ),
+            (
+                [0x01; 32],
+                1,
+                "915c75942a26bb3a433a8ce2cb0427c29ec6c1775cfc78328b57f6ba7bfeaa9c",
+            ),
+        ] {
+            assert_eq!(per_commitment_secret(seed, *index).to_hex(), *secret);
+        }
+    }
+
+    #[test]
+    fn test_shachain() {
+        let seed = [0x01u8; 32];
+        let max_index = 0xFFFF_FFFF_FFFF;
+        let mut shachain = ShaChain::default();
+        for index in (max_index - 100..=max_index).rev() {
+            shachain
+                .insert(index, per_commitment_secret(&seed, index))
+                .unwrap();
+        }
+        assert_eq!(shachain.last_index(), Some(max_index - 100));
+        assert!(shachain.known.iter().flatten().count() <= 49);
+        for index in max_index - 100..=max_index {
+            assert_eq!(
+                shachain.secret(index),
+                Some(per_commitment_secret(&seed, index))
+            );
+        }
+        assert_eq!(shachain.secret(max_index - 101), None);
+
+        // Secret produced from a different seed does not allow to derive
+        // the already known secrets
+        let index = max_index - 101;
+        assert_eq!(
+            shachain.check(index, per_commitment_secret(&[0x02; 32], index)),
+            Err(ShaChainError::Underivable(max_index - 100))
+        );
+        assert_eq!(
+            shachain.insert(max_index, per_commitment_secret(&seed, max_index)),
+            Err(ShaChainError::OutOfOrder(max_index))
+        );
+        assert_eq!(shachain.last_index(), Some(max_index - 100));
+    }
+
+    // Channel from BOLT-3 Appendix C
+    fn bolt3_channel() -> Bolt3 {
+        let per_commitment_point = point(secret(
+            "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100",
+        ));
+        Bolt3 {
+            local_amount_msat: 7_000_000_000,
+            remote_amount_msat: 3_000_000_000,
+            commitment_number: 42,
+            feerate_per_kw: 15000,
+            dust_limit_satoshis: 546,
+            to_self_delay: 144,
+            funding_outpoint: OutPoint::new(
+                Txid::from_hex(
+                    "8984484a580b825b9972d7adb15050b3ab624ccd731946b3eeddb92f4e7ef6be",
+                )
+                .unwrap(),
+                0,
+            ),
+            local_keys: Keyset {
+                funding_pubkey: pubkey("023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb"),
+                payment_basepoint: point(secret(
+                    "1111111111111111111111111111111111111111111111111111111111111111",
+                )),
+                delayed_payment_basepoint: point(secret(
+                    "3333333333333333333333333333333333333333333333333333333333333333",
+                )),
+                per_commitment_point,
+                ..Keyset::dumb_default()
+            },
+            next_per_commitment_point: *SECP256K1_PUBKEY_DUMB,
+            remote_keys: Keyset {
+                funding_pubkey: pubkey("030e9f7b623d2ccc7c9bd44d66d5ce21ce504c0acf6385a132cec6d3c39fa711c1"),
+                revocation_basepoint: point(secret(
+                    "2222222222222222222222222222222222222222222222222222222222222222",
+                )),
+                payment_basepoint: point(secret(
+                    "4444444444444444444444444444444444444444444444444444444444444444",
+                )),
+                ..Keyset::dumb_default()
+            },
+            local_features: CommitmentFeatures::default(),
+            remote_features: CommitmentFeatures::default(),
+            features_fixed: false,
+            is_originator: true,
+        }
+    }
+
+    #[test]
+    fn test_commitment_keys() {
+        let channel = bolt3_channel();
+        assert_eq!(channel.obscuring_factor(), 0x2bb038521914);
+
+        let keys = channel.commitment_keys();
+        assert_eq!(
+            keys.localpubkey,
+            pubkey("030d417a46946384f88d5f3337267c5e579765875dc4daca813e21734b140639e7")
+        );
+        assert_eq!(
+            keys.remotepubkey,
+            pubkey("0394854aa6eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b")
+        );
+        assert_eq!(
+            keys.local_delayedpubkey,
+            pubkey("03fd5960528dc152014952efdb702a88f71e3c1653b2314431701ec77e57fde83c")
+        );
+        assert_eq!(
+            keys.revocationpubkey,
+            pubkey("0212a140cd0c6539d07cd08dfe09984dec3251ea808b892efeac3ede9402bf2b19")
+        );
+    }
+
+    #[test]
+    fn test_static_remotekey() {
+        let mut channel = bolt3_channel();
+        let static_remotekey = commitment_features(true, false);
+        channel
+            .update_from_local(&init(static_remotekey.clone()))
+            .unwrap();
+        assert_eq!(channel.features(), CommitmentFeatures::default());
+        channel.update_from_peer(&init(static_remotekey)).unwrap();
+        assert!(channel.features().static_remotekey);
+        assert_eq!(
+            channel.commitment_keys().remotepubkey,
+            channel.remote_keys.payment_basepoint
+        );
+
+        // Anchor outputs require static remote key
+        let anchors = CommitmentFeatures {
+            static_remotekey: false,
+            anchor_outputs: true,
+        };
+        assert_eq!(
+            anchors.intersection(&anchors),
+            CommitmentFeatures {
+                static_remotekey: true,
+                anchor_outputs: true,
+            }
+        );
+        assert_eq!(
+            anchors.intersection(&CommitmentFeatures::default()),
+            CommitmentFeatures::default()
+        );
+    }
+
+    #[test]
+    fn test_features_fixed() {
+        let mut channel = Bolt3::dumb_default();
+        let static_remotekey = commitment_features(true, false);
+        channel
+            .update_from_local(&init(static_remotekey.clone()))
+            .unwrap();
+        channel.update_from_peer(&init(static_remotekey)).unwrap();
+        channel
+            .update_from_peer(&Messages::OpenChannel(OpenChannel {
+                funding_satoshis: 10_000_000,
+                ..OpenChannel::dumb_default()
+            }))
+            .unwrap();
+        let features = channel.features();
+        assert!(features.static_remotekey);
+
+        // Features negotiated on reconnection must not affect the channel
+        channel
+            .update_from_local(&init(commitment_features(true, true)))
+            .unwrap();
+        channel
+            .update_from_peer(&init(commitment_features(true, true)))
+            .unwrap();
+        assert_eq!(channel.features(), features);
+        channel
+            .update_from_peer(&init(commitment_features(false, false)))
+            .unwrap();
+        assert_eq!(channel.features(), features);
+    }
+
+    #[test]
+    fn test_commitment_tx() {
+        // "simple commitment tx with no HTLCs" vector from BOLT-3 Appendix C;
+        // we compare the transaction without the witness, since signing is
+        // not a part of the transaction graph construction
+        let mut tx_graph = channel::TxGraph::default();
+        bolt3_channel().apply(&mut tx_graph).unwrap();
+        Bip96.apply(&mut tx_graph).unwrap();
+        let tx = tx_graph.render_cmt().global.unsigned_tx;
+        assert_eq!(
+            serialize(&tx).to_hex(),
+            "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b820b58\
+            4a488489000000000038b02b8002c0c62d0000000000160014ccf1af2f2aabee14\
+            bb40fa3851ab2301de84311054a56a00000000002200204adb4e2f00643db396dd\
+            120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220"
+        );
+
+        // Outputs below the dust limit must be trimmed
+        let mut channel = bolt3_channel();
+        channel.remote_amount_msat = 545_999;
+        channel.apply(&mut tx_graph).unwrap();
+        assert_eq!(tx_graph.cmt_outs.len(), 1);
+        assert_eq!(tx_graph.cmt_outs[0].value, 6_989_140);
+    }
+
+    #[test]
+    fn test_channel_updates() {
+        let mut channel = Bolt3::dumb_default();
+        let open_channel = OpenChannel {
+            funding_satoshis: 10_000_000,
+            push_msat: 3_000_000_000,
+            feerate_per_kw: 15000,
+            to_self_delay: 144,
+            ..OpenChannel::dumb_default()
+        };
+        channel
+            .update_from_peer(&Messages::OpenChannel(open_channel))
+            .unwrap();
+        assert!(!channel.is_originator);
+        assert_eq!(channel.local_amount_msat, 3_000_000_000);
+        assert_eq!(channel.remote_amount_msat, 7_000_000_000);
+        assert_eq!(channel.to_self_delay, 144);
+        assert_eq!(channel.commitment_fee(0), 10860);
+        assert_eq!(channel.commitment_fee(5), 23760);
+    }
+
+    #[test]
+    fn test_funding_overflow() {
+        let mut channel = Bolt3::dumb_default();
+        let open_channel = OpenChannel {
+            funding_satoshis: u64::MAX,
+            ..OpenChannel::dumb_default()
+        };
+        let message = Messages::OpenChannel(open_channel);
+        assert!(channel.update_from_peer(&message).is_err());
+        assert!(channel.update_from_local(&message).is_err());
+        assert_eq!(channel, Bolt3::dumb_default());
+
+        let open_channel = OpenChannel {
+            funding_satoshis: 1000,
+            push_msat: 1_000_001,
+            ..OpenChannel::dumb_default()
+        };
+        let message = Messages::OpenChannel(open_channel);
+        assert!(channel.update_from_peer(&message).is_err());
+        assert!(channel.update_from_local(&message).is_err());
+        assert_eq!(channel, Bolt3::dumb_default());
+    }
+}
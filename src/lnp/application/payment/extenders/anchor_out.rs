@@ -10,3 +10,576 @@
 // You should have received a copy of the MIT License
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
+
+use amplify::{DumbDefault, Wrapper};
+use std::io;
+
+use bitcoin::blockdata::{opcodes::all::*, script};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::{OutPoint, SigHashType, Transaction, TxIn, TxOut};
+
+use crate::bp::{IntoPk, LockScript, PubkeyScript, WitnessScript};
+use crate::lnp::application::payment::bolt3::{
+    ScriptGenerators as _, COMMITMENT_TX_HTLC_WEIGHT,
+};
+use crate::lnp::application::payment::{Bolt3, ExtensionId, TxType};
+use crate::lnp::application::{channel, ChannelExtension, Extension, Messages};
+use crate::strict_encoding::{self, StrictDecode, StrictEncode};
+
+/// Amount of each of the anchor outputs
+pub const ANCHOR_OUTPUT_VALUE: u64 = 330;
+
+/// Weight of the commitment transaction with anchor outputs and without HTLC
+/// outputs, used for the commitment fee computation according to BOLT-3
+pub const COMMITMENT_TX_ANCHOR_WEIGHT: u64 = 1124;
+
+/// Channel extender following `option_anchor_outputs`: it adds anchor
+/// outputs to the commitment transaction, which may be spent by each of the
+/// channel parties for CPFP fee bumping.
+///
+/// Since HTLC outputs of such channels can't be spent without a delay, the
+/// remote peer signs the second-stage HTLC transactions with
+/// `SIGHASH_SINGLE|ANYONECANPAY`, allowing their fees to be increased by
+/// adding more inputs. The extender does nothing unless the feature was
+/// negotiated by both channel parties with `init` messages.
+///
+/// Commitment keys and channel parameters are read from the [`Bolt3`] channel
+/// constructor shared with the extender (see [`AnchorOut::with`]). The
+/// constructor is not a part of the extender strict encoding, so the decoded
+/// extender must be connected to it with [`AnchorOut::connect`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AnchorOut {
+    /// Channel constructor shared with the extender
+    bolt3: channel::Shared<Bolt3>,
+}
+
+impl DumbDefault for AnchorOut {
+    fn dumb_default() -> Self {
+        AnchorOut {
+            bolt3: channel::Shared::dumb_default(),
+        }
+    }
+}
+
+impl StrictEncode for AnchorOut {
+    type Error = strict_encoding::Error;
+
+    #[inline]
+    fn strict_encode<E: io::Write>(&self, _: E) -> Result<usize, Self::Error> {
+        // The extender has no data besides the shared channel constructor
+        Ok(0)
+    }
+}
+
+impl StrictDecode for AnchorOut {
+    type Error = strict_encoding::Error;
+
+    #[inline]
+    fn strict_decode<D: io::Read>(_: D) -> Result<Self, Self::Error> {
+        Ok(AnchorOut::dumb_default())
+    }
+}
+
+impl AnchorOut {
+    /// Constructs extender reading channel parameters from the `bolt3`
+    /// channel constructor, which must be added to the same channel
+    #[inline]
+    pub fn with(bolt3: channel::Shared<Bolt3>) -> Self {
+        AnchorOut { bolt3 }
+    }
+
+    /// Connects extender to the `bolt3` channel constructor after it was
+    /// restored from the strict encoding
+    #[inline]
+    pub fn connect(&mut self, bolt3: channel::Shared<Bolt3>) {
+        self.bolt3 = bolt3;
+    }
+
+    /// Returns anchor output which may be spent by the local node
+    #[inline]
+    pub fn local_anchor(&self) -> TxOut {
+        TxOut::ln_anchor(
+            ANCHOR_OUTPUT_VALUE,
+            self.bolt3.borrow().local_keys().funding_pubkey,
+        )
+    }
+
+    /// Returns anchor output which may be spent by the remote peer
+    #[inline]
+    pub fn remote_anchor(&self) -> TxOut {
+        TxOut::ln_anchor(
+            ANCHOR_OUTPUT_VALUE,
+            self.bolt3.borrow().remote_keys().funding_pubkey,
+        )
+    }
+
+    /// Returns fee which must be paid by CPFP child transaction of
+    /// `child_weight` for the package with the commitment transaction from
+    /// `tx_graph` to reach `feerate_per_kw`
+    pub fn cpfp_fee(
+        &self,
+        tx_graph: &channel::TxGraph,
+        feerate_per_kw: u32,
+        child_weight: u64,
+    ) -> u64 {
+        let bolt3 = self.bolt3.borrow();
+        let funding_amount =
+            (*bolt3.local_amount_msat() + *bolt3.remote_amount_msat()) / 1000;
+        let cmt_amount = tx_graph
+            .cmt_outs
+            .iter()
+            .map(|txout| txout.value)
+            .sum::<u64>();
+        let cmt_fee = funding_amount.saturating_sub(cmt_amount);
+        let cmt_weight = COMMITMENT_TX_ANCHOR_WEIGHT
+            + COMMITMENT_TX_HTLC_WEIGHT * htlc_count(tx_graph) as u64;
+        (feerate_per_kw as u64 * (cmt_weight + child_weight) / 1000)
+            .saturating_sub(cmt_fee)
+    }
+
+    /// Constructs CPFP child transaction spending the local anchor output of
+    /// the commitment transaction from `tx_graph` together with the wallet
+    /// `utxos`. The transaction pays `fee` and sends the rest of the funds to
+    /// the `change` output.
+    pub fn cpfp(
+        &self,
+        tx_graph: &channel::TxGraph,
+        utxos: &[(OutPoint, TxOut)],
+        change: PubkeyScript,
+        fee: u64,
+    ) -> Result<Psbt, channel::Error> {
+        let local_anchor = self.local_anchor();
+        let vout = tx_graph
+            .cmt_outs
+            .iter()
+            .position(|txout| *txout == local_anchor)
+            .ok_or_else(|| {
+                channel::Error::Extension(s!(
+                    "commitment transaction has no local anchor output"
+                ))
+            })?;
+        let anchor = OutPoint::new(
+            tx_graph.render_cmt().global.unsigned_tx.txid(),
+            vout as u32,
+        );
+
+        let amount = ANCHOR_OUTPUT_VALUE
+            + utxos.iter().map(|(_, txout)| txout.value).sum::<u64>();
+        if amount < fee {
+            return Err(channel::Error::Extension(format!(
+                "CPFP fee {} exceeds available amount {}",
+                fee, amount
+            )));
+        }
+        Ok(Psbt::ln_cpfp(
+            anchor,
+            self.bolt3.borrow().local_keys().funding_pubkey,
+            utxos,
+            change,
+            amount - fee,
+        ))
+    }
+}
+
+impl channel::State for AnchorOut {}
+
+impl Extension for AnchorOut {
+    type Identity = ExtensionId;
+
+    fn identity(&self) -> Self::Identity {
+        ExtensionId::AnchorOut
+    }
+
+    fn update_from_peer(&mut self, _: &Messages) -> Result<(), channel::Error> {
+        Ok(())
+    }
+
+    fn extension_state(&self) -> Box<dyn channel::State> {
+        Box::new(self.clone())
+    }
+}
+
+impl ChannelExtension for AnchorOut {
+    fn channel_state(&self) -> Box<dyn channel::State> {
+        Box::new(self.clone())
+    }
+
+    fn apply(
+        &mut self,
+        tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        let bolt3 = *self.bolt3.borrow();
+        if !bolt3.features().anchor_outputs {
+            return Ok(());
+        }
+        let keys = bolt3.commitment_keys();
+
+        // The local node signs its HTLC transactions with `SIGHASH_ALL`, while
+        // the remote peer signatures commit only to the spent HTLC output and
+        // the matching output of the transaction
+        for (role, _, psbt) in tx_graph.vec_mut() {
+            match TxType::from(role) {
+                TxType::HtlcTimeout | TxType::HtlcSuccess => {
+                    psbt.inputs[0].sighash_type = Some(SigHashType::All)
+                }
+                _ => {}
+            }
+        }
+        tx_graph.set_remote_sighash_type(SigHashType::SinglePlusAnyoneCanPay);
+        let htlc_count = htlc_count(tx_graph);
+
+        // Commitment fee and the anchor amounts are already deducted from
+        // the funder balance output by the channel constructor and HTLC
+        // extender, see `Bolt3::funder_fee`
+        let to_local = PubkeyScript::ln_to_local(
+            0,
+            keys.revocationpubkey,
+            keys.local_delayedpubkey,
+            *bolt3.to_self_delay(),
+        )
+        .into_inner();
+        let to_remote =
+            PubkeyScript::ln_to_remote_v1(0, keys.remotepubkey).into_inner();
+
+        let mut has_to_local = false;
+        let mut has_to_remote = false;
+        for txout in &mut tx_graph.cmt_outs {
+            if txout.script_pubkey == to_local {
+                has_to_local = true;
+            } else if txout.script_pubkey == to_remote {
+                has_to_remote = true;
+                *txout = TxOut::ln_to_remote_v2(txout.value, keys.remotepubkey);
+            }
+        }
+        if has_to_local || htlc_count > 0 {
+            tx_graph.cmt_outs.push(self.local_anchor());
+        }
+        if has_to_remote || htlc_count > 0 {
+            tx_graph.cmt_outs.push(self.remote_anchor());
+        }
+
+        Ok(())
+    }
+
+    fn strict_encode_extension(
+        &self,
+        e: &mut dyn io::Write,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.strict_encode(e)
+    }
+}
+
+/// Counts untrimmed HTLC outputs of the commitment transaction, which are
+/// spent by the second-stage HTLC transactions from the graph
+fn htlc_count(tx_graph: &channel::TxGraph) -> usize {
+    tx_graph.role_count(TxType::HtlcTimeout)
+        + tx_graph.role_count(TxType::HtlcSuccess)
+}
+
+pub trait ScriptGenerators {
+    fn ln_anchor(amount: u64, funding_pubkey: PublicKey) -> Self;
+}
+
+impl ScriptGenerators for LockScript {
+    fn ln_anchor(_: u64, funding_pubkey: PublicKey) -> Self {
+        script::Builder::new()
+            .push_key(&funding_pubkey.into_pk())
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_IFDUP)
+            .push_opcode(OP_NOTIF)
+            .push_int(16)
+            .push_opcode(OP_CSV)
+            .push_opcode(OP_ENDIF)
+            .into_script()
+            .into()
+    }
+}
+
+impl ScriptGenerators for WitnessScript {
+    #[inline]
+    fn ln_anchor(amount: u64, funding_pubkey: PublicKey) -> Self {
+        LockScript::ln_anchor(amount, funding_pubkey).into()
+    }
+}
+
+impl ScriptGenerators for PubkeyScript {
+    #[inline]
+    fn ln_anchor(amount: u64, funding_pubkey: PublicKey) -> Self {
+        WitnessScript::ln_anchor(amount, funding_pubkey).to_p2wsh()
+    }
+}
+
+impl ScriptGenerators for TxOut {
+    #[inline]
+    fn ln_anchor(amount: u64, funding_pubkey: PublicKey) -> Self {
+        TxOut {
+            value: amount,
+            script_pubkey: PubkeyScript::ln_anchor(amount, funding_pubkey)
+                .into(),
+        }
+    }
+}
+
+pub trait TxGenerators {
+    /// Constructs CPFP child transaction spending `anchor` output, locked
+    /// to the `funding_pubkey`, together with the wallet `utxos` and sending
+    /// `amount` to the `change` output
+    fn ln_cpfp(
+        anchor: OutPoint,
+        funding_pubkey: PublicKey,
+        utxos: &[(OutPoint, TxOut)],
+        change: PubkeyScript,
+        amount: u64,
+    ) -> Self;
+}
+
+impl TxGenerators for Transaction {
+    fn ln_cpfp(
+        anchor: OutPoint,
+        _: PublicKey,
+        utxos: &[(OutPoint, TxOut)],
+        change: PubkeyScript,
+        amount: u64,
+    ) -> Self {
+        let input = Some(anchor)
+            .into_iter()
+            .chain(utxos.iter().map(|(outpoint, _)| *outpoint))
+            .map(|previous_output| TxIn {
+                previous_output,
+                script_sig: none!(),
+                // Signals opt-in replace-by-fee
+                sequence: 0xFFFF_FFFD,
+                witness: empty!(),
+            })
+            .collect();
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input,
+            output: vec![TxOut {
+                value: amount,
+                script_pubkey: change.into(),
+            }],
+        }
+    }
+}
+
+impl TxGenerators for Psbt {
+    fn ln_cpfp(
+        anchor: OutPoint,
+        funding_pubkey: PublicKey,
+        utxos: &[(OutPoint, TxOut)],
+        change: PubkeyScript,
+        amount: u64,
+    ) -> Self {
+        let mut psbt = Psbt::from_unsigned_tx(Transaction::ln_cpfp(
+            anchor,
+            funding_pubkey,
+            utxos,
+            change,
+            amount,
+        ))
+        .expect("Tx has empty sigs so PSBT creation does not faile");
+        psbt.inputs[0].witness_utxo =
+            Some(TxOut::ln_anchor(ANCHOR_OUTPUT_VALUE, funding_pubkey));
+        psbt.inputs[0].witness_script = Some(
+            LockScript::ln_anchor(ANCHOR_OUTPUT_VALUE, funding_pubkey)
+                .into_inner(),
+        );
+        for (input, (_, txout)) in psbt.inputs[1..].iter_mut().zip(utxos) {
+            input.witness_utxo = Some(txout.clone());
+        }
+        psbt
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+
+    use crate::lnp::application::payment::bip96::Bip96;
+    use crate::lnp::application::payment::bolt3::ScriptGenerators as _;
+    use crate::lnp::message::{AcceptChannel, OpenChannel};
+    use crate::lnp::test_helpers::{commitment_features, init, point};
+
+    fn channel(
+        push_msat: u64,
+        anchors: bool,
+    ) -> (channel::Shared<Bolt3>, AnchorOut) {
+        let mut bolt3 = channel::Shared::new(Bolt3::dumb_default());
+        let mut anchor_out = AnchorOut::with(bolt3.clone());
+        let open_channel = Messages::OpenChannel(OpenChannel {
+            funding_satoshis: 10_000_000,
+            push_msat,
+            dust_limit_satoshis: 546,
+            feerate_per_kw: 15000,
+            funding_pubkey: point(0x01),
+            payment_point: point(0x11),
+            delayed_payment_basepoint: point(0x33),
+            htlc_basepoint: point(0x11),
+            first_per_commitment_point: point(0x1f),
+            ..OpenChannel::dumb_default()
+        });
+        let accept_channel = Messages::AcceptChannel(AcceptChannel {
+            to_self_delay: 144,
+            funding_pubkey: point(0x02),
+            revocation_basepoint: point(0x22),
+            payment_point: point(0x44),
+            htlc_basepoint: point(0x44),
+            ..AcceptChannel::dumb_default()
+        });
+        let init = init(commitment_features(anchors, anchors));
+        for message in &[init.clone(), open_channel] {
+            bolt3.update_from_local(message).unwrap();
+            anchor_out.update_from_local(message).unwrap();
+        }
+        for message in &[init, accept_channel] {
+            bolt3.update_from_peer(message).unwrap();
+            anchor_out.update_from_peer(message).unwrap();
+        }
+        (bolt3, anchor_out)
+    }
+
+    fn build_graph(
+        bolt3: &mut channel::Shared<Bolt3>,
+        anchor_out: &mut AnchorOut,
+        htlc_tx: Option<Psbt>,
+    ) -> channel::TxGraph {
+        let mut tx_graph = channel::TxGraph::default();
+        bolt3.apply(&mut tx_graph).unwrap();
+        if let Some(psbt) = htlc_tx {
+            tx_graph.insert_tx(TxType::HtlcTimeout, 0u64, psbt);
+        }
+        anchor_out.apply(&mut tx_graph).unwrap();
+        Bip96.apply(&mut tx_graph).unwrap();
+        tx_graph.link_cmt_outs();
+        tx_graph
+    }
+
+    #[test]
+    fn test_anchor_script() {
+        let pubkey = point(0x01);
+        let mut script = vec![0x21];
+        script.extend(&pubkey.serialize()[..]);
+        script.extend(&[0xac, 0x73, 0x64, 0x60, 0xb2, 0x68]);
+        assert_eq!(
+            LockScript::ln_anchor(ANCHOR_OUTPUT_VALUE, pubkey)
+                .into_inner()
+                .into_bytes(),
+            script
+        );
+    }
+
+    #[test]
+    fn test_anchor_outputs() {
+        // Without negotiated feature the extender does nothing
+        let (mut bolt3, mut anchor_out) = channel(3_000_000_000, false);
+        let tx_graph = build_graph(&mut bolt3, &mut anchor_out, None);
+        assert_eq!(tx_graph.cmt_outs.len(), 2);
+        assert!(!tx_graph.cmt_outs.contains(&anchor_out.local_anchor()));
+
+        let (mut bolt3, mut anchor_out) = channel(3_000_000_000, true);
+        let tx_graph = build_graph(&mut bolt3, &mut anchor_out, None);
+        assert_eq!(tx_graph.cmt_outs.len(), 4);
+        assert!(tx_graph.cmt_outs.contains(&anchor_out.local_anchor()));
+        assert!(tx_graph.cmt_outs.contains(&anchor_out.remote_anchor()));
+        // Funder pays both anchors and the fee for the commitment
+        // transaction weight with anchors
+        assert_eq!(tx_graph.cmt_outs[3].value, 6_982_480);
+        // `to_remote` uses static remote key and is delayed by one block
+        assert!(tx_graph
+            .cmt_outs
+            .contains(&TxOut::ln_to_remote_v2(3_000_000, point(0x44))));
+
+        // Anchor for the absent `to_remote` output is not added
+        let (mut bolt3, mut anchor_out) = channel(0, true);
+        let tx_graph = build_graph(&mut bolt3, &mut anchor_out, None);
+        assert_eq!(tx_graph.cmt_outs.len(), 2);
+        assert!(tx_graph.cmt_outs.contains(&anchor_out.local_anchor()));
+    }
+
+    #[test]
+    fn test_htlc_sighash() {
+        let htlc_tx = Psbt::from_unsigned_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: none!(),
+                sequence: 1,
+                witness: empty!(),
+            }],
+            output: vec![],
+        })
+        .unwrap();
+
+        let (mut bolt3, mut anchor_out) = channel(0, true);
+        let tx_graph = build_graph(&mut bolt3, &mut anchor_out, Some(htlc_tx));
+        // HTLC output requires both anchors to be present
+        assert!(tx_graph.cmt_outs.contains(&anchor_out.remote_anchor()));
+        // Funder output is not charged for the HTLC which was not accounted
+        // for by the channel constructor
+        assert!(tx_graph
+            .cmt_outs
+            .iter()
+            .any(|txout| txout.value == 9_982_480));
+        let psbt = tx_graph.tx(TxType::HtlcTimeout, 0u64).unwrap();
+        assert_eq!(psbt.inputs[0].sighash_type, Some(SigHashType::All));
+        assert_eq!(
+            tx_graph.remote_sighash_type(),
+            SigHashType::SinglePlusAnyoneCanPay
+        );
+
+        let (mut bolt3, mut anchor_out) = channel(0, false);
+        let tx_graph = build_graph(&mut bolt3, &mut anchor_out, None);
+        assert_eq!(tx_graph.remote_sighash_type(), SigHashType::All);
+    }
+
+    #[test]
+    fn test_cpfp() {
+        let (mut bolt3, mut anchor_out) = channel(3_000_000_000, true);
+        let tx_graph = build_graph(&mut bolt3, &mut anchor_out, None);
+
+        // Commitment transaction already pays 16860 sat
+        let fee = anchor_out.cpfp_fee(&tx_graph, 30000, 500);
+        assert_eq!(fee, 31860);
+
+        let utxo = (
+            OutPoint::new(Txid::hash(b"wallet"), 1),
+            TxOut::ln_to_remote_v1(100_000, point(0x55)),
+        );
+        let change = PubkeyScript::ln_to_remote_v1(0, point(0x66));
+        let psbt = anchor_out
+            .cpfp(&tx_graph, &[utxo.clone()], change.clone(), fee)
+            .unwrap();
+        let tx = &psbt.global.unsigned_tx;
+        let cmt_txid = tx_graph.render_cmt().global.unsigned_tx.txid();
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.input[0].previous_output.txid, cmt_txid);
+        assert_eq!(
+            tx_graph.cmt_outs[tx.input[0].previous_output.vout as usize],
+            anchor_out.local_anchor()
+        );
+        assert_eq!(tx.input[1].previous_output, utxo.0);
+        assert_eq!(
+            psbt.inputs[0].witness_utxo,
+            Some(anchor_out.local_anchor())
+        );
+        assert_eq!(psbt.inputs[1].witness_utxo, Some(utxo.1.clone()));
+        assert_eq!(tx.output[0].value, 100_330 - fee);
+        assert_eq!(
+            PubkeyScript::from(tx.output[0].script_pubkey.clone()),
+            change
+        );
+
+        assert!(anchor_out
+            .cpfp(&tx_graph, &[utxo.clone()], change.clone(), 100_331)
+            .is_err());
+        let (mut bolt3, mut anchor_out) = channel(3_000_000_000, false);
+        let tx_graph = build_graph(&mut bolt3, &mut anchor_out, None);
+        assert!(anchor_out.cpfp(&tx_graph, &[utxo], change, fee).is_err());
+    }
+}
@@ -11,39 +11,687 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use amplify::{DumbDefault, Wrapper};
+use std::collections::BTreeMap;
+use std::io;
+
 use bitcoin::blockdata::{opcodes::all::*, script};
+use bitcoin::hashes::{ripemd160, Hash};
 use bitcoin::secp256k1::PublicKey;
 use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
-use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::{OutPoint, Transaction, TxIn, TxOut, Txid};
 
 use crate::bp::{
     chain::AssetId, HashLock, HashPreimage, IntoPk, LockScript, PubkeyScript,
     WitnessScript,
 };
-use crate::lnp::application::payment::ExtensionId;
+use crate::lnp::application::payment::{Bolt3, ExtensionId, TxType};
 use crate::lnp::application::{channel, ChannelExtension, Extension, Messages};
+use crate::lnp::message::{AcceptChannel, OpenChannel};
+use crate::strict_encoding::{self, StrictDecode, StrictEncode};
+
+/// Weight of HTLC-timeout transaction, used for its fee computation
+/// according to BOLT-3
+pub const HTLC_TIMEOUT_WEIGHT: u64 = 663;
+
+/// Weight of HTLC-success transaction, used for its fee computation
+/// according to BOLT-3
+pub const HTLC_SUCCESS_WEIGHT: u64 = 703;
+
+/// Weight of HTLC-timeout transaction for channels with
+/// `option_anchor_outputs`
+pub const HTLC_TIMEOUT_ANCHOR_WEIGHT: u64 = 666;
+
+/// Weight of HTLC-success transaction for channels with
+/// `option_anchor_outputs`
+pub const HTLC_SUCCESS_ANCHOR_WEIGHT: u64 = 706;
+
+/// Maximal number of irrevocably fulfilled HTLCs kept by the extender; the
+/// oldest ones are forgotten once the number is exceeded
+pub const MAX_RESOLVED_HTLCS: usize = 1000;
+
+/// Limits on the HTLCs offered by the other channel party, announced by the
+/// party in `open_channel` or `accept_channel` message according to BOLT-2
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, Debug, Default, StrictEncode, StrictDecode,
+)]
+#[lnpbp_crate(crate)]
+pub struct HtlcLimits {
+    pub htlc_minimum_msat: u64,
+    pub max_htlc_value_in_flight_msat: u64,
+    pub max_accepted_htlcs: u16,
+    /// Balance which must be kept by the other channel party
+    pub channel_reserve_satoshis: u64,
+}
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+impl From<&OpenChannel> for HtlcLimits {
+    fn from(msg: &OpenChannel) -> Self {
+        Self {
+            htlc_minimum_msat: msg.htlc_minimum_msat,
+            max_htlc_value_in_flight_msat: msg.max_htlc_value_in_flight_msat,
+            max_accepted_htlcs: msg.max_accepted_htlcs,
+            channel_reserve_satoshis: msg.channel_reserve_satoshis,
+        }
+    }
+}
+
+impl From<&AcceptChannel> for HtlcLimits {
+    fn from(msg: &AcceptChannel) -> Self {
+        Self {
+            htlc_minimum_msat: msg.htlc_minimum_msat,
+            max_htlc_value_in_flight_msat: msg.max_htlc_value_in_flight_msat,
+            max_accepted_htlcs: msg.max_accepted_htlcs,
+            channel_reserve_satoshis: msg.channel_reserve_satoshis,
+        }
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
+#[lnpbp_crate(crate)]
 pub struct HtlcKnown {
     pub preimage: HashPreimage,
     pub id: u64,
+    pub amount_msat: u64,
     pub cltv_expiry: u32,
     pub asset_id: Option<AssetId>,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
+#[lnpbp_crate(crate)]
 pub struct HtlcSecret {
     pub hashlock: HashLock,
     pub id: u64,
+    pub amount_msat: u64,
     pub cltv_expiry: u32,
     pub asset_id: Option<AssetId>,
 }
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+/// Progress of a channel update (addition or removal of an HTLC) according to
+/// BOLT-2. The update is originated by one of the channel parties; it gets
+/// committed first into the commitment transaction of the other party and
+/// only after its revocation - into the commitment transaction of the
+/// originating party.
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    StrictEncode,
+    StrictDecode,
+)]
+#[lnpbp_crate(crate)]
+#[display(Debug)]
+pub enum UpdateState {
+    /// Update is sent, but not yet committed
+    Pending,
+
+    /// Update is signed by the originating party into the commitment
+    /// transaction of the other party
+    Committed,
+
+    /// Other party has revoked its previous commitment transaction, which
+    /// did not contain the update
+    Revoked,
+
+    /// Update is signed by the other party into the commitment transaction
+    /// of the originating party
+    AckCommitted,
+
+    /// Originating party has revoked its previous commitment transaction;
+    /// the update is irrevocably committed
+    Completed,
+}
+
+impl UpdateState {
+    /// Processes `commitment_signed` message sent by the originating party of
+    /// the update (if `by_originator` is `true`) or by the other party
+    fn commitment_signed(&mut self, by_originator: bool) {
+        *self = match (*self, by_originator) {
+            (UpdateState::Pending, true) => UpdateState::Committed,
+            (UpdateState::Revoked, false) => UpdateState::AckCommitted,
+            (state, _) => state,
+        }
+    }
+
+    /// Processes `revoke_and_ack` message sent by the originating party of the
+    /// update (if `by_originator` is `true`) or by the other party
+    fn revoke_and_ack(&mut self, by_originator: bool) {
+        *self = match (*self, by_originator) {
+            (UpdateState::Committed, false) => UpdateState::Revoked,
+            (UpdateState::AckCommitted, true) => UpdateState::Completed,
+            (state, _) => state,
+        }
+    }
+
+    /// Detects whether the update is a part of the current commitment
+    /// transaction of the originating party (if `originator_commitment` is
+    /// `true`) or of the other party
+    pub fn is_committed(self, originator_commitment: bool) -> bool {
+        if originator_commitment {
+            self >= UpdateState::AckCommitted
+        } else {
+            self >= UpdateState::Committed
+        }
+    }
+}
+
+/// HTLC known to the channel with the progress of its addition and removal
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    StrictEncode,
+    StrictDecode,
+)]
+#[lnpbp_crate(crate)]
+pub struct HtlcState {
+    pub htlc: HtlcSecret,
+    pub added: UpdateState,
+    pub removed: Option<UpdateState>,
+    /// Preimage provided by the HTLC receiver when fulfilling the HTLC;
+    /// if the HTLC is removed without preimage, it has failed
+    pub preimage: Option<HashPreimage>,
+}
+
+impl HtlcState {
+    fn with(htlc: HtlcSecret) -> Self {
+        Self {
+            htlc,
+            added: UpdateState::Pending,
+            removed: None,
+            preimage: None,
+        }
+    }
+
+    /// Detects whether the HTLC output is present in the commitment
+    /// transaction of the party which has offered the HTLC (if
+    /// `offerer_commitment` is `true`) or of the other party
+    pub fn is_pending(&self, offerer_commitment: bool) -> bool {
+        self.added.is_committed(offerer_commitment)
+            && !self.is_removed(offerer_commitment)
+    }
+
+    /// Detects whether the HTLC removal is committed into the commitment
+    /// transaction of the party which has offered the HTLC (if
+    /// `offerer_commitment` is `true`) or of the other party
+    pub fn is_removed(&self, offerer_commitment: bool) -> bool {
+        // HTLC removal is originated by the party which has received the HTLC
+        self.removed
+            .map(|state| state.is_committed(!offerer_commitment))
+            .unwrap_or(false)
+    }
+
+    /// Detects whether the HTLC is fulfilled and this is committed into the
+    /// commitment transaction of the party which has offered the HTLC (if
+    /// `offerer_commitment` is `true`) or of the other party
+    pub fn is_fulfilled(&self, offerer_commitment: bool) -> bool {
+        self.preimage.is_some() && self.is_removed(offerer_commitment)
+    }
+}
+
+/// HTLC channel extender: tracks HTLCs offered and received by the local node
+/// through the lifecycle of their addition and removal and adds HTLC outputs
+/// and second-stage HTLC transactions to the local commitment transaction
+/// graph.
+///
+/// Commitment keys and channel parameters are read from the [`Bolt3`] channel
+/// constructor shared with the extender (see [`Htlc::with`]). The constructor
+/// is not a part of the extender strict encoding, so the decoded extender must
+/// be connected to it with [`Htlc::connect`].
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Htlc {
-    offered_htlc: Vec<HtlcKnown>,
-    received_htlc: Vec<HtlcSecret>,
+    offered_htlc: BTreeMap<u64, HtlcState>,
+    received_htlc: BTreeMap<u64, HtlcState>,
+    /// Last [`MAX_RESOLVED_HTLCS`] irrevocably fulfilled HTLCs
     resolved_htlc: Vec<HtlcKnown>,
+    /// Id expected for the next HTLC offered by the local node
+    next_offered_id: u64,
+    /// Id expected for the next HTLC offered by the remote peer
+    next_received_id: u64,
+
+    /// Limits announced by the local node, applied to the received HTLCs
+    local_limits: HtlcLimits,
+    /// Limits announced by the remote peer, applied to the offered HTLCs
+    remote_limits: HtlcLimits,
+
+    /// Total amount of the offered HTLCs which were irrevocably fulfilled
+    paid_msat: u64,
+    /// Total amount of the received HTLCs which were irrevocably fulfilled
+    received_msat: u64,
+
+    /// Channel constructor shared with the extender
+    bolt3: channel::Shared<Bolt3>,
+}
+
+impl DumbDefault for Htlc {
+    fn dumb_default() -> Self {
+        Htlc {
+            offered_htlc: empty!(),
+            received_htlc: empty!(),
+            resolved_htlc: empty!(),
+            next_offered_id: 0,
+            next_received_id: 0,
+            local_limits: HtlcLimits::default(),
+            remote_limits: HtlcLimits::default(),
+            paid_msat: 0,
+            received_msat: 0,
+            bolt3: channel::Shared::dumb_default(),
+        }
+    }
+}
+
+impl StrictEncode for Htlc {
+    type Error = strict_encoding::Error;
+
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, Self::Error> {
+        Ok(strict_encode_list!(e;
+            self.offered_htlc,
+            self.received_htlc,
+            self.resolved_htlc,
+            self.next_offered_id,
+            self.next_received_id,
+            self.local_limits,
+            self.remote_limits,
+            self.paid_msat,
+            self.received_msat
+        ))
+    }
+}
+
+impl StrictDecode for Htlc {
+    type Error = strict_encoding::Error;
+
+    fn strict_decode<D: io::Read>(mut d: D) -> Result<Self, Self::Error> {
+        Ok(Htlc {
+            offered_htlc: StrictDecode::strict_decode(&mut d)?,
+            received_htlc: StrictDecode::strict_decode(&mut d)?,
+            resolved_htlc: StrictDecode::strict_decode(&mut d)?,
+            next_offered_id: StrictDecode::strict_decode(&mut d)?,
+            next_received_id: StrictDecode::strict_decode(&mut d)?,
+            local_limits: StrictDecode::strict_decode(&mut d)?,
+            remote_limits: StrictDecode::strict_decode(&mut d)?,
+            paid_msat: StrictDecode::strict_decode(&mut d)?,
+            received_msat: StrictDecode::strict_decode(&mut d)?,
+            bolt3: channel::Shared::dumb_default(),
+        })
+    }
+}
+
+impl Htlc {
+    /// Constructs extender reading channel parameters from the `bolt3`
+    /// channel constructor, which must be added to the same channel
+    pub fn with(bolt3: channel::Shared<Bolt3>) -> Self {
+        Htlc {
+            bolt3,
+            ..Htlc::dumb_default()
+        }
+    }
+
+    /// Connects extender to the `bolt3` channel constructor after it was
+    /// restored from the strict encoding
+    #[inline]
+    pub fn connect(&mut self, bolt3: channel::Shared<Bolt3>) {
+        self.bolt3 = bolt3;
+    }
+
+    /// Returns HTLCs offered by the local node which are not yet irrevocably
+    /// removed from the channel
+    #[inline]
+    pub fn offered_htlcs(&self) -> impl Iterator<Item = &HtlcState> {
+        self.offered_htlc.values()
+    }
+
+    /// Returns HTLCs received by the local node which are not yet irrevocably
+    /// removed from the channel
+    #[inline]
+    pub fn received_htlcs(&self) -> impl Iterator<Item = &HtlcState> {
+        self.received_htlc.values()
+    }
+
+    /// Returns last [`MAX_RESOLVED_HTLCS`] irrevocably fulfilled HTLCs
+    #[inline]
+    pub fn resolved_htlcs(&self) -> &[HtlcKnown] {
+        &self.resolved_htlc
+    }
+
+    /// Returns current state of the channel constructor read by the extender
+    #[inline]
+    pub fn bolt3(&self) -> Bolt3 {
+        *self.bolt3.borrow()
+    }
+
+    /// Returns channel balances in millisatoshis for the local and remote
+    /// node, which are not locked in HTLCs, as they are seen by the current
+    /// local commitment transaction. Fails if HTLC amounts exceed the channel
+    /// balances, which is prevented by the checks of the added HTLCs.
+    pub fn balances(&self) -> Result<(u64, u64), channel::Error> {
+        let bolt3 = self.bolt3();
+        let mut local = *bolt3.local_amount_msat() + self.received_msat;
+        let mut remote = *bolt3.remote_amount_msat() + self.paid_msat;
+        let mut local_spent = self.paid_msat;
+        let mut remote_spent = self.received_msat;
+        // Amounts of failed HTLCs are returned to the offerer, so they are
+        // deducted only from the balance of pending and fulfilled HTLCs
+        for state in self.offered_htlc.values() {
+            if state.is_fulfilled(true) {
+                remote += state.htlc.amount_msat;
+            }
+            if state.is_pending(true) || state.is_fulfilled(true) {
+                local_spent += state.htlc.amount_msat;
+            }
+        }
+        for state in self.received_htlc.values() {
+            if state.is_fulfilled(false) {
+                local += state.htlc.amount_msat;
+            }
+            if state.is_pending(false) || state.is_fulfilled(false) {
+                remote_spent += state.htlc.amount_msat;
+            }
+        }
+        match (
+            local.checked_sub(local_spent),
+            remote.checked_sub(remote_spent),
+        ) {
+            (Some(local), Some(remote)) => Ok((local, remote)),
+            _ => Err(channel::Error::Extension(s!(
+                "HTLC amounts exceed channel balance"
+            ))),
+        }
+    }
+
+    /// Returns amount in millisatoshis which can be offered in a new HTLC by
+    /// the local node (if `by_local` is `true`) or by the remote peer. The
+    /// amount excludes all HTLCs offered by the party which are not yet
+    /// irrevocably removed, the channel reserve required by the other party
+    /// and, for the channel funder, the commitment transaction fee including
+    /// the new HTLC.
+    pub fn available_msat(&self, by_local: bool) -> u64 {
+        let bolt3 = self.bolt3();
+        let (balance, spent, htlcs, limits, is_funder) = if by_local {
+            (
+                *bolt3.local_amount_msat() + self.received_msat,
+                self.paid_msat,
+                &self.offered_htlc,
+                &self.remote_limits,
+                *bolt3.is_originator(),
+            )
+        } else {
+            (
+                *bolt3.remote_amount_msat() + self.paid_msat,
+                self.received_msat,
+                &self.received_htlc,
+                &self.local_limits,
+                !*bolt3.is_originator(),
+            )
+        };
+        let fee = if is_funder {
+            bolt3.funder_fee(
+                self.offered_htlc.len() + self.received_htlc.len() + 1,
+            )
+        } else {
+            0
+        };
+        let locked = htlcs.values().map(|state| state.htlc.amount_msat).sum();
+        let required = limits
+            .channel_reserve_satoshis
+            .saturating_add(fee)
+            .saturating_mul(1000)
+            .saturating_add(spent)
+            .saturating_add(locked);
+        // Nothing is available if the balance is below the requirements
+        balance.saturating_sub(required)
+    }
+
+    /// Adds HTLC offered by the local node (if `by_local` is `true`) or by the
+    /// remote peer, checking it against BOLT-2 requirements: HTLC ids must be
+    /// sequential, HTLCs must comply with the limits announced by the
+    /// receiving party and must be affordable by the offering party
+    fn add_htlc(
+        &mut self,
+        htlc: HtlcSecret,
+        by_local: bool,
+    ) -> Result<(), channel::Error> {
+        let available_msat = self.available_msat(by_local);
+        let (htlcs, limits, next_id) = if by_local {
+            (
+                &mut self.offered_htlc,
+                &self.remote_limits,
+                &mut self.next_offered_id,
+            )
+        } else {
+            (
+                &mut self.received_htlc,
+                &self.local_limits,
+                &mut self.next_received_id,
+            )
+        };
+        if htlcs.contains_key(&htlc.id) {
+            return Err(channel::Error::Extension(format!(
+                "HTLC with id {} already exists",
+                htlc.id
+            )));
+        }
+        if htlc.id != *next_id {
+            return Err(channel::Error::Extension(format!(
+                "HTLC id {} is out of sequence; expected id {}",
+                htlc.id, next_id
+            )));
+        }
+        if htlc.amount_msat == 0 || htlc.amount_msat < limits.htlc_minimum_msat
+        {
+            return Err(channel::Error::Extension(format!(
+                "HTLC amount {} msat is below the minimum of {} msat",
+                htlc.amount_msat, limits.htlc_minimum_msat
+            )));
+        }
+        if htlcs.len() >= limits.max_accepted_htlcs as usize {
+            return Err(channel::Error::Extension(format!(
+                "number of HTLCs exceeds the limit of {}",
+                limits.max_accepted_htlcs
+            )));
+        }
+        let in_flight_msat = htlcs
+            .values()
+            .map(|state| state.htlc.amount_msat)
+            .sum::<u64>();
+        if htlc.amount_msat
+            > limits
+                .max_htlc_value_in_flight_msat
+                .saturating_sub(in_flight_msat)
+        {
+            return Err(channel::Error::Extension(format!(
+                "HTLC amount {} msat exceeds the limit of {} msat in flight",
+                htlc.amount_msat, limits.max_htlc_value_in_flight_msat
+            )));
+        }
+        if htlc.amount_msat > available_msat {
+            return Err(channel::Error::Extension(format!(
+                "HTLC amount {} msat exceeds {} msat available to the \
+                 offering party",
+                htlc.amount_msat, available_msat
+            )));
+        }
+        htlcs.insert(htlc.id, HtlcState::with(htlc));
+        *next_id += 1;
+        Ok(())
+    }
+
+    fn remove_htlc(
+        htlcs: &mut BTreeMap<u64, HtlcState>,
+        id: u64,
+        preimage: Option<HashPreimage>,
+    ) -> Result<(), channel::Error> {
+        let state = htlcs.get_mut(&id).ok_or_else(|| {
+            channel::Error::Extension(format!("unknown HTLC with id {}", id))
+        })?;
+        if state.added != UpdateState::Completed || state.removed.is_some() {
+            return Err(channel::Error::Extension(format!(
+                "HTLC with id {} can't be removed in its current state",
+                id
+            )));
+        }
+        if let Some(preimage) = preimage {
+            if HashLock::from(preimage) != state.htlc.hashlock {
+                return Err(channel::Error::Extension(format!(
+                    "preimage for HTLC with id {} does not match its hash",
+                    id
+                )));
+            }
+        }
+        state.removed = Some(UpdateState::Pending);
+        state.preimage = preimage;
+        Ok(())
+    }
+
+    /// Processes `commitment_signed` message sent by the local node (if
+    /// `by_local` is `true`) or by the remote peer
+    fn commitment_signed(&mut self, by_local: bool) {
+        for state in self.offered_htlc.values_mut() {
+            state.added.commitment_signed(by_local);
+            if let Some(removed) = &mut state.removed {
+                removed.commitment_signed(!by_local);
+            }
+        }
+        for state in self.received_htlc.values_mut() {
+            state.added.commitment_signed(!by_local);
+            if let Some(removed) = &mut state.removed {
+                removed.commitment_signed(by_local);
+            }
+        }
+    }
+
+    /// Processes `revoke_and_ack` message sent by the local node (if
+    /// `by_local` is `true`) or by the remote peer
+    fn revoke_and_ack(&mut self, by_local: bool) {
+        for state in self.offered_htlc.values_mut() {
+            state.added.revoke_and_ack(by_local);
+            if let Some(removed) = &mut state.removed {
+                removed.revoke_and_ack(!by_local);
+            }
+        }
+        for state in self.received_htlc.values_mut() {
+            state.added.revoke_and_ack(!by_local);
+            if let Some(removed) = &mut state.removed {
+                removed.revoke_and_ack(by_local);
+            }
+        }
+        self.resolve();
+    }
+
+    /// Forgets HTLCs which were irrevocably removed from the channel,
+    /// accounting for the fulfilled ones in the channel balances
+    fn resolve(&mut self) {
+        for known in drain_removed(&mut self.offered_htlc) {
+            self.paid_msat += known.amount_msat;
+            self.resolved_htlc.push(known);
+        }
+        for known in drain_removed(&mut self.received_htlc) {
+            self.received_msat += known.amount_msat;
+            self.resolved_htlc.push(known);
+        }
+        let len = self.resolved_htlc.len();
+        if len > MAX_RESOLVED_HTLCS {
+            self.resolved_htlc.drain(..len - MAX_RESOLVED_HTLCS);
+        }
+    }
+
+    fn update(
+        &mut self,
+        message: &Messages,
+        by_local: bool,
+    ) -> Result<(), channel::Error> {
+        let received = if by_local {
+            &mut self.received_htlc
+        } else {
+            &mut self.offered_htlc
+        };
+        match message {
+            Messages::OpenChannel(open_channel) if by_local => {
+                self.local_limits = HtlcLimits::from(open_channel);
+            }
+            Messages::OpenChannel(open_channel) => {
+                self.remote_limits = HtlcLimits::from(open_channel);
+            }
+            Messages::AcceptChannel(accept_channel) if by_local => {
+                self.local_limits = HtlcLimits::from(accept_channel);
+            }
+            Messages::AcceptChannel(accept_channel) => {
+                self.remote_limits = HtlcLimits::from(accept_channel);
+            }
+            Messages::UpdateAddHtlc(update_add_htlc) => {
+                #[cfg(feature = "rgb")]
+                let asset_id = update_add_htlc.asset_id;
+                #[cfg(not(feature = "rgb"))]
+                let asset_id = None;
+                self.add_htlc(
+                    HtlcSecret {
+                        hashlock: update_add_htlc.payment_hash,
+                        id: update_add_htlc.htlc_id,
+                        amount_msat: update_add_htlc.amount_msat,
+                        cltv_expiry: update_add_htlc.cltv_expiry,
+                        asset_id,
+                    },
+                    by_local,
+                )?;
+            }
+            Messages::UpdateFulfillHtlc(update_fulfill_htlc) => {
+                Self::remove_htlc(
+                    received,
+                    update_fulfill_htlc.htlc_id,
+                    Some(update_fulfill_htlc.payment_preimage),
+                )?;
+            }
+            Messages::UpdateFailHtlc(update_fail_htlc) => {
+                Self::remove_htlc(received, update_fail_htlc.htlc_id, None)?;
+            }
+            Messages::UpdateFailMalformedHtlc(update_fail_malformed_htlc) => {
+                Self::remove_htlc(
+                    received,
+                    update_fail_malformed_htlc.htlc_id,
+                    None,
+                )?;
+            }
+            Messages::CommitmentSigned(_) => self.commitment_signed(by_local),
+            Messages::RevokeAndAck(_) => self.revoke_and_ack(by_local),
+            _ => {}
+        }
+        Ok(())
+    }
 }
 
 impl channel::State for Htlc {}
@@ -59,17 +707,14 @@ impl Extension for Htlc {
         &mut self,
         message: &Messages,
     ) -> Result<(), channel::Error> {
-        match message {
-            Messages::UpdateAddHtlc(_) => {}
-            Messages::UpdateFulfillHtlc(_) => {}
-            Messages::UpdateFailHtlc(_) => {}
-            Messages::UpdateFailMalformedHtlc(_) => {}
-            Messages::CommitmentSigned(_) => {}
-            Messages::RevokeAndAck(_) => {}
-            Messages::ChannelReestablish(_) => {}
-            _ => {}
-        }
-        Ok(())
+        self.update(message, false)
+    }
+
+    fn update_from_local(
+        &mut self,
+        message: &Messages,
+    ) -> Result<(), channel::Error> {
+        self.update(message, true)
     }
 
     fn extension_state(&self) -> Box<dyn channel::State> {
@@ -86,10 +731,186 @@ impl ChannelExtension for Htlc {
         &mut self,
         tx_graph: &mut channel::TxGraph,
     ) -> Result<(), channel::Error> {
-        unimplemented!()
+        let bolt3 = self.bolt3();
+        let keys = bolt3.commitment_keys();
+        let feerate = *bolt3.feerate_per_kw() as u64;
+        let dust_limit = *bolt3.dust_limit_satoshis();
+        let anchors = bolt3.features().anchor_outputs;
+        let (timeout_weight, success_weight) = if anchors {
+            (HTLC_TIMEOUT_ANCHOR_WEIGHT, HTLC_SUCCESS_ANCHOR_WEIGHT)
+        } else {
+            (HTLC_TIMEOUT_WEIGHT, HTLC_SUCCESS_WEIGHT)
+        };
+        let timeout_fee = feerate * timeout_weight / 1000;
+        let success_fee = feerate * success_weight / 1000;
+
+        tx_graph.remove_role(TxType::HtlcTimeout);
+        tx_graph.remove_role(TxType::HtlcSuccess);
+
+        let mut htlc_outs = vec![];
+        for state in self.offered_htlc.values().filter(|s| s.is_pending(true)) {
+            let htlc = state.htlc;
+            let amount = htlc.amount_msat / 1000;
+            if amount < dust_limit + timeout_fee {
+                continue;
+            }
+            let witness_script = WitnessScript::ln_offered_htlc(
+                amount,
+                keys.revocationpubkey,
+                keys.local_htlcpubkey,
+                keys.remote_htlcpubkey,
+                htlc.hashlock,
+            );
+            let witness_script = csv_locked(witness_script, anchors);
+            let txout = TxOut {
+                value: amount,
+                script_pubkey: witness_script.to_p2wsh().into(),
+            };
+            let psbt = htlc_psbt(
+                &txout,
+                witness_script,
+                anchors,
+                Psbt::ln_htlc(
+                    amount - timeout_fee,
+                    OutPoint::new(Txid::default(), 0),
+                    htlc.cltv_expiry,
+                    keys.revocationpubkey,
+                    keys.local_delayedpubkey,
+                    *bolt3.to_self_delay(),
+                ),
+            );
+            tx_graph.insert_tx(TxType::HtlcTimeout, htlc.id, psbt);
+            htlc_outs.push(txout);
+        }
+        for state in self.received_htlc.values().filter(|s| s.is_pending(false))
+        {
+            let htlc = state.htlc;
+            let amount = htlc.amount_msat / 1000;
+            if amount < dust_limit + success_fee {
+                continue;
+            }
+            let witness_script = WitnessScript::ln_received_htlc(
+                amount,
+                keys.revocationpubkey,
+                keys.local_htlcpubkey,
+                keys.remote_htlcpubkey,
+                htlc.cltv_expiry,
+                htlc.hashlock,
+            );
+            let witness_script = csv_locked(witness_script, anchors);
+            let txout = TxOut {
+                value: amount,
+                script_pubkey: witness_script.to_p2wsh().into(),
+            };
+            let psbt = htlc_psbt(
+                &txout,
+                witness_script,
+                anchors,
+                // HTLC-success transaction is not timelocked
+                Psbt::ln_htlc(
+                    amount - success_fee,
+                    OutPoint::new(Txid::default(), 0),
+                    0,
+                    keys.revocationpubkey,
+                    keys.local_delayedpubkey,
+                    *bolt3.to_self_delay(),
+                ),
+            );
+            tx_graph.insert_tx(TxType::HtlcSuccess, htlc.id, psbt);
+            htlc_outs.push(txout);
+        }
+
+        // Replacing balance outputs created by the channel constructor with
+        // the ones accounting for HTLCs and their fees. The constructor puts
+        // its balance outputs first, and HTLC extender is applied before any
+        // other extender, so they are replaced by their position and other
+        // outputs with the same value and script are kept.
+        let base_count = bolt3
+            .balance_outs(
+                *bolt3.local_amount_msat(),
+                *bolt3.remote_amount_msat(),
+                0,
+            )
+            .len()
+            .min(tx_graph.cmt_outs.len());
+        let (local_amount, remote_amount) = self.balances()?;
+        let balance_outs =
+            bolt3.balance_outs(local_amount, remote_amount, htlc_outs.len());
+        tx_graph.cmt_outs.splice(..base_count, balance_outs);
+        tx_graph.cmt_outs.extend(htlc_outs);
+
+        Ok(())
+    }
+
+    fn strict_encode_extension(
+        &self,
+        e: &mut dyn io::Write,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.strict_encode(e)
     }
 }
 
+/// Removes HTLCs which removal is irrevocably committed, returning the
+/// fulfilled ones
+fn drain_removed(htlcs: &mut BTreeMap<u64, HtlcState>) -> Vec<HtlcKnown> {
+    let removed = htlcs
+        .values()
+        .filter(|state| state.removed == Some(UpdateState::Completed))
+        .map(|state| state.htlc.id)
+        .collect::<Vec<_>>();
+    removed
+        .into_iter()
+        .filter_map(|id| htlcs.remove(&id))
+        .filter_map(|state| {
+            state.preimage.map(|preimage| HtlcKnown {
+                preimage,
+                id: state.htlc.id,
+                amount_msat: state.htlc.amount_msat,
+                cltv_expiry: state.htlc.cltv_expiry,
+                asset_id: state.htlc.asset_id,
+            })
+        })
+        .collect()
+}
+
+/// Adds information on the spent HTLC output to the second-stage HTLC
+/// transaction; the input is linked to the commitment transaction with
+/// [`channel::TxGraph::link_cmt_outs`]
+fn htlc_psbt(
+    txout: &TxOut,
+    witness_script: WitnessScript,
+    anchors: bool,
+    mut psbt: Psbt,
+) -> Psbt {
+    psbt.inputs[0].witness_utxo = Some(txout.clone());
+    psbt.inputs[0].witness_script = Some(witness_script.into_inner());
+    if anchors {
+        // Required by `1 OP_CHECKSEQUENCEVERIFY` in the HTLC output script
+        psbt.global.unsigned_tx.input[0].sequence = 1;
+    }
+    psbt
+}
+
+/// Adds `1 OP_CHECKSEQUENCEVERIFY OP_DROP` before the final `OP_ENDIF` of the
+/// offered or received HTLC output script if `anchors` are used, preventing
+/// the output from being spent by the remote peer in CPFP transactions.
+/// Both HTLC scripts end with `OP_ENDIF`, and `option_anchor_outputs`
+/// variants differ from the original ones only by this insertion.
+fn csv_locked(witness_script: WitnessScript, anchors: bool) -> WitnessScript {
+    if !anchors {
+        return witness_script;
+    }
+    let mut code = witness_script.into_inner().into_bytes();
+    code.pop();
+    script::Builder::from(code)
+        .push_int(1)
+        .push_opcode(OP_CSV)
+        .push_opcode(OP_DROP)
+        .push_opcode(OP_ENDIF)
+        .into_script()
+        .into()
+}
+
 pub trait ScriptGenerators {
     fn ln_offered_htlc(
         amount: u64,
@@ -146,7 +967,7 @@ impl ScriptGenerators for LockScript {
             .push_opcode(OP_CHECKMULTISIG)
             .push_opcode(OP_ELSE)
             .push_opcode(OP_HASH160)
-            .push_slice(payment_hash.as_ref())
+            .push_slice(&ripemd160::Hash::hash(payment_hash.as_ref()))
             .push_opcode(OP_EQUALVERIFY)
             .push_opcode(OP_CHECKSIG)
             .push_opcode(OP_ENDIF)
@@ -178,7 +999,7 @@ impl ScriptGenerators for LockScript {
             .push_opcode(OP_EQUAL)
             .push_opcode(OP_IF)
             .push_opcode(OP_HASH160)
-            .push_slice(payment_hash.as_ref())
+            .push_slice(&ripemd160::Hash::hash(payment_hash.as_ref()))
             .push_opcode(OP_EQUALVERIFY)
             .push_int(2)
             .push_opcode(OP_SWAP)
@@ -456,3 +1277,670 @@ impl TxGenerators for Psbt {
         .expect("Tx has empty sigs so PSBT creation does not faile")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use bitcoin::secp256k1::{SecretKey, Signature};
+    use std::str::FromStr;
+
+    use crate::lnp::application::payment::bip96::Bip96;
+    use crate::lnp::application::payment::{ChannelId, TempChannelId};
+    use crate::lnp::application::OnionPacket;
+    use crate::lnp::message::{
+        CommitmentSigned, FundingCreated, FundingLocked, UpdateAddHtlc,
+        UpdateFailHtlc, UpdateFee, UpdateFulfillHtlc,
+    };
+    use crate::lnp::test_helpers::{commitment_features, init, point};
+    use crate::SECP256K1;
+
+    // Updates the channel constructor before the extender, as it is done by
+    // the channel
+    impl Htlc {
+        fn from_local(
+            &mut self,
+            message: &Messages,
+        ) -> Result<(), channel::Error> {
+            self.bolt3.borrow_mut().update_from_local(message)?;
+            self.update_from_local(message)
+        }
+
+        fn from_peer(
+            &mut self,
+            message: &Messages,
+        ) -> Result<(), channel::Error> {
+            self.bolt3.borrow_mut().update_from_peer(message)?;
+            self.update_from_peer(message)
+        }
+    }
+
+    fn preimage(byte: u8) -> HashPreimage {
+        HashPreimage::from_inner([byte; 32].into())
+    }
+
+    fn update_add_htlc(id: u64, amount: u64, byte: u8) -> Messages {
+        Messages::UpdateAddHtlc(UpdateAddHtlc {
+            channel_id: ChannelId::default(),
+            htlc_id: id,
+            amount_msat: amount * 1000,
+            payment_hash: preimage(byte).into(),
+            cltv_expiry: 500 + byte as u32,
+            onion_routing_packet: OnionPacket::dumb_default(),
+            #[cfg(feature = "rgb")]
+            asset_id: None,
+        })
+    }
+
+    // Channel from BOLT-3 Appendix C with the given feerate and the balances
+    // before the addition of the Appendix C HTLCs
+    fn channel(feerate_per_kw: u32) -> Htlc {
+        let per_commitment_point = PublicKey::from_secret_key(
+            &SECP256K1,
+            &SecretKey::from_str(
+                "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100",
+            )
+            .unwrap(),
+        );
+        let signature = Signature::from_compact(&[1u8; 64]).unwrap();
+        let mut channel = Htlc::dumb_default();
+        channel
+            .from_local(&Messages::OpenChannel(OpenChannel {
+                funding_satoshis: 10_000_000,
+                push_msat: 3_007_000_000,
+                dust_limit_satoshis: 546,
+                max_htlc_value_in_flight_msat: 10_000_000_000,
+                htlc_minimum_msat: 1000,
+                feerate_per_kw,
+                max_accepted_htlcs: 483,
+                payment_point: point(0x11),
+                delayed_payment_basepoint: point(0x33),
+                htlc_basepoint: point(0x11),
+                first_per_commitment_point: per_commitment_point,
+                ..OpenChannel::dumb_default()
+            }))
+            .unwrap();
+        channel
+            .from_peer(&Messages::AcceptChannel(AcceptChannel {
+                max_htlc_value_in_flight_msat: 10_000_000_000,
+                htlc_minimum_msat: 1000,
+                to_self_delay: 144,
+                max_accepted_htlcs: 483,
+                revocation_basepoint: point(0x22),
+                payment_point: point(0x44),
+                htlc_basepoint: point(0x44),
+                ..AcceptChannel::dumb_default()
+            }))
+            .unwrap();
+        channel
+            .from_local(&Messages::FundingCreated(FundingCreated {
+                temporary_channel_id: TempChannelId::dumb_default(),
+                funding_txid: Txid::from_hex(
+                    "8984484a580b825b9972d7adb15050b3ab624ccd731946b3eeddb92f4e7ef6be",
+                )
+                .unwrap(),
+                funding_output_index: 0,
+                signature,
+            }))
+            .unwrap();
+        // Appendix C commitment transaction has number 42 and uses the same
+        // per-commitment point as the first one
+        channel
+            .from_local(&Messages::FundingLocked(FundingLocked {
+                channel_id: ChannelId::default(),
+                next_per_commitment_point: per_commitment_point,
+            }))
+            .unwrap();
+        for _ in 0..42 {
+            channel
+                .from_peer(&Messages::CommitmentSigned(CommitmentSigned {
+                    channel_id: ChannelId::default(),
+                    signature,
+                    htlc_signatures: vec![],
+                }))
+                .unwrap();
+        }
+        channel
+    }
+
+    // Adds HTLCs from BOLT-3 Appendix C, with the HTLC ids assigned in the
+    // order of the Appendix C HTLC numbers for each direction
+    fn add_htlcs(channel: &mut Htlc) {
+        for (id, (amount, byte)) in [(2000, 2), (3000, 3)].iter().enumerate() {
+            channel
+                .from_local(&update_add_htlc(id as u64, *amount, *byte))
+                .unwrap();
+        }
+        for (id, (amount, byte)) in
+            [(1000, 0), (2000, 1), (4000, 4)].iter().enumerate()
+        {
+            channel
+                .from_peer(&update_add_htlc(id as u64, *amount, *byte))
+                .unwrap();
+        }
+    }
+
+    fn commit_all(channel: &mut Htlc) {
+        channel.commitment_signed(true);
+        channel.revoke_and_ack(false);
+        channel.commitment_signed(false);
+        channel.revoke_and_ack(true);
+    }
+
+    #[test]
+    fn test_htlc_lifecycle() {
+        let mut channel = channel(0);
+        let initial = (6_993_000_000, 3_007_000_000);
+        assert_eq!(channel.balances().unwrap(), initial);
+
+        channel.from_local(&update_add_htlc(0, 2000, 1)).unwrap();
+        assert_eq!(
+            channel.from_local(&update_add_htlc(0, 2000, 1)),
+            Err(channel::Error::Extension(s!(
+                "HTLC with id 0 already exists"
+            )))
+        );
+        let state = |channel: &Htlc| *channel.offered_htlcs().next().unwrap();
+
+        // Offered HTLC gets into the local commitment only after the remote
+        // peer has revoked its previous commitment and signed the new one
+        channel.commitment_signed(true);
+        channel.revoke_and_ack(false);
+        assert_eq!(state(&channel).added, UpdateState::Revoked);
+        assert!(state(&channel).is_pending(false));
+        assert!(!state(&channel).is_pending(true));
+        assert_eq!(channel.balances().unwrap(), initial);
+        channel.commitment_signed(false);
+        assert!(state(&channel).is_pending(true));
+        assert_eq!(channel.balances().unwrap(), (6_991_000_000, 3_007_000_000));
+
+        // HTLC can't be removed before it is irrevocably committed
+        let fulfill = |byte| {
+            Messages::UpdateFulfillHtlc(UpdateFulfillHtlc {
+                channel_id: ChannelId::default(),
+                htlc_id: 0,
+                payment_preimage: preimage(byte),
+            })
+        };
+        assert!(channel.from_peer(&fulfill(1)).is_err());
+        channel.revoke_and_ack(true);
+        assert_eq!(state(&channel).added, UpdateState::Completed);
+        assert!(channel.from_peer(&fulfill(2)).is_err());
+        channel.from_peer(&fulfill(1)).unwrap();
+        assert_eq!(state(&channel).removed, Some(UpdateState::Pending));
+
+        // Removal is originated by the remote peer, so it gets into the local
+        // commitment first
+        channel.commitment_signed(false);
+        assert!(state(&channel).is_fulfilled(true));
+        assert!(!state(&channel).is_fulfilled(false));
+        assert_eq!(channel.balances().unwrap(), (6_991_000_000, 3_009_000_000));
+        channel.revoke_and_ack(true);
+        channel.commitment_signed(true);
+        assert_eq!(channel.offered_htlcs().count(), 1);
+        channel.revoke_and_ack(false);
+        assert_eq!(channel.offered_htlcs().count(), 0);
+        assert_eq!(channel.resolved_htlcs().len(), 1);
+        assert_eq!(channel.resolved_htlcs()[0].preimage, preimage(1));
+        assert_eq!(channel.balances().unwrap(), (6_991_000_000, 3_009_000_000));
+    }
+
+    #[test]
+    fn test_htlc_failure() {
+        let mut channel = channel(0);
+        let initial = channel.balances().unwrap();
+        channel.from_peer(&update_add_htlc(0, 2000, 1)).unwrap();
+        commit_all(&mut channel);
+        assert_eq!(channel.balances().unwrap(), (6_993_000_000, 3_005_000_000));
+
+        channel
+            .from_local(&Messages::UpdateFailHtlc(UpdateFailHtlc {
+                channel_id: ChannelId::default(),
+                htlc_id: 0,
+                reason: vec![],
+            }))
+            .unwrap();
+        commit_all(&mut channel);
+        assert_eq!(channel.received_htlcs().count(), 0);
+        assert!(channel.resolved_htlcs().is_empty());
+        assert_eq!(channel.balances().unwrap(), initial);
+    }
+
+    #[test]
+    fn test_htlc_limits() {
+        let error =
+            |message: &str| Err(channel::Error::Extension(message.to_string()));
+        let mut channel = channel(0);
+        assert_eq!(
+            channel.from_peer(&update_add_htlc(1, 2000, 1)),
+            error("HTLC id 1 is out of sequence; expected id 0")
+        );
+        let mut below_minimum = update_add_htlc(0, 0, 1);
+        if let Messages::UpdateAddHtlc(update_add_htlc) = &mut below_minimum {
+            update_add_htlc.amount_msat = 999;
+        }
+        assert_eq!(
+            channel.from_peer(&below_minimum),
+            error("HTLC amount 999 msat is below the minimum of 1000 msat")
+        );
+        assert_eq!(
+            channel.from_peer(&update_add_htlc(0, 3_007_001, 1)),
+            error(
+                "HTLC amount 3007001000 msat exceeds 3007000000 msat \
+                 available to the offering party"
+            )
+        );
+
+        channel.local_limits.max_accepted_htlcs = 2;
+        channel.local_limits.max_htlc_value_in_flight_msat = 5_000_000;
+        channel.from_peer(&update_add_htlc(0, 2000, 1)).unwrap();
+        assert_eq!(
+            channel.from_peer(&update_add_htlc(1, 3001, 2)),
+            error(
+                "HTLC amount 3001000 msat exceeds the limit of 5000000 msat \
+                 in flight"
+            )
+        );
+        channel.from_peer(&update_add_htlc(1, 3000, 2)).unwrap();
+        assert_eq!(
+            channel.from_peer(&update_add_htlc(2, 1, 3)),
+            error("number of HTLCs exceeds the limit of 2")
+        );
+
+        // Channel funder must keep the reserve and pay the commitment fee
+        channel.remote_limits.channel_reserve_satoshis = 6_000_000;
+        channel
+            .from_local(&Messages::UpdateFee(UpdateFee {
+                channel_id: ChannelId::default(),
+                feerate_per_kw: 1000,
+            }))
+            .unwrap();
+        let fee = channel.bolt3().commitment_fee(3) * 1000;
+        assert_eq!(channel.available_msat(true), 993_000_000 - fee);
+        assert!(channel
+            .from_local(&update_add_htlc(0, 993_000 - fee / 1000, 1))
+            .is_ok());
+        assert_eq!(channel.available_msat(true), 0);
+    }
+
+    // HTLCs, feerates and transactions (without witnesses) from BOLT-3
+    // Appendix C commitment transaction test vectors. HTLC transactions are
+    // listed in the order of the spent commitment outputs together with the
+    // Appendix C number of the HTLC.
+    #[test]
+    fn test_htlc_outputs() {
+        let scripts = [
+            "76a91414011f7254d96b819c76986c277d115efce6f7b58763ac67210394854aa6\
+            eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b7c82012087\
+            63a914b8bcb07f6344b42ab04250c86a6e8b75d3fdbbc688527c21030d417a4694\
+            6384f88d5f3337267c5e579765875dc4daca813e21734b140639e752ae677502f4\
+            01b175ac6868",
+            "76a91414011f7254d96b819c76986c277d115efce6f7b58763ac67210394854aa6\
+            eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b7c82012087\
+            63a9144b6b2e5444c2639cc0fb7bcea5afba3f3cdce23988527c21030d417a4694\
+            6384f88d5f3337267c5e579765875dc4daca813e21734b140639e752ae677502f5\
+            01b175ac6868",
+            "76a91414011f7254d96b819c76986c277d115efce6f7b58763ac67210394854aa6\
+            eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b7c82012087\
+            6475527c21030d417a46946384f88d5f3337267c5e579765875dc4daca813e2173\
+            4b140639e752ae67a914b43e1b38138a41b37f7cd9a1d274bc63e3a9b5d188ac68\
+            68",
+            "76a91414011f7254d96b819c76986c277d115efce6f7b58763ac67210394854aa6\
+            eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b7c82012087\
+            6475527c21030d417a46946384f88d5f3337267c5e579765875dc4daca813e2173\
+            4b140639e752ae67a9148a486ff2e31d6158bf39e2608864d63fefd09d5b88ac68\
+            68",
+            "76a91414011f7254d96b819c76986c277d115efce6f7b58763ac67210394854aa6\
+            eab5b2a8122cc726e9dded053a2184d88256816826d6231c068d4a5b7c82012087\
+            63a91418bc1a114ccf9c052d3d23e28d3b0a9d1227434288527c21030d417a4694\
+            6384f88d5f3337267c5e579765875dc4daca813e21734b140639e752ae677502f8\
+            01b175ac6868",
+        ];
+        for (feerate, to_local, cmt_tx_hex, htlc_txs) in &[
+            (
+                0u32,
+                6_988_000u64,
+                "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b82\
+                0b584a488489000000000038b02b8007e80300000000000022002052bfef04\
+                79d7b293c27e0f1eb294bea154c63a3294ef092c19af51409bce0e2ad00700\
+                0000000000220020403d394747cae42e98ff01734ad5c08f82ba123d3d9a62\
+                0abda88989651e2ab5d007000000000000220020748eba944fedc8827f6b06\
+                bc44678f93c0f9e6078b35c6331ed31e75f8ce0c2db80b0000000000002200\
+                20c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0\
+                f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb66\
+                65fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aab\
+                ee14bb40fa3851ab2301de843110e0a06a00000000002200204adb4e2f0064\
+                3db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220",
+                &[
+                    (
+                        0usize,
+                        "02000000018154ecccf11a5fb56c39654c4deb4d2296f83c692682\
+                        80b94d021370c94e219700000000000000000001e8030000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                    (
+                        2,
+                        "02000000018154ecccf11a5fb56c39654c4deb4d2296f83c692682\
+                        80b94d021370c94e219701000000000000000001d0070000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef6010000",
+                    ),
+                    (
+                        1,
+                        "02000000018154ecccf11a5fb56c39654c4deb4d2296f83c692682\
+                        80b94d021370c94e219702000000000000000001d0070000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                    (
+                        3,
+                        "02000000018154ecccf11a5fb56c39654c4deb4d2296f83c692682\
+                        80b94d021370c94e219703000000000000000001b80b0000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef7010000",
+                    ),
+                    (
+                        4,
+                        "02000000018154ecccf11a5fb56c39654c4deb4d2296f83c692682\
+                        80b94d021370c94e219704000000000000000001a00f0000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                ][..],
+            ),
+            (
+                647,
+                6_986_976,
+                "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b82\
+                0b584a488489000000000038b02b8007e80300000000000022002052bfef04\
+                79d7b293c27e0f1eb294bea154c63a3294ef092c19af51409bce0e2ad00700\
+                0000000000220020403d394747cae42e98ff01734ad5c08f82ba123d3d9a62\
+                0abda88989651e2ab5d007000000000000220020748eba944fedc8827f6b06\
+                bc44678f93c0f9e6078b35c6331ed31e75f8ce0c2db80b0000000000002200\
+                20c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d04dbbd012635c0\
+                f419a00f0000000000002200208c48d15160397c9731df9bc3b236656efb66\
+                65fbfe92b4a6878e88a499f741c4c0c62d0000000000160014ccf1af2f2aab\
+                ee14bb40fa3851ab2301de843110e09c6a00000000002200204adb4e2f0064\
+                3db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7dd80e3e195220",
+                &[
+                    (
+                        0,
+                        "02000000018323148ce2419f21ca3d6780053747715832e18ac780\
+                        931a514b187768882bb60000000000000000000122020000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                    (
+                        2,
+                        "02000000018323148ce2419f21ca3d6780053747715832e18ac780\
+                        931a514b187768882bb60100000000000000000124060000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef6010000",
+                    ),
+                    (
+                        1,
+                        "02000000018323148ce2419f21ca3d6780053747715832e18ac780\
+                        931a514b187768882bb6020000000000000000010a060000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                    (
+                        3,
+                        "02000000018323148ce2419f21ca3d6780053747715832e18ac780\
+                        931a514b187768882bb6030000000000000000010c0a0000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef7010000",
+                    ),
+                    (
+                        4,
+                        "02000000018323148ce2419f21ca3d6780053747715832e18ac780\
+                        931a514b187768882bb604000000000000000001da0d0000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                ][..],
+            ),
+            (
+                648,
+                6_987_086,
+                "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b82\
+                0b584a488489000000000038b02b8006d007000000000000220020403d3947\
+                47cae42e98ff01734ad5c08f82ba123d3d9a620abda88989651e2ab5d00700\
+                0000000000220020748eba944fedc8827f6b06bc44678f93c0f9e6078b35c6\
+                331ed31e75f8ce0c2db80b000000000000220020c20b5d1f8584fd90443e7b\
+                7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200\
+                208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f7\
+                41c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de84\
+                31104e9d6a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5\
+                f2c11a40d857accc862d6b7dd80e3e195220",
+                &[
+                    (
+                        2,
+                        "0200000001579c183eca9e8236a5d7f5dcd79cfec32c497fdc0ec6\
+                        1533cde99ecd436cadd10000000000000000000123060000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef6010000",
+                    ),
+                    (
+                        1,
+                        "0200000001579c183eca9e8236a5d7f5dcd79cfec32c497fdc0ec6\
+                        1533cde99ecd436cadd10100000000000000000109060000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                    (
+                        3,
+                        "0200000001579c183eca9e8236a5d7f5dcd79cfec32c497fdc0ec6\
+                        1533cde99ecd436cadd1020000000000000000010b0a0000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef7010000",
+                    ),
+                    (
+                        4,
+                        "0200000001579c183eca9e8236a5d7f5dcd79cfec32c497fdc0ec6\
+                        1533cde99ecd436cadd103000000000000000001d90d0000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                ][..],
+            ),
+            (
+                2069,
+                6_985_079,
+                "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b82\
+                0b584a488489000000000038b02b8006d007000000000000220020403d3947\
+                47cae42e98ff01734ad5c08f82ba123d3d9a620abda88989651e2ab5d00700\
+                0000000000220020748eba944fedc8827f6b06bc44678f93c0f9e6078b35c6\
+                331ed31e75f8ce0c2db80b000000000000220020c20b5d1f8584fd90443e7b\
+                7b720136174fa4b9333c261d04dbbd012635c0f419a00f0000000000002200\
+                208c48d15160397c9731df9bc3b236656efb6665fbfe92b4a6878e88a499f7\
+                41c4c0c62d0000000000160014ccf1af2f2aabee14bb40fa3851ab2301de84\
+                311077956a00000000002200204adb4e2f00643db396dd120d4e7dc17625f5\
+                f2c11a40d857accc862d6b7dd80e3e195220",
+                &[
+                    (
+                        2,
+                        "0200000001ca94a9ad516ebc0c4bdd7b6254871babfa978d5accaf\
+                        b554214137d398bfcf6a0000000000000000000175020000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef6010000",
+                    ),
+                    (
+                        1,
+                        "0200000001ca94a9ad516ebc0c4bdd7b6254871babfa978d5accaf\
+                        b554214137d398bfcf6a0100000000000000000122020000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                    (
+                        3,
+                        "0200000001ca94a9ad516ebc0c4bdd7b6254871babfa978d5accaf\
+                        b554214137d398bfcf6a020000000000000000015d060000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef7010000",
+                    ),
+                    (
+                        4,
+                        "0200000001ca94a9ad516ebc0c4bdd7b6254871babfa978d5accaf\
+                        b554214137d398bfcf6a03000000000000000001f2090000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                ][..],
+            ),
+            (
+                2070,
+                6_985_434,
+                "0200000001bef67e4e2fb9ddeeb3461973cd4c62abb35050b1add772995b82\
+                0b584a488489000000000038b02b8005d007000000000000220020403d3947\
+                47cae42e98ff01734ad5c08f82ba123d3d9a620abda88989651e2ab5b80b00\
+                0000000000220020c20b5d1f8584fd90443e7b7b720136174fa4b9333c261d\
+                04dbbd012635c0f419a00f0000000000002200208c48d15160397c9731df9b\
+                c3b236656efb6665fbfe92b4a6878e88a499f741c4c0c62d00000000001600\
+                14ccf1af2f2aabee14bb40fa3851ab2301de843110da966a00000000002200\
+                204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d857accc862d6b7d\
+                d80e3e195220",
+                &[
+                    (
+                        2,
+                        "020000000140a83ce364747ff277f4d7595d8d15f708418798922c\
+                        40bc2b056aca5485a2180000000000000000000174020000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef6010000",
+                    ),
+                    (
+                        3,
+                        "020000000140a83ce364747ff277f4d7595d8d15f708418798922c\
+                        40bc2b056aca5485a218010000000000000000015c060000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80ef7010000",
+                    ),
+                    (
+                        4,
+                        "020000000140a83ce364747ff277f4d7595d8d15f708418798922c\
+                        40bc2b056aca5485a21802000000000000000001f1090000000000\
+                        002200204adb4e2f00643db396dd120d4e7dc17625f5f2c11a40d8\
+                        57accc862d6b7dd80e00000000",
+                    ),
+                ][..],
+            ),
+        ] {
+            let mut channel = channel(*feerate);
+            add_htlcs(&mut channel);
+            commit_all(&mut channel);
+            assert_eq!(
+                channel.balances().unwrap(),
+                (6_988_000_000, 3_000_000_000)
+            );
+
+            let mut tx_graph = channel::TxGraph::default();
+            channel.bolt3.apply(&mut tx_graph).unwrap();
+            channel.apply(&mut tx_graph).unwrap();
+            Bip96.apply(&mut tx_graph).unwrap();
+            tx_graph.link_cmt_outs();
+
+            assert_eq!(tx_graph.cmt_outs.len(), htlc_txs.len() + 2);
+            assert_eq!(tx_graph.len(), htlc_txs.len());
+            assert_eq!(tx_graph.cmt_outs.last().unwrap().value, *to_local);
+            assert_eq!(
+                tx_graph.cmt_outs[tx_graph.cmt_outs.len() - 2].value,
+                3_000_000
+            );
+            let cmt_tx = tx_graph.render_cmt().global.unsigned_tx;
+            assert_eq!(serialize(&cmt_tx).to_hex(), *cmt_tx_hex);
+
+            let cmt_outs = tx_graph.cmt_outs.clone();
+            let mut rendered = vec![];
+            for (role, id, psbt) in tx_graph.vec_mut() {
+                let tx = &psbt.global.unsigned_tx;
+                let prevout = tx.input[0].previous_output;
+                let spent = psbt.inputs[0].witness_utxo.as_ref().unwrap();
+                assert_eq!(prevout.txid, cmt_tx.txid());
+                assert_eq!(&cmt_outs[prevout.vout as usize], spent);
+                let (fee, lock_time) = match TxType::from(role) {
+                    TxType::HtlcTimeout => (
+                        *feerate as u64 * HTLC_TIMEOUT_WEIGHT / 1000,
+                        502 + id as u32,
+                    ),
+                    TxType::HtlcSuccess => {
+                        (*feerate as u64 * HTLC_SUCCESS_WEIGHT / 1000, 0)
+                    }
+                    _ => unreachable!(),
+                };
+                assert_eq!(tx.lock_time, lock_time);
+                assert_eq!(tx.output[0].value, spent.value - fee);
+                let script = psbt.inputs[0].witness_script.as_ref().unwrap();
+                rendered.push((
+                    prevout.vout,
+                    script.as_bytes().to_hex(),
+                    serialize(tx).to_hex(),
+                ));
+            }
+            rendered.sort();
+            for ((_, script, tx), (no, htlc_tx)) in
+                rendered.into_iter().zip(htlc_txs.iter())
+            {
+                assert_eq!(script, scripts[*no]);
+                assert_eq!(tx, *htlc_tx);
+            }
+        }
+    }
+
+    #[test]
+    fn test_anchor_htlc_outputs() {
+        let init = init(commitment_features(true, true));
+        let feerate = 2069u64;
+        let mut channel = channel(feerate as u32);
+        channel.from_local(&init).unwrap();
+        channel.from_peer(&init).unwrap();
+        add_htlcs(&mut channel);
+        commit_all(&mut channel);
+
+        let mut tx_graph = channel::TxGraph::default();
+        channel.bolt3.apply(&mut tx_graph).unwrap();
+        channel.apply(&mut tx_graph).unwrap();
+        tx_graph.link_cmt_outs();
+
+        // Received 2000 sat HTLC is trimmed because of the larger weight of
+        // the HTLC-success transaction
+        assert_eq!(tx_graph.len(), 3);
+        for (role, _, psbt) in tx_graph.vec_mut() {
+            let tx = &psbt.global.unsigned_tx;
+            let spent = psbt.inputs[0].witness_utxo.as_ref().unwrap();
+            let script = psbt.inputs[0].witness_script.as_ref().unwrap();
+            assert!(script.as_bytes().ends_with(&[0x51, 0xb2, 0x75, 0x68]));
+            assert_eq!(tx.input[0].sequence, 1);
+            let fee = match TxType::from(role) {
+                TxType::HtlcTimeout => feerate * HTLC_TIMEOUT_ANCHOR_WEIGHT,
+                TxType::HtlcSuccess => feerate * HTLC_SUCCESS_ANCHOR_WEIGHT,
+                _ => unreachable!(),
+            } / 1000;
+            assert_eq!(tx.output[0].value, spent.value - fee);
+        }
+    }
+
+    #[test]
+    fn test_apply_keeps_equal_outputs() {
+        let mut channel = channel(0);
+        add_htlcs(&mut channel);
+        commit_all(&mut channel);
+
+        let mut tx_graph = channel::TxGraph::default();
+        channel.bolt3.apply(&mut tx_graph).unwrap();
+        let base_outs = tx_graph.cmt_outs.clone();
+        // Outputs added by other parties may be equal to the balance outputs
+        // of the channel constructor
+        tx_graph.cmt_outs.extend(base_outs.clone());
+        channel.apply(&mut tx_graph).unwrap();
+
+        assert_eq!(tx_graph.len(), 5);
+        assert_eq!(tx_graph.cmt_outs.len(), 2 + base_outs.len() + 5);
+        assert_eq!(&tx_graph.cmt_outs[2..2 + base_outs.len()], &base_outs[..]);
+        assert_eq!(
+            (tx_graph.cmt_outs[0].value, tx_graph.cmt_outs[1].value),
+            (6_988_000, 3_000_000)
+        );
+    }
+}
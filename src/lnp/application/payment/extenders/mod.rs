@@ -24,4 +24,6 @@ pub mod ptlc;
 pub mod dlc;
 pub mod lightspeed;
 
+pub use anchor_out::AnchorOut;
 pub use htlc::Htlc;
+pub use shutdown_script::ShutdownScript;
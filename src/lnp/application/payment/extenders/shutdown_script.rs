@@ -10,3 +10,773 @@
 // You should have received a copy of the MIT License
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
+
+use amplify::{DumbDefault, Wrapper};
+use std::io;
+
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::{Script, TxOut};
+
+use crate::bp::LockScript;
+use crate::lnp::application::payment::bolt3::{ScriptGenerators, TxGenerators};
+use crate::lnp::application::payment::{ExtensionId, Htlc, TxType};
+use crate::lnp::application::{channel, ChannelExtension, Extension, Messages};
+use crate::strict_encoding::{self, StrictDecode, StrictEncode};
+
+/// Weight of the closing transaction witness spending the funding output,
+/// including segwit marker and flag, according to BOLT-3
+pub const CLOSING_TX_WITNESS_WEIGHT: u64 = 224;
+
+/// Range of the closing transaction fees acceptable by the local node during
+/// `closing_signed` negotiation
+#[derive(
+    Copy, Clone, PartialEq, Eq, Hash, Debug, StrictEncode, StrictDecode,
+)]
+#[lnpbp_crate(crate)]
+pub struct FeeRange {
+    pub min_fee_satoshis: u64,
+    pub max_fee_satoshis: u64,
+}
+
+impl FeeRange {
+    /// Constructs range from a half to the double of the `fee_estimate`
+    #[inline]
+    pub fn around(fee_estimate: u64) -> Self {
+        Self {
+            min_fee_satoshis: fee_estimate / 2,
+            max_fee_satoshis: fee_estimate.saturating_mul(2),
+        }
+    }
+
+    /// Checks whether the `fee` is acceptable
+    #[inline]
+    pub fn contains(&self, fee: u64) -> bool {
+        fee >= self.min_fee_satoshis && fee <= self.max_fee_satoshis
+    }
+
+    /// Returns the closest to `fee` acceptable value
+    #[inline]
+    pub fn clamp(&self, fee: u64) -> u64 {
+        fee.max(self.min_fee_satoshis).min(self.max_fee_satoshis)
+    }
+}
+
+/// Channel extender enforcing upfront shutdown scripts committed in
+/// `open_channel` and `accept_channel` messages and negotiating the
+/// cooperative closing transaction fee with `closing_signed` messages.
+///
+/// Closing transaction paying the fee from the last `closing_signed` message
+/// is added to the transaction graph as [`TxType::Closing`]; once both
+/// parties have agreed on the fee, it is the final one. Channel balances
+/// and parameters are read from the [`Htlc`] extender shared with this one
+/// (see [`ShutdownScript::with`]). The [`Htlc`] extender is not a part of the
+/// strict encoding, so the decoded extender must be connected to it with
+/// [`ShutdownScript::connect`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ShutdownScript {
+    local_upfront_script: Option<Script>,
+    remote_upfront_script: Option<Script>,
+    local_script: Option<Script>,
+    remote_script: Option<Script>,
+
+    /// Fee range set by the user, if any
+    fee_range: Option<FeeRange>,
+    /// Fee from the last `closing_signed` sent by the local node
+    local_fee: Option<u64>,
+    /// Fee from the last `closing_signed` received from the remote peer
+    remote_fee: Option<u64>,
+    /// Fee from the last `closing_signed` sent by any of the parties
+    proposed_fee: Option<u64>,
+
+    /// HTLC extender shared with this one
+    htlc: channel::Shared<Htlc>,
+}
+
+impl DumbDefault for ShutdownScript {
+    fn dumb_default() -> Self {
+        ShutdownScript {
+            local_upfront_script: None,
+            remote_upfront_script: None,
+            local_script: None,
+            remote_script: None,
+            fee_range: None,
+            local_fee: None,
+            remote_fee: None,
+            proposed_fee: None,
+            htlc: channel::Shared::dumb_default(),
+        }
+    }
+}
+
+impl StrictEncode for ShutdownScript {
+    type Error = strict_encoding::Error;
+
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, Self::Error> {
+        Ok(strict_encode_list!(e;
+            self.local_upfront_script,
+            self.remote_upfront_script,
+            self.local_script,
+            self.remote_script,
+            self.fee_range,
+            self.local_fee,
+            self.remote_fee,
+            self.proposed_fee
+        ))
+    }
+}
+
+impl StrictDecode for ShutdownScript {
+    type Error = strict_encoding::Error;
+
+    fn strict_decode<D: io::Read>(mut d: D) -> Result<Self, Self::Error> {
+        Ok(ShutdownScript {
+            local_upfront_script: StrictDecode::strict_decode(&mut d)?,
+            remote_upfront_script: StrictDecode::strict_decode(&mut d)?,
+            local_script: StrictDecode::strict_decode(&mut d)?,
+            remote_script: StrictDecode::strict_decode(&mut d)?,
+            fee_range: StrictDecode::strict_decode(&mut d)?,
+            local_fee: StrictDecode::strict_decode(&mut d)?,
+            remote_fee: StrictDecode::strict_decode(&mut d)?,
+            proposed_fee: StrictDecode::strict_decode(&mut d)?,
+            htlc: channel::Shared::dumb_default(),
+        })
+    }
+}
+
+impl ShutdownScript {
+    /// Constructs extender reading channel balances and parameters from the
+    /// `htlc` extender, which must be added to the same channel
+    pub fn with(htlc: channel::Shared<Htlc>) -> Self {
+        ShutdownScript {
+            htlc,
+            ..ShutdownScript::dumb_default()
+        }
+    }
+
+    /// Connects extender to the `htlc` extender after it was restored from
+    /// the strict encoding
+    #[inline]
+    pub fn connect(&mut self, htlc: channel::Shared<Htlc>) {
+        self.htlc = htlc;
+    }
+
+    /// Returns range of the closing transaction fees acceptable by the local
+    /// node. Unless it was set with [`ShutdownScript::set_fee_range`], the
+    /// range is built [`FeeRange::around`] the closing transaction fee for
+    /// the current channel feerate.
+    #[inline]
+    pub fn fee_range(&self) -> FeeRange {
+        self.fee_range
+            .unwrap_or_else(|| FeeRange::around(self.fee_estimate()))
+    }
+
+    /// Sets range of the closing transaction fees acceptable by the local
+    /// node
+    #[inline]
+    pub fn set_fee_range(&mut self, fee_range: FeeRange) {
+        self.fee_range = Some(fee_range)
+    }
+
+    /// Returns script to which the local node funds are sent on closing
+    #[inline]
+    pub fn local_script(&self) -> Option<&Script> {
+        self.local_script.as_ref()
+    }
+
+    /// Returns script to which the remote peer funds are sent on closing
+    #[inline]
+    pub fn remote_script(&self) -> Option<&Script> {
+        self.remote_script.as_ref()
+    }
+
+    /// Returns fee from the last `closing_signed` message sent by any of the
+    /// parties, which is paid by the closing transaction in the transaction
+    /// graph
+    #[inline]
+    pub fn proposed_fee(&self) -> Option<u64> {
+        self.proposed_fee
+    }
+
+    /// Returns closing transaction fee agreed by both parties, if the
+    /// negotiation is complete
+    pub fn closing_fee(&self) -> Option<u64> {
+        match (self.local_fee, self.remote_fee) {
+            (Some(local), Some(remote)) if local == remote => Some(local),
+            _ => None,
+        }
+    }
+
+    /// Returns fee which should be proposed by the local node with the next
+    /// `closing_signed` message, or `None` if it is the turn of the remote
+    /// peer or the negotiation is complete.
+    ///
+    /// The channel funder starts with the fee for the current channel
+    /// feerate; each following proposal is halfway between the previous
+    /// proposals of both parties. Fees from the local [`FeeRange`] are
+    /// accepted as they are.
+    pub fn next_fee(&self) -> Option<u64> {
+        if !self.is_shutdown() || self.closing_fee().is_some() {
+            return None;
+        }
+        let fee_range = self.fee_range();
+        match (self.local_fee, self.remote_fee) {
+            (_, Some(remote)) if fee_range.contains(remote) => Some(remote),
+            (Some(local), Some(remote)) => {
+                Some(fee_range.clamp((local + remote) / 2))
+            }
+            (None, Some(remote)) => Some(fee_range.clamp(remote)),
+            (None, None) if *self.htlc.borrow().bolt3().is_originator() => {
+                Some(fee_range.clamp(self.fee_estimate()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Constructs closing transaction paying `fee`, which is deducted from
+    /// the channel funder balance. Outputs below the local dust limit are
+    /// trimmed.
+    pub fn closing_tx(&self, fee: u64) -> Result<Psbt, channel::Error> {
+        let (local_script, remote_script) =
+            match (&self.local_script, &self.remote_script) {
+                (Some(local), Some(remote)) => (local, remote),
+                _ => Err(channel::Error::Extension(s!(
+                    "shutdown scripts are not yet exchanged"
+                )))?,
+            };
+        let htlc = self.htlc.borrow();
+        let bolt3 = htlc.bolt3();
+        let (local_amount_msat, remote_amount_msat) = htlc.balances()?;
+        let mut to_local = local_amount_msat / 1000;
+        let mut to_remote = remote_amount_msat / 1000;
+        let funder = if *bolt3.is_originator() {
+            &mut to_local
+        } else {
+            &mut to_remote
+        };
+        if *funder < fee {
+            Err(channel::Error::Extension(format!(
+                "closing fee {} exceeds channel funder balance {}",
+                fee, funder
+            )))?
+        }
+        *funder -= fee;
+
+        let outs = vec![(to_local, local_script), (to_remote, remote_script)]
+            .into_iter()
+            .filter(|(value, _)| *value >= *bolt3.dust_limit_satoshis())
+            .map(|(value, script)| TxOut {
+                value,
+                script_pubkey: script.clone(),
+            })
+            .collect();
+
+        let funding_amount =
+            (*bolt3.local_amount_msat() + *bolt3.remote_amount_msat()) / 1000;
+        let local_funding_pubkey = bolt3.local_keys().funding_pubkey;
+        let remote_funding_pubkey = bolt3.remote_keys().funding_pubkey;
+        let mut psbt = Psbt::ln_closing(*bolt3.funding_outpoint(), outs);
+        psbt.inputs[0].witness_utxo = Some(TxOut::ln_funding(
+            funding_amount,
+            local_funding_pubkey,
+            remote_funding_pubkey,
+        ));
+        psbt.inputs[0].witness_script = Some(
+            LockScript::ln_funding(
+                funding_amount,
+                local_funding_pubkey,
+                remote_funding_pubkey,
+            )
+            .into_inner(),
+        );
+        Ok(psbt)
+    }
+
+    /// Checks whether both parties have sent their `shutdown` messages
+    #[inline]
+    fn is_shutdown(&self) -> bool {
+        self.local_script.is_some() && self.remote_script.is_some()
+    }
+
+    /// Returns fee for the closing transaction at the current channel
+    /// feerate
+    fn fee_estimate(&self) -> u64 {
+        let weight = self
+            .closing_tx(0)
+            .map(|psbt| psbt.global.unsigned_tx.get_weight() as u64)
+            .unwrap_or_default()
+            + CLOSING_TX_WITNESS_WEIGHT;
+        *self.htlc.borrow().bolt3().feerate_per_kw() as u64 * weight / 1000
+    }
+
+    fn shutdown(
+        &mut self,
+        script: &Script,
+        by_local: bool,
+    ) -> Result<(), channel::Error> {
+        check_script(script)?;
+        let (upfront, current) = if by_local {
+            (&self.local_upfront_script, &mut self.local_script)
+        } else {
+            (&self.remote_upfront_script, &mut self.remote_script)
+        };
+        if let Some(upfront) = upfront {
+            if upfront != script {
+                Err(channel::Error::Extension(format!(
+                    "shutdown script {:?} does not match upfront shutdown \
+                     script {:?}",
+                    script, upfront
+                )))?
+            }
+        }
+        if let Some(current) = current {
+            if current != script {
+                Err(channel::Error::Extension(s!(
+                    "shutdown script can't be changed"
+                )))?
+            }
+        }
+        *current = Some(script.clone());
+        Ok(())
+    }
+
+    fn closing_signed(
+        &mut self,
+        fee: u64,
+        by_local: bool,
+    ) -> Result<(), channel::Error> {
+        if !self.is_shutdown() {
+            Err(channel::Error::Extension(s!(
+                "closing_signed before both parties have sent shutdown"
+            )))?
+        }
+        let has_htlcs = {
+            let htlc = self.htlc.borrow();
+            htlc.offered_htlcs().count() > 0
+                || htlc.received_htlcs().count() > 0
+        };
+        if has_htlcs {
+            Err(channel::Error::Extension(s!(
+                "closing_signed while HTLCs are pending"
+            )))?
+        }
+        // Check that the fee can be paid by the funder
+        self.closing_tx(fee)?;
+
+        if by_local {
+            self.local_fee = Some(fee);
+            self.proposed_fee = Some(fee);
+            return Ok(());
+        }
+        if let (Some(local), Some(remote)) = (self.local_fee, self.remote_fee) {
+            let (low, high) = (local.min(remote), local.max(remote));
+            if fee != local && (fee <= low || fee >= high) {
+                Err(channel::Error::Extension(format!(
+                    "closing fee {} is not between previously proposed \
+                     fees {} and {}",
+                    fee, low, high
+                )))?
+            }
+        }
+        self.remote_fee = Some(fee);
+        self.proposed_fee = Some(fee);
+        Ok(())
+    }
+
+    fn update(
+        &mut self,
+        message: &Messages,
+        by_local: bool,
+    ) -> Result<(), channel::Error> {
+        match message {
+            Messages::OpenChannel(open_channel) => {
+                self.upfront_script(
+                    &open_channel.shutdown_scriptpubkey,
+                    by_local,
+                )?;
+            }
+            Messages::AcceptChannel(accept_channel) => {
+                self.upfront_script(
+                    &accept_channel.shutdown_scriptpubkey,
+                    by_local,
+                )?;
+            }
+            Messages::Shutdown(shutdown) => {
+                self.shutdown(&shutdown.scriptpubkey, by_local)?
+            }
+            Messages::ClosingSigned(closing_signed) => {
+                self.closing_signed(closing_signed.fee_satoshis, by_local)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn upfront_script(
+        &mut self,
+        script: &Option<Script>,
+        by_local: bool,
+    ) -> Result<(), channel::Error> {
+        let script = match check_upfront_script(script)? {
+            Some(script) => script,
+            None => return Ok(()),
+        };
+        if by_local {
+            self.local_upfront_script = Some(script.clone());
+        } else {
+            self.remote_upfront_script = Some(script.clone());
+        }
+        Ok(())
+    }
+}
+
+impl channel::State for ShutdownScript {}
+
+impl Extension for ShutdownScript {
+    type Identity = ExtensionId;
+
+    fn identity(&self) -> Self::Identity {
+        ExtensionId::ShutdownScript
+    }
+
+    fn update_from_peer(
+        &mut self,
+        message: &Messages,
+    ) -> Result<(), channel::Error> {
+        self.update(message, false)
+    }
+
+    fn update_from_local(
+        &mut self,
+        message: &Messages,
+    ) -> Result<(), channel::Error> {
+        self.update(message, true)
+    }
+
+    fn extension_state(&self) -> Box<dyn channel::State> {
+        Box::new(self.clone())
+    }
+}
+
+impl ChannelExtension for ShutdownScript {
+    fn channel_state(&self) -> Box<dyn channel::State> {
+        Box::new(self.clone())
+    }
+
+    fn apply(
+        &mut self,
+        tx_graph: &mut channel::TxGraph,
+    ) -> Result<(), channel::Error> {
+        tx_graph.remove_role(TxType::Closing);
+        if let Some(fee) = self.proposed_fee {
+            tx_graph.insert_tx(TxType::Closing, 0u64, self.closing_tx(fee)?);
+        }
+        Ok(())
+    }
+
+    fn strict_encode_extension(
+        &self,
+        e: &mut dyn io::Write,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.strict_encode(e)
+    }
+}
+
+/// Checks that the shutdown script has one of the forms allowed by BOLT-2:
+/// P2PKH, P2SH, P2WPKH or P2WSH
+pub fn check_script(script: &Script) -> Result<(), channel::Error> {
+    if script.is_p2pkh()
+        || script.is_p2sh()
+        || script.is_v0_p2wpkh()
+        || script.is_v0_p2wsh()
+    {
+        Ok(())
+    } else {
+        Err(channel::Error::Extension(format!(
+            "shutdown script {:?} has non-standard form",
+            script
+        )))
+    }
+}
+
+/// Checks upfront shutdown script from `open_channel` or `accept_channel`
+/// message, returning it unless the node has opted out of the upfront
+/// shutdown script commitment with a zero-length script
+pub fn check_upfront_script(
+    script: &Option<Script>,
+) -> Result<Option<&Script>, channel::Error> {
+    match script {
+        Some(script) if !script.is_empty() => {
+            check_script(script)?;
+            Ok(Some(script))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{Message, SecretKey};
+    use std::ops::{Deref, DerefMut};
+
+    use crate::bp::PubkeyScript;
+    use crate::lnp::application::payment::bip96::Bip96;
+    use crate::lnp::application::payment::{Bolt3, ChannelId};
+    use crate::lnp::message::{
+        AcceptChannel, ClosingSigned, OpenChannel, Shutdown,
+    };
+    use crate::lnp::test_helpers::point;
+    use crate::SECP256K1;
+
+    fn script(byte: u8) -> Script {
+        PubkeyScript::ln_to_remote_v1(0, point(byte)).into_inner()
+    }
+
+    fn shutdown(script: Script) -> Messages {
+        Messages::Shutdown(Shutdown {
+            channel_id: ChannelId::default(),
+            scriptpubkey: script,
+        })
+    }
+
+    fn closing_signed(fee_satoshis: u64) -> Messages {
+        let secret = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let message = Message::from_slice(&[1u8; 32]).unwrap();
+        Messages::ClosingSigned(ClosingSigned {
+            channel_id: ChannelId::default(),
+            fee_satoshis,
+            signature: SECP256K1.sign(&message, &secret),
+        })
+    }
+
+    // Shutdown script extender together with the channel constructor and the
+    // HTLC extender it reads, which are updated in the same order as by the
+    // channel
+    struct Party {
+        bolt3: channel::Shared<Bolt3>,
+        htlc: channel::Shared<Htlc>,
+        shutdown_script: ShutdownScript,
+    }
+
+    impl Party {
+        fn new() -> Self {
+            let bolt3 = channel::Shared::new(Bolt3::dumb_default());
+            let htlc = channel::Shared::new(Htlc::with(bolt3.clone()));
+            let shutdown_script = ShutdownScript::with(htlc.clone());
+            Party {
+                bolt3,
+                htlc,
+                shutdown_script,
+            }
+        }
+
+        fn update_from_local(
+            &mut self,
+            message: &Messages,
+        ) -> Result<(), channel::Error> {
+            self.bolt3.update_from_local(message)?;
+            self.htlc.update_from_local(message)?;
+            self.shutdown_script.update_from_local(message)
+        }
+
+        fn update_from_peer(
+            &mut self,
+            message: &Messages,
+        ) -> Result<(), channel::Error> {
+            self.bolt3.update_from_peer(message)?;
+            self.htlc.update_from_peer(message)?;
+            self.shutdown_script.update_from_peer(message)
+        }
+    }
+
+    impl Deref for Party {
+        type Target = ShutdownScript;
+
+        fn deref(&self) -> &Self::Target {
+            &self.shutdown_script
+        }
+    }
+
+    impl DerefMut for Party {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.shutdown_script
+        }
+    }
+
+    // Alice is the channel funder committing to the upfront shutdown script;
+    // Bob opts out of the commitment
+    fn channels() -> (Party, Party) {
+        let mut alice = Party::new();
+        let mut bob = Party::new();
+        let open_channel = Messages::OpenChannel(OpenChannel {
+            funding_satoshis: 10_000_000,
+            push_msat: 3_000_000_000,
+            dust_limit_satoshis: 546,
+            feerate_per_kw: 2000,
+            funding_pubkey: point(0x01),
+            shutdown_scriptpubkey: Some(script(0xa1)),
+            ..OpenChannel::dumb_default()
+        });
+        let accept_channel = Messages::AcceptChannel(AcceptChannel {
+            dust_limit_satoshis: 546,
+            funding_pubkey: point(0x02),
+            shutdown_scriptpubkey: Some(Script::new()),
+            ..AcceptChannel::dumb_default()
+        });
+        alice.update_from_local(&open_channel).unwrap();
+        bob.update_from_peer(&open_channel).unwrap();
+        bob.update_from_local(&accept_channel).unwrap();
+        alice.update_from_peer(&accept_channel).unwrap();
+        (alice, bob)
+    }
+
+    fn send(
+        sender: &mut Party,
+        receiver: &mut Party,
+        message: &Messages,
+    ) -> Result<(), channel::Error> {
+        sender.update_from_local(message)?;
+        receiver.update_from_peer(message)
+    }
+
+    fn negotiate(
+        alice: &mut Party,
+        bob: &mut Party,
+    ) -> Result<u64, channel::Error> {
+        send(alice, bob, &shutdown(script(0xa1)))?;
+        send(bob, alice, &shutdown(script(0xb0)))?;
+        for _ in 0..10 {
+            if let Some(fee) = alice.next_fee() {
+                send(alice, bob, &closing_signed(fee))?;
+            }
+            if let Some(fee) = bob.next_fee() {
+                send(bob, alice, &closing_signed(fee))?;
+            }
+            if let (Some(fee), Some(bob_fee)) =
+                (alice.closing_fee(), bob.closing_fee())
+            {
+                assert_eq!(fee, bob_fee);
+                return Ok(fee);
+            }
+        }
+        panic!("closing fee negotiation does not converge")
+    }
+
+    #[test]
+    fn test_upfront_script() {
+        let (mut alice, mut bob) = channels();
+        assert!(bob.update_from_local(&closing_signed(1000)).is_err());
+
+        // Alice has committed to the upfront shutdown script
+        assert!(send(&mut alice, &mut bob, &shutdown(script(0xa2))).is_err());
+        assert!(bob.update_from_peer(&shutdown(script(0xa2))).is_err());
+        send(&mut alice, &mut bob, &shutdown(script(0xa1))).unwrap();
+        assert_eq!(bob.remote_script(), Some(&script(0xa1)));
+
+        // Bob may use any script of the standard form
+        assert!(bob.update_from_local(&shutdown(Script::new())).is_err());
+        send(&mut bob, &mut alice, &shutdown(script(0xb0))).unwrap();
+        assert_eq!(alice.remote_script(), Some(&script(0xb0)));
+        assert!(alice.update_from_peer(&shutdown(script(0xb1))).is_err());
+    }
+
+    #[test]
+    fn test_fee_negotiation() {
+        let (mut alice, mut bob) = channels();
+        alice.set_fee_range(FeeRange {
+            min_fee_satoshis: 500,
+            max_fee_satoshis: 1700,
+        });
+        bob.set_fee_range(FeeRange {
+            min_fee_satoshis: 1500,
+            max_fee_satoshis: 5000,
+        });
+        // Alice starts with 1352 sat fee for the 676 wu closing transaction,
+        // Bob replies with his minimum, which is accepted by Alice
+        assert_eq!(negotiate(&mut alice, &mut bob), Ok(1500));
+
+        let mut alice_graph = channel::TxGraph::default();
+        alice.apply(&mut alice_graph).unwrap();
+        Bip96.apply(&mut alice_graph).unwrap();
+        let mut bob_graph = channel::TxGraph::default();
+        bob.apply(&mut bob_graph).unwrap();
+        Bip96.apply(&mut bob_graph).unwrap();
+
+        let psbt = alice_graph.tx(TxType::Closing, 0u64).unwrap();
+        let tx = &psbt.global.unsigned_tx;
+        assert_eq!(
+            tx,
+            &bob_graph
+                .tx(TxType::Closing, 0u64)
+                .unwrap()
+                .global
+                .unsigned_tx
+        );
+        assert_eq!(
+            tx.output,
+            vec![
+                TxOut {
+                    value: 3_000_000,
+                    script_pubkey: script(0xb0),
+                },
+                TxOut {
+                    value: 6_998_500,
+                    script_pubkey: script(0xa1),
+                },
+            ]
+        );
+        assert_eq!(
+            psbt.inputs[0].witness_utxo,
+            Some(TxOut::ln_funding(10_000_000, point(0x01), point(0x02)))
+        );
+
+        // Negotiation fails if fee ranges do not overlap
+        let (mut alice, mut bob) = channels();
+        alice.set_fee_range(FeeRange {
+            min_fee_satoshis: 500,
+            max_fee_satoshis: 1200,
+        });
+        bob.set_fee_range(FeeRange {
+            min_fee_satoshis: 2000,
+            max_fee_satoshis: 5000,
+        });
+        assert!(negotiate(&mut alice, &mut bob).is_err());
+        assert_eq!(alice.closing_fee(), None);
+    }
+
+    #[test]
+    fn test_default_fee_range() {
+        // Without the fee range set by the user both parties accept fees
+        // from a half to the double of the 1352 sat fee estimate
+        let (mut alice, mut bob) = channels();
+        assert_eq!(negotiate(&mut alice, &mut bob), Ok(1352));
+        let fee_range = FeeRange {
+            min_fee_satoshis: 676,
+            max_fee_satoshis: 2704,
+        };
+        assert_eq!(alice.fee_range(), fee_range);
+        assert_eq!(bob.fee_range(), fee_range);
+
+        // Excessive fee proposed by the funder is countered with the
+        // maximum of the range
+        let (mut alice, mut bob) = channels();
+        send(&mut alice, &mut bob, &shutdown(script(0xa1))).unwrap();
+        send(&mut bob, &mut alice, &shutdown(script(0xb0))).unwrap();
+        send(&mut alice, &mut bob, &closing_signed(1_000_000)).unwrap();
+        assert_eq!(bob.next_fee(), Some(2704));
+
+        // Closing transaction for the proposed fee is signed by Alice and
+        // verified by Bob before the fee is agreed
+        assert_eq!(bob.closing_fee(), None);
+        assert_eq!(bob.proposed_fee(), Some(1_000_000));
+        let mut bob_graph = channel::TxGraph::default();
+        bob.apply(&mut bob_graph).unwrap();
+        assert_eq!(
+            bob_graph.tx(TxType::Closing, 0u64),
+            Some(&bob.closing_tx(1_000_000).unwrap())
+        );
+    }
+}
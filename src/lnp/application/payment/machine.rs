@@ -0,0 +1,1314 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! BOLT-2 channel lifecycle state machine. It validates channel messages
+//! against the current channel state, forwards them to the channel
+//! extensions and generates channel establishment and commitment update
+//! messages signed over the channel transaction graph.
+
+use amplify::{DumbDefault, Wrapper};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+
+use bitcoin::secp256k1::{self, PublicKey, SecretKey, Signature};
+use bitcoin::util::bip143::SigHashCache;
+use bitcoin::{OutPoint, Script, SigHash, SigHashType, Transaction};
+
+use super::bolt3::{self, ScriptGenerators, ShaChain, ShaChainError};
+use super::channel::{Keyset, NegotiationError, Params};
+use super::shutdown_script;
+use super::{ChannelId, Lifecycle, TempChannelId, TxType};
+use crate::bp::chain::AssetId;
+use crate::bp::LockScript;
+use crate::lnp::application::channel::{self, Channel, TxGraph};
+use crate::lnp::application::{extension, ChannelExtension, Extension};
+use crate::lnp::message::{
+    AcceptChannel, ClosingSigned, CommitmentSigned, FundingCreated,
+    FundingLocked, FundingSigned, OpenChannel, RevokeAndAck, Shutdown,
+    UpdateAddHtlc, UpdateFailHtlc, UpdateFailMalformedHtlc, UpdateFee,
+    UpdateFulfillHtlc,
+};
+use crate::lnp::{Messages, TypeId, TypedEnum};
+use crate::{SECP256K1, SECP256K1_PUBKEY_DUMB};
+
+/// Maximal per-commitment secret index; commitment transaction number `n`
+/// uses secret with index `MAX_COMMITMENT_INDEX - n`
+const MAX_COMMITMENT_INDEX: u64 = 0xFFFF_FFFF_FFFF;
+
+/// Errors of the channel state machine
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum Error {
+    /// message of type {0} is not expected in {1} channel state
+    UnexpectedMessage(TypeId, Lifecycle),
+
+    /// {0}
+    #[from]
+    Negotiation(NegotiationError),
+
+    /// {0}
+    #[from]
+    Channel(channel::Error),
+
+    /// message refers to the channel {0} which differs from the current one
+    ChannelMismatch(ChannelId),
+
+    /// funding output index {0} does not fit into 16 bits of
+    /// `funding_created` message
+    FundingOutputIndex(u32),
+
+    /// signature does not match the commitment or closing transaction
+    InvalidSignature,
+
+    /// closing transaction for the proposed fee is not constructed by the
+    /// channel extensions
+    NoClosingTx,
+
+    /// number of HTLC signatures ({0}) does not match the number of HTLC
+    /// transactions ({1})
+    HtlcSignatureCount(usize, usize),
+
+    /// signature does not match HTLC transaction spending commitment output
+    /// #{0}
+    InvalidHtlcSignature(u32),
+
+    /// revealed per-commitment secret does not match the per-commitment
+    /// point of the revoked commitment transaction
+    InvalidRevocation,
+
+    /// {0}
+    #[from]
+    Revocation(ShaChainError),
+}
+
+/// Private keys of the local node used by the channel. Its [`Debug`]
+/// implementation does not reveal the keys.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ChannelSecrets {
+    pub funding_key: SecretKey,
+    pub revocation_basepoint_secret: SecretKey,
+    pub payment_basepoint_secret: SecretKey,
+    pub delayed_payment_basepoint_secret: SecretKey,
+    pub htlc_basepoint_secret: SecretKey,
+    /// Seed for the per-commitment secrets generation according to BOLT-3
+    pub per_commitment_seed: [u8; 32],
+}
+
+impl Debug for ChannelSecrets {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("ChannelSecrets(..)")
+    }
+}
+
+impl ChannelSecrets {
+    /// Returns per-commitment secret of the local commitment transaction
+    /// with the given number
+    pub fn per_commitment_secret(&self, commitment_number: u64) -> SecretKey {
+        let secret = bolt3::per_commitment_secret(
+            &self.per_commitment_seed,
+            MAX_COMMITMENT_INDEX - commitment_number,
+        );
+        SecretKey::from_slice(&secret).expect(
+            "SHA256 output is a valid secret key with overwhelming probability",
+        )
+    }
+
+    /// Returns per-commitment point of the local commitment transaction
+    /// with the given number
+    #[inline]
+    pub fn per_commitment_point(&self, commitment_number: u64) -> PublicKey {
+        PublicKey::from_secret_key(
+            &SECP256K1,
+            &self.per_commitment_secret(commitment_number),
+        )
+    }
+
+    /// Returns public keys announced to the remote peer in `open_channel`
+    /// or `accept_channel` message
+    pub fn keyset(&self, shutdown_scriptpubkey: Option<Script>) -> Keyset {
+        let pubkey = |secret| PublicKey::from_secret_key(&SECP256K1, secret);
+        Keyset {
+            funding_pubkey: pubkey(&self.funding_key),
+            revocation_basepoint: pubkey(&self.revocation_basepoint_secret),
+            payment_basepoint: pubkey(&self.payment_basepoint_secret),
+            delayed_payment_basepoint: pubkey(
+                &self.delayed_payment_basepoint_secret,
+            ),
+            htlc_basepoint: pubkey(&self.htlc_basepoint_secret),
+            first_per_commitment_point: self.per_commitment_point(0),
+            shutdown_scriptpubkey,
+        }
+    }
+}
+
+/// Channel state machine driving the channel through its [`Lifecycle`].
+///
+/// Each message sent to or received from the remote peer is checked against
+/// the current channel state and is passed to two channels composed of the
+/// same extensions. The local channel constructs commitment transaction of
+/// the local node; the remote one receives the same messages with swapped
+/// directions, and thus mirrors the commitment transaction of the remote
+/// peer, which is signed by the local node.
+pub struct StateMachine<N>
+where
+    N: 'static + extension::Nomenclature,
+{
+    lifecycle: Lifecycle,
+    is_originator: bool,
+    temporary_channel_id: TempChannelId,
+    channel_id: ChannelId,
+    params: Params,
+    funding_outpoint: OutPoint,
+
+    secrets: ChannelSecrets,
+    remote_keys: Keyset,
+
+    local_channel: Channel<N>,
+    remote_channel: Channel<N>,
+
+    /// Number of the current local commitment transaction
+    commitment_number: u64,
+    /// Per-commitment point of the current remote commitment transaction
+    remote_point: PublicKey,
+    /// Per-commitment point for the next remote commitment transaction;
+    /// it is unknown until the remote peer revokes its previous commitment
+    remote_next_point: Option<PublicKey>,
+    /// Per-commitment point of the remote commitment transaction which must
+    /// be revoked by the remote peer with `revoke_and_ack`
+    revoked_point: Option<PublicKey>,
+    /// Local node has received `commitment_signed` and has not yet revoked
+    /// its previous commitment transaction
+    revocation_owed: bool,
+    /// Per-commitment secrets of the revoked remote commitment transactions
+    remote_secrets: ShaChain,
+
+    local_locked: bool,
+    remote_locked: bool,
+    local_shutdown: bool,
+    remote_shutdown: bool,
+    local_closing_fee: Option<u64>,
+    remote_closing_fee: Option<u64>,
+}
+
+impl<N> StateMachine<N>
+where
+    N: 'static + extension::Nomenclature,
+{
+    /// Constructs state machine for a new channel. Both channels must be
+    /// composed of the same extensions: `local_channel` is used for the
+    /// local commitment transaction and `remote_channel` - for the
+    /// commitment transaction of the remote peer.
+    pub fn with(
+        local_channel: Channel<N>,
+        remote_channel: Channel<N>,
+        secrets: ChannelSecrets,
+    ) -> Self {
+        Self {
+            lifecycle: Lifecycle::Initial,
+            is_originator: false,
+            temporary_channel_id: TempChannelId::dumb_default(),
+            channel_id: ChannelId::default(),
+            params: Params::default(),
+            funding_outpoint: OutPoint::null(),
+            secrets,
+            remote_keys: Keyset::dumb_default(),
+            local_channel,
+            remote_channel,
+            commitment_number: 0,
+            remote_point: *SECP256K1_PUBKEY_DUMB,
+            remote_next_point: None,
+            revoked_point: None,
+            revocation_owed: false,
+            remote_secrets: ShaChain::default(),
+            local_locked: false,
+            remote_locked: false,
+            local_shutdown: false,
+            remote_shutdown: false,
+            local_closing_fee: None,
+            remote_closing_fee: None,
+        }
+    }
+
+    #[inline]
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.lifecycle
+    }
+
+    #[inline]
+    pub fn is_originator(&self) -> bool {
+        self.is_originator
+    }
+
+    #[inline]
+    pub fn temporary_channel_id(&self) -> TempChannelId {
+        self.temporary_channel_id
+    }
+
+    #[inline]
+    pub fn channel_id(&self) -> ChannelId {
+        self.channel_id
+    }
+
+    #[inline]
+    pub fn params(&self) -> Params {
+        self.params
+    }
+
+    #[inline]
+    pub fn funding_outpoint(&self) -> OutPoint {
+        self.funding_outpoint
+    }
+
+    /// Returns number of the current local commitment transaction
+    #[inline]
+    pub fn commitment_number(&self) -> u64 {
+        self.commitment_number
+    }
+
+    /// Returns per-commitment secrets revealed by the remote peer when
+    /// revoking its commitment transactions
+    #[inline]
+    pub fn remote_secrets(&self) -> &ShaChain {
+        &self.remote_secrets
+    }
+
+    /// Returns channel holding the local commitment transaction
+    #[inline]
+    pub fn local_channel(&self) -> &Channel<N> {
+        &self.local_channel
+    }
+
+    /// Returns channel mirroring the commitment transaction of the remote
+    /// peer
+    #[inline]
+    pub fn remote_channel(&self) -> &Channel<N> {
+        &self.remote_channel
+    }
+
+    /// Constructs transaction graph of the current local commitment
+    pub fn local_tx_graph(&mut self) -> Result<TxGraph, Error> {
+        let mut tx_graph = TxGraph::default();
+        self.local_channel.apply(&mut tx_graph)?;
+        Ok(tx_graph)
+    }
+
+    /// Constructs transaction graph of the current remote commitment
+    pub fn remote_tx_graph(&mut self) -> Result<TxGraph, Error> {
+        let mut tx_graph = TxGraph::default();
+        self.remote_channel.apply(&mut tx_graph)?;
+        Ok(tx_graph)
+    }
+
+    /// Proposes a new channel funded by the local node
+    pub fn open_channel(
+        &mut self,
+        chain_hash: AssetId,
+        temporary_channel_id: TempChannelId,
+        params: Params,
+        shutdown_scriptpubkey: Option<Script>,
+    ) -> Result<Messages, Error> {
+        let keyset = self.secrets.keyset(shutdown_scriptpubkey);
+        self.send(Messages::OpenChannel(OpenChannel {
+            chain_hash,
+            temporary_channel_id,
+            funding_satoshis: params.funding_satoshis,
+            push_msat: params.push_msat,
+            dust_limit_satoshis: params.dust_limit_satoshis,
+            max_htlc_value_in_flight_msat: params.max_htlc_value_in_flight_msat,
+            channel_reserve_satoshis: params.channel_reserve_satoshis,
+            htlc_minimum_msat: params.htlc_minimum_msat,
+            feerate_per_kw: params.feerate_per_kw,
+            to_self_delay: params.to_self_delay,
+            max_accepted_htlcs: params.max_accepted_htlcs,
+            funding_pubkey: keyset.funding_pubkey,
+            revocation_basepoint: keyset.revocation_basepoint,
+            payment_point: keyset.payment_basepoint,
+            delayed_payment_basepoint: keyset.delayed_payment_basepoint,
+            htlc_basepoint: keyset.htlc_basepoint,
+            first_per_commitment_point: keyset.first_per_commitment_point,
+            channel_flags: params.channel_flags,
+            shutdown_scriptpubkey: keyset.shutdown_scriptpubkey,
+            unknown_tlvs: none!(),
+        }))
+    }
+
+    /// Accepts channel proposed by the remote peer. Only the parameters
+    /// present in `accept_channel` message are taken from `params`.
+    pub fn accept_channel(
+        &mut self,
+        params: Params,
+        shutdown_scriptpubkey: Option<Script>,
+    ) -> Result<Messages, Error> {
+        let keyset = self.secrets.keyset(shutdown_scriptpubkey);
+        self.send(Messages::AcceptChannel(AcceptChannel {
+            temporary_channel_id: self.temporary_channel_id,
+            dust_limit_satoshis: params.dust_limit_satoshis,
+            max_htlc_value_in_flight_msat: params.max_htlc_value_in_flight_msat,
+            channel_reserve_satoshis: params.channel_reserve_satoshis,
+            htlc_minimum_msat: params.htlc_minimum_msat,
+            minimum_depth: params.minimum_depth,
+            to_self_delay: params.to_self_delay,
+            max_accepted_htlcs: params.max_accepted_htlcs,
+            funding_pubkey: keyset.funding_pubkey,
+            revocation_basepoint: keyset.revocation_basepoint,
+            payment_point: keyset.payment_basepoint,
+            delayed_payment_basepoint: keyset.delayed_payment_basepoint,
+            htlc_basepoint: keyset.htlc_basepoint,
+            first_per_commitment_point: keyset.first_per_commitment_point,
+            shutdown_scriptpubkey: keyset.shutdown_scriptpubkey,
+            unknown_tlvs: none!(),
+        }))
+    }
+
+    /// Signs the first commitment transaction of the remote peer spending
+    /// funding output at `funding_outpoint`. Fails if the funding output
+    /// index can't be represented in `funding_created` message.
+    pub fn funding_created(
+        &mut self,
+        funding_outpoint: OutPoint,
+    ) -> Result<Messages, Error> {
+        let funding_output_index = u16::try_from(funding_outpoint.vout)
+            .map_err(|_| Error::FundingOutputIndex(funding_outpoint.vout))?;
+        self.send(Messages::FundingCreated(FundingCreated {
+            temporary_channel_id: self.temporary_channel_id,
+            funding_txid: funding_outpoint.txid,
+            funding_output_index,
+            signature: blank_signature(),
+        }))
+    }
+
+    /// Signs the first commitment transaction of the channel funder
+    pub fn funding_signed(&mut self) -> Result<Messages, Error> {
+        self.send(Messages::FundingSigned(FundingSigned {
+            channel_id: self.channel_id,
+            signature: blank_signature(),
+        }))
+    }
+
+    /// Confirms that the funding transaction has reached the minimum depth
+    pub fn funding_locked(&mut self) -> Result<Messages, Error> {
+        self.send(Messages::FundingLocked(FundingLocked {
+            channel_id: self.channel_id,
+            next_per_commitment_point: self
+                .secrets
+                .per_commitment_point(self.commitment_number + 1),
+        }))
+    }
+
+    /// Signs the next commitment transaction of the remote peer, including
+    /// all updates made to the channel so far
+    pub fn commitment_signed(&mut self) -> Result<Messages, Error> {
+        self.send(Messages::CommitmentSigned(CommitmentSigned {
+            channel_id: self.channel_id,
+            signature: blank_signature(),
+            htlc_signatures: vec![],
+        }))
+    }
+
+    /// Revokes the previous local commitment transaction once the remote
+    /// peer has signed the next one
+    pub fn revoke_and_ack(&mut self) -> Result<Messages, Error> {
+        let mut per_commitment_secret = [0u8; 32];
+        per_commitment_secret.copy_from_slice(
+            &self.secrets.per_commitment_secret(
+                self.commitment_number.saturating_sub(1),
+            )[..],
+        );
+        self.send(Messages::RevokeAndAck(RevokeAndAck {
+            channel_id: self.channel_id,
+            per_commitment_secret,
+            next_per_commitment_point: self
+                .secrets
+                .per_commitment_point(self.commitment_number + 1),
+        }))
+    }
+
+    /// Processes message which is going to be sent to the remote peer.
+    /// Signatures of `funding_created`, `funding_signed` and
+    /// `commitment_signed` messages are replaced with the signatures of the
+    /// remote commitment transaction made by the local node, and signature
+    /// of `closing_signed` - with the signature of the closing transaction;
+    /// the resulting message is returned.
+    pub fn send(&mut self, mut message: Messages) -> Result<Messages, Error> {
+        self.validate(&message, true)?;
+        self.remote_channel.update_from_peer(&message)?;
+        self.local_channel.update_from_local(&message)?;
+        self.transition(&message, true)?;
+        self.sign(&mut message)?;
+        Ok(message)
+    }
+
+    /// Processes message received from the remote peer. Errors happening
+    /// after the message was applied to the channel (i.e. invalid signatures
+    /// of the local commitment transaction) require the channel to be failed
+    /// according to BOLT-2.
+    pub fn receive(&mut self, message: &Messages) -> Result<(), Error> {
+        self.validate(message, false)?;
+        self.local_channel.update_from_peer(message)?;
+        self.remote_channel.update_from_local(message)?;
+        self.transition(message, false)?;
+        self.verify(message)
+    }
+
+    /// Checks whether the `message` sent by the local node (if `outgoing`)
+    /// or by the remote peer is allowed in the current channel state
+    fn is_expected(&self, message: &Messages, outgoing: bool) -> bool {
+        // Message is sent by the channel funder
+        let by_funder = outgoing == self.is_originator;
+        match (self.lifecycle, message) {
+            (Lifecycle::Initial, Messages::Init(_))
+            | (Lifecycle::Initial, Messages::OpenChannel(_)) => true,
+            (Lifecycle::Proposed, Messages::AcceptChannel(_)) => !by_funder,
+            (Lifecycle::Accepted, Messages::FundingCreated(_)) => by_funder,
+            (Lifecycle::Funding, Messages::FundingSigned(_)) => !by_funder,
+            (Lifecycle::Signed, Messages::FundingLocked(_))
+            | (Lifecycle::Locked, Messages::FundingLocked(_)) => {
+                if outgoing {
+                    !self.local_locked
+                } else {
+                    !self.remote_locked
+                }
+            }
+            (Lifecycle::Active, Messages::UpdateAddHtlc(_)) => true,
+            (Lifecycle::Active, Messages::UpdateFee(_)) => by_funder,
+            (Lifecycle::Active, Messages::Shutdown(_)) => true,
+            (Lifecycle::Shutdown, Messages::Shutdown(_)) => {
+                if outgoing {
+                    !self.local_shutdown
+                } else {
+                    !self.remote_shutdown
+                }
+            }
+            (Lifecycle::Closing { .. }, Messages::ClosingSigned(_)) => true,
+            (Lifecycle::Active, message)
+            | (Lifecycle::Shutdown, message)
+            | (Lifecycle::Closing { round: 0 }, message) => match message {
+                Messages::UpdateFulfillHtlc(_)
+                | Messages::UpdateFailHtlc(_)
+                | Messages::UpdateFailMalformedHtlc(_) => true,
+                // Next commitment can't be signed before the remote peer
+                // has revoked the previous one
+                Messages::CommitmentSigned(_) if outgoing => {
+                    self.revoked_point.is_none()
+                        && self.remote_next_point.is_some()
+                }
+                Messages::CommitmentSigned(_) => !self.revocation_owed,
+                Messages::RevokeAndAck(_) if outgoing => self.revocation_owed,
+                Messages::RevokeAndAck(_) => self.revoked_point.is_some(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Validates the `message` before it is applied to the channel
+    fn validate(
+        &self,
+        message: &Messages,
+        outgoing: bool,
+    ) -> Result<(), Error> {
+        if !self.is_expected(message, outgoing) {
+            Err(Error::UnexpectedMessage(message.get_type(), self.lifecycle))?
+        }
+        if let Some(channel_id) = channel_id(message) {
+            if channel_id != self.channel_id {
+                Err(Error::ChannelMismatch(channel_id))?
+            }
+        }
+        match message {
+            Messages::OpenChannel(open_channel) => {
+                Params::with(open_channel)?;
+                shutdown_script::check_upfront_script(
+                    &open_channel.shutdown_scriptpubkey,
+                )?;
+            }
+            Messages::AcceptChannel(accept_channel) => {
+                self.check_temp_id(accept_channel.temporary_channel_id)?;
+                self.params.updated(accept_channel, None)?;
+                shutdown_script::check_upfront_script(
+                    &accept_channel.shutdown_scriptpubkey,
+                )?;
+            }
+            Messages::FundingCreated(funding_created) => {
+                self.check_temp_id(funding_created.temporary_channel_id)?;
+            }
+            Messages::Shutdown(shutdown) => {
+                shutdown_script::check_script(&shutdown.scriptpubkey)?;
+            }
+            _ => {}
+        }
+        match message {
+            Messages::RevokeAndAck(revoke_and_ack) if !outgoing => {
+                self.check_revocation(revoke_and_ack)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the revealed per-commitment secret matches the remote
+    /// commitment transaction which is being revoked and allows to derive
+    /// the previously revealed secrets
+    fn check_revocation(
+        &self,
+        revoke_and_ack: &RevokeAndAck,
+    ) -> Result<(), Error> {
+        let secret =
+            SecretKey::from_slice(&revoke_and_ack.per_commitment_secret)
+                .map_err(|_| Error::InvalidRevocation)?;
+        let point = PublicKey::from_secret_key(&SECP256K1, &secret);
+        if Some(point) != self.revoked_point {
+            Err(Error::InvalidRevocation)?
+        }
+        self.remote_secrets.check(
+            self.revocation_index()?,
+            revoke_and_ack.per_commitment_secret,
+        )?;
+        Ok(())
+    }
+
+    /// Returns per-commitment secret index of the next remote commitment
+    /// transaction to be revoked
+    fn revocation_index(&self) -> Result<u64, Error> {
+        match self.remote_secrets.last_index() {
+            None => Ok(MAX_COMMITMENT_INDEX),
+            Some(index) => index.checked_sub(1).ok_or(Error::InvalidRevocation),
+        }
+    }
+
+    fn check_temp_id(&self, temp_id: TempChannelId) -> Result<(), Error> {
+        if temp_id != self.temporary_channel_id {
+            Err(Error::ChannelMismatch(ChannelId::from(temp_id)))?
+        }
+        Ok(())
+    }
+
+    /// Updates channel state with the `message` which was already applied
+    /// to the channel extensions
+    fn transition(
+        &mut self,
+        message: &Messages,
+        outgoing: bool,
+    ) -> Result<(), Error> {
+        match message {
+            Messages::OpenChannel(open_channel) => {
+                self.is_originator = outgoing;
+                self.temporary_channel_id = open_channel.temporary_channel_id;
+                self.params = Params::with(open_channel)?;
+                if !outgoing {
+                    self.remote_keys = Keyset::from(open_channel);
+                    self.remote_point = open_channel.first_per_commitment_point;
+                }
+                self.lifecycle = Lifecycle::Proposed;
+            }
+            Messages::AcceptChannel(accept_channel) => {
+                self.params = self.params.updated(accept_channel, None)?;
+                if !outgoing {
+                    self.remote_keys = Keyset::from(accept_channel);
+                    self.remote_point =
+                        accept_channel.first_per_commitment_point;
+                }
+                self.lifecycle = Lifecycle::Accepted;
+            }
+            Messages::FundingCreated(funding_created) => {
+                self.funding_outpoint = OutPoint::new(
+                    funding_created.funding_txid,
+                    funding_created.funding_output_index as u32,
+                );
+                self.channel_id = ChannelId::with(self.funding_outpoint);
+                self.lifecycle = Lifecycle::Funding;
+            }
+            Messages::FundingSigned(_) => {
+                self.lifecycle = Lifecycle::Signed;
+            }
+            Messages::FundingLocked(funding_locked) => {
+                if outgoing {
+                    self.local_locked = true;
+                } else {
+                    self.remote_locked = true;
+                    self.remote_next_point =
+                        Some(funding_locked.next_per_commitment_point);
+                }
+                self.lifecycle = if self.local_locked && self.remote_locked {
+                    Lifecycle::Active
+                } else {
+                    Lifecycle::Locked
+                };
+            }
+            Messages::CommitmentSigned(_) if outgoing => {
+                if let Some(next_point) = self.remote_next_point.take() {
+                    self.revoked_point = Some(self.remote_point);
+                    self.remote_point = next_point;
+                }
+            }
+            Messages::CommitmentSigned(_) => {
+                self.commitment_number += 1;
+                self.revocation_owed = true;
+            }
+            Messages::RevokeAndAck(_) if outgoing => {
+                self.revocation_owed = false;
+            }
+            Messages::RevokeAndAck(revoke_and_ack) => {
+                self.remote_secrets.insert(
+                    self.revocation_index()?,
+                    revoke_and_ack.per_commitment_secret,
+                )?;
+                self.revoked_point = None;
+                self.remote_next_point =
+                    Some(revoke_and_ack.next_per_commitment_point);
+            }
+            Messages::Shutdown(_) => {
+                if outgoing {
+                    self.local_shutdown = true;
+                } else {
+                    self.remote_shutdown = true;
+                }
+                self.lifecycle = if self.local_shutdown && self.remote_shutdown
+                {
+                    Lifecycle::Closing { round: 0 }
+                } else {
+                    Lifecycle::Shutdown
+                };
+            }
+            Messages::ClosingSigned(closing_signed) => {
+                if outgoing {
+                    self.local_closing_fee = Some(closing_signed.fee_satoshis);
+                } else {
+                    self.remote_closing_fee = Some(closing_signed.fee_satoshis);
+                }
+                self.lifecycle = match self.lifecycle {
+                    _ if self.local_closing_fee.is_some()
+                        && self.local_closing_fee
+                            == self.remote_closing_fee =>
+                    {
+                        Lifecycle::Closed
+                    }
+                    Lifecycle::Closing { round } => {
+                        Lifecycle::Closing { round: round + 1 }
+                    }
+                    lifecycle => lifecycle,
+                };
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Fills in signatures of the remote commitment transaction and the
+    /// HTLC transactions spending its outputs, or of the closing transaction
+    fn sign(&mut self, message: &mut Messages) -> Result<(), Error> {
+        if let Messages::ClosingSigned(ClosingSigned { signature, .. }) =
+            message
+        {
+            let tx_graph = self.remote_tx_graph()?;
+            let sighash = self.closing_sighash(&tx_graph)?;
+            *signature = sign(sighash, &self.secrets.funding_key);
+            return Ok(());
+        }
+
+        let signature = match message {
+            Messages::FundingCreated(FundingCreated { signature, .. })
+            | Messages::FundingSigned(FundingSigned { signature, .. })
+            | Messages::CommitmentSigned(CommitmentSigned {
+                signature, ..
+            }) => signature,
+            _ => return Ok(()),
+        };
+
+        let mut tx_graph = self.remote_tx_graph()?;
+        let sighash = self.commitment_sighash(&tx_graph);
+        *signature = sign(sighash, &self.secrets.funding_key);
+
+        if let Messages::CommitmentSigned(commitment_signed) = message {
+            let htlc_key = bolt3::derive_privkey(
+                self.secrets.htlc_basepoint_secret,
+                self.remote_point,
+            );
+            commitment_signed.htlc_signatures = htlc_sighashes(&mut tx_graph)
+                .into_iter()
+                .map(|(_, sighash)| sign(sighash, &htlc_key))
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Verifies signatures of the local commitment transaction and the HTLC
+    /// transactions spending its outputs, or of the closing transaction,
+    /// made by the remote peer
+    fn verify(&mut self, message: &Messages) -> Result<(), Error> {
+        if let Messages::ClosingSigned(ClosingSigned { signature, .. }) =
+            message
+        {
+            let tx_graph = self.local_tx_graph()?;
+            let sighash = self.closing_sighash(&tx_graph)?;
+            if !verify(sighash, signature, &self.remote_keys.funding_pubkey) {
+                Err(Error::InvalidSignature)?
+            }
+            return Ok(());
+        }
+
+        let (signature, htlc_signatures) = match message {
+            Messages::FundingCreated(FundingCreated { signature, .. })
+            | Messages::FundingSigned(FundingSigned { signature, .. }) => {
+                (signature, &[][..])
+            }
+            Messages::CommitmentSigned(commitment_signed) => (
+                &commitment_signed.signature,
+                &commitment_signed.htlc_signatures[..],
+            ),
+            _ => return Ok(()),
+        };
+
+        let mut tx_graph = self.local_tx_graph()?;
+        let sighash = self.commitment_sighash(&tx_graph);
+        if !verify(sighash, signature, &self.remote_keys.funding_pubkey) {
+            Err(Error::InvalidSignature)?
+        }
+
+        let htlc_pubkey = bolt3::derive_pubkey(
+            self.remote_keys.htlc_basepoint,
+            self.secrets.per_commitment_point(self.commitment_number),
+        );
+        let sighashes = htlc_sighashes(&mut tx_graph);
+        if sighashes.len() != htlc_signatures.len() {
+            Err(Error::HtlcSignatureCount(
+                htlc_signatures.len(),
+                sighashes.len(),
+            ))?
+        }
+        for ((vout, sighash), signature) in
+            sighashes.into_iter().zip(htlc_signatures)
+        {
+            if !verify(sighash, signature, &htlc_pubkey) {
+                Err(Error::InvalidHtlcSignature(vout))?
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns signature hash of the commitment transaction from `tx_graph`
+    /// spending the channel funding output
+    fn commitment_sighash(&self, tx_graph: &TxGraph) -> SigHash {
+        self.funding_sighash(&tx_graph.render_cmt().global.unsigned_tx)
+    }
+
+    /// Returns signature hash of the closing transaction from `tx_graph`
+    /// paying the last proposed fee
+    fn closing_sighash(&self, tx_graph: &TxGraph) -> Result<SigHash, Error> {
+        let psbt = tx_graph
+            .tx(TxType::Closing, 0u64)
+            .ok_or(Error::NoClosingTx)?;
+        Ok(self.funding_sighash(&psbt.global.unsigned_tx))
+    }
+
+    /// Returns signature hash of the transaction `tx` spending the channel
+    /// funding output with its first input
+    fn funding_sighash(&self, tx: &Transaction) -> SigHash {
+        let funding_script = LockScript::ln_funding(
+            self.params.funding_satoshis,
+            PublicKey::from_secret_key(&SECP256K1, &self.secrets.funding_key),
+            self.remote_keys.funding_pubkey,
+        )
+        .into_inner();
+        SigHashCache::new(tx).signature_hash(
+            0,
+            &funding_script,
+            self.params.funding_satoshis,
+            SigHashType::All,
+        )
+    }
+}
+
+/// Returns id of the channel to which the channel-scoped `message` refers
+fn channel_id(message: &Messages) -> Option<ChannelId> {
+    match message {
+        Messages::FundingSigned(FundingSigned { channel_id, .. })
+        | Messages::FundingLocked(FundingLocked { channel_id, .. })
+        | Messages::UpdateAddHtlc(UpdateAddHtlc { channel_id, .. })
+        | Messages::UpdateFulfillHtlc(UpdateFulfillHtlc {
+            channel_id, ..
+        })
+        | Messages::UpdateFailHtlc(UpdateFailHtlc { channel_id, .. })
+        | Messages::UpdateFailMalformedHtlc(UpdateFailMalformedHtlc {
+            channel_id,
+            ..
+        })
+        | Messages::CommitmentSigned(CommitmentSigned { channel_id, .. })
+        | Messages::RevokeAndAck(RevokeAndAck { channel_id, .. })
+        | Messages::UpdateFee(UpdateFee { channel_id, .. })
+        | Messages::Shutdown(Shutdown { channel_id, .. })
+        | Messages::ClosingSigned(ClosingSigned { channel_id, .. }) => {
+            Some(*channel_id)
+        }
+        _ => None,
+    }
+}
+
+/// Placeholder for the signatures of the generated messages, which are
+/// replaced with the actual signatures by [`StateMachine::send`]
+fn blank_signature() -> Signature {
+    Signature::from_compact(&[1u8; 64])
+        .expect("compact signature with r = s = 1 is always valid")
+}
+
+/// Returns signature hashes of the HTLC transactions from the `tx_graph`
+/// ordered by the commitment transaction outputs they spend, together with
+/// the numbers of these outputs. The hashes are computed with the signature
+/// hash type of the party not holding the commitment transaction, since its
+/// signatures are the ones exchanged in `commitment_signed`.
+fn htlc_sighashes(tx_graph: &mut TxGraph) -> Vec<(u32, SigHash)> {
+    let sighash_type = tx_graph.remote_sighash_type();
+    let mut sighashes = tx_graph
+        .vec_mut()
+        .into_iter()
+        .filter(|(role, _, _)| {
+            matches!(
+                TxType::from(*role),
+                TxType::HtlcSuccess | TxType::HtlcTimeout
+            )
+        })
+        .filter_map(|(_, _, psbt)| {
+            let input = psbt.inputs.first()?;
+            let witness_script = input.witness_script.as_ref()?;
+            let value = input.witness_utxo.as_ref()?.value;
+            let tx = &psbt.global.unsigned_tx;
+            let sighash = SigHashCache::new(tx).signature_hash(
+                0,
+                witness_script,
+                value,
+                sighash_type,
+            );
+            Some((tx.input.first()?.previous_output.vout, sighash))
+        })
+        .collect::<Vec<_>>();
+    sighashes.sort_by_key(|(vout, _)| *vout);
+    sighashes
+}
+
+fn sign(sighash: SigHash, secret_key: &SecretKey) -> Signature {
+    let msg = secp256k1::Message::from_slice(&sighash[..])
+        .expect("signature hash is always a valid secp256k1 message");
+    SECP256K1.sign(&msg, secret_key)
+}
+
+fn verify(sighash: SigHash, signature: &Signature, pubkey: &PublicKey) -> bool {
+    let msg = secp256k1::Message::from_slice(&sighash[..])
+        .expect("signature hash is always a valid secp256k1 message");
+    SECP256K1.verify(&msg, signature, pubkey).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+
+    use crate::bp::{HashPreimage, PubkeyScript};
+    use crate::lnp::application::payment::bip96::Bip96;
+    use crate::lnp::application::payment::{
+        Bolt3, ExtensionId, Htlc, ShutdownScript,
+    };
+    use crate::lnp::application::OnionPacket;
+    use crate::strict_encoding::{strict_decode, strict_encode};
+
+    fn channel() -> Channel<ExtensionId> {
+        let bolt3 = channel::Shared::new(Bolt3::dumb_default());
+        let htlc = channel::Shared::new(Htlc::with(bolt3.clone()));
+        let mut channel = Channel::with(bolt3, vec![htlc.clone()], vec![Bip96]);
+        channel.add_extender(ShutdownScript::with(htlc));
+        channel
+    }
+
+    fn machine(byte: u8) -> StateMachine<ExtensionId> {
+        let secret = |i: u8| SecretKey::from_slice(&[byte + i; 32]).unwrap();
+        StateMachine::with(
+            channel(),
+            channel(),
+            ChannelSecrets {
+                funding_key: secret(0),
+                revocation_basepoint_secret: secret(1),
+                payment_basepoint_secret: secret(2),
+                delayed_payment_basepoint_secret: secret(3),
+                htlc_basepoint_secret: secret(4),
+                per_commitment_seed: [byte + 5; 32],
+            },
+        )
+    }
+
+    fn params() -> Params {
+        Params {
+            funding_satoshis: 10_000_000,
+            push_msat: 3_000_000_000,
+            dust_limit_satoshis: 546,
+            max_htlc_value_in_flight_msat: 5_000_000_000,
+            channel_reserve_satoshis: 100_000,
+            htlc_minimum_msat: 1000,
+            feerate_per_kw: 253,
+            minimum_depth: 3,
+            to_self_delay: 144,
+            max_accepted_htlcs: 483,
+            channel_flags: 0,
+        }
+    }
+
+    fn funding_outpoint() -> OutPoint {
+        OutPoint::new(Txid::from_inner([0x24; 32]), 1)
+    }
+
+    fn deliver(
+        message: Result<Messages, Error>,
+        receiver: &mut StateMachine<ExtensionId>,
+    ) -> Messages {
+        let message = message.unwrap();
+        receiver.receive(&message).unwrap();
+        message
+    }
+
+    fn accepted() -> (StateMachine<ExtensionId>, StateMachine<ExtensionId>) {
+        let mut alice = machine(0x10);
+        let mut bob = machine(0x30);
+        let temp_id = TempChannelId::dumb_default();
+        deliver(
+            alice.open_channel(none!(), temp_id, params(), None),
+            &mut bob,
+        );
+        deliver(bob.accept_channel(params(), None), &mut alice);
+        (alice, bob)
+    }
+
+    fn active() -> (StateMachine<ExtensionId>, StateMachine<ExtensionId>) {
+        let (mut alice, mut bob) = accepted();
+        deliver(alice.funding_created(funding_outpoint()), &mut bob);
+        deliver(bob.funding_signed(), &mut alice);
+        deliver(alice.funding_locked(), &mut bob);
+        deliver(bob.funding_locked(), &mut alice);
+        (alice, bob)
+    }
+
+    // P2WPKH script paying to the payment basepoint of the party
+    fn script(machine: &StateMachine<ExtensionId>) -> Script {
+        let pubkey = machine.secrets.keyset(None).payment_basepoint;
+        PubkeyScript::ln_to_remote_v1(0, pubkey).into_inner()
+    }
+
+    fn shutdown(channel_id: ChannelId, scriptpubkey: Script) -> Messages {
+        Messages::Shutdown(Shutdown {
+            channel_id,
+            scriptpubkey,
+        })
+    }
+
+    fn closing_signed(channel_id: ChannelId, fee_satoshis: u64) -> Messages {
+        Messages::ClosingSigned(ClosingSigned {
+            channel_id,
+            fee_satoshis,
+            signature: blank_signature(),
+        })
+    }
+
+    #[test]
+    fn test_channel_lifecycle() {
+        let mut alice = machine(0x10);
+        let mut bob = machine(0x30);
+        let temp_id = TempChannelId::dumb_default();
+
+        let open_channel = deliver(
+            alice.open_channel(none!(), temp_id, params(), None),
+            &mut bob,
+        );
+        assert_eq!(alice.lifecycle(), Lifecycle::Proposed);
+        assert_eq!(bob.lifecycle(), Lifecycle::Proposed);
+        assert!(alice.is_originator());
+        assert!(!bob.is_originator());
+        assert_eq!(
+            bob.receive(&open_channel),
+            Err(Error::UnexpectedMessage(
+                TypeId::from(32),
+                Lifecycle::Proposed
+            ))
+        );
+        assert_eq!(
+            alice.funding_created(funding_outpoint()).unwrap_err(),
+            Error::UnexpectedMessage(TypeId::from(34), Lifecycle::Proposed)
+        );
+
+        deliver(bob.accept_channel(params(), None), &mut alice);
+        assert_eq!(alice.lifecycle(), Lifecycle::Accepted);
+        assert_eq!(alice.params(), bob.params());
+        assert_eq!(alice.params().minimum_depth, 3);
+
+        // Each party verifies signature of its own first commitment
+        deliver(alice.funding_created(funding_outpoint()), &mut bob);
+        assert_eq!(bob.lifecycle(), Lifecycle::Funding);
+        deliver(bob.funding_signed(), &mut alice);
+        assert_eq!(alice.lifecycle(), Lifecycle::Signed);
+        let channel_id = ChannelId::with(funding_outpoint());
+        assert_eq!(alice.channel_id(), channel_id);
+        assert_eq!(bob.channel_id(), channel_id);
+
+        deliver(alice.funding_locked(), &mut bob);
+        assert_eq!(alice.lifecycle(), Lifecycle::Locked);
+        assert_eq!(bob.lifecycle(), Lifecycle::Locked);
+        assert_eq!(
+            alice.funding_locked().unwrap_err(),
+            Error::UnexpectedMessage(TypeId::from(36), Lifecycle::Locked)
+        );
+        deliver(bob.funding_locked(), &mut alice);
+        assert_eq!(alice.lifecycle(), Lifecycle::Active);
+        assert_eq!(bob.lifecycle(), Lifecycle::Active);
+
+        let update_add_htlc = Messages::UpdateAddHtlc(UpdateAddHtlc {
+            channel_id,
+            htlc_id: 0,
+            amount_msat: 5_000_000,
+            payment_hash: HashPreimage::from_inner([0x01; 32].into()).into(),
+            cltv_expiry: 500,
+            onion_routing_packet: OnionPacket::dumb_default(),
+            #[cfg(feature = "rgb")]
+            asset_id: None,
+        });
+        deliver(alice.send(update_add_htlc), &mut bob);
+        let htlc_signatures = |message: Messages| match message {
+            Messages::CommitmentSigned(commitment_signed) => {
+                commitment_signed.htlc_signatures.len()
+            }
+            _ => unreachable!(),
+        };
+        let commitment_signed = deliver(alice.commitment_signed(), &mut bob);
+        assert_eq!(htlc_signatures(commitment_signed), 1);
+        assert_eq!(
+            alice.commitment_signed().unwrap_err(),
+            Error::UnexpectedMessage(TypeId::from(132), Lifecycle::Active)
+        );
+        deliver(bob.revoke_and_ack(), &mut alice);
+        let commitment_signed = deliver(bob.commitment_signed(), &mut alice);
+        assert_eq!(htlc_signatures(commitment_signed), 1);
+        deliver(alice.revoke_and_ack(), &mut bob);
+        assert_eq!(alice.commitment_number(), 1);
+        assert_eq!(bob.commitment_number(), 1);
+
+        // Remote channel mirrors commitment transaction of the remote peer
+        let alice_graph = alice.local_tx_graph().unwrap();
+        let bob_graph = bob.local_tx_graph().unwrap();
+        assert_eq!(alice_graph.role_count(TxType::HtlcTimeout), 1);
+        assert_eq!(bob_graph.role_count(TxType::HtlcSuccess), 1);
+        assert!(alice.remote_tx_graph().unwrap() == bob_graph);
+        assert!(bob.remote_tx_graph().unwrap() == alice_graph);
+
+        // HTLC must be removed from the channel before it can be closed
+        let update_fulfill_htlc =
+            Messages::UpdateFulfillHtlc(UpdateFulfillHtlc {
+                channel_id,
+                htlc_id: 0,
+                payment_preimage: HashPreimage::from_inner([0x01; 32].into()),
+            });
+        deliver(bob.send(update_fulfill_htlc), &mut alice);
+        deliver(bob.commitment_signed(), &mut alice);
+        deliver(alice.revoke_and_ack(), &mut bob);
+        deliver(alice.commitment_signed(), &mut bob);
+        deliver(bob.revoke_and_ack(), &mut alice);
+        assert_eq!(alice.commitment_number(), 2);
+        assert_eq!(bob.commitment_number(), 2);
+
+        // Secrets of both revoked commitments are stored
+        assert_eq!(
+            alice.remote_secrets().last_index(),
+            Some(MAX_COMMITMENT_INDEX - 1)
+        );
+        for commitment_number in 0..2 {
+            let index = MAX_COMMITMENT_INDEX - commitment_number;
+            assert_eq!(
+                alice.remote_secrets().secret(index),
+                Some(bolt3::per_commitment_secret(&[0x35; 32], index))
+            );
+            assert_eq!(
+                bob.remote_secrets().secret(index),
+                Some(bolt3::per_commitment_secret(&[0x15; 32], index))
+            );
+        }
+
+        // Zero-length shutdown script is not allowed by BOLT-2
+        assert!(matches!(
+            alice.send(shutdown(channel_id, Script::new())),
+            Err(Error::Channel(_))
+        ));
+        deliver(alice.send(shutdown(channel_id, script(&alice))), &mut bob);
+        assert_eq!(bob.lifecycle(), Lifecycle::Shutdown);
+        deliver(bob.send(shutdown(channel_id, script(&bob))), &mut alice);
+        assert_eq!(alice.lifecycle(), Lifecycle::Closing { round: 0 });
+
+        // Each party verifies signature of the closing transaction paying
+        // the proposed fee
+        deliver(alice.send(closing_signed(channel_id, 1000)), &mut bob);
+        assert_eq!(bob.lifecycle(), Lifecycle::Closing { round: 1 });
+        deliver(bob.send(closing_signed(channel_id, 1000)), &mut alice);
+        assert_eq!(alice.lifecycle(), Lifecycle::Closed);
+        assert_eq!(bob.lifecycle(), Lifecycle::Closed);
+
+        let closing_tx = |machine: &mut StateMachine<ExtensionId>| {
+            machine
+                .local_tx_graph()
+                .unwrap()
+                .tx(TxType::Closing, 0u64)
+                .unwrap()
+                .global
+                .unsigned_tx
+                .clone()
+        };
+        let tx = closing_tx(&mut alice);
+        assert_eq!(tx, closing_tx(&mut bob));
+        assert_eq!(tx.output.len(), 2);
+        assert_eq!(
+            tx.output.iter().map(|txout| txout.value).sum::<u64>(),
+            params().funding_satoshis - 1000
+        );
+    }
+
+    #[test]
+    fn test_invalid_closing_signed() {
+        let (mut alice, mut bob) = active();
+        let channel_id = alice.channel_id();
+        deliver(alice.send(shutdown(channel_id, script(&alice))), &mut bob);
+        deliver(bob.send(shutdown(channel_id, script(&bob))), &mut alice);
+
+        // Signature made for one fee does not match closing transaction
+        // paying another fee
+        let mut closing_signed =
+            alice.send(closing_signed(channel_id, 1000)).unwrap();
+        if let Messages::ClosingSigned(closing_signed) = &mut closing_signed {
+            closing_signed.fee_satoshis = 1100;
+        }
+        assert_eq!(bob.receive(&closing_signed), Err(Error::InvalidSignature));
+    }
+
+    #[test]
+    fn test_channel_secrets_debug() {
+        let alice = machine(0x10);
+        assert_eq!(format!("{:?}", alice.secrets), "ChannelSecrets(..)");
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let (mut alice, mut bob) = accepted();
+        assert_eq!(
+            alice
+                .funding_created(OutPoint::new(
+                    Txid::from_inner([0x24; 32]),
+                    0x1_0000
+                ))
+                .unwrap_err(),
+            Error::FundingOutputIndex(0x1_0000)
+        );
+        let mut funding_created =
+            alice.funding_created(funding_outpoint()).unwrap();
+        if let Messages::FundingCreated(funding_created) = &mut funding_created
+        {
+            funding_created.signature = blank_signature();
+        }
+        assert_eq!(bob.receive(&funding_created), Err(Error::InvalidSignature));
+
+        let (mut alice, mut bob) = accepted();
+        deliver(alice.funding_created(funding_outpoint()), &mut bob);
+        deliver(bob.funding_signed(), &mut alice);
+        deliver(alice.funding_locked(), &mut bob);
+        let mut funding_locked = bob.funding_locked().unwrap();
+        if let Messages::FundingLocked(funding_locked) = &mut funding_locked {
+            funding_locked.channel_id = ChannelId::default();
+        }
+        assert_eq!(
+            alice.receive(&funding_locked),
+            Err(Error::ChannelMismatch(ChannelId::default()))
+        );
+
+        let (mut alice, mut bob) = active();
+        let update_add_htlc = |channel_id| {
+            Messages::UpdateAddHtlc(UpdateAddHtlc {
+                channel_id,
+                htlc_id: 0,
+                amount_msat: 5_000_000,
+                payment_hash: HashPreimage::from_inner([0x01; 32].into())
+                    .into(),
+                cltv_expiry: 500,
+                onion_routing_packet: OnionPacket::dumb_default(),
+                #[cfg(feature = "rgb")]
+                asset_id: None,
+            })
+        };
+        assert_eq!(
+            alice.send(update_add_htlc(ChannelId::default())),
+            Err(Error::ChannelMismatch(ChannelId::default()))
+        );
+        let channel_id = alice.channel_id();
+        assert_eq!(
+            bob.receive(&shutdown(ChannelId::default(), script(&alice))),
+            Err(Error::ChannelMismatch(ChannelId::default()))
+        );
+        deliver(alice.send(update_add_htlc(channel_id)), &mut bob);
+    }
+
+    #[test]
+    fn test_invalid_revocation() {
+        let (mut alice, mut bob) = active();
+
+        deliver(alice.commitment_signed(), &mut bob);
+        let mut revoke_and_ack = bob.revoke_and_ack().unwrap();
+        if let Messages::RevokeAndAck(revoke_and_ack) = &mut revoke_and_ack {
+            revoke_and_ack.per_commitment_secret = [0x01; 32];
+        }
+        assert_eq!(
+            alice.receive(&revoke_and_ack),
+            Err(Error::InvalidRevocation)
+        );
+        assert_eq!(alice.remote_secrets().last_index(), None);
+    }
+
+    #[test]
+    fn test_channel_strict_encoding() {
+        let bolt3 =
+            channel::Shared::new(Bolt3::new(true, 7_000_000, 3_000_000, 144));
+        let htlc = channel::Shared::new(Htlc::with(bolt3.clone()));
+        let mut original =
+            Channel::with(bolt3, vec![htlc.clone()], vec![Bip96]);
+        original.add_extender(ShutdownScript::with(htlc.clone()));
+
+        // Shared channel constructor is encoded only once, by the channel
+        assert_eq!(
+            strict_encode(&*htlc.borrow()).unwrap(),
+            strict_encode(&Htlc::dumb_default()).unwrap()
+        );
+
+        let mut restored: Channel<ExtensionId> =
+            strict_decode(&strict_encode(&original).unwrap()).unwrap();
+        let mut stale = TxGraph::default();
+        restored.apply(&mut stale).unwrap();
+
+        // Extenders must read the constructor state updated after decoding
+        let update_fee = Messages::UpdateFee(UpdateFee {
+            channel_id: ChannelId::default(),
+            feerate_per_kw: 2500,
+        });
+        original.update_from_peer(&update_fee).unwrap();
+        restored.update_from_peer(&update_fee).unwrap();
+        assert_eq!(*htlc.borrow().bolt3().feerate_per_kw(), 2500);
+
+        let mut expected = TxGraph::default();
+        original.apply(&mut expected).unwrap();
+        let mut tx_graph = TxGraph::default();
+        restored.apply(&mut tx_graph).unwrap();
+        assert_ne!(tx_graph.cmt_outs, stale.cmt_outs);
+        assert!(tx_graph == expected);
+        assert_eq!(
+            strict_encode(&restored).unwrap(),
+            strict_encode(&original).unwrap()
+        );
+    }
+}
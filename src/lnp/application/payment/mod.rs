@@ -13,6 +13,7 @@
 
 pub mod channel;
 pub mod invoice;
+pub mod machine;
 mod types;
 
 mod constructors;
@@ -20,12 +21,15 @@ mod extenders;
 mod modifiers;
 
 pub use invoice::Invoice;
+pub use machine::{ChannelSecrets, StateMachine};
 pub use types::{
-    AssetsBalance, ChannelId, ExtensionId, Lifecycle, TempChannelId,
+    AssetsBalance, ChannelId, ExtensionId, Lifecycle, SharedExtensions,
+    TempChannelId, TxType,
 };
 
 pub use constructors::{bolt3, eltoo, taproot, Bolt3};
 pub use extenders::{
-    anchor_out, dlc, htlc, lightspeed, ptlc, shutdown_script, Htlc,
+    anchor_out, dlc, htlc, lightspeed, ptlc, shutdown_script, AnchorOut, Htlc,
+    ShutdownScript,
 };
 pub use modifiers::{bip96, rgb};
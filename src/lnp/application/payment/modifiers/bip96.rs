@@ -11,9 +11,12 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+use std::io;
+
 use crate::bp::LexOrder;
 use crate::lnp::application::payment::ExtensionId;
 use crate::lnp::application::{channel, ChannelExtension, Extension, Messages};
+use crate::strict_encoding;
 
 pub struct Bip96;
 
@@ -57,4 +60,13 @@ impl ChannelExtension for Bip96 {
             .for_each(|(_, _, tx)| tx.lex_order());
         Ok(())
     }
+
+    #[inline]
+    fn strict_encode_extension(
+        &self,
+        _: &mut dyn io::Write,
+    ) -> Result<usize, strict_encoding::Error> {
+        // Stateless modifier has no data to persist
+        Ok(0)
+    }
 }
@@ -15,15 +15,20 @@ use amplify::{DumbDefault, Wrapper};
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::fmt::Debug;
+use std::io;
 
 use bitcoin::hashes::hex::{Error, FromHex};
 use bitcoin::hashes::Hash;
 use bitcoin::OutPoint;
 
+use super::bip96::Bip96;
+use super::{AnchorOut, Bolt3, Htlc, ShutdownScript};
 use crate::bp::chain::AssetId;
 use crate::bp::Slice32;
-use crate::lnp::application::extension;
-use crate::strict_encoding::{self, strict_decode, strict_encode};
+use crate::lnp::application::{channel, extension, ChannelExtension};
+use crate::strict_encoding::{
+    self, strict_decode, strict_encode, StrictDecode,
+};
 
 /// Shorthand for representing asset - amount pairs
 pub type AssetsBalance = BTreeMap<AssetId, u64>;
@@ -89,6 +94,131 @@ impl TryFrom<u16> for ExtensionId {
 
 impl extension::Nomenclature for ExtensionId {}
 
+/// Handles to the payment channel extensions which are shared with other
+/// extensions, collected while the channel is restored from its strict
+/// encoding
+#[derive(Default)]
+pub struct SharedExtensions {
+    bolt3: Option<channel::Shared<Bolt3>>,
+    htlc: Option<channel::Shared<Htlc>>,
+    anchor_out: Option<channel::Shared<AnchorOut>>,
+    shutdown_script: Option<channel::Shared<ShutdownScript>>,
+}
+
+impl channel::ExtensionDecoder for ExtensionId {
+    type Shared = SharedExtensions;
+
+    fn strict_decode_extension(
+        self,
+        d: &mut dyn io::Read,
+        shared: &mut SharedExtensions,
+    ) -> Result<
+        Box<dyn ChannelExtension<Identity = Self>>,
+        strict_encoding::Error,
+    > {
+        Ok(match self {
+            ExtensionId::Bolt3 => {
+                let bolt3 = channel::Shared::new(Bolt3::strict_decode(d)?);
+                shared.bolt3 = Some(bolt3.clone());
+                Box::new(bolt3)
+            }
+            ExtensionId::Htlc => {
+                let htlc = channel::Shared::new(Htlc::strict_decode(d)?);
+                shared.htlc = Some(htlc.clone());
+                Box::new(htlc)
+            }
+            ExtensionId::AnchorOut => {
+                let anchor_out =
+                    channel::Shared::new(AnchorOut::strict_decode(d)?);
+                shared.anchor_out = Some(anchor_out.clone());
+                Box::new(anchor_out)
+            }
+            ExtensionId::ShutdownScript => {
+                let shutdown_script =
+                    channel::Shared::new(ShutdownScript::strict_decode(d)?);
+                shared.shutdown_script = Some(shutdown_script.clone());
+                Box::new(shutdown_script)
+            }
+            ExtensionId::Bip96 => Box::new(Bip96),
+            _ => Err(strict_encoding::Error::UnsupportedDataStructure(
+                "payment channel extension which is not implemented",
+            ))?,
+        })
+    }
+
+    fn connect(shared: SharedExtensions) -> Result<(), strict_encoding::Error> {
+        let missing = |extension: ExtensionId, dependency: ExtensionId| {
+            strict_encoding::Error::DataIntegrityError(format!(
+                "channel extension {} requires absent extension {}",
+                extension, dependency
+            ))
+        };
+        if let Some(htlc) = &shared.htlc {
+            let bolt3 = shared.bolt3.clone().ok_or_else(|| {
+                missing(ExtensionId::Htlc, ExtensionId::Bolt3)
+            })?;
+            htlc.borrow_mut().connect(bolt3);
+        }
+        if let Some(anchor_out) = &shared.anchor_out {
+            let bolt3 = shared.bolt3.clone().ok_or_else(|| {
+                missing(ExtensionId::AnchorOut, ExtensionId::Bolt3)
+            })?;
+            anchor_out.borrow_mut().connect(bolt3);
+        }
+        if let Some(shutdown_script) = &shared.shutdown_script {
+            let htlc = shared.htlc.clone().ok_or_else(|| {
+                missing(ExtensionId::ShutdownScript, ExtensionId::Htlc)
+            })?;
+            shutdown_script.borrow_mut().connect(htlc);
+        }
+        Ok(())
+    }
+}
+
+/// Types of the transactions in the channel transaction graph, which are used
+/// by the payment channel extensions as [`channel::TxRole`]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display)]
+#[display(Debug)]
+pub enum TxType {
+    /// HTLC-success transaction spending received HTLC output of the
+    /// commitment transaction
+    HtlcSuccess,
+
+    /// HTLC-timeout transaction spending offered HTLC output of the
+    /// commitment transaction
+    HtlcTimeout,
+
+    /// Cooperative closing transaction spending the funding output
+    Closing,
+
+    /// Transaction type defined by some other extension
+    Unknown(u16),
+}
+
+impl From<TxType> for u16 {
+    fn from(ty: TxType) -> Self {
+        match ty {
+            TxType::HtlcSuccess => 0x0,
+            TxType::HtlcTimeout => 0x1,
+            TxType::Closing => 0x2,
+            TxType::Unknown(ty) => ty,
+        }
+    }
+}
+
+impl From<u16> for TxType {
+    fn from(ty: u16) -> Self {
+        match ty {
+            0x0 => TxType::HtlcSuccess,
+            0x1 => TxType::HtlcTimeout,
+            0x2 => TxType::Closing,
+            ty => TxType::Unknown(ty),
+        }
+    }
+}
+
+impl channel::TxRole for TxType {}
+
 #[cfg_attr(feature = "serde", serde_as(as = "DisplayFromStr"))]
 #[cfg_attr(
     feature = "serde",
@@ -156,6 +286,8 @@ impl Default for Lifecycle {
     From,
     StrictEncode,
     StrictDecode,
+    LightningEncode,
+    LightningDecode,
 )]
 #[lnpbp_crate(crate)]
 #[display(LowerHex)]
@@ -204,6 +336,8 @@ impl ChannelId {
     From,
     StrictEncode,
     StrictDecode,
+    LightningEncode,
+    LightningDecode,
 )]
 #[lnpbp_crate(crate)]
 #[display(LowerHex)]
@@ -16,13 +16,12 @@
 
 use amplify::Bipolar;
 
-use crate::lnp::application::Messages;
+use crate::lnp::application::{message, Messages};
 use crate::lnp::presentation::{Encode, Error, Unmarshall};
 use crate::lnp::session::{
-    self, Accept, Connect, LocalNode, PlainTranscoder, Session, Split,
-    ToNodeAddr,
+    self, Accept, CloseReason, Connect, LocalNode, Session, ToNodeAddr,
 };
-use crate::lnp::transport::{ftcp, zmqsocket};
+use crate::lnp::transport::memory;
 use crate::lnp::{LIGHTNING_P2P_DEFAULT_PORT, LNPWP_UNMARSHALLER};
 
 pub trait RecvMessage {
@@ -33,8 +32,12 @@ pub trait SendMessage {
     fn send_message(&mut self, message: Messages) -> Result<usize, Error>;
 }
 
+/// Connection with the remote peer. Once the connection gets closed – either
+/// locally, by the remote peer or because of the remote peer protocol
+/// violation – the underlying session is dropped and all further operations
+/// fail with [`session::Error::Closed`].
 pub struct PeerConnection {
-    session: Box<dyn Session>,
+    session: Result<Box<dyn Session>, CloseReason>,
 }
 
 pub struct PeerReceiver {
@@ -54,7 +57,7 @@ pub struct PeerSender {
 impl PeerConnection {
     pub fn with(session: impl Session + 'static) -> Self {
         Self {
-            session: Box::new(session),
+            session: Ok(Box::new(session)),
         }
     }
 
@@ -66,7 +69,9 @@ impl PeerConnection {
             .to_node_addr(LIGHTNING_P2P_DEFAULT_PORT)
             .ok_or(Error::InvalidEndpoint)?;
         let session = endpoint.connect(local)?;
-        Ok(Self { session })
+        Ok(Self {
+            session: Ok(session),
+        })
     }
 
     pub fn accept(
@@ -77,20 +82,155 @@ impl PeerConnection {
             .to_node_addr(LIGHTNING_P2P_DEFAULT_PORT)
             .ok_or(Error::InvalidEndpoint)?;
         let session = endpoint.accept(local)?;
-        Ok(Self { session })
+        Ok(Self {
+            session: Ok(session),
+        })
     }
+
+    /// Constructs pair of unencrypted peer connections wired together
+    /// in-memory, such that channel logic may be tested deterministically
+    /// without network sockets. `faults` are injected into frames sent by the
+    /// first connection and `reverse_faults` – by the second one.
+    pub fn memory_pair(
+        faults: memory::Faults,
+        reverse_faults: memory::Faults,
+    ) -> (Self, Self) {
+        let (left, right) = memory::pair_with(faults, reverse_faults);
+        (
+            Self::with(session::Raw::with_memory_unencrypted(left)),
+            Self::with(session::Raw::with_memory_unencrypted(right)),
+        )
+    }
+
+    /// Constructs pair of peer connections wired together in-memory and
+    /// encrypted with BOLT-8 noise handshake run between `initiator` and
+    /// `responder` nodes. The first returned connection belongs to the
+    /// initiator; `faults` are injected into frames sent by it and
+    /// `reverse_faults` – into frames sent by the responder, including the
+    /// handshake frames.
+    pub fn memory_pair_encrypted(
+        initiator: &LocalNode,
+        responder: &LocalNode,
+        faults: memory::Faults,
+        reverse_faults: memory::Faults,
+    ) -> Result<(Self, Self), Error> {
+        let (left, right) = memory::pair_with(faults, reverse_faults);
+        let node_id = responder.node_id();
+        let responder = responder.clone();
+        let handle = std::thread::spawn(move || {
+            session::Raw::accept_memory_encrypted(right, &responder)
+        });
+        let initiator =
+            session::Raw::connect_memory_encrypted(left, node_id, initiator);
+        // We panic here because this is a program architecture design
+        // error: handshake thread must not panic
+        let (responder, _) = handle
+            .join()
+            .expect("In-memory responder handshake thread has panicked")?;
+        Ok((Self::with(initiator?), Self::with(responder)))
+    }
+
+    /// Splits the connection into receiving and sending halves, which may be
+    /// used from different threads. Fails with [`session::Error::Closed`] if
+    /// the connection is already closed.
+    pub fn try_split(self) -> Result<(PeerReceiver, PeerSender), Error> {
+        let session = self.session.map_err(session::Error::Closed)?;
+        let (receiver, sender) = session.split();
+        Ok((PeerReceiver { receiver }, PeerSender { sender }))
+    }
+
+    /// Returns reason for which the connection was closed, or `None` if the
+    /// connection is still open
+    #[inline]
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.session.as_ref().err().copied()
+    }
+
+    /// Closes connection without notifying the remote peer
+    #[inline]
+    pub fn close(&mut self) {
+        self.shutdown(CloseReason::Local)
+    }
+
+    /// Fails the connection according to BOLT-1: sends the remote peer
+    /// `error` message referring to all channels with the `reason` and
+    /// closes the connection
+    pub fn fail(&mut self, reason: &str) -> Result<(), Error> {
+        let result = self.send_message(connection_error(reason));
+        self.shutdown(CloseReason::Local);
+        result.map(|_| ())
+    }
+
+    fn session(&mut self) -> Result<&mut Box<dyn Session>, session::Error> {
+        self.session
+            .as_mut()
+            .map_err(|reason| session::Error::Closed(*reason))
+    }
+
+    fn shutdown(&mut self, reason: CloseReason) {
+        if self.session.is_ok() {
+            self.session = Err(reason);
+        }
+    }
+
+    /// Closes the connection if the session can't be used after `err`
+    fn check(&mut self, err: session::Error) -> session::Error {
+        if let Some(reason) = err.close_reason() {
+            self.shutdown(reason);
+        }
+        err
+    }
+}
+
+/// Constructs BOLT-1 `error` message referring to all channels
+fn connection_error(reason: &str) -> Messages {
+    Messages::Error(message::Error {
+        channel_id: None,
+        data: reason.as_bytes().to_vec(),
+    })
 }
 
 impl RecvMessage for PeerConnection {
     fn recv_message(&mut self) -> Result<Messages, Error> {
-        let payload = self.session.recv_raw_message()?;
-        Ok((&*LNPWP_UNMARSHALLER.unmarshall(&payload)?).clone())
+        loop {
+            let result = self.session()?.recv_raw_message();
+            let payload = match result {
+                Ok(payload) => payload,
+                Err(err) => Err(self.check(err))?,
+            };
+            match LNPWP_UNMARSHALLER.unmarshall(&payload) {
+                Ok(message) => {
+                    let message = (&*message).clone();
+                    if let Messages::Error(ref err) = message {
+                        if err.channel_id.is_none() {
+                            self.shutdown(CloseReason::RemoteError);
+                        }
+                    }
+                    return Ok(message);
+                }
+                // Messages of unknown odd types are ignored
+                Err(err) if !err.is_protocol_violation() => continue,
+                Err(err) => {
+                    // Failure to notify the remote peer does not matter
+                    // here, since we close the connection anyway
+                    let _ =
+                        self.send_message(connection_error(&err.to_string()));
+                    self.shutdown(CloseReason::ProtocolViolation);
+                    return Err(err);
+                }
+            }
+        }
     }
 }
 
 impl SendMessage for PeerConnection {
     fn send_message(&mut self, message: Messages) -> Result<usize, Error> {
-        Ok(self.session.send_raw_message(&message.encode()?)?)
+        let data = message.encode()?;
+        let result = self.session()?.send_raw_message(&data);
+        match result {
+            Ok(len) => Ok(len),
+            Err(err) => Err(self.check(err))?,
+        }
     }
 }
 
@@ -107,6 +247,16 @@ impl SendMessage for PeerSender {
     }
 }
 
+impl PeerSender {
+    /// Sends the remote peer BOLT-1 `error` message referring to all
+    /// channels with the `reason`, after which the remote peer is expected
+    /// to close the connection. Used by the owners of the split connection
+    /// to report protocol violations detected by [`PeerReceiver`].
+    pub fn fail(&mut self, reason: &str) -> Result<(), Error> {
+        self.send_message(connection_error(reason)).map(|_| ())
+    }
+}
+
 impl Bipolar for PeerConnection {
     type Left = PeerReceiver;
     type Right = PeerSender;
@@ -116,31 +266,129 @@ impl Bipolar for PeerConnection {
     }
 
     fn split(self) -> (Self::Left, Self::Right) {
-        let session = self.session.into_any();
-        let (input, output) = if let Some(_) = session
-            .downcast_ref::<session::Raw<PlainTranscoder, ftcp::Connection>>()
-        {
-            let session = session
-                .downcast::<session::Raw<PlainTranscoder, ftcp::Connection>>()
-                .expect(
-                    "Must not fail; we just ensured that with downcast_ref",
-                );
-            (*session).split()
-        } else if let Some(_) = session
-            .downcast_ref::<session::Raw<PlainTranscoder, zmqsocket::Connection>>()
-        {
-            let session = session
-                .downcast::<session::Raw<PlainTranscoder, ftcp::Connection>>()
-                .expect(
-                    "Must not fail; we just ensured that with downcast_ref",
-                );
-            (*session).split()
-        } else {
-            panic!("Impossible to split this type of Session")
-        };
-        (
-            PeerReceiver { receiver: input },
-            PeerSender { sender: output },
+        // We panic here because this is a program architecture design
+        // error: closed connection must not be used anymore
+        self.try_split()
+            .expect("Closed peer connection can't be split")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lnp::application::message::Ping;
+    use crate::lnp::test_helpers::local_node;
+
+    fn ping(size: u16) -> Messages {
+        Messages::Ping(Ping {
+            pong_size: size,
+            ignored: vec![0x00; 2],
+        })
+    }
+
+    fn assert_ping(message: Messages, size: u16) {
+        match message {
+            Messages::Ping(ping) => assert_eq!(ping.pong_size, size),
+            other => panic!("unexpected message {}", other),
+        }
+    }
+
+    #[test]
+    fn test_memory_pair_encrypted() {
+        let initiator = local_node(0x11);
+        let responder = local_node(0x21);
+        let (mut alice, mut bob) = PeerConnection::memory_pair_encrypted(
+            &initiator,
+            &responder,
+            memory::Faults::default(),
+            memory::Faults::default(),
         )
+        .unwrap();
+
+        alice.send_message(ping(1)).unwrap();
+        assert_ping(bob.recv_message().unwrap(), 1);
+
+        let (mut bob_rx, mut bob_tx) = bob.split();
+        bob_tx.send_message(ping(2)).unwrap();
+        assert_ping(alice.recv_message().unwrap(), 2);
+        alice.send_message(ping(3)).unwrap();
+        assert_ping(bob_rx.recv_message().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_memory_pair_dropped_frame() {
+        let (mut alice, mut bob) = PeerConnection::memory_pair(
+            memory::Faults::default().dropping(0),
+            memory::Faults::default(),
+        );
+        alice.send_message(ping(1)).unwrap();
+        alice.send_message(ping(2)).unwrap();
+        assert_ping(bob.recv_message().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_protocol_violation() {
+        let (left, right) = memory::pair();
+        let mut remote = session::Raw::with_memory_unencrypted(left);
+        let mut peer =
+            PeerConnection::with(session::Raw::with_memory_unencrypted(right));
+
+        // Message with unknown odd type must be ignored
+        remote.send_raw_message(&[0x00, 0x03, 0xff]).unwrap();
+        remote.send_raw_message(&ping(1).encode().unwrap()).unwrap();
+        assert_ping(peer.recv_message().unwrap(), 1);
+        assert_eq!(peer.close_reason(), None);
+
+        // Message with unknown even type must fail the connection
+        remote.send_raw_message(&[0x00, 0x02]).unwrap();
+        assert_eq!(peer.recv_message().unwrap_err(), Error::MessageEvenType);
+        assert_eq!(peer.close_reason(), Some(CloseReason::ProtocolViolation));
+        let payload = remote.recv_raw_message().unwrap();
+        match &*LNPWP_UNMARSHALLER.unmarshall(&payload).unwrap() {
+            Messages::Error(err) => {
+                assert_eq!(err.channel_id, None);
+                assert_eq!(
+                    err.data,
+                    Error::MessageEvenType.to_string().as_bytes()
+                );
+            }
+            other => panic!("unexpected message {}", other),
+        }
+        assert_eq!(
+            remote.recv_raw_message().unwrap_err(),
+            session::Error::Closed(CloseReason::Remote)
+        );
+        assert_eq!(
+            peer.send_message(ping(1)).unwrap_err(),
+            Error::Session(session::Error::Closed(
+                CloseReason::ProtocolViolation
+            ))
+        );
+    }
+
+    #[test]
+    fn test_graceful_close() {
+        let (mut alice, mut bob) = PeerConnection::memory_pair(
+            memory::Faults::default(),
+            memory::Faults::default(),
+        );
+        alice.fail("shutting down").unwrap();
+        assert_eq!(alice.close_reason(), Some(CloseReason::Local));
+        match bob.recv_message().unwrap() {
+            Messages::Error(err) => assert_eq!(err.data, b"shutting down"),
+            other => panic!("unexpected message {}", other),
+        }
+        assert_eq!(bob.close_reason(), Some(CloseReason::RemoteError));
+
+        let (mut alice, mut bob) = PeerConnection::memory_pair(
+            memory::Faults::default(),
+            memory::Faults::default(),
+        );
+        alice.close();
+        assert_eq!(
+            bob.recv_message().unwrap_err(),
+            Error::Session(session::Error::Closed(CloseReason::Remote))
+        );
+        assert_eq!(bob.close_reason(), Some(CloseReason::Remote));
     }
 }
@@ -0,0 +1,344 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Peer connection supervisor implementing BOLT-1 keepalive: it sends pings
+//! on a schedule, answers pings from the remote peer, tracks round-trip time
+//! and detects dead peers with read and idle timeouts.
+
+use amplify::Bipolar;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::message::{Messages, Ping};
+use super::peer_connection::{
+    PeerConnection, PeerSender, RecvMessage, SendMessage,
+};
+use crate::lnp::presentation::Error;
+use crate::lnp::session::{self, CloseReason};
+use crate::lnp::transport;
+
+/// Pings with `num_pong_bytes` equal or exceeding this value must be ignored
+/// according to BOLT-1
+pub const PONG_SIZE_LIMIT: u16 = 65532;
+
+/// Keepalive and timeout configuration used by [`Supervisor`]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct KeepaliveConfig {
+    /// Interval between the pings sent to the remote peer
+    pub ping_interval: Duration,
+
+    /// Maximum time to wait for the pong reply to our ping
+    pub read_timeout: Duration,
+
+    /// Maximum time without any message from the remote peer
+    pub idle_timeout: Duration,
+
+    /// Number of bytes which the remote peer is asked to put into its pong
+    pub pong_size: u16,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(90),
+            pong_size: 4,
+        }
+    }
+}
+
+/// Supervisor of the peer connection. Messages are read by a dedicated
+/// thread, so the supervisor timers do not depend on the read timeouts of
+/// the underlying transport. When the supervisor is dropped, the thread is
+/// signalled to stop and detached: it exits once the pending read returns or
+/// times out, without blocking the owner of the supervisor.
+pub struct Supervisor {
+    sender: PeerSender,
+    inbox: mpsc::Receiver<Result<Messages, Error>>,
+    stop: Arc<AtomicBool>,
+    config: KeepaliveConfig,
+    last_received: Instant,
+    next_ping: Instant,
+    ping_sent: Option<Instant>,
+    rtt: Option<Duration>,
+}
+
+impl Supervisor {
+    /// Starts supervision of the `connection` with the given `config`
+    pub fn with(connection: PeerConnection, config: KeepaliveConfig) -> Self {
+        let (mut receiver, sender) = connection.split();
+        let (tx, inbox) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                let result = match receiver.recv_message() {
+                    // Timeouts are tracked by the supervisor itself
+                    Err(Error::Transport(transport::Error::TimedOut)) => {
+                        continue
+                    }
+                    result => result,
+                };
+                let failed = result.is_err();
+                if tx.send(result).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        let now = Instant::now();
+        Self {
+            sender,
+            inbox,
+            stop,
+            config,
+            last_received: now,
+            next_ping: now + config.ping_interval,
+            ping_sent: None,
+            rtt: None,
+        }
+    }
+
+    /// Returns round-trip time measured with the last ping, if any
+    #[inline]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Returns sender which may be used to send messages to the remote peer
+    #[inline]
+    pub fn sender(&mut self) -> &mut PeerSender {
+        &mut self.sender
+    }
+
+    /// Processes single event: either a message from the remote peer or a
+    /// timer expiration. Keepalive messages are processed by the supervisor
+    /// itself; application-level messages are returned to the caller.
+    /// Errors mean that the peer is dead.
+    pub fn step(&mut self) -> Result<Option<Messages>, Error> {
+        let now = Instant::now();
+        let mut deadline = self.last_received + self.config.idle_timeout;
+        match self.ping_sent {
+            Some(sent) => {
+                deadline = deadline.min(sent + self.config.read_timeout)
+            }
+            None => deadline = deadline.min(self.next_ping),
+        }
+        let wait = deadline.saturating_duration_since(now);
+
+        let mut received = None;
+        match self.inbox.recv_timeout(wait) {
+            Ok(Ok(message)) => {
+                self.last_received = Instant::now();
+                received = self.process(message)?;
+            }
+            Ok(Err(err)) => {
+                if err.is_protocol_violation() {
+                    // Failure to notify the remote peer does not matter here,
+                    // since the peer is considered dead anyway
+                    let _ = self.sender.fail(&err.to_string());
+                }
+                Err(err)?
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(session::Error::Closed(CloseReason::Remote))?
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        let now = Instant::now();
+        if now >= self.last_received + self.config.idle_timeout {
+            Err(transport::Error::TimedOut)?
+        }
+        match self.ping_sent {
+            Some(sent) if now >= sent + self.config.read_timeout => {
+                Err(transport::Error::TimedOut)?
+            }
+            None if now >= self.next_ping => {
+                self.sender.send_message(Messages::Ping(Ping {
+                    pong_size: self.config.pong_size,
+                    ignored: vec![],
+                }))?;
+                self.ping_sent = Some(now);
+                self.next_ping = now + self.config.ping_interval;
+            }
+            _ => {}
+        }
+        Ok(received)
+    }
+
+    fn process(
+        &mut self,
+        message: Messages,
+    ) -> Result<Option<Messages>, Error> {
+        match message {
+            Messages::Ping(ping) if ping.pong_size < PONG_SIZE_LIMIT => {
+                let pong = vec![0u8; ping.pong_size as usize];
+                self.sender.send_message(Messages::Pong(pong))?;
+            }
+            Messages::Ping(_) => {}
+            Messages::Pong(_) => {
+                // Unsolicited pongs are used for traffic obfuscation and
+                // only keep the connection alive
+                if let Some(sent) = self.ping_sent.take() {
+                    self.rtt = Some(sent.elapsed());
+                }
+            }
+            message => return Ok(Some(message)),
+        }
+        Ok(None)
+    }
+}
+
+impl RecvMessage for Supervisor {
+    /// Returns next application-level message from the remote peer, keeping
+    /// the connection alive while waiting for it. Errors mean that the peer
+    /// is dead.
+    fn recv_message(&mut self) -> Result<Messages, Error> {
+        loop {
+            if let Some(message) = self.step()? {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        // NB: Reader thread is not joined, since it may be blocked reading
+        //     from a silent peer for the whole transport read timeout. It
+        //     has nothing to report: all its results are delivered through
+        //     the inbox
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lnp::transport::memory;
+
+    fn config(ping_interval: u64, read_timeout: u64) -> KeepaliveConfig {
+        KeepaliveConfig {
+            ping_interval: Duration::from_millis(ping_interval),
+            read_timeout: Duration::from_millis(read_timeout),
+            idle_timeout: Duration::from_secs(10),
+            pong_size: 2,
+        }
+    }
+
+    #[test]
+    fn test_pong_reply() {
+        let (alice, mut bob) = PeerConnection::memory_pair(
+            memory::Faults::default(),
+            memory::Faults::default(),
+        );
+        let handle = thread::spawn(move || {
+            let mut supervisor = Supervisor::with(alice, config(10_000, 1000));
+            let mut messages = vec![];
+            loop {
+                match supervisor.recv_message() {
+                    Ok(message) => messages.push(message),
+                    Err(err) => {
+                        assert_eq!(supervisor.rtt(), None);
+                        return (messages, err);
+                    }
+                }
+            }
+        });
+
+        bob.send_message(Messages::Ping(Ping {
+            pong_size: 5,
+            ignored: vec![],
+        }))
+        .unwrap();
+        match bob.recv_message().unwrap() {
+            Messages::Pong(data) => assert_eq!(data, vec![0u8; 5]),
+            other => panic!("unexpected message {}", other),
+        }
+        // Pings above the limit must be ignored
+        bob.send_message(Messages::Ping(Ping {
+            pong_size: PONG_SIZE_LIMIT,
+            ignored: vec![],
+        }))
+        .unwrap();
+        bob.send_message(Messages::Pong(vec![])).unwrap();
+        bob.send_message(Messages::Pong(vec![0u8; 1])).unwrap();
+        drop(bob);
+
+        let (messages, death) = handle.join().unwrap();
+        assert!(messages.is_empty());
+        assert_eq!(
+            death,
+            Error::Session(session::Error::Closed(CloseReason::Remote))
+        );
+    }
+
+    #[test]
+    fn test_rtt_tracking() {
+        let (alice, bob) = PeerConnection::memory_pair(
+            memory::Faults::default(),
+            memory::Faults::default(),
+        );
+        let handle = thread::spawn(move || {
+            let mut supervisor = Supervisor::with(bob, config(20, 1000));
+            while supervisor.recv_message().is_ok() {}
+        });
+
+        let mut supervisor = Supervisor::with(alice, config(20, 1000));
+        let mut rtts = 0;
+        while rtts < 2 {
+            assert!(supervisor.step().unwrap().is_none());
+            if supervisor.ping_sent.is_none() && supervisor.rtt().is_some() {
+                rtts += 1;
+                supervisor.rtt = None;
+            }
+        }
+        // Dropping the supervisor disconnects the remote peer
+        drop(supervisor);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_drop_with_silent_peer() {
+        let (alice, bob) = PeerConnection::memory_pair(
+            memory::Faults::default(),
+            memory::Faults::default(),
+        );
+        let supervisor = Supervisor::with(alice, config(10_000, 1000));
+        // The remote peer never closes the connection and never sends
+        // anything, so the reader thread is blocked for the whole transport
+        // read timeout
+        thread::sleep(Duration::from_millis(10));
+        let started = Instant::now();
+        drop(supervisor);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(bob);
+    }
+
+    #[test]
+    fn test_dead_peer() {
+        let (alice, bob) = PeerConnection::memory_pair(
+            memory::Faults::default(),
+            memory::Faults::default(),
+        );
+        let mut supervisor = Supervisor::with(alice, config(20, 50));
+        let reason = supervisor.recv_message().unwrap_err();
+        assert_eq!(reason, Error::Transport(transport::Error::TimedOut));
+        assert_eq!(supervisor.rtt(), None);
+        drop(bob);
+    }
+}
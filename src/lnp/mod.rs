@@ -19,25 +19,37 @@ pub mod presentation;
 pub mod session;
 pub mod transport;
 
+#[cfg(test)]
+pub(crate) mod test_helpers;
+
 pub use application::payment::{ChannelId, TempChannelId};
 pub use application::{
-    channel, factories, message, rpc_connection, FeatureContext, FeatureFlag,
-    Features, Messages, OnionPacket, PeerConnection, PeerReceiver, PeerSender,
-    RecvMessage, RpcConnection, SendMessage, LNPWP_UNMARSHALLER,
+    channel, factories, message, rpc_connection, FeatureContext, FeatureError,
+    FeatureFlag, Features, Messages, OnionPacket, PeerConnection, PeerReceiver,
+    PeerSender, RecvMessage, RpcConnection, SendMessage, LNPWP_UNMARSHALLER,
 };
 pub use presentation::payload::{TypeId, TypedEnum};
 pub use presentation::{
-    payload, tlv, CreateUnmarshaller, Payload, UnknownTypeError, Unmarshall,
-    UnmarshallFn, Unmarshaller,
+    lightning_encoding, payload, tlv, CreateUnmarshaller, LightningDecode,
+    LightningEncode, Payload, UnknownTypeError, Unmarshall, UnmarshallFn,
+    Unmarshaller,
 };
 pub use session::{
     Accept, Connect, Decrypt, Encrypt, LocalNode, NodeAddr, NoiseDecryptor,
     NoiseEncryptor, NoiseTranscoder, PartialNodeAddr, PlainTranscoder,
     RemoteNodeAddr, Session, Split, ToNodeAddr, ToRemoteNodeAddr, Transcode,
 };
+#[cfg(not(target_os = "windows"))]
+pub use transport::posix;
+#[cfg(feature = "smtp")]
+pub use transport::smtp;
+#[cfg(feature = "tor")]
+pub use transport::tor;
+#[cfg(feature = "websockets")]
+pub use transport::websocket;
 pub use transport::{
-    ftcp, websocket, zmqsocket, Duplex, FramingProtocol, LocalSocketAddr,
-    RemoteSocketAddr, RoutedFrame,
+    capture, ftcp, http, memory, socks5, zmqsocket, Duplex, FramingProtocol,
+    LocalSocketAddr, RemoteSocketAddr, RoutedFrame,
 };
 
 pub const LNP_MSG_MAX_LEN: usize = core::u16::MAX as usize;
@@ -131,6 +143,13 @@ pub enum AddrError {
     /// can't be persisted. This, it can't be provided through this type.
     ZmqContextRequired,
 
+    /// Unexpected URL query parameter `{0}` for the used scheme
+    UnexpectedQuery(String),
+
+    /// Unsupported encryption option `{0}`; only ZMQ TCP addresses with node
+    /// id may select `curve` encryption
+    InvalidEncryption(String),
+
     /// The provided protocol can't be used for {0}
     Unsupported(&'static str),
 
@@ -11,12 +11,10 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use crate::lnp::transport;
+use crate::lnp::session::CloseReason;
+use crate::lnp::{session, transport};
 use crate::strict_encoding;
 
-#[cfg(feature = "lightning")]
-use lightning::ln::msgs::DecodeError;
-
 /// Presentation-level LNP error types. They do not include error source
 /// for the simplicity of their encoding
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error, From)]
@@ -73,6 +71,18 @@ pub enum Error {
     /// {0}
     #[from]
     Transport(transport::Error),
+
+    /// {0}
+    Session(session::Error),
+}
+
+impl From<session::Error> for Error {
+    fn from(err: session::Error) -> Self {
+        match err {
+            session::Error::Transport(err) => Error::Transport(err),
+            err => Error::Session(err),
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -81,6 +91,26 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl Error {
+    /// Returns whether the error is caused by data received from the remote
+    /// peer which violate the protocol, in which case the connection with
+    /// the peer must be failed. Messages of unknown odd types
+    /// ([`Error::UnknownDataType`]) must be ignored according to BOLT-1 and
+    /// are not considered protocol violations.
+    pub fn is_protocol_violation(&self) -> bool {
+        match self {
+            Error::InvalidEndpoint
+            | Error::NoEncoder
+            | Error::UnknownDataType
+            | Error::Transport(_) => false,
+            Error::Session(err) => {
+                err.close_reason() == Some(CloseReason::ProtocolViolation)
+            }
+            _ => true,
+        }
+    }
+}
+
 impl From<Error> for u8 {
     fn from(err: Error) -> Self {
         match err {
@@ -99,20 +129,7 @@ impl From<Error> for u8 {
             Error::TlvRecordEvenType => 12,
             Error::TlvRecordInvalidLen => 13,
             Error::Transport(_) => 14,
-        }
-    }
-}
-
-#[cfg(feature = "lightning")]
-impl From<DecodeError> for Error {
-    fn from(err: DecodeError) -> Self {
-        match err {
-            DecodeError::UnknownVersion => Error::UnknownProtocolVersion,
-            DecodeError::UnknownRequiredFeature => Error::MessageEvenType,
-            DecodeError::InvalidValue => Error::InvalidValue,
-            DecodeError::ShortRead => Error::NoData,
-            DecodeError::BadLengthDescriptor => Error::BadLengthDescriptor,
-            DecodeError::Io(_) => Error::Io,
+            Error::Session(_) => 15,
         }
     }
 }
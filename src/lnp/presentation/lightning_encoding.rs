@@ -0,0 +1,466 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Encoding of Lightning network peer messages according to BOLT-1 and BOLT-2
+//! rules. Unlike strict encoding, lightning encoding uses big-endian integers,
+//! does not prefix fixed-size data with their length, prefixes variable-length
+//! data with `u16` length, and supports optional trailing fields and TLV
+//! streams.
+
+use amplify::Wrapper;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::Hash as StdHash;
+use std::io;
+
+use bitcoin::hashes::{sha256, Hash, Hmac};
+use bitcoin::secp256k1::{PublicKey, Signature};
+use bitcoin::{Script, Txid};
+
+use super::{tlv, BigSize, Decode, Encode, Error};
+use crate::bp::chain::AssetId;
+use crate::bp::{HashLock, HashPreimage, ShortId, Slice32};
+
+/// Binary encoding according to the rules of Lightning network peer protocol
+/// (BOLT-1 and BOLT-2)
+pub trait LightningEncode {
+    /// Encode with the given [`std::io::Write`] instance; must return result
+    /// with either amount of bytes encoded – or presentation-level error.
+    fn lightning_encode<E: io::Write>(&self, e: E) -> Result<usize, Error>;
+}
+
+/// Binary decoding according to the rules of Lightning network peer protocol
+/// (BOLT-1 and BOLT-2)
+pub trait LightningDecode
+where
+    Self: Sized,
+{
+    /// Decode with the given [`std::io::Read`] instance; must either
+    /// construct an instance or return presentation-level error.
+    fn lightning_decode<D: io::Read>(d: D) -> Result<Self, Error>;
+}
+
+/// Convenience method for lightning encoding of data structures implementing
+/// [`LightningEncode`] into a byte vector.
+pub fn lightning_encode<T>(data: &T) -> Result<Vec<u8>, Error>
+where
+    T: LightningEncode,
+{
+    let mut encoder = io::Cursor::new(vec![]);
+    data.lightning_encode(&mut encoder)?;
+    Ok(encoder.into_inner())
+}
+
+/// Convenience method for lightning decoding of data structures implementing
+/// [`LightningDecode`] from any byte data source.
+///
+/// NB: Unlike strict decoding, the function does not fail if the data were
+/// not consumed entirely, since BOLT-1 requires to ignore additional data at
+/// the end of the message for the sake of forward compatibility.
+pub fn lightning_decode<T>(data: &impl AsRef<[u8]>) -> Result<T, Error>
+where
+    T: LightningDecode,
+{
+    T::lightning_decode(data.as_ref())
+}
+
+macro_rules! impl_lightning_encoding_int {
+    ($type:ty) => {
+        impl LightningEncode for $type {
+            #[inline]
+            fn lightning_encode<E: io::Write>(
+                &self,
+                mut e: E,
+            ) -> Result<usize, Error> {
+                e.write_all(&self.to_be_bytes())?;
+                Ok(core::mem::size_of::<$type>())
+            }
+        }
+
+        impl LightningDecode for $type {
+            #[inline]
+            fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+                let mut buf = [0u8; core::mem::size_of::<$type>()];
+                d.read_exact(&mut buf)?;
+                Ok(<$type>::from_be_bytes(buf))
+            }
+        }
+    };
+}
+
+macro_rules! impl_lightning_encoding_array {
+    ($len:expr) => {
+        impl LightningEncode for [u8; $len] {
+            #[inline]
+            fn lightning_encode<E: io::Write>(
+                &self,
+                mut e: E,
+            ) -> Result<usize, Error> {
+                e.write_all(&self[..])?;
+                Ok($len)
+            }
+        }
+
+        impl LightningDecode for [u8; $len] {
+            #[inline]
+            fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+                let mut buf = [0u8; $len];
+                d.read_exact(&mut buf)?;
+                Ok(buf)
+            }
+        }
+    };
+}
+
+macro_rules! impl_lightning_encoding_hash {
+    ($type:ty) => {
+        impl LightningEncode for $type {
+            #[inline]
+            fn lightning_encode<E: io::Write>(
+                &self,
+                mut e: E,
+            ) -> Result<usize, Error> {
+                e.write_all(&self[..])?;
+                Ok(<$type>::LEN)
+            }
+        }
+
+        impl LightningDecode for $type {
+            #[inline]
+            fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+                let mut buf = [0u8; <$type>::LEN];
+                d.read_exact(&mut buf)?;
+                <$type>::from_slice(&buf).map_err(|_| Error::InvalidValue)
+            }
+        }
+    };
+}
+
+macro_rules! impl_lightning_encoding_wrapper {
+    ($type:ty) => {
+        impl LightningEncode for $type {
+            #[inline]
+            fn lightning_encode<E: io::Write>(
+                &self,
+                e: E,
+            ) -> Result<usize, Error> {
+                self.as_inner().lightning_encode(e)
+            }
+        }
+
+        impl LightningDecode for $type {
+            #[inline]
+            fn lightning_decode<D: io::Read>(d: D) -> Result<Self, Error> {
+                Ok(Self::from_inner(LightningDecode::lightning_decode(d)?))
+            }
+        }
+    };
+}
+
+impl_lightning_encoding_int!(u8);
+impl_lightning_encoding_int!(u16);
+impl_lightning_encoding_int!(u32);
+impl_lightning_encoding_int!(u64);
+
+impl_lightning_encoding_array!(3);
+impl_lightning_encoding_array!(32);
+
+impl_lightning_encoding_hash!(sha256::Hash);
+impl_lightning_encoding_hash!(Hmac<sha256::Hash>);
+impl_lightning_encoding_hash!(Txid);
+impl_lightning_encoding_hash!(AssetId);
+
+impl_lightning_encoding_wrapper!(Slice32);
+impl_lightning_encoding_wrapper!(HashLock);
+impl_lightning_encoding_wrapper!(HashPreimage);
+
+/// Short channel ids are encoded as big-endian `u64` values
+impl LightningEncode for ShortId {
+    #[inline]
+    fn lightning_encode<E: io::Write>(&self, e: E) -> Result<usize, Error> {
+        self.into_u64().lightning_encode(e)
+    }
+}
+
+impl LightningDecode for ShortId {
+    #[inline]
+    fn lightning_decode<D: io::Read>(d: D) -> Result<Self, Error> {
+        Ok(ShortId::from(u64::lightning_decode(d)?))
+    }
+}
+
+/// Variable-length data are prefixed with `u16` number of items
+impl<T> LightningEncode for Vec<T>
+where
+    T: LightningEncode,
+{
+    fn lightning_encode<E: io::Write>(&self, mut e: E) -> Result<usize, Error> {
+        let len = self.len();
+        if len > core::u16::MAX as usize {
+            Err(Error::BadLengthDescriptor)?
+        }
+        let mut count = (len as u16).lightning_encode(&mut e)?;
+        for item in self {
+            count += item.lightning_encode(&mut e)?;
+        }
+        Ok(count)
+    }
+}
+
+impl<T> LightningDecode for Vec<T>
+where
+    T: LightningDecode,
+{
+    fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+        let len = u16::lightning_decode(&mut d)? as usize;
+        let mut data = Vec::with_capacity(len);
+        for _ in 0..len {
+            data.push(T::lightning_decode(&mut d)?);
+        }
+        Ok(data)
+    }
+}
+
+/// Optional values are allowed only as trailing fields of the message:
+/// `None` is not encoded at all, and decoding results in `None` only if there
+/// is no more data left in the message
+impl<T> LightningEncode for Option<T>
+where
+    T: LightningEncode,
+{
+    fn lightning_encode<E: io::Write>(&self, e: E) -> Result<usize, Error> {
+        match self {
+            None => Ok(0),
+            Some(value) => value.lightning_encode(e),
+        }
+    }
+}
+
+impl<T> LightningDecode for Option<T>
+where
+    T: LightningDecode,
+{
+    fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+        let mut byte = [0u8; 1];
+        if d.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(T::lightning_decode(io::Read::chain(&byte[..], d))?))
+    }
+}
+
+impl LightningEncode for PublicKey {
+    fn lightning_encode<E: io::Write>(&self, mut e: E) -> Result<usize, Error> {
+        e.write_all(&self.serialize())?;
+        Ok(33)
+    }
+}
+
+impl LightningDecode for PublicKey {
+    fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+        let mut buf = [0u8; 33];
+        d.read_exact(&mut buf)?;
+        PublicKey::from_slice(&buf).map_err(|_| Error::InvalidValue)
+    }
+}
+
+impl LightningEncode for Signature {
+    fn lightning_encode<E: io::Write>(&self, mut e: E) -> Result<usize, Error> {
+        e.write_all(&self.serialize_compact())?;
+        Ok(64)
+    }
+}
+
+impl LightningDecode for Signature {
+    fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+        let mut buf = [0u8; 64];
+        d.read_exact(&mut buf)?;
+        Signature::from_compact(&buf).map_err(|_| Error::InvalidValue)
+    }
+}
+
+impl LightningEncode for Script {
+    #[inline]
+    fn lightning_encode<E: io::Write>(&self, e: E) -> Result<usize, Error> {
+        self.as_bytes().to_vec().lightning_encode(e)
+    }
+}
+
+impl LightningDecode for Script {
+    #[inline]
+    fn lightning_decode<D: io::Read>(d: D) -> Result<Self, Error> {
+        Ok(Script::from(Vec::<u8>::lightning_decode(d)?))
+    }
+}
+
+impl LightningEncode for BigSize {
+    fn lightning_encode<E: io::Write>(&self, mut e: E) -> Result<usize, Error> {
+        let data = self.encode().map_err(|_| Error::InvalidValue)?;
+        e.write_all(&data)?;
+        Ok(data.len())
+    }
+}
+
+impl LightningDecode for BigSize {
+    fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+        let mut buf = vec![u8::lightning_decode(&mut d)?];
+        let len = match buf[0] {
+            0xFF => 8,
+            0xFE => 4,
+            0xFD => 2,
+            _ => 0,
+        };
+        buf.resize(len + 1, 0);
+        d.read_exact(&mut buf[1..])?;
+        BigSize::decode(&buf).map_err(|_| Error::InvalidValue)
+    }
+}
+
+/// TLV stream consisting of the records unknown to the current
+/// implementation, represented as a map of raw record values. Since all of
+/// the records are unknown, decoding fails on any even record type, as
+/// required by BOLT-1.
+impl LightningEncode for BTreeMap<u64, Vec<u8>> {
+    #[inline]
+    fn lightning_encode<E: io::Write>(&self, e: E) -> Result<usize, Error> {
+        tlv::RawStream::from(self.clone()).lightning_encode(e)
+    }
+}
+
+impl LightningDecode for BTreeMap<u64, Vec<u8>> {
+    #[inline]
+    fn lightning_decode<D: io::Read>(d: D) -> Result<Self, Error> {
+        let stream = tlv::RawStream::lightning_decode(d)?;
+        stream.ensure_odd()?;
+        Ok(stream.into())
+    }
+}
+
+/// Sets are serialized as a sorted sequence of items without any length
+/// prefix (`...*` notation of BOLT-1), so they consume all of the remaining
+/// data. Thus they may be used only as the last field of the message or as a
+/// TLV record value.
+impl<T> LightningEncode for HashSet<T>
+where
+    T: LightningEncode + Ord + StdHash,
+{
+    fn lightning_encode<E: io::Write>(&self, mut e: E) -> Result<usize, Error> {
+        let mut items = self.iter().collect::<Vec<_>>();
+        items.sort();
+        let mut len = 0;
+        for item in items {
+            len += item.lightning_encode(&mut e)?;
+        }
+        Ok(len)
+    }
+}
+
+impl<T> LightningDecode for HashSet<T>
+where
+    T: LightningDecode + Eq + StdHash,
+{
+    fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+        let mut set = HashSet::new();
+        while let Some(item) = Option::<T>::lightning_decode(&mut d)? {
+            if !set.insert(item) {
+                Err(Error::InvalidValue)?
+            }
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_roundtrip<T>(value: T, bytes: &[u8])
+    where
+        T: LightningEncode + LightningDecode + PartialEq + core::fmt::Debug,
+    {
+        assert_eq!(lightning_encode(&value).unwrap(), bytes);
+        assert_eq!(lightning_decode::<T>(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn test_integers() {
+        test_roundtrip(0xA5u8, &[0xA5]);
+        test_roundtrip(0x0102u16, &[0x01, 0x02]);
+        test_roundtrip(0x01020304u32, &[0x01, 0x02, 0x03, 0x04]);
+        test_roundtrip(
+            0x0102030405060708u64,
+            &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08],
+        );
+    }
+
+    #[test]
+    fn test_vec() {
+        test_roundtrip(Vec::<u8>::new(), &[0x00, 0x00]);
+        test_roundtrip(vec![0xFFu8, 0x01], &[0x00, 0x02, 0xFF, 0x01]);
+        test_roundtrip(vec![0x0102u16], &[0x00, 0x01, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn test_trailing_option() {
+        test_roundtrip(None::<u16>, &[]);
+        test_roundtrip(Some(0x0102u16), &[0x01, 0x02]);
+        test_roundtrip(Some(vec![0x00u8]), &[0x00, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn test_unknown_tlvs() {
+        let mut stream = BTreeMap::new();
+        stream.insert(1u64, vec![0x2A]);
+        stream.insert(253u64, vec![]);
+        test_roundtrip(stream, &[0x01, 0x01, 0x2A, 0xFD, 0x00, 0xFD, 0x00]);
+        test_roundtrip(BTreeMap::<u64, Vec<u8>>::new(), &[]);
+
+        assert_eq!(
+            lightning_decode::<BTreeMap<u64, Vec<u8>>>(&[0x02, 0x00])
+                .unwrap_err(),
+            Error::TlvRecordEvenType
+        );
+        assert_eq!(
+            lightning_decode::<BTreeMap<u64, Vec<u8>>>(&[
+                0x03, 0x00, 0x01, 0x00
+            ])
+            .unwrap_err(),
+            Error::TlvStreamWrongOrder
+        );
+        assert_eq!(
+            lightning_decode::<BTreeMap<u64, Vec<u8>>>(&[
+                0x01, 0x00, 0x01, 0x00
+            ])
+            .unwrap_err(),
+            Error::TlvStreamDuplicateItem
+        );
+        assert_eq!(
+            lightning_decode::<BTreeMap<u64, Vec<u8>>>(&[0x01, 0x02, 0x00])
+                .unwrap_err(),
+            Error::TlvRecordInvalidLen
+        );
+    }
+
+    #[test]
+    fn test_set() {
+        let mut set = HashSet::new();
+        test_roundtrip(set.clone(), &[]);
+        set.insert(0x0304u16);
+        set.insert(0x0102u16);
+        test_roundtrip(set, &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(
+            lightning_decode::<HashSet<u16>>(&[0x01, 0x02, 0x01, 0x02])
+                .unwrap_err(),
+            Error::InvalidValue
+        );
+    }
+}
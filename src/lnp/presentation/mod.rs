@@ -13,6 +13,7 @@
 
 mod encoding;
 mod error;
+pub mod lightning_encoding;
 pub mod payload;
 pub mod tlv;
 
@@ -20,6 +21,7 @@ pub use encoding::{
     CreateUnmarshaller, Decode, Encode, Unmarshall, UnmarshallFn,
 };
 pub use error::{Error, UnknownTypeError};
+pub use lightning_encoding::{LightningDecode, LightningEncode};
 pub use payload::{Payload, Unmarshaller};
 
 use amplify::Wrapper;
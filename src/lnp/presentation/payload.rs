@@ -22,9 +22,9 @@ use std::sync::Arc;
 
 use super::tlv;
 use super::{
-    Encode, Error, EvenOdd, UnknownTypeError, Unmarshall, UnmarshallFn,
+    Encode, Error, EvenOdd, LightningDecode, LightningEncode, UnknownTypeError,
+    Unmarshall, UnmarshallFn,
 };
-use crate::strict_encoding::{StrictDecode, StrictEncode};
 
 /// Message type field value
 #[derive(
@@ -101,9 +101,11 @@ impl Encode for Payload {
 
     fn encode(&self) -> Result<Vec<u8>, Self::Error> {
         let mut e = io::Cursor::new(vec![]);
+        // Message type is always encoded as a big-endian `u16` value, as
+        // required by BOLT-1
         self.type_id
             .to_inner()
-            .strict_encode(&mut e)
+            .lightning_encode(&mut e)
             .expect("Memory encoders do not fail");
         e.write(&self.payload)?;
         Ok(e.into_inner())
@@ -175,8 +177,9 @@ where
         data: &dyn Borrow<[u8]>,
     ) -> Result<Self::Data, Self::Error> {
         let mut reader = io::Cursor::new(data.borrow());
-        let type_id =
-            TypeId(u16::strict_decode(&mut reader).map_err(|_| Error::NoData)?);
+        let type_id = TypeId(
+            u16::lightning_decode(&mut reader).map_err(|_| Error::NoData)?,
+        );
         match self.known_types.get(&type_id) {
             None if type_id.is_even() => Err(Error::MessageEvenType),
             None => {
@@ -18,13 +18,11 @@ use std::collections::BTreeMap;
 use std::io;
 use std::sync::Arc;
 
-#[cfg(feature = "lightning")]
-use lightning::{
-    ln::msgs::DecodeError,
-    util::ser::{BigSize, Readable},
+use super::lightning_encoding::{lightning_encode, LightningDecode};
+use super::{
+    BigSize, Error, EvenOdd, LightningEncode, Unmarshall, UnmarshallFn,
 };
-
-use super::{Error, EvenOdd, Unmarshall, UnmarshallFn};
+use crate::lnp::LNP_MSG_MAX_LEN;
 
 /// TLV type field value
 #[derive(
@@ -64,6 +62,54 @@ pub struct RawRecord(Vec<u8>);
 
 impl EvenOdd for Type {}
 
+impl LightningEncode for Type {
+    #[inline]
+    fn lightning_encode<E: io::Write>(&self, e: E) -> Result<usize, Error> {
+        BigSize(self.0).lightning_encode(e)
+    }
+}
+
+impl LightningDecode for Type {
+    #[inline]
+    fn lightning_decode<D: io::Read>(d: D) -> Result<Self, Error> {
+        Ok(Type(BigSize::lightning_decode(d)?.0))
+    }
+}
+
+/// Raw TLV record is serialized as a `BigSize` length followed by the record
+/// value bytes
+impl LightningEncode for RawRecord {
+    fn lightning_encode<E: io::Write>(&self, mut e: E) -> Result<usize, Error> {
+        let len = BigSize(self.0.len() as u64).lightning_encode(&mut e)?;
+        e.write_all(&self.0)?;
+        Ok(len + self.0.len())
+    }
+}
+
+impl LightningDecode for RawRecord {
+    fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+        let len = BigSize::lightning_decode(&mut d)?.0 as usize;
+
+        // if length exceeds the number of bytes remaining in the message
+        // MUST fail to parse the tlv_stream
+        // Here we don't known how many bytes are remaining, but we can be
+        // sure that this number is below Lightning message size limit, so we
+        // check against this conditions to make sure we are not attacked
+        // with excessive memory allocation vector. The actual condition from
+        // BOLT-1 is checked during `read_exact` call below: if the length
+        // exceeds the number of bytes left in the message it will return
+        // a error
+        if len > LNP_MSG_MAX_LEN {
+            Err(Error::TlvRecordInvalidLen)?;
+        }
+
+        let mut buf = vec![0u8; len];
+        d.read_exact(&mut buf[..])
+            .map_err(|_| Error::TlvRecordInvalidLen)?;
+        Ok(RawRecord(buf))
+    }
+}
+
 #[derive(Debug, Display, Default)]
 #[display(Debug)]
 pub struct Stream(BTreeMap<Type, Arc<dyn Any>>);
@@ -90,6 +136,142 @@ impl Stream {
     }
 }
 
+/// TLV stream consisting of raw (not yet parsed) records. Used by derive
+/// macros for serializing structure fields marked with `#[tlv(...)]`
+/// attributes and for keeping records unknown to the current implementation.
+#[derive(
+    Wrapper, Clone, PartialEq, Eq, Hash, Default, Debug, Display, From,
+)]
+#[display(Debug)]
+pub struct RawStream(BTreeMap<Type, RawRecord>);
+
+impl RawStream {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serializes `value` with lightning encoding and stores it as a record
+    /// with the given type. If the value serializes into an empty byte string
+    /// (like `None` or an empty set) the record is omitted.
+    pub fn set_value<T>(&mut self, type_id: u64, value: &T) -> Result<(), Error>
+    where
+        T: LightningEncode,
+    {
+        let data = lightning_encode(value)?;
+        if data.is_empty() {
+            self.0.remove(&Type(type_id));
+        } else {
+            self.0.insert(Type(type_id), RawRecord(data));
+        }
+        Ok(())
+    }
+
+    /// Extracts record with the given type and parses it as a value of type
+    /// `T`. Missed record is parsed from an empty byte string, which gives
+    /// `None` for optional values and empty collections.
+    ///
+    /// # Errors
+    /// Returns [`Error::TlvRecordInvalidLen`] if the record length is not
+    /// exactly equal to that required for `T` encoding; other errors are
+    /// returned as they are produced by `T` decoder.
+    pub fn take_value<T>(&mut self, type_id: u64) -> Result<T, Error>
+    where
+        T: LightningDecode,
+    {
+        let data = self
+            .0
+            .remove(&Type(type_id))
+            .map(RawRecord::into_inner)
+            .unwrap_or_default();
+        let mut cursor = io::Cursor::new(data);
+        let value =
+            T::lightning_decode(&mut cursor).map_err(|err| match err {
+                // Record value is shorter than required for `T` encoding
+                Error::Io(_) => Error::TlvRecordInvalidLen,
+                err => err,
+            })?;
+        if cursor.position() as usize != cursor.get_ref().len() {
+            Err(Error::TlvRecordInvalidLen)?
+        }
+        Ok(value)
+    }
+
+    /// Checks that the stream does not contain even records. Must be called
+    /// once all records known to the implementation were taken out of the
+    /// stream, since an unknown even record makes the whole stream invalid.
+    pub fn ensure_odd(&self) -> Result<(), Error> {
+        // if type is unknown and even MUST fail to parse the tlv_stream
+        if self.0.keys().any(Type::is_even) {
+            Err(Error::TlvRecordEvenType)?
+        }
+        Ok(())
+    }
+}
+
+impl From<BTreeMap<u64, Vec<u8>>> for RawStream {
+    fn from(map: BTreeMap<u64, Vec<u8>>) -> Self {
+        RawStream(
+            map.into_iter()
+                .map(|(type_id, value)| (Type(type_id), RawRecord(value)))
+                .collect(),
+        )
+    }
+}
+
+impl From<RawStream> for BTreeMap<u64, Vec<u8>> {
+    fn from(stream: RawStream) -> Self {
+        stream
+            .0
+            .into_iter()
+            .map(|(type_id, rec)| (type_id.into_inner(), rec.into_inner()))
+            .collect()
+    }
+}
+
+impl LightningEncode for RawStream {
+    fn lightning_encode<E: io::Write>(&self, mut e: E) -> Result<usize, Error> {
+        let mut len = 0;
+        // BTreeMap guarantees that the records are written in a
+        // strictly-increasing order of their types
+        for (type_id, rec) in &self.0 {
+            len += type_id.lightning_encode(&mut e)?;
+            len += rec.lightning_encode(&mut e)?;
+        }
+        Ok(len)
+    }
+}
+
+/// Parses the stream up to the end of the data. Since the records are not
+/// interpreted, unknown even types are not checked; use
+/// [`RawStream::ensure_odd`] after the known records were extracted.
+impl LightningDecode for RawStream {
+    fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, Error> {
+        let mut stream = BTreeMap::new();
+        let mut prev_type_id: Option<Type> = None;
+        // if zero bytes remain before parsing a type
+        // MUST stop parsing the tlv_stream
+        while let Some(type_id) = Option::<Type>::lightning_decode(&mut d)? {
+            match prev_type_id {
+                // if decoded `type`s are not strictly-increasing
+                // (including situations when two or more occurrences of the
+                // same `type` are met)
+                // MUST fail to parse the tlv_stream.
+                Some(prev) if type_id == prev => {
+                    Err(Error::TlvStreamDuplicateItem)?
+                }
+                Some(prev) if type_id < prev => {
+                    Err(Error::TlvStreamWrongOrder)?
+                }
+                _ => {}
+            }
+            stream.insert(type_id, RawRecord::lightning_decode(&mut d)?);
+            prev_type_id = Some(type_id);
+        }
+        Ok(RawStream(stream))
+    }
+}
+
 pub struct Unmarshaller {
     known_types: BTreeMap<Type, UnmarshallFn<Error>>,
     raw_parser: UnmarshallFn<Error>,
@@ -99,81 +281,67 @@ impl Unmarshall for Unmarshaller {
     type Data = Stream;
     type Error = Error;
 
-    // TODO: (v0.2.0) Right now TLV implementation uses BigInt from lightning
-    //       library. It has to be re-implemented in order to get TLVs working
-    #[cfg(not(feature = "lightning"))]
-    fn unmarshall(
-        &self,
-        data: &dyn Borrow<[u8]>,
-    ) -> Result<Stream, Self::Error> {
-        unimplemented!()
-    }
-
-    #[cfg(feature = "lightning")]
     fn unmarshall(
         &self,
         data: &dyn Borrow<[u8]>,
     ) -> Result<Stream, Self::Error> {
         let mut reader = io::Cursor::new(data.borrow());
         let mut tlv = Stream::new();
-        let mut prev_type_id = Type(0);
+        let mut prev_type_id: Option<Type> = None;
         loop {
-            match BigSize::read(&mut reader).map(|big_size| Type(big_size.0)) {
+            // The following rule is handled by BigSize type:
+            // if a type or length is not minimally encoded
+            // MUST fail to parse the tlv_stream.
+            match Option::<Type>::lightning_decode(&mut reader)? {
                 // if zero bytes remain before parsing a type
                 // MUST stop parsing the tlv_stream
-                Err(DecodeError::ShortRead) => break Ok(tlv),
-
-                // The following rule is handled by BigSize type:
-                // if a type or length is not minimally encoded
-                // MUST fail to parse the tlv_stream.
-                Err(err) => break Err(Error::from(err)),
-
-                // if decoded types are not monotonically-increasing
-                // MUST fail to parse the tlv_stream.
-                Ok(type_id) if type_id > prev_type_id => {
-                    break Err(Error::TlvStreamWrongOrder)
-                }
+                None => break Ok(tlv),
 
                 // if decoded `type`s are not strictly-increasing
-                // (including situations when two or more occurrences of the \
+                // (including situations when two or more occurrences of the
                 // same `type` are met)
                 // MUST fail to parse the tlv_stream.
-                Ok(type_id) if tlv.contains_key(&type_id) => {
+                Some(type_id) if tlv.contains_key(&type_id) => {
                     break Err(Error::TlvStreamDuplicateItem)
                 }
+                Some(type_id)
+                    if prev_type_id.map(|prev| type_id < prev)
+                        == Some(true) =>
+                {
+                    break Err(Error::TlvStreamWrongOrder)
+                }
 
-                Ok(type_id) => {
-                    let rec = if let Some(parser) =
-                        self.known_types.get(&type_id)
-                    {
-                        // if type is known:
-                        // MUST decode the next length bytes using the known
-                        // encoding for type.
-                        // The rest of rules MUST be supported by the parser:
-                        // - if length is not exactly equal to that required for
-                        //   the known encoding for type MUST fail to parse the
-                        //   tlv_stream.
-                        // - if variable-length fields within the known encoding
-                        //   for type are not minimal MUST fail to parse the
-                        //   tlv_stream.
-                        parser(&mut reader)?
-                    }
-                    // otherwise, if type is unknown:
-                    // if type is even:
-                    // MUST fail to parse the tlv_stream.
-                    else if type_id.is_even() {
-                        break Err(Error::TlvRecordEvenType);
-                    }
-                    // otherwise, if type is odd:
-                    // MUST discard the next length bytes.
-                    else {
-                        // Here we are actually not discarding the bytes but
-                        // rather store them for an upstream users of the
-                        // library which may know the meaning of the bytes
-                        (self.raw_parser)(&mut reader)?
-                    };
-                    tlv.insert(type_id, rec);
-                    prev_type_id = type_id;
+                Some(type_id) => {
+                    let rec =
+                        if let Some(parser) = self.known_types.get(&type_id) {
+                            // if type is known:
+                            // MUST decode the next length bytes using the known
+                            // encoding for type.
+                            // The rest of rules MUST be supported by the parser:
+                            // - if length is not exactly equal to that required for
+                            //   the known encoding for type MUST fail to parse the
+                            //   tlv_stream.
+                            // - if variable-length fields within the known encoding
+                            //   for type are not minimal MUST fail to parse the
+                            //   tlv_stream.
+                            parser(&mut reader)?
+                        }
+                        // otherwise, if type is unknown:
+                        // if type is even:
+                        // MUST fail to parse the tlv_stream.
+                        else if type_id.is_even() {
+                            break Err(Error::TlvRecordEvenType);
+                        }
+                        // otherwise, if type is odd:
+                        // MUST discard the next length bytes.
+                        else {
+                            // Here we are actually not discarding the bytes but
+                            // rather store them for an upstream users of the
+                            // library which may know the meaning of the bytes
+                            (self.raw_parser)(&mut reader)?
+                        };
+                    tlv.0.insert(type_id, rec);
+                    prev_type_id = Some(type_id);
                 }
             }
         }
@@ -188,38 +356,105 @@ impl Unmarshaller {
         }
     }
 
-    // TODO: (v0.2.0) Right now TLV implementation uses BigInt from lightning
-    //       library. It has to be re-implemented in order to get TLVs working
-    #[cfg(not(feature = "lightning"))]
+    /// Registers parser for a TLV record type known to the implementation.
+    /// The parser must read both record length and value.
+    #[inline]
+    pub fn insert_parser(
+        &mut self,
+        type_id: Type,
+        parser: UnmarshallFn<Error>,
+    ) -> bool {
+        self.known_types.insert(type_id, parser).is_none()
+    }
+
     fn raw_parser(reader: &mut dyn io::Read) -> Result<Arc<dyn Any>, Error> {
-        unimplemented!()
+        Ok(Arc::new(RawRecord::lightning_decode(reader)?))
     }
+}
 
-    #[cfg(feature = "lightning")]
-    fn raw_parser(
-        mut reader: &mut dyn io::Read,
-    ) -> Result<Arc<dyn Any>, Error> {
-        let len = BigSize::read(&mut reader)?.0 as usize;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        // if length exceeds the number of bytes remaining in the message
-        // MUST fail to parse the tlv_stream
-        // Here we don't known how many bytes are remaining, but we can be
-        // sure that this number is below Lightning message size limit, so we
-        // check against this conditions to make sure we are not attacked
-        // with excessive memory allocation vector. The actual condition from
-        // BOLT-2 is checked during `read_exact` call below: if the length
-        // exceeds the number of bytes left in the message it will return
-        // a error
-        if len > crate::lnp::LNP_MSG_MAX_LEN {
-            Err(Error::TlvRecordInvalidLen)?;
-        }
+    fn raw_stream(data: &[u8]) -> Result<RawStream, Error> {
+        RawStream::lightning_decode(data)
+    }
 
-        let mut buf = vec![0u8; len];
-        reader
-            .read_exact(&mut buf[..])
-            .map_err(|_| Error::TlvRecordInvalidLen)?;
+    #[test]
+    fn test_raw_stream_roundtrip() {
+        let data = [0x01, 0x00, 0x02, 0x01, 0x2a, 0xfd, 0x00, 0xfd, 0x00];
+        let stream = raw_stream(&data).unwrap();
+        assert_eq!(stream.as_inner().len(), 3);
+        assert_eq!(
+            stream.as_inner().get(&Type(2)),
+            Some(&RawRecord(vec![0x2a]))
+        );
+        assert_eq!(lightning_encode(&stream).unwrap(), data.to_vec());
+        assert_eq!(raw_stream(&[]).unwrap(), RawStream::new());
+    }
+
+    #[test]
+    fn test_raw_stream_failures() {
+        // Wrong order
+        assert_eq!(
+            raw_stream(&[0x02, 0x00, 0x01, 0x00]),
+            Err(Error::TlvStreamWrongOrder)
+        );
+        // Duplicated type
+        assert_eq!(
+            raw_stream(&[0x01, 0x00, 0x01, 0x00]),
+            Err(Error::TlvStreamDuplicateItem)
+        );
+        // Length exceeding the remaining data
+        assert_eq!(
+            raw_stream(&[0x01, 0x02, 0x00]),
+            Err(Error::TlvRecordInvalidLen)
+        );
+        // Non-minimally encoded type
+        assert_eq!(
+            raw_stream(&[0xfd, 0x00, 0x01, 0x00]),
+            Err(Error::InvalidValue)
+        );
+        // Unknown even type
+        let stream = raw_stream(&[0x02, 0x00]).unwrap();
+        assert_eq!(stream.ensure_odd(), Err(Error::TlvRecordEvenType));
+        assert_eq!(raw_stream(&[0x03, 0x00]).unwrap().ensure_odd(), Ok(()));
+    }
+
+    #[test]
+    fn test_raw_stream_values() {
+        let mut stream = RawStream::new();
+        stream.set_value(1, &Some(5u16)).unwrap();
+        stream.set_value(3, &Option::<u16>::None).unwrap();
+        assert_eq!(
+            lightning_encode(&stream).unwrap(),
+            vec![0x01, 0x02, 0x00, 0x05]
+        );
+        assert_eq!(stream.clone().take_value::<Option<u16>>(1), Ok(Some(5)));
+        assert_eq!(stream.clone().take_value::<Option<u16>>(3), Ok(None));
+        assert_eq!(
+            stream.clone().take_value::<u8>(1),
+            Err(Error::TlvRecordInvalidLen)
+        );
+    }
 
-        let rec = RawRecord(buf.to_vec());
-        Ok(Arc::new(rec))
+    #[test]
+    fn test_unmarshaller() {
+        let unmarshaller = Unmarshaller::new();
+        let stream = unmarshaller.unmarshall(&[0x01, 0x01, 0x2a]).unwrap();
+        assert_eq!(
+            stream.get::<RawRecord>(&Type(1)),
+            Some(&RawRecord(vec![0x2a]))
+        );
+        assert_eq!(
+            unmarshaller.unmarshall(&[0x02, 0x00]).unwrap_err(),
+            Error::TlvRecordEvenType
+        );
+        assert_eq!(
+            unmarshaller
+                .unmarshall(&[0x03, 0x00, 0x01, 0x00])
+                .unwrap_err(),
+            Error::TlvStreamWrongOrder
+        );
     }
 }
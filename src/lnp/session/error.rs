@@ -0,0 +1,137 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::io::ErrorKind;
+
+use super::{DecryptionError, HandshakeError};
+use crate::lnp::transport;
+
+/// Reasons for which a session gets closed
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display)]
+#[display(doc_comments)]
+pub enum CloseReason {
+    /// session was closed by the local node
+    Local,
+
+    /// connection was closed by the remote peer
+    Remote,
+
+    /// remote peer has failed the connection with an error message
+    RemoteError,
+
+    /// remote peer has violated the protocol
+    ProtocolViolation,
+
+    /// connection has failed at the transport level
+    TransportFailure,
+}
+
+/// Session-level errors
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum Error {
+    /// {0}
+    Transport(transport::Error),
+
+    /// noise handshake with the remote peer has failed
+    HandshakeFailure,
+
+    /// unable to decrypt the received frame: the frame is either corrupted
+    /// or encrypted with a different key
+    DecryptionFailure,
+
+    /// remote peer has violated the protocol: {0}
+    ProtocolViolation(&'static str),
+
+    /// session is closed: {0}
+    Closed(CloseReason),
+}
+
+impl From<transport::Error> for Error {
+    fn from(err: transport::Error) -> Self {
+        match err {
+            transport::Error::HandshakeFailure => Error::HandshakeFailure,
+            transport::Error::DecryptionFailure => Error::DecryptionFailure,
+            transport::Error::SocketIo(ErrorKind::UnexpectedEof)
+            | transport::Error::SocketIo(ErrorKind::ConnectionReset)
+            | transport::Error::SocketIo(ErrorKind::ConnectionAborted)
+            | transport::Error::SocketIo(ErrorKind::BrokenPipe) => {
+                Error::Closed(CloseReason::Remote)
+            }
+            err => Error::Transport(err),
+        }
+    }
+}
+
+impl From<DecryptionError> for Error {
+    fn from(_: DecryptionError) -> Self {
+        Error::DecryptionFailure
+    }
+}
+
+impl From<HandshakeError> for Error {
+    fn from(_: HandshakeError) -> Self {
+        Error::HandshakeFailure
+    }
+}
+
+impl Error {
+    /// Returns reason for closing the session after the error, or `None` if
+    /// the session still can be used
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        match self {
+            Error::Transport(transport::Error::TimedOut) => None,
+            Error::Transport(_) => Some(CloseReason::TransportFailure),
+            Error::HandshakeFailure
+            | Error::DecryptionFailure
+            | Error::ProtocolViolation(_) => {
+                Some(CloseReason::ProtocolViolation)
+            }
+            Error::Closed(reason) => Some(*reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transport_conversion() {
+        assert_eq!(
+            Error::from(transport::Error::SocketIo(ErrorKind::UnexpectedEof)),
+            Error::Closed(CloseReason::Remote)
+        );
+        assert_eq!(
+            Error::from(transport::Error::HandshakeFailure),
+            Error::HandshakeFailure
+        );
+        assert_eq!(Error::from(DecryptionError), Error::DecryptionFailure);
+        assert_eq!(
+            Error::from(transport::Error::TimedOut),
+            Error::Transport(transport::Error::TimedOut)
+        );
+        assert_eq!(
+            Error::from(transport::Error::TimedOut).close_reason(),
+            None
+        );
+        assert_eq!(
+            Error::DecryptionFailure.close_reason(),
+            Some(CloseReason::ProtocolViolation)
+        );
+        assert_eq!(
+            Error::from(transport::Error::FrameTooSmall(2)).close_reason(),
+            Some(CloseReason::TransportFailure)
+        );
+    }
+}
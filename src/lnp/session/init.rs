@@ -11,49 +11,84 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use crate::lnp::transport::Error;
+use amplify::internet::InetSocketAddr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::lnp::transport::{ftcp, Error};
 use crate::lnp::{
     session, zmqsocket, LocalNode, LocalSocketAddr, NodeAddr, RemoteNodeAddr,
     RemoteSocketAddr, Session,
 };
 
+lazy_static! {
+    /// TCP listeners bound by [`Accept`] for remote node addresses, which are
+    /// kept open to accept further sessions on the same address
+    static ref LISTENERS: Mutex<HashMap<InetSocketAddr, Arc<ftcp::Listener>>> =
+        Mutex::new(empty!());
+}
+
+/// Returns TCP listener bound to `inet_addr`, binding it on the first call
+fn listener(inet_addr: InetSocketAddr) -> Result<Arc<ftcp::Listener>, Error> {
+    let mut listeners = LISTENERS
+        .lock()
+        .expect("TCP listeners registry is poisoned");
+    if let Some(listener) = listeners.get(&inet_addr) {
+        return Ok(listener.clone());
+    }
+    let listener = Arc::new(ftcp::Listener::bind(inet_addr)?);
+    listeners.insert(inet_addr, listener.clone());
+    Ok(listener)
+}
+
 pub trait Connect {
     fn connect(&self, node: &LocalNode) -> Result<Box<dyn Session>, Error>;
 }
 
+/// Accepts a single incoming session. For network addresses the listening
+/// socket is bound on the first call and kept open, so the next calls accept
+/// further sessions on the same address.
 pub trait Accept {
     fn accept(&self, node: &LocalNode) -> Result<Box<dyn Session>, Error>;
 }
 
 impl Connect for LocalSocketAddr {
     fn connect(&self, local: &LocalNode) -> Result<Box<dyn Session>, Error> {
-        Ok(Box::new(match self {
-            LocalSocketAddr::Zmq(locator) => {
-                session::Raw::with_zmq_unencrypted(
-                    zmqsocket::ZmqType::Req,
-                    locator,
-                    None,
-                    None,
-                )?
+        Ok(match self {
+            LocalSocketAddr::Zmq(locator, zmq_type) => {
+                Box::new(session::Raw::with_zmq_unencrypted(
+                    *zmq_type, locator, None, None,
+                )?) as Box<dyn Session>
+            }
+            #[cfg(not(target_os = "windows"))]
+            LocalSocketAddr::Posix(path) => {
+                Box::new(session::Raw::connect_posix_unencrypted(path)?)
+            }
+            #[cfg(target_os = "windows")]
+            LocalSocketAddr::Posix(_) => {
+                Err(Error::SocketIo(std::io::ErrorKind::AddrNotAvailable))?
             }
-            LocalSocketAddr::Posix(_) => unimplemented!(),
-        }))
+        })
     }
 }
 
 impl Accept for LocalSocketAddr {
     fn accept(&self, local: &LocalNode) -> Result<Box<dyn Session>, Error> {
-        Ok(Box::new(match self {
-            LocalSocketAddr::Zmq(locator) => {
-                session::Raw::with_zmq_unencrypted(
-                    zmqsocket::ZmqType::Req,
-                    locator,
-                    None,
-                    None,
-                )?
+        Ok(match self {
+            LocalSocketAddr::Zmq(locator, zmq_type) => {
+                Box::new(session::Raw::with_zmq_unencrypted(
+                    *zmq_type, locator, None, None,
+                )?) as Box<dyn Session>
+            }
+            #[cfg(not(target_os = "windows"))]
+            LocalSocketAddr::Posix(path) => {
+                Box::new(session::Raw::accept_posix_unencrypted(path)?)
             }
-            LocalSocketAddr::Posix(_) => unimplemented!(),
-        }))
+            #[cfg(target_os = "windows")]
+            LocalSocketAddr::Posix(_) => {
+                Err(Error::SocketIo(std::io::ErrorKind::AddrNotAvailable))?
+            }
+        })
     }
 }
 
@@ -61,24 +96,36 @@ impl Connect for RemoteNodeAddr {
     fn connect(&self, local: &LocalNode) -> Result<Box<dyn Session>, Error> {
         Ok(match self.remote_addr {
             RemoteSocketAddr::Ftcp(inet) => {
-                Box::new(session::Raw::connect_ftcp_unencrypted(inet)?)
-                    as Box<dyn Session>
+                Box::new(session::Raw::connect_ftcp_encrypted(
+                    self.node_id,
+                    inet,
+                    local,
+                )?) as Box<dyn Session>
             }
             #[cfg(feature = "zmq")]
-            // TODO: (v0.3) pass specific ZMQ API type using additional
-            //       `RemoteAddr` field
-            RemoteSocketAddr::Zmq(socket) => {
+            RemoteSocketAddr::Zmq(socket, zmq_type) => {
                 Box::new(session::Raw::with_zmq_unencrypted(
-                    zmqsocket::ZmqType::Req,
+                    zmq_type,
                     &zmqsocket::ZmqSocketAddr::Tcp(socket),
                     None,
                     None,
                 )?)
             }
-            RemoteSocketAddr::Http(_) => unimplemented!(),
-            #[cfg(feature = "websocket")]
-            RemoteSocketAddr::Websocket(_) => unimplemented!(),
-            RemoteSocketAddr::Smtp(_) => unimplemented!(),
+            // NB: HTTP is used for request/response RPC APIs exposed through
+            //     proxies, which can't run noise handshake with unbalanced
+            //     number of acts, while remote node sessions must always be
+            //     authenticated. RPC clients should use
+            //     `session::Raw::connect_http_unencrypted` directly.
+            RemoteSocketAddr::Http(_) => Err(Error::HttpNodeNotSupported)?,
+            #[cfg(feature = "websockets")]
+            RemoteSocketAddr::Websocket(inet) => {
+                Box::new(session::Raw::connect_websocket_encrypted(
+                    self.node_id,
+                    inet,
+                    local,
+                )?)
+            }
+            RemoteSocketAddr::Smtp(_) => Err(Error::SmtpConfigRequired)?,
         })
     }
 }
@@ -86,29 +133,63 @@ impl Connect for RemoteNodeAddr {
 impl Accept for RemoteNodeAddr {
     fn accept(&self, local: &LocalNode) -> Result<Box<dyn Session>, Error> {
         Ok(match self.remote_addr {
-            RemoteSocketAddr::Ftcp(inet) => {
-                Box::new(session::Raw::accept_ftcp_unencrypted(inet)?)
-                    as Box<dyn Session>
-            }
+            // NB: Sessions are accepted from any remote node; callers which
+            //     need to know the remote node id should use
+            //     `session::Raw::accept_ftcp_encrypted_from` directly
+            RemoteSocketAddr::Ftcp(inet) => Box::new(
+                session::Raw::accept_ftcp_encrypted_from(
+                    &*listener(inet)?,
+                    local,
+                )?
+                .0,
+            ) as Box<dyn Session>,
             #[cfg(feature = "zmq")]
-            // TODO: (v0.3) pass specific ZMQ API type using additional
-            //       `RemoteAddr` field
-            RemoteSocketAddr::Zmq(socket) => {
+            RemoteSocketAddr::Zmq(socket, zmq_type) => {
                 Box::new(session::Raw::with_zmq_unencrypted(
-                    zmqsocket::ZmqType::Req,
+                    zmq_type,
                     &zmqsocket::ZmqSocketAddr::Tcp(socket),
                     None,
                     None,
                 )?)
             }
-            RemoteSocketAddr::Http(_) => unimplemented!(),
-            #[cfg(feature = "websocket")]
-            RemoteSocketAddr::Websocket(_) => unimplemented!(),
-            RemoteSocketAddr::Smtp(_) => unimplemented!(),
+            RemoteSocketAddr::Http(_) => Err(Error::HttpNodeNotSupported)?,
+            #[cfg(feature = "websockets")]
+            RemoteSocketAddr::Websocket(inet) => Box::new(
+                session::Raw::accept_websocket_encrypted_from(
+                    &*listener(inet)?,
+                    local,
+                )?
+                .0,
+            ),
+            RemoteSocketAddr::Smtp(_) => Err(Error::SmtpConfigRequired)?,
         })
     }
 }
 
+impl RemoteNodeAddr {
+    /// Connects to the remote node through SOCKS5 `proxy`, which is required
+    /// for remote nodes having Tor onion addresses. Only framed TCP
+    /// connections can be proxied; for other framing protocols this is the
+    /// same as [`Connect::connect`].
+    pub fn connect_via(
+        &self,
+        proxy: std::net::SocketAddr,
+        local: &LocalNode,
+    ) -> Result<Box<dyn Session>, Error> {
+        match self.remote_addr {
+            RemoteSocketAddr::Ftcp(inet) => {
+                Ok(Box::new(session::Raw::connect_ftcp_encrypted_via(
+                    self.node_id,
+                    inet,
+                    proxy,
+                    local,
+                )?))
+            }
+            _ => self.connect(local),
+        }
+    }
+}
+
 impl Connect for NodeAddr {
     fn connect(&self, local: &LocalNode) -> Result<Box<dyn Session>, Error> {
         match self {
@@ -57,6 +57,11 @@ impl LocalNode {
     pub fn sign(&self, message: &secp256k1::Message) -> secp256k1::Signature {
         SECP256K1.sign(message, &self.private_key)
     }
+
+    /// Static node private key, used during BOLT-8 noise handshake
+    pub(crate) fn private_key(&self) -> secp256k1::SecretKey {
+        self.private_key
+    }
 }
 
 impl Display for LocalNode {
@@ -14,20 +14,31 @@
 //! BOLT-8 related structures and functions covering Lightning network
 //! transport layer
 
+mod error;
 mod init;
 mod local_node;
+pub mod mux;
 pub mod node_addr;
 mod noise;
 mod session;
 mod transcoders;
 
+pub use error::{CloseReason, Error};
 pub use init::{Accept, Connect};
 pub use local_node::LocalNode;
+pub use mux::{Multiplexer, StreamId};
 pub use node_addr::{
     NodeAddr, PartialNodeAddr, RemoteNodeAddr, ToNodeAddr, ToRemoteNodeAddr,
 };
-pub use noise::{NoiseDecryptor, NoiseEncryptor, NoiseTranscoder};
+pub use noise::{
+    HandshakeError, HandshakeState, NoiseDecryptor, NoiseEncryptor,
+    NoiseTranscoder,
+};
 pub use session::{Input, Output, Raw, RawInput, RawOutput, Session, Split};
 pub use transcoders::{
     Decrypt, DecryptionError, Encrypt, PlainTranscoder, Transcode,
 };
+#[cfg(feature = "async")]
+pub use session::{AsyncRaw, AsyncSession};
+#[cfg(feature = "async")]
+pub use transcoders::AsyncDecrypt;
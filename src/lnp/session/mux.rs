@@ -0,0 +1,559 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Multiplexing of named logical streams (like "lnpwp", "rgb" or "storm")
+//! over a single [`Session`], such that different protocols may share the
+//! same encrypted connection to a remote node.
+//!
+//! Each session message carries a multiplexer frame consisting of a 2-byte
+//! big-endian stream id, 1-byte frame type and frame body:
+//! * `OPEN` frame body is the UTF-8 name of the stream being opened;
+//! * `DATA` frame body is the stream message;
+//! * `CLOSE` frame has no body;
+//! * `CREDIT` frame body is a 4-byte big-endian number of messages the
+//!   remote peer is allowed to send in addition to the already granted ones.
+//!
+//! Flow control is credit-based: each stream starts with [`INITIAL_CREDIT`]
+//! messages, and the receiving side grants more credit as the application
+//! consumes messages. Messages exceeding the credit are queued locally and
+//! are sent in round-robin fashion across streams, so a single busy stream
+//! can't starve others.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use super::{Error as SessionError, Session};
+use crate::lnp::presentation::payload::TypedEnum;
+use crate::lnp::presentation::{self, Unmarshall, Unmarshaller};
+use crate::lnp::transport;
+use crate::lnp::LNP_MSG_MAX_LEN;
+
+/// Identifier of the logical stream within a multiplexed session
+pub type StreamId = u16;
+
+/// Number of messages each side may send over a newly opened stream before
+/// receiving additional credit
+pub const INITIAL_CREDIT: u32 = 16;
+
+/// Size of the multiplexer frame header
+pub const MUX_HEADER_SIZE: usize = 3;
+
+/// Maximum size of the message which can be sent over a logical stream
+pub const MAX_STREAM_MESSAGE_LEN: usize = LNP_MSG_MAX_LEN - MUX_HEADER_SIZE;
+
+const FRAME_OPEN: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_CLOSE: u8 = 2;
+const FRAME_CREDIT: u8 = 3;
+
+/// Multiplexer errors
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum Error {
+    /// transport error: {0}
+    #[from]
+    Transport(transport::Error),
+
+    /// session error: {0}
+    #[from]
+    Session(SessionError),
+
+    /// unable to parse stream message: {0}
+    #[from]
+    Presentation(presentation::Error),
+
+    /// stream with id {0} is not known
+    UnknownStream(StreamId),
+
+    /// stream with id {0} is closed
+    StreamClosed(StreamId),
+
+    /// stream named `{0}` is already open
+    DuplicateStream(String),
+
+    /// all stream ids are used by open streams
+    StreamIdsExhausted,
+
+    /// remote peer has sent more messages to stream {0} than it was allowed
+    FlowViolation(StreamId),
+
+    /// multiplexer frame is broken: {0}
+    BrokenFrame(&'static str),
+}
+
+#[derive(Debug)]
+struct Stream {
+    name: String,
+    /// Number of messages we are allowed to send
+    send_credit: u32,
+    /// Number of messages the remote peer is allowed to send
+    recv_credit: u32,
+    /// Number of messages consumed by the application since the last credit
+    /// grant
+    consumed: u32,
+    outbound: VecDeque<Vec<u8>>,
+    inbound: VecDeque<Vec<u8>>,
+    closed_locally: bool,
+    closed_remotely: bool,
+    close_sent: bool,
+}
+
+impl Stream {
+    fn with(name: String) -> Self {
+        Self {
+            name,
+            send_credit: INITIAL_CREDIT,
+            recv_credit: INITIAL_CREDIT,
+            consumed: 0,
+            outbound: empty!(),
+            inbound: empty!(),
+            closed_locally: false,
+            closed_remotely: false,
+            close_sent: false,
+        }
+    }
+}
+
+/// Multiplexer running named logical streams over a single session. Both
+/// peers must wrap their sides of the session into the multiplexer, with one
+/// of them being an initiator and the other one a responder.
+pub struct Multiplexer {
+    session: Box<dyn Session>,
+    initiator: bool,
+    streams: BTreeMap<StreamId, Stream>,
+    accepted: VecDeque<StreamId>,
+    /// Id of the last stream which has sent a queued message; used for
+    /// round-robin scheduling
+    last_served: StreamId,
+}
+
+impl Multiplexer {
+    /// Wraps `session` into the multiplexer. The `initiator` flag must be set
+    /// on exactly one side of the session: stream ids opened by the initiator
+    /// are odd and by the responder – even, so the sides never collide.
+    pub fn with(session: impl Session + 'static, initiator: bool) -> Self {
+        Self {
+            session: Box::new(session),
+            initiator,
+            streams: empty!(),
+            accepted: empty!(),
+            last_served: 0,
+        }
+    }
+
+    /// Opens new logical stream with the given `name`
+    pub fn open(&mut self, name: &str) -> Result<StreamId, Error> {
+        if self.find(name).is_some() {
+            Err(Error::DuplicateStream(name.to_owned()))?
+        }
+        let start = if self.initiator { 1 } else { 2 };
+        let id = (start..=StreamId::MAX)
+            .step_by(2)
+            .find(|id| !self.streams.contains_key(id))
+            .ok_or(Error::StreamIdsExhausted)?;
+        self.send_frame(id, FRAME_OPEN, name.as_bytes())?;
+        self.streams.insert(id, Stream::with(name.to_owned()));
+        Ok(id)
+    }
+
+    /// Waits for the remote peer to open a new stream, returning its id and
+    /// name
+    pub fn accept(&mut self) -> Result<(StreamId, String), Error> {
+        loop {
+            if let Some(id) = self.accepted.pop_front() {
+                let name = self.stream(id)?.name.clone();
+                return Ok((id, name));
+            }
+            self.pump()?;
+        }
+    }
+
+    /// Closes stream with the given `id`. Messages which were received but
+    /// not consumed yet are discarded; queued outbound messages are sent
+    /// before the stream gets closed. The stream id is released once the
+    /// remote peer acknowledges the closing.
+    pub fn close(&mut self, id: StreamId) -> Result<(), Error> {
+        let stream = self.stream_mut(id)?;
+        if stream.closed_locally {
+            Err(Error::StreamClosed(id))?
+        }
+        stream.closed_locally = true;
+        stream.inbound.clear();
+        self.flush()
+    }
+
+    /// Returns name of the stream with the given `id`
+    pub fn stream_name(&self, id: StreamId) -> Option<&str> {
+        self.streams.get(&id).map(|stream| stream.name.as_str())
+    }
+
+    /// Returns id of the open stream with the given `name`
+    pub fn stream_id(&self, name: &str) -> Option<StreamId> {
+        self.find(name)
+    }
+
+    /// Returns number of messages queued for the stream because of the
+    /// exhausted flow control credit
+    pub fn pending(&self, id: StreamId) -> usize {
+        self.streams
+            .get(&id)
+            .map(|stream| stream.outbound.len())
+            .unwrap_or_default()
+    }
+
+    /// Sends message over the stream with the given `id`. If the stream has
+    /// no flow control credit left the message is queued and sent once the
+    /// remote peer grants more credit.
+    pub fn send(&mut self, id: StreamId, message: &[u8]) -> Result<(), Error> {
+        if message.len() > MAX_STREAM_MESSAGE_LEN {
+            Err(transport::Error::OversizedFrame(message.len()))?
+        }
+        let stream = self.stream_mut(id)?;
+        if stream.closed_locally || stream.closed_remotely {
+            Err(Error::StreamClosed(id))?
+        }
+        stream.outbound.push_back(message.to_vec());
+        self.flush()
+    }
+
+    /// Receives next message from the stream with the given `id`, processing
+    /// messages for other streams which may arrive in between. Once the
+    /// stream is closed by the remote peer and all its messages are
+    /// consumed, the stream id is released.
+    pub fn recv(&mut self, id: StreamId) -> Result<Vec<u8>, Error> {
+        self.stream(id)?;
+        loop {
+            // Stream may be released while we process incoming frames
+            let stream =
+                self.streams.get_mut(&id).ok_or(Error::StreamClosed(id))?;
+            if stream.closed_locally {
+                Err(Error::StreamClosed(id))?
+            }
+            if let Some(message) = stream.inbound.pop_front() {
+                stream.consumed += 1;
+                let grant = if stream.consumed >= INITIAL_CREDIT / 2
+                    && !stream.closed_remotely
+                {
+                    let grant = stream.consumed;
+                    stream.consumed = 0;
+                    stream.recv_credit += grant;
+                    Some(grant)
+                } else {
+                    None
+                };
+                if stream.closed_remotely && stream.inbound.is_empty() {
+                    self.streams.remove(&id);
+                }
+                if let Some(grant) = grant {
+                    self.send_frame(id, FRAME_CREDIT, &grant.to_be_bytes())?;
+                }
+                return Ok(message);
+            }
+            if stream.closed_remotely {
+                Err(Error::StreamClosed(id))?
+            }
+            self.pump()?;
+        }
+    }
+
+    /// Receives next message from the stream with the given `id` and parses
+    /// it with the `unmarshaller` specific for the stream protocol
+    pub fn recv_message<T>(
+        &mut self,
+        id: StreamId,
+        unmarshaller: &Unmarshaller<T>,
+    ) -> Result<Arc<T>, Error>
+    where
+        T: TypedEnum,
+    {
+        let payload = self.recv(id)?;
+        Ok(unmarshaller.unmarshall(&payload)?)
+    }
+
+    /// Sends queued messages within the available flow control credit,
+    /// taking one message from each of the streams in turn
+    fn flush(&mut self) -> Result<(), Error> {
+        loop {
+            let next = self
+                .streams
+                .range((Bound::Excluded(self.last_served), Bound::Unbounded))
+                .chain(self.streams.range(..=self.last_served))
+                .find(|(_, stream)| {
+                    stream.send_credit > 0 && !stream.outbound.is_empty()
+                })
+                .map(|(id, _)| *id);
+            let id = match next {
+                Some(id) => id,
+                None => break,
+            };
+            let stream = self.stream_mut(id)?;
+            stream.send_credit -= 1;
+            let message = stream.outbound.pop_front().expect(
+                "Must not fail; we just ensured the queue is not empty",
+            );
+            self.send_frame(id, FRAME_DATA, &message)?;
+            self.last_served = id;
+        }
+
+        // Sending close frames for the streams which were closed locally
+        // and have no more queued messages
+        let closing = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| {
+                stream.closed_locally && stream.outbound.is_empty()
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in closing {
+            let stream = self.stream_mut(id)?;
+            let send_close = !stream.close_sent;
+            stream.close_sent = true;
+            if stream.closed_remotely {
+                self.streams.remove(&id);
+            }
+            if send_close {
+                self.send_frame(id, FRAME_CLOSE, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives single session message and dispatches it to the stream
+    fn pump(&mut self) -> Result<(), Error> {
+        let frame = self.session.recv_raw_message()?;
+        if frame.len() < MUX_HEADER_SIZE {
+            Err(Error::BrokenFrame("frame header is too short"))?
+        }
+        let id = StreamId::from_be_bytes([frame[0], frame[1]]);
+        let body = &frame[MUX_HEADER_SIZE..];
+        match frame[2] {
+            FRAME_OPEN => {
+                // Remote side must use ids of its own parity
+                if id == 0 || (id % 2 == 1) == self.initiator {
+                    Err(Error::BrokenFrame("stream id has wrong parity"))?
+                }
+                let name = String::from_utf8(body.to_vec())
+                    .map_err(|_| Error::BrokenFrame("non-UTF-8 stream name"))?;
+                // Remote peer re-uses stream id only after we have
+                // acknowledged its closing, so messages of the previous
+                // stream which were not consumed are discarded
+                let used = self
+                    .streams
+                    .get(&id)
+                    .map(|stream| !stream.closed_remotely)
+                    .unwrap_or_default();
+                if used {
+                    Err(Error::BrokenFrame("stream id is already used"))?
+                }
+                if self.find(&name).is_some() {
+                    Err(Error::DuplicateStream(name.clone()))?
+                }
+                self.streams.insert(id, Stream::with(name));
+                self.accepted.retain(|accepted| *accepted != id);
+                self.accepted.push_back(id);
+            }
+            FRAME_DATA => {
+                let stream = self.stream_mut(id)?;
+                if stream.closed_locally {
+                    // Messages sent before the remote side has learned about
+                    // the stream closing are discarded
+                    return Ok(());
+                }
+                if stream.recv_credit == 0 {
+                    Err(Error::FlowViolation(id))?
+                }
+                stream.recv_credit -= 1;
+                stream.inbound.push_back(body.to_vec());
+            }
+            FRAME_CLOSE => {
+                // Close frame is either an acknowledgement of our closing or
+                // a request from the remote peer, which we acknowledge. In
+                // both cases the close handshake is complete and the stream
+                // id is released, unless there are messages to be consumed
+                let stream = self.stream_mut(id)?;
+                let send_close = !stream.close_sent;
+                if stream.closed_locally || stream.inbound.is_empty() {
+                    self.streams.remove(&id);
+                    self.accepted.retain(|accepted| *accepted != id);
+                } else {
+                    stream.closed_remotely = true;
+                    stream.close_sent = true;
+                    stream.outbound.clear();
+                }
+                if send_close {
+                    self.send_frame(id, FRAME_CLOSE, &[])?;
+                }
+            }
+            FRAME_CREDIT => {
+                if body.len() != 4 {
+                    Err(Error::BrokenFrame("wrong credit frame length"))?
+                }
+                let credit =
+                    u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let stream = self.stream_mut(id)?;
+                stream.send_credit = stream.send_credit.saturating_add(credit);
+                self.flush()?;
+            }
+            _ => Err(Error::BrokenFrame("unknown frame type"))?,
+        }
+        Ok(())
+    }
+
+    fn send_frame(
+        &mut self,
+        id: StreamId,
+        frame_type: u8,
+        body: &[u8],
+    ) -> Result<(), Error> {
+        let mut frame = Vec::with_capacity(MUX_HEADER_SIZE + body.len());
+        frame.extend(&id.to_be_bytes());
+        frame.push(frame_type);
+        frame.extend(body);
+        self.session.send_raw_message(&frame)?;
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Option<StreamId> {
+        self.streams
+            .iter()
+            .find(|(_, stream)| {
+                stream.name == name
+                    && !stream.closed_locally
+                    && !stream.closed_remotely
+            })
+            .map(|(id, _)| *id)
+    }
+
+    fn stream(&self, id: StreamId) -> Result<&Stream, Error> {
+        self.streams.get(&id).ok_or(Error::UnknownStream(id))
+    }
+
+    fn stream_mut(&mut self, id: StreamId) -> Result<&mut Stream, Error> {
+        self.streams.get_mut(&id).ok_or(Error::UnknownStream(id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lnp::session::Raw;
+    use crate::lnp::transport::memory;
+
+    fn mux_pair() -> (Multiplexer, Multiplexer) {
+        let (left, right) = memory::pair();
+        (
+            Multiplexer::with(Raw::with_memory_unencrypted(left), true),
+            Multiplexer::with(Raw::with_memory_unencrypted(right), false),
+        )
+    }
+
+    #[test]
+    fn test_named_streams() {
+        let (mut alice, mut bob) = mux_pair();
+        let lnpwp = alice.open("lnpwp").unwrap();
+        let rgb = alice.open("rgb").unwrap();
+        assert_eq!((lnpwp, rgb), (1, 3));
+        assert_eq!(alice.open("rgb"), Err(Error::DuplicateStream(s!("rgb"))));
+
+        alice.send(rgb, b"rgb message").unwrap();
+        alice.send(lnpwp, b"lnpwp message").unwrap();
+        assert_eq!(bob.accept().unwrap(), (lnpwp, s!("lnpwp")));
+        assert_eq!(bob.accept().unwrap(), (rgb, s!("rgb")));
+        // Messages for other streams are buffered while waiting
+        assert_eq!(bob.recv(lnpwp).unwrap(), b"lnpwp message");
+        assert_eq!(bob.recv(rgb).unwrap(), b"rgb message");
+
+        let storm = bob.open("storm").unwrap();
+        assert_eq!(storm, 2);
+        bob.send(storm, b"storm message").unwrap();
+        assert_eq!(alice.accept().unwrap(), (storm, s!("storm")));
+        assert_eq!(alice.recv(storm).unwrap(), b"storm message");
+
+        alice.close(rgb).unwrap();
+        assert_eq!(alice.send(rgb, b"late"), Err(Error::StreamClosed(rgb)));
+        bob.send(lnpwp, b"ping").unwrap();
+        assert_eq!(alice.recv(lnpwp).unwrap(), b"ping");
+        assert_eq!(bob.recv(rgb), Err(Error::StreamClosed(rgb)));
+        assert_eq!(bob.stream_id("rgb"), None);
+        assert_eq!(bob.stream_name(rgb), None);
+        assert_eq!(bob.close(rgb), Err(Error::UnknownStream(rgb)));
+        assert_eq!(bob.stream_name(lnpwp), Some("lnpwp"));
+
+        // Alice releases the stream id once she gets acknowledgement
+        bob.send(lnpwp, b"pong").unwrap();
+        assert_eq!(alice.recv(lnpwp).unwrap(), b"pong");
+        assert_eq!(alice.stream_name(rgb), None);
+        assert_eq!(alice.open("rgb").unwrap(), rgb);
+    }
+
+    #[test]
+    fn test_flow_control_fairness() {
+        let (mut alice, mut bob) = mux_pair();
+        let bulk = alice.open("bulk").unwrap();
+        let urgent = alice.open("urgent").unwrap();
+        bob.accept().unwrap();
+        bob.accept().unwrap();
+
+        let total = INITIAL_CREDIT as usize * 3;
+        for no in 0..total {
+            alice.send(bulk, &(no as u32).to_be_bytes()).unwrap();
+        }
+        assert_eq!(alice.pending(bulk), total - INITIAL_CREDIT as usize);
+        // Urgent stream has its own credit and is not blocked by the bulk one
+        alice.send(urgent, b"urgent").unwrap();
+        assert_eq!(alice.pending(urgent), 0);
+        assert_eq!(bob.recv(urgent).unwrap(), b"urgent");
+
+        // Consuming messages grants credit, which lets alice send more of the
+        // queued messages once she processes incoming frames
+        let mut no = 0u32;
+        for round in 1..=3 {
+            for _ in 0..INITIAL_CREDIT {
+                assert_eq!(bob.recv(bulk).unwrap(), no.to_be_bytes());
+                no += 1;
+            }
+            bob.send(urgent, b"next").unwrap();
+            assert_eq!(alice.recv(urgent).unwrap(), b"next");
+            assert_eq!(
+                alice.pending(bulk),
+                total.saturating_sub(INITIAL_CREDIT as usize * (round + 1))
+            );
+        }
+        assert_eq!(no as usize, total);
+    }
+
+    #[test]
+    fn test_reopen_stream() {
+        let (mut alice, mut bob) = mux_pair();
+        let id = alice.open("lnpwp").unwrap();
+        alice.send(id, b"first").unwrap();
+        assert_eq!(bob.accept().unwrap(), (id, s!("lnpwp")));
+        alice.close(id).unwrap();
+        // Bob consumes the message and acknowledges the closing
+        assert_eq!(bob.recv(id).unwrap(), b"first");
+        assert_eq!(bob.recv(id), Err(Error::StreamClosed(id)));
+        assert_eq!(bob.stream_name(id), None);
+
+        // Alice gets the acknowledgement and re-uses the same stream id
+        let storm = bob.open("storm").unwrap();
+        bob.send(storm, b"ack").unwrap();
+        assert_eq!(alice.accept().unwrap(), (storm, s!("storm")));
+        assert_eq!(alice.recv(storm).unwrap(), b"ack");
+        assert_eq!(alice.open("lnpwp").unwrap(), id);
+        alice.send(id, b"second").unwrap();
+        assert_eq!(bob.accept().unwrap(), (id, s!("lnpwp")));
+        assert_eq!(bob.recv(id).unwrap(), b"second");
+    }
+}
@@ -21,6 +21,11 @@ use url::Url;
 use amplify::internet::{InetAddr, InetSocketAddr};
 use bitcoin::secp256k1;
 
+#[cfg(feature = "url")]
+use crate::lnp::transport::socket_addr::{
+    check_url_query, url_inet_addr, url_ip_addr,
+};
+use crate::lnp::transport::socket_addr::{url_host, url_host_port};
 use crate::lnp::transport::{LocalSocketAddr, RemoteSocketAddr};
 #[cfg(feature = "zmq")]
 use crate::lnp::zmqsocket::{ZmqSocketAddr, ZmqType};
@@ -63,7 +68,7 @@ impl FromStr for NodeAddr {
     type Err = AddrError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(PartialNodeAddr::from_str(s)?.into())
+        PartialNodeAddr::from_str(s)?.try_into()
     }
 }
 
@@ -103,7 +108,7 @@ impl TryFrom<Url> for NodeAddr {
     type Error = AddrError;
 
     fn try_from(url: Url) -> Result<Self, Self::Error> {
-        Ok(PartialNodeAddr::try_from(url)?.into())
+        PartialNodeAddr::try_from(url)?.try_into()
     }
 }
 
@@ -125,10 +130,10 @@ impl TryFrom<NodeAddr> for ZmqSocketAddr {
 
     fn try_from(value: NodeAddr) -> Result<Self, Self::Error> {
         Ok(match value {
-            NodeAddr::Local(LocalSocketAddr::Zmq(locator)) => locator,
+            NodeAddr::Local(LocalSocketAddr::Zmq(locator, _)) => locator,
             NodeAddr::Remote(RemoteNodeAddr {
-                node_id,
-                remote_addr: RemoteSocketAddr::Zmq(addr),
+                remote_addr: RemoteSocketAddr::Zmq(addr, _),
+                ..
             }) => ZmqSocketAddr::Tcp(addr),
             _ => Err(AddrError::Unsupported("ZMQ socket"))?,
         })
@@ -175,13 +180,11 @@ impl_into_stringly_standard!(NodeAddr);
 impl fmt::Display for RemoteNodeAddr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if f.alternate() {
-            write!(
-                f,
-                "{}{}@{}",
-                self.remote_addr.url_scheme(),
-                self.node_id,
-                self.remote_addr
-            )
+            // Node id is put in front of the host part of the socket address
+            // URL, which may be followed by the query
+            let scheme = self.remote_addr.url_scheme();
+            let url = format!("{:#}", self.remote_addr);
+            write!(f, "{}{}@{}", scheme, self.node_id, &url[scheme.len()..])
         } else {
             write!(f, "{}@{}", self.node_id, self.remote_addr)
         }
@@ -373,6 +376,18 @@ where
 ///
 /// NB: DNS addressing is not used since it is considered insecure in terms of
 ///     censorship resistance.
+///
+/// # URL grammar
+/// Each of the variants has its own URL scheme, described in the variant
+/// documentation. Host part is either IPv4, IPv6 address in square brackets
+/// or (where mentioned) Tor onion address. ZMQ-based schemes require `api`
+/// query parameter and may specify socket type with `socket` parameter, as
+/// described in [`crate::lnp::transport::socket_addr`]. ZMQ TCP addresses
+/// with node id may select ZMQ CURVE encryption with `encrypt=curve`
+/// parameter. No other query parameters are allowed. Otherwise encryption is
+/// defined by the scheme: only `lnp://` and `lnpws://` sessions are
+/// end-to-end encrypted with the node key (see
+/// [`PartialNodeAddr::is_encrypted`]).
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 #[non_exhaustive]
 pub enum PartialNodeAddr {
@@ -405,7 +420,7 @@ pub enum PartialNodeAddr {
     /// specific socket pair for ZMQ is provided via query parameter
     ///
     /// # URL Schema
-    /// lnpz:<file-path>?api=<p2p|rpc|sub>
+    /// lnpz:<file-path>?api=<p2p|rpc|sub|esb>[&socket=<type>]
     #[cfg(feature = "zmq")]
     ZmqIpc(String, ZmqType),
 
@@ -415,7 +430,9 @@ pub enum PartialNodeAddr {
     /// connection can be done only withing the same process, and can't be
     /// represented in the form of URL: it requires presence of ZMQ context
     /// object, which can't be encoded as a string (context object is taken
-    /// from a global variable).
+    /// from a global variable). The address is formatted as
+    /// `lnpz:?api=<p2p|rpc|sub|esb>[&socket=<type>]#<name>`, but parsing of
+    /// such URLs always fails.
     #[cfg(feature = "zmq")]
     ZmqInproc(String, ZmqType),
 
@@ -423,20 +440,38 @@ pub enum PartialNodeAddr {
     /// [`PartialNodeAddr::Native`] or [`PartialNodeAddr::Websocket`]
     /// connection MUST be used
     ///
+    /// NB: Node id is used only for the node identification, while the
+    ///     session is not encrypted with it. ZMQ sessions may be secured only
+    ///     with ZMQ CURVE, which requires CURVE keys provided to
+    ///     `session::Raw::with_zmq_curve`.
+    ///
     /// # URL Scheme
-    /// lnpz://<node-id>@<ip>[:<port>]/?api=<p2p|rpc|sub>
+    /// lnpz://<node-id>@<ip>[:<port>]/?api=<p2p|rpc|sub|esb>[&socket=<type>]
     #[cfg(feature = "zmq")]
     ZmqTcpEncrypted(secp256k1::PublicKey, ZmqType, IpAddr, Option<u16>),
 
+    /// ZMQ connection secured with ZMQ CURVE. Node id is used only for the
+    /// node identification; CURVE keys are not part of the address and must
+    /// be provided to `session::Raw::with_zmq_curve`, so the address can't be
+    /// converted into [`RemoteNodeAddr`].
+    ///
+    /// # URL Scheme
+    /// lnpz://<node-id>@<ip>[:<port>]/?api=<p2p|rpc|sub|esb>[&socket=<type>]&encrypt=curve
+    #[cfg(feature = "zmq")]
+    ZmqTcpCurve(secp256k1::PublicKey, ZmqType, IpAddr, Option<u16>),
+
     /// SHOULD be used only for DMZ area connections; otherwise
     /// [`PartialNodeAddr::Native`] or [`PartialNodeAddr::Websocket`]
     /// connection MUST be used
     ///
     /// # URL Schema
-    /// lnpz://<ip>[:<port>]/?api=<p2p|rpc|sub>
+    /// lnpz://<ip>[:<port>]/?api=<p2p|rpc|sub|esb>[&socket=<type>]
     #[cfg(feature = "zmq")]
     ZmqTcpUnencrypted(ZmqType, IpAddr, Option<u16>),
 
+    /// HTTP connection. Node id is used for the node identification, while
+    /// the session is not encrypted.
+    ///
     /// # URL Scheme
     /// lnph://<node-id>@<ip>|<onion>[:<port>]
     Http(secp256k1::PublicKey, InetAddr, Option<u16>),
@@ -446,6 +481,12 @@ pub enum PartialNodeAddr {
     #[cfg(feature = "websockets")]
     Websocket(secp256k1::PublicKey, InetAddr, Option<u16>),
 
+    /// SMTP connection through the relay with the given address
+    ///
+    /// # URL Scheme
+    /// lnpm://<node-id>@<ip>|<onion>[:<port>]
+    Smtp(secp256k1::PublicKey, InetAddr, Option<u16>),
+
     /// Text (Bech32-based) connection for high latency or non-interactive
     /// protocols. Can work with SMPT, for mesh and satellite networks – or
     /// with other mediums of communications (chat messages, QR codes etc).
@@ -472,6 +513,10 @@ impl PartialNodeAddr {
                 PartialNodeAddr::ZmqTcpEncrypted(a, b, c, Some(port))
             }
             #[cfg(feature = "zmq")]
+            PartialNodeAddr::ZmqTcpCurve(a, b, c, _) => {
+                PartialNodeAddr::ZmqTcpCurve(a, b, c, Some(port))
+            }
+            #[cfg(feature = "zmq")]
             PartialNodeAddr::ZmqTcpUnencrypted(a, b, _) => {
                 PartialNodeAddr::ZmqTcpUnencrypted(a, b, Some(port))
             }
@@ -482,10 +527,28 @@ impl PartialNodeAddr {
             PartialNodeAddr::Websocket(a, b, _) => {
                 PartialNodeAddr::Websocket(a, b, Some(port))
             }
+            PartialNodeAddr::Smtp(a, b, _) => {
+                PartialNodeAddr::Smtp(a, b, Some(port))
+            }
             me => me,
         }
     }
 
+    /// Returns whether sessions established with the node at this address
+    /// through [`Connect`](super::Connect) and [`Accept`](super::Accept) are
+    /// end-to-end encrypted with the node key. Node id in ZMQ addresses is
+    /// used only for the node identification, and addresses which can't be
+    /// used to establish a session by themselves (UDP, HTTP, SMTP and text)
+    /// are not considered encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        match self {
+            PartialNodeAddr::Native(..) => true,
+            #[cfg(feature = "websockets")]
+            PartialNodeAddr::Websocket(..) => true,
+            _ => false,
+        }
+    }
+
     /// Parses [`PartialNodeAddr`] into it's optional components, returned as a
     /// single tuple of optionals:
     /// 1) node public key,
@@ -519,7 +582,8 @@ impl PartialNodeAddr {
                 (None, None, None, Some(name.clone()), Some(*api))
             }
             #[cfg(feature = "zmq")]
-            PartialNodeAddr::ZmqTcpEncrypted(pubkey, api, ip, port) => (
+            PartialNodeAddr::ZmqTcpEncrypted(pubkey, api, ip, port)
+            | PartialNodeAddr::ZmqTcpCurve(pubkey, api, ip, port) => (
                 Some(*pubkey),
                 Some(InetAddr::from(*ip)),
                 *port,
@@ -537,6 +601,9 @@ impl PartialNodeAddr {
             PartialNodeAddr::Websocket(pubkey, inet, port) => {
                 (Some(*pubkey), Some(*inet), *port, None, None)
             }
+            PartialNodeAddr::Smtp(pubkey, inet, port) => {
+                (Some(*pubkey), Some(*inet), *port, None, None)
+            }
             PartialNodeAddr::Text(pubkey) => {
                 (Some(*pubkey), None, None, None, None)
             }
@@ -615,10 +682,13 @@ impl FromStr for PartialNodeAddr {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut s = s.to_string();
-        if vec!["lnp:", "lnpu:", "lnpz:", "lnpws:", "lnpt:", "lnph:"]
-            .into_iter()
-            .find(|p| s.starts_with(*p))
-            .is_none()
+        if vec![
+            "lnp:", "lnpu:", "lnpz:", "lnpws:", "ws:", "lnpt:", "lnph:",
+            "lnpm:",
+        ]
+        .into_iter()
+        .find(|p| s.starts_with(*p))
+        .is_none()
         {
             s = format!("lnp://{}", s);
         }
@@ -637,72 +707,84 @@ impl UrlString for PartialNodeAddr {
             }
             #[cfg(feature = "zmq")]
             PartialNodeAddr::ZmqTcpEncrypted(..)
+            | PartialNodeAddr::ZmqTcpCurve(..)
             | PartialNodeAddr::ZmqTcpUnencrypted(..) => "lnpz",
             PartialNodeAddr::Http(..) => "lnph",
             #[cfg(feature = "websockets")]
             PartialNodeAddr::Websocket(..) => "lnpws",
+            PartialNodeAddr::Smtp(..) => "lnpm",
             PartialNodeAddr::Text(..) => "lnpt",
         }
     }
 
     fn to_url_string(&self) -> String {
         match self {
-            PartialNodeAddr::Native(pubkey, inet, port) => {
-                let p = port.map(|x| format!(":{}", x)).unwrap_or_default();
-                format!("{}://{}@{}{}", self.url_scheme(), pubkey, inet, p)
-            }
-            PartialNodeAddr::Udp(pubkey, ip, port) => {
-                let p = port.map(|x| format!(":{}", x)).unwrap_or_default();
-                format!("{}://{}@{}{}", self.url_scheme(), pubkey, ip, p)
-            }
+            PartialNodeAddr::Native(pubkey, inet, port)
+            | PartialNodeAddr::Http(pubkey, inet, port)
+            | PartialNodeAddr::Smtp(pubkey, inet, port) => format!(
+                "{}://{}@{}",
+                self.url_scheme(),
+                pubkey,
+                host_port(*inet, *port)
+            ),
+            #[cfg(feature = "websockets")]
+            PartialNodeAddr::Websocket(pubkey, inet, port) => format!(
+                "{}://{}@{}",
+                self.url_scheme(),
+                pubkey,
+                host_port(*inet, *port)
+            ),
+            PartialNodeAddr::Udp(pubkey, ip, port) => format!(
+                "{}://{}@{}",
+                self.url_scheme(),
+                pubkey,
+                host_port(InetAddr::from(*ip), *port)
+            ),
             PartialNodeAddr::Posix(path) => {
                 format!("{}:{}", self.url_scheme(), path)
             }
             #[cfg(feature = "zmq")]
             PartialNodeAddr::ZmqIpc(path, zmq_type) => format!(
-                "{}:{}?api={}",
+                "{}:{}?{}",
                 self.url_scheme(),
                 path,
-                zmq_type.api_name()
+                zmq_type.url_query()
             ),
             #[cfg(feature = "zmq")]
             PartialNodeAddr::ZmqInproc(name, zmq_type) => format!(
-                "{}:?api={}#{}",
+                "{}:?{}#{}",
                 self.url_scheme(),
-                zmq_type.api_name(),
+                zmq_type.url_query(),
                 name
             ),
             #[cfg(feature = "zmq")]
             PartialNodeAddr::ZmqTcpEncrypted(pubkey, zmq_type, ip, port) => {
-                let p = port.map(|x| format!(":{}", x)).unwrap_or_default();
                 format!(
-                    "{}://{}@{}{}/?api={}",
+                    "{}://{}@{}/?{}",
                     self.url_scheme(),
                     pubkey,
-                    ip,
-                    p,
-                    zmq_type.api_name()
+                    host_port(InetAddr::from(*ip), *port),
+                    zmq_type.url_query()
                 )
             }
             #[cfg(feature = "zmq")]
-            PartialNodeAddr::ZmqTcpUnencrypted(zmq_type, ip, port) => {
-                let p = port.map(|x| format!(":{}", x)).unwrap_or_default();
+            PartialNodeAddr::ZmqTcpCurve(pubkey, zmq_type, ip, port) => {
                 format!(
-                    "{}://{}{}/?api={}",
+                    "{}://{}@{}/?{}&encrypt=curve",
                     self.url_scheme(),
-                    ip,
-                    p,
-                    zmq_type.api_name()
+                    pubkey,
+                    host_port(InetAddr::from(*ip), *port),
+                    zmq_type.url_query()
                 )
             }
-            PartialNodeAddr::Http(pubkey, inet, port) => {
-                let p = port.map(|x| format!(":{}", x)).unwrap_or_default();
-                format!("{}://{}@{}{}", self.url_scheme(), pubkey, inet, p)
-            }
-            #[cfg(feature = "websockets")]
-            PartialNodeAddr::Websocket(pubkey, inet, port) => {
-                let p = port.map(|x| format!(":{}", x)).unwrap_or_default();
-                format!("{}://{}@{}{}", self.url_scheme(), pubkey, inet, p)
+            #[cfg(feature = "zmq")]
+            PartialNodeAddr::ZmqTcpUnencrypted(zmq_type, ip, port) => {
+                format!(
+                    "{}://{}/?{}",
+                    self.url_scheme(),
+                    host_port(InetAddr::from(*ip), *port),
+                    zmq_type.url_query()
+                )
             }
             PartialNodeAddr::Text(pubkey) => {
                 format!("{}://{}", self.url_scheme(), pubkey)
@@ -711,6 +793,14 @@ impl UrlString for PartialNodeAddr {
     }
 }
 
+/// Formats host and optional port parts of the URL
+fn host_port(inet: InetAddr, port: Option<u16>) -> String {
+    match port {
+        Some(port) => url_host_port(&InetSocketAddr::new(inet, port)),
+        None => url_host(&inet),
+    }
+}
+
 #[cfg(feature = "url")]
 impl From<PartialNodeAddr> for Url {
     fn from(addr: PartialNodeAddr) -> Self {
@@ -731,112 +821,116 @@ impl TryFrom<Url> for PartialNodeAddr {
     type Error = AddrError;
 
     fn try_from(url: Url) -> Result<Self, Self::Error> {
+        let allowed_query: &[&str] = match url.scheme() {
+            "lnpz" => &["api", "socket", "encrypt"],
+            _ => &[],
+        };
+        check_url_query(&url, allowed_query)?;
+        #[cfg(feature = "zmq")]
+        let encrypt = url
+            .query_pairs()
+            .find(|(key, _)| key == "encrypt")
+            .map(|(_, val)| val.into_owned());
         let pubkey = secp256k1::PublicKey::from_str(url.username());
         let host = url.host_str();
-        let ip = host
-            .map(IpAddr::from_str)
-            .transpose()
-            .map_err(AddrError::from)
-            .and_then(|o| o.ok_or(AddrError::HostRequired));
         let port = url.port();
-        match url.scheme() {
-            "lnp" => Ok(PartialNodeAddr::Native(
-                pubkey?,
-                host.ok_or(AddrError::HostRequired)?.parse::<InetAddr>()?,
-                port,
-            )),
-            "lnpu" => Ok(PartialNodeAddr::Udp(pubkey?, ip?, port)),
-            "lnph" => Ok(PartialNodeAddr::Http(
-                pubkey?,
-                host.ok_or(AddrError::HostRequired)?.parse::<InetAddr>()?,
-                port,
-            )),
+        Ok(match url.scheme() {
+            // Without host part the scheme is used for POSIX sockets
+            "lnp" if !url.has_host() => {
+                PartialNodeAddr::Posix(url.path().to_owned())
+            }
+            "lnp" => {
+                PartialNodeAddr::Native(pubkey?, url_inet_addr(&url)?, port)
+            }
+            "lnpu" => PartialNodeAddr::Udp(pubkey?, url_ip_addr(&url)?, port),
+            "lnph" => {
+                PartialNodeAddr::Http(pubkey?, url_inet_addr(&url)?, port)
+            }
+            "lnpm" => {
+                PartialNodeAddr::Smtp(pubkey?, url_inet_addr(&url)?, port)
+            }
             #[cfg(feature = "websockets")]
-            "lnpws" => Ok(PartialNodeAddr::Websocket(
+            "lnpws" => {
+                PartialNodeAddr::Websocket(pubkey?, url_inet_addr(&url)?, port)
+            }
+            // Standard websocket URL, which port defaults to 80
+            #[cfg(feature = "websockets")]
+            "ws" => PartialNodeAddr::Websocket(
                 pubkey?,
-                host.ok_or(AddrError::HostRequired)?.parse::<InetAddr>()?,
-                port,
-            )),
+                url_inet_addr(&url)?,
+                url.port_or_known_default(),
+            ),
             #[cfg(feature = "zmq")]
             "lnpz" => {
-                let zmq_type = match url
-                    .query_pairs()
-                    .find_map(
-                        |(key, val)| {
-                            if key == "api" {
-                                Some(val)
-                            } else {
-                                None
-                            }
-                        },
-                    )
-                    .ok_or(AddrError::ZmqTypeRequired)?
-                    .to_ascii_lowercase()
-                    .as_str()
-                {
-                    "p2p" => Ok(ZmqType::Push),
-                    "rpc" => Ok(ZmqType::Req),
-                    "sub" => Ok(ZmqType::Sub),
-                    "esb" => Ok(ZmqType::RouterBind),
-                    unknown => {
-                        Err(AddrError::InvalidZmqType(unknown.to_string()))
+                let zmq_type = ZmqType::from_url_query(&url)?;
+                // CURVE encryption requires node id to identify the node
+                let curve = match encrypt {
+                    None => false,
+                    Some(encrypt)
+                        if encrypt == "curve"
+                            && url.has_host()
+                            && !url.username().is_empty() =>
+                    {
+                        true
                     }
-                }?;
-                Ok(match (ip, pubkey) {
-                    (_, Err(_)) if !url.username().is_empty() => {
-                        Err(AddrError::InvalidPubkey)?
+                    Some(encrypt) => {
+                        Err(AddrError::InvalidEncryption(encrypt))?
                     }
-                    (Ok(ip), Ok(pubkey)) => PartialNodeAddr::ZmqTcpEncrypted(
-                        pubkey, zmq_type, ip, port,
-                    ),
-                    (Ok(ip), _) => {
+                };
+                if url.has_host() {
+                    let ip = url_ip_addr(&url)?;
+                    if url.username().is_empty() {
                         PartialNodeAddr::ZmqTcpUnencrypted(zmq_type, ip, port)
-                    }
-                    (Err(_), _) => {
-                        if url.path().is_empty() {
-                            Err(AddrError::ZmqContextRequired)?
-                        }
-                        PartialNodeAddr::ZmqIpc(
-                            url.path().to_string(),
-                            zmq_type,
+                    } else if curve {
+                        PartialNodeAddr::ZmqTcpCurve(
+                            pubkey?, zmq_type, ip, port,
+                        )
+                    } else {
+                        PartialNodeAddr::ZmqTcpEncrypted(
+                            pubkey?, zmq_type, ip, port,
                         )
                     }
-                })
+                } else if url.path().is_empty() {
+                    Err(AddrError::ZmqContextRequired)?
+                } else {
+                    PartialNodeAddr::ZmqIpc(url.path().to_string(), zmq_type)
+                }
             }
             "lnpt" => {
                 // In this URL scheme we must not use IP address
-                if let Ok(pubkey) = pubkey {
+                if pubkey.is_ok() {
                     Err(AddrError::UnexpectedHost)?
                 }
                 // In this URL scheme we must not use IP address
-                if let Some(port) = port {
+                if port.is_some() {
                     Err(AddrError::UnexpectedPort)?
                 }
                 if let Some(host) = host {
-                    Ok(PartialNodeAddr::Text(secp256k1::PublicKey::from_str(
-                        host,
-                    )?))
+                    PartialNodeAddr::Text(secp256k1::PublicKey::from_str(host)?)
                 } else {
                     Err(AddrError::InvalidPubkey)?
                 }
             }
-            unknown => Err(AddrError::UnknownUrlScheme(unknown.to_string())),
-        }
+            unknown => Err(AddrError::UnknownUrlScheme(unknown.to_string()))?,
+        })
     }
 }
 
-impl From<PartialNodeAddr> for NodeAddr {
-    fn from(locator: PartialNodeAddr) -> Self {
+impl TryFrom<PartialNodeAddr> for NodeAddr {
+    type Error = AddrError;
+
+    fn try_from(locator: PartialNodeAddr) -> Result<Self, Self::Error> {
         RemoteNodeAddr::try_from(locator.clone())
-            .map(|addr| NodeAddr::Remote(addr))
-            .unwrap_or_else(|_| {
-                NodeAddr::Local(LocalSocketAddr::try_from(locator).expect(
-                    "PartialNodeAddr must convert to either NodeAddr or LocalAddr",
-                ))
+            .map(NodeAddr::Remote)
+            .or_else(|err| {
+                LocalSocketAddr::try_from(locator)
+                    .map(NodeAddr::Local)
+                    // Addresses which can't be local fail with the error of
+                    // the remote address conversion
+                    .map_err(|_| err)
             })
     }
 }
-
 impl TryFrom<PartialNodeAddr> for LocalSocketAddr {
     type Error = AddrError;
 
@@ -844,18 +938,23 @@ impl TryFrom<PartialNodeAddr> for LocalSocketAddr {
         Ok(match value {
             PartialNodeAddr::Posix(path) => LocalSocketAddr::Posix(path),
             #[cfg(feature = "zmq")]
-            PartialNodeAddr::ZmqIpc(path, ..) => {
-                LocalSocketAddr::Zmq(ZmqSocketAddr::Ipc(path))
+            PartialNodeAddr::ZmqIpc(path, zmq_type) => {
+                LocalSocketAddr::Zmq(ZmqSocketAddr::Ipc(path), zmq_type)
             }
             #[cfg(feature = "zmq")]
-            PartialNodeAddr::ZmqInproc(name, ..) => {
-                LocalSocketAddr::Zmq(ZmqSocketAddr::Inproc(name))
+            PartialNodeAddr::ZmqInproc(name, zmq_type) => {
+                LocalSocketAddr::Zmq(ZmqSocketAddr::Inproc(name), zmq_type)
+            }
+            #[cfg(feature = "zmq")]
+            PartialNodeAddr::ZmqTcpUnencrypted(zmq_type, ip, Some(port)) => {
+                LocalSocketAddr::Zmq(
+                    ZmqSocketAddr::Tcp(SocketAddr::new(ip, port)),
+                    zmq_type,
+                )
             }
             #[cfg(feature = "zmq")]
-            PartialNodeAddr::ZmqTcpUnencrypted(_, ip, Some(port)) => {
-                LocalSocketAddr::Zmq(ZmqSocketAddr::Tcp(SocketAddr::new(
-                    ip, port,
-                )))
+            PartialNodeAddr::ZmqTcpUnencrypted(.., None) => {
+                Err(AddrError::PortRequired)?
             }
             _ => Err(AddrError::Unsupported("local socket address"))?,
         })
@@ -867,10 +966,12 @@ impl TryFrom<PartialNodeAddr> for RemoteNodeAddr {
 
     fn try_from(locator: PartialNodeAddr) -> Result<Self, Self::Error> {
         match locator {
-            PartialNodeAddr::Native(.., None) => Err(AddrError::PortRequired),
-            #[cfg(feature = "websocket")]
+            PartialNodeAddr::Native(.., None)
+            | PartialNodeAddr::Http(.., None)
+            | PartialNodeAddr::Smtp(.., None) => Err(AddrError::PortRequired),
+            #[cfg(feature = "websockets")]
             PartialNodeAddr::Websocket(.., None) => {
-                Err(AddrError::HostRequired)
+                Err(AddrError::PortRequired)
             }
             #[cfg(feature = "zmq")]
             PartialNodeAddr::ZmqTcpEncrypted(.., None) => {
@@ -889,12 +990,31 @@ impl TryFrom<PartialNodeAddr> for RemoteNodeAddr {
             PartialNodeAddr::ZmqTcpEncrypted(pubkey, api, ip, Some(port)) => {
                 Ok(RemoteNodeAddr {
                     node_id: pubkey,
-                    remote_addr: RemoteSocketAddr::Zmq(SocketAddr::new(
-                        ip, port,
-                    )),
+                    remote_addr: RemoteSocketAddr::Zmq(
+                        SocketAddr::new(ip, port),
+                        api,
+                    ),
+                })
+            }
+            PartialNodeAddr::Http(pubkey, address, Some(port)) => {
+                Ok(RemoteNodeAddr {
+                    node_id: pubkey,
+                    remote_addr: RemoteSocketAddr::Http(InetSocketAddr {
+                        address,
+                        port,
+                    }),
+                })
+            }
+            PartialNodeAddr::Smtp(pubkey, address, Some(port)) => {
+                Ok(RemoteNodeAddr {
+                    node_id: pubkey,
+                    remote_addr: RemoteSocketAddr::Smtp(InetSocketAddr {
+                        address,
+                        port,
+                    }),
                 })
             }
-            #[cfg(feature = "websocket")]
+            #[cfg(feature = "websockets")]
             PartialNodeAddr::Websocket(pubkey, addr, Some(port)) => {
                 Ok(RemoteNodeAddr {
                     node_id: pubkey,
@@ -917,29 +1037,30 @@ impl From<RemoteNodeAddr> for PartialNodeAddr {
                 Some(addr.port),
             ),
             #[cfg(feature = "zmq")]
-            RemoteSocketAddr::Zmq(addr) => PartialNodeAddr::ZmqTcpEncrypted(
-                node_addr.node_id,
-                ZmqType::Rep,
-                InetSocketAddr::from(addr)
-                    .address
-                    .try_into()
-                    .expect("Conversion from just generated type can't fail"),
-                Some(addr.port()),
-            ),
+            RemoteSocketAddr::Zmq(addr, api) => {
+                PartialNodeAddr::ZmqTcpEncrypted(
+                    node_addr.node_id,
+                    api,
+                    addr.ip(),
+                    Some(addr.port()),
+                )
+            }
             RemoteSocketAddr::Http(addr) => PartialNodeAddr::Http(
                 node_addr.node_id,
                 addr.address,
                 Some(addr.port),
             ),
-            #[cfg(feature = "websocket")]
+            #[cfg(feature = "websockets")]
             RemoteSocketAddr::Websocket(addr) => PartialNodeAddr::Websocket(
                 node_addr.node_id,
                 addr.address,
                 Some(addr.port),
             ),
-            RemoteSocketAddr::Smtp(addr) => {
-                PartialNodeAddr::Text(node_addr.node_id)
-            }
+            RemoteSocketAddr::Smtp(addr) => PartialNodeAddr::Smtp(
+                node_addr.node_id,
+                addr.address,
+                Some(addr.port),
+            ),
         }
     }
 }
@@ -950,11 +1071,12 @@ impl TryFrom<PartialNodeAddr> for ZmqSocketAddr {
 
     fn try_from(socket_addr: PartialNodeAddr) -> Result<Self, Self::Error> {
         Ok(match socket_addr {
-            PartialNodeAddr::ZmqIpc(path, ty) => {
+            PartialNodeAddr::ZmqIpc(path, _) => {
                 ZmqSocketAddr::Ipc(path)
             }
             PartialNodeAddr::ZmqTcpUnencrypted(_, ip, Some(port)) |
-            PartialNodeAddr::ZmqTcpEncrypted(_, _, ip, Some(port)) => {
+            PartialNodeAddr::ZmqTcpEncrypted(_, _, ip, Some(port)) |
+            PartialNodeAddr::ZmqTcpCurve(_, _, ip, Some(port)) => {
                 ZmqSocketAddr::Tcp(SocketAddr::new(ip, port))
             }
             _ => Err(AddrError::Unsupported(
@@ -967,6 +1089,12 @@ impl TryFrom<PartialNodeAddr> for ZmqSocketAddr {
 #[cfg(test)]
 mod test {
     use super::*;
+    #[cfg(feature = "zmq")]
+    use crate::lnp::test_helpers::random_zmq_type;
+    use crate::lnp::test_helpers::{
+        random_inet_addr, random_ip, random_opt_port, random_path,
+        random_pubkey, PROPERTY_ROUNDS,
+    };
 
     #[test]
     fn test_native() {
@@ -1159,11 +1287,16 @@ mod test {
 
         assert_eq!(
             RemoteNodeAddr::try_from(locator1.clone()),
-            Err(AddrError::Unsupported("Given PartialNodeAddr type can't be converted into RemoteNodeAddr"))
+            Err(AddrError::PortRequired)
         );
         assert_eq!(
             RemoteNodeAddr::try_from(locator_with_port.clone()),
-            Err(AddrError::Unsupported("Given PartialNodeAddr type can't be converted into RemoteNodeAddr"))
+            Ok(RemoteNodeAddr {
+                node_id: pubkey1,
+                remote_addr: RemoteSocketAddr::Websocket(InetSocketAddr::new(
+                    inet1, 24
+                ))
+            })
         );
 
         assert_eq!(
@@ -1189,6 +1322,23 @@ mod test {
                 ).unwrap(),
                 locator_with_port
             );
+            assert_eq!(
+                PartialNodeAddr::from_str(
+                    "ws://022e58afe51f9ed8ad3cc7897f634d881fdbe49a81564629ded8156bebd2ffd1af@127.0.0.1:24"
+                ).unwrap(),
+                locator_with_port
+            );
+            assert_eq!(
+                RemoteNodeAddr::from_str(
+                    "ws://022e58afe51f9ed8ad3cc7897f634d881fdbe49a81564629ded8156bebd2ffd1af@127.0.0.1"
+                ).unwrap(),
+                RemoteNodeAddr {
+                    node_id: pubkey1,
+                    remote_addr: RemoteSocketAddr::Websocket(
+                        InetSocketAddr::new(inet1, 80)
+                    )
+                }
+            );
         }
     }
 
@@ -1251,7 +1401,10 @@ mod test {
             RemoteNodeAddr::try_from(locator_with_port.clone()),
             Ok(RemoteNodeAddr {
                 node_id: pubkey1,
-                remote_addr: RemoteSocketAddr::Zmq(SocketAddr::new(inet1, 24))
+                remote_addr: RemoteSocketAddr::Zmq(
+                    SocketAddr::new(inet1, 24),
+                    ZmqType::Push
+                )
             })
         );
 
@@ -1495,4 +1648,180 @@ mod test {
             );
         }
     }
+
+    fn random_addrs() -> Vec<PartialNodeAddr> {
+        vec![
+            PartialNodeAddr::Native(
+                random_pubkey(),
+                random_inet_addr(),
+                random_opt_port(),
+            ),
+            PartialNodeAddr::Udp(
+                random_pubkey(),
+                random_ip(),
+                random_opt_port(),
+            ),
+            PartialNodeAddr::Posix(random_path()),
+            #[cfg(feature = "zmq")]
+            PartialNodeAddr::ZmqIpc(random_path(), random_zmq_type()),
+            #[cfg(feature = "zmq")]
+            PartialNodeAddr::ZmqTcpEncrypted(
+                random_pubkey(),
+                random_zmq_type(),
+                random_ip(),
+                random_opt_port(),
+            ),
+            #[cfg(feature = "zmq")]
+            PartialNodeAddr::ZmqTcpCurve(
+                random_pubkey(),
+                random_zmq_type(),
+                random_ip(),
+                random_opt_port(),
+            ),
+            #[cfg(feature = "zmq")]
+            PartialNodeAddr::ZmqTcpUnencrypted(
+                random_zmq_type(),
+                random_ip(),
+                random_opt_port(),
+            ),
+            PartialNodeAddr::Http(
+                random_pubkey(),
+                random_inet_addr(),
+                random_opt_port(),
+            ),
+            #[cfg(feature = "websockets")]
+            PartialNodeAddr::Websocket(
+                random_pubkey(),
+                random_inet_addr(),
+                random_opt_port(),
+            ),
+            PartialNodeAddr::Smtp(
+                random_pubkey(),
+                random_inet_addr(),
+                random_opt_port(),
+            ),
+            PartialNodeAddr::Text(random_pubkey()),
+        ]
+    }
+
+    #[cfg(feature = "url")]
+    #[test]
+    fn test_url_roundtrip() {
+        for addr in (0..PROPERTY_ROUNDS).flat_map(|_| random_addrs()) {
+            let url = addr.to_url_string();
+            assert!(url.starts_with(addr.url_scheme()));
+            assert_eq!(
+                PartialNodeAddr::from_str(&url),
+                Ok(addr.clone()),
+                "{}",
+                url
+            );
+            assert_eq!(
+                PartialNodeAddr::try_from(Url::from(&addr)),
+                Ok(addr.clone())
+            );
+
+            // Addresses which are convertible into node addresses must
+            // round-trip as node addresses as well
+            let node_addr = match NodeAddr::try_from(addr.clone()) {
+                Ok(node_addr) => node_addr,
+                Err(_) => continue,
+            };
+            let url = node_addr.to_url_string();
+            assert_eq!(
+                NodeAddr::from_str(&url),
+                Ok(node_addr.clone()),
+                "{}",
+                url
+            );
+            if let NodeAddr::Remote(remote) = node_addr {
+                assert_eq!(PartialNodeAddr::from(remote), addr);
+            }
+        }
+
+        #[cfg(feature = "zmq")]
+        assert_eq!(
+            PartialNodeAddr::from_str(
+                &PartialNodeAddr::ZmqInproc(s!("socket1"), ZmqType::Rep)
+                    .to_url_string()
+            ),
+            Err(AddrError::ZmqContextRequired)
+        );
+    }
+
+    #[test]
+    fn test_encryption() {
+        for addr in (0..PROPERTY_ROUNDS).flat_map(|_| random_addrs()) {
+            // Only framed TCP and websocket sessions to remote nodes are
+            // encrypted by `Connect` and `Accept`
+            let encrypted = match RemoteNodeAddr::try_from(addr.with_port(1)) {
+                Ok(RemoteNodeAddr {
+                    remote_addr: RemoteSocketAddr::Ftcp(_),
+                    ..
+                }) => true,
+                #[cfg(feature = "websockets")]
+                Ok(RemoteNodeAddr {
+                    remote_addr: RemoteSocketAddr::Websocket(_),
+                    ..
+                }) => true,
+                _ => false,
+            };
+            assert_eq!(addr.is_encrypted(), encrypted, "{}", addr);
+        }
+
+        #[cfg(feature = "url")]
+        assert_eq!(
+            PartialNodeAddr::from_str(
+                "lnp://022e58afe51f9ed8ad3cc7897f634d881fdbe49a81564629ded8156bebd2ffd1af@127.0.0.1:9735?encrypt=false"
+            ),
+            Err(AddrError::UnexpectedQuery(s!("encrypt")))
+        );
+        #[cfg(feature = "url")]
+        assert_eq!(
+            PartialNodeAddr::from_str("lnp:/tmp/lnp.sock?api=rpc"),
+            Err(AddrError::UnexpectedQuery(s!("api")))
+        );
+    }
+
+    #[cfg(all(feature = "url", feature = "zmq"))]
+    #[test]
+    fn test_zmq_curve() {
+        let pubkey = secp256k1::PublicKey::from_str(
+            "022e58afe51f9ed8ad3cc7897f634d881fdbe49a81564629ded8156bebd2ffd1af"
+        ).unwrap();
+        let url =
+            format!("lnpz://{}@127.0.0.1:9735/?api=rpc&encrypt=curve", pubkey);
+        let addr = PartialNodeAddr::ZmqTcpCurve(
+            pubkey,
+            ZmqType::Req,
+            IpAddr::from([127, 0, 0, 1]),
+            Some(9735),
+        );
+        assert_eq!(PartialNodeAddr::from_str(&url), Ok(addr.clone()));
+        assert_eq!(
+            PartialNodeAddr::from_str(&addr.to_url_string()),
+            Ok(addr.clone())
+        );
+        assert!(!addr.is_encrypted());
+        assert_eq!(
+            ZmqSocketAddr::try_from(addr.clone()),
+            Ok(ZmqSocketAddr::Tcp("127.0.0.1:9735".parse().unwrap()))
+        );
+        assert!(RemoteNodeAddr::try_from(addr).is_err());
+
+        // CURVE requires node id and is the only supported option
+        assert_eq!(
+            PartialNodeAddr::from_str(
+                "lnpz://127.0.0.1:9735/?api=rpc&encrypt=curve"
+            ),
+            Err(AddrError::InvalidEncryption(s!("curve")))
+        );
+        assert_eq!(
+            PartialNodeAddr::from_str(&format!(
+                "lnpz://{}@127.0.0.1:9735/?api=rpc&encrypt=true",
+                pubkey
+            )),
+            Err(AddrError::InvalidEncryption(s!("true")))
+        );
+    }
 }
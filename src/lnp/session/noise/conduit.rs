@@ -15,9 +15,15 @@ use amplify::Bipolar;
 use chacha20poly1305::aead::Error;
 use std::borrow::Borrow;
 
-use super::handshake::HandshakeError;
 use super::{chacha, hkdf};
-use crate::lnp::session::transcoders::{Decrypt, Encrypt, Transcode};
+use crate::lnp::session::transcoders::{
+    Decrypt, DecryptionError, Encrypt, Transcode,
+};
+use crate::lnp::transport::{self, capture, RecvFrame};
+#[cfg(feature = "async")]
+use crate::lnp::{
+    session::transcoders::AsyncDecrypt, transport::AsyncRecvFrame,
+};
 
 pub type SymmetricKey = [u8; 32];
 
@@ -90,6 +96,10 @@ pub struct NoiseDecryptor {
     receiving_nonce: u32,
 
     pending_message_length: Option<usize>,
+    /// Header and body length of the frame which body is being read by
+    /// [`Decrypt::read_frame`]; kept until the body is read, so an
+    /// interrupted read can be resumed
+    partial_frame: Option<(Vec<u8>, usize)>,
     read_buffer: Option<Vec<u8>>,
     poisoned: bool, /* signal an error has occurred so None is returned on
                      * iteration after failure */
@@ -140,22 +150,9 @@ impl NoiseDecryptor {
                 return Ok((None, 0));
             }
 
-            let encrypted_length =
-                &buffer[0..TAGGED_MESSAGE_LENGTH_HEADER_SIZE];
-            let mut length_bytes = [0u8; MESSAGE_LENGTH_HEADER_SIZE];
-            chacha::decrypt(
-                &self.receiving_key,
-                self.receiving_nonce as u64,
-                &[0; 0],
-                encrypted_length,
-                &mut length_bytes,
-            )?;
-
-            self.increment_nonce();
-
-            // the message length
-            //byte_utils::slice_to_be16(&length_bytes) as usize
-            u16::from_be_bytes(length_bytes) as usize
+            self.decrypt_length_header(
+                &buffer[0..TAGGED_MESSAGE_LENGTH_HEADER_SIZE],
+            )?
         };
 
         let message_end_index = TAGGED_MESSAGE_LENGTH_HEADER_SIZE
@@ -186,6 +183,45 @@ impl NoiseDecryptor {
         Ok((Some(message), message_end_index))
     }
 
+    fn decrypt_length_header(
+        &mut self,
+        encrypted_length: &[u8],
+    ) -> Result<usize, Error> {
+        let mut length_bytes = [0u8; MESSAGE_LENGTH_HEADER_SIZE];
+        chacha::decrypt(
+            &self.receiving_key,
+            self.receiving_nonce as u64,
+            &[0; 0],
+            encrypted_length,
+            &mut length_bytes,
+        )?;
+
+        self.increment_nonce();
+
+        // the message length
+        Ok(u16::from_be_bytes(length_bytes) as usize)
+    }
+
+    /// Decrypts the header of the frame and keeps it as a partial frame
+    /// until its body is read
+    fn start_frame(
+        &mut self,
+        header: Vec<u8>,
+    ) -> Result<(Vec<u8>, usize), transport::Error> {
+        if self.pending_message_length.is_some() {
+            // We panic here because this is a program architecture design
+            // error and developer must be notified about it; the frame must
+            // be decrypted before the next one is read
+            panic!("Previously read noise frame was not decrypted")
+        }
+        let length = self
+            .decrypt_length_header(&header)
+            .map_err(|_| transport::Error::DecryptionFailure)?;
+        self.pending_message_length = Some(length);
+        self.partial_frame = Some((header.clone(), length));
+        Ok((header, length))
+    }
+
     fn increment_nonce(&mut self) {
         NoiseTranscoder::increment_nonce(
             &mut self.receiving_nonce,
@@ -226,7 +262,7 @@ impl Iterator for NoiseDecryptor {
 }
 
 impl Decrypt for NoiseDecryptor {
-    type Error = HandshakeError;
+    type Error = DecryptionError;
     fn decrypt(
         &mut self,
         buffer: impl Borrow<[u8]>,
@@ -234,9 +270,62 @@ impl Decrypt for NoiseDecryptor {
         match self.decrypt_single_message(Some(buffer.borrow())) {
             Ok(Some(data)) => Ok(data),
             Ok(None) => Ok(Vec::new()),
-            Err(e) => Err(HandshakeError::ChaCha20(e)),
+            Err(_) => Err(DecryptionError),
         }
     }
+
+    /// Reads encrypted frame from the `reader`. Since the frame length is
+    /// encrypted, we decrypt the frame header first and keep the decrypted
+    /// length as pending for the subsequent [`Decrypt::decrypt`] call.
+    /// If reading the frame body fails (for instance, with a read timeout),
+    /// the next call resumes reading the body of the same frame. Bytes read
+    /// before the failure are kept by the transport receiver (see
+    /// [`transport::ftcp::Receiver`]), so interrupted header reads are
+    /// resumed by the transport as well.
+    fn read_frame(
+        &mut self,
+        reader: &mut dyn RecvFrame,
+    ) -> Result<Vec<u8>, transport::Error> {
+        let (mut frame, length) = match self.partial_frame.clone() {
+            Some(partial) => partial,
+            None => self.start_frame(
+                reader.recv_raw(TAGGED_MESSAGE_LENGTH_HEADER_SIZE)?,
+            )?,
+        };
+        frame.extend(reader.recv_raw(length + chacha::TAG_SIZE)?);
+        self.partial_frame = None;
+        Ok(frame)
+    }
+}
+
+impl capture::Inspect for NoiseDecryptor {
+    fn inspect(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        self.decrypt_single_message(Some(data)).ok().flatten()
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait]
+impl AsyncDecrypt for NoiseDecryptor {
+    /// Async version of [`NoiseDecryptor::read_frame`]. The decrypted header
+    /// is kept before awaiting the frame body, so if the future is cancelled
+    /// in between, the next call resumes reading the body of the same frame.
+    async fn async_read_frame(
+        &mut self,
+        reader: &mut (dyn AsyncRecvFrame + Send),
+    ) -> Result<Vec<u8>, transport::Error> {
+        let (mut frame, length) = match self.partial_frame.clone() {
+            Some(partial) => partial,
+            None => self.start_frame(
+                reader
+                    .async_recv_raw(TAGGED_MESSAGE_LENGTH_HEADER_SIZE)
+                    .await?,
+            )?,
+        };
+        frame.extend(reader.async_recv_raw(length + chacha::TAG_SIZE).await?);
+        self.partial_frame = None;
+        Ok(frame)
+    }
 }
 
 /// Returned after a successful handshake to encrypt and decrypt communication
@@ -268,6 +357,7 @@ impl NoiseTranscoder {
                 receiving_nonce: 0,
                 read_buffer: None,
                 pending_message_length: None,
+                partial_frame: None,
                 poisoned: false,
             },
         }
@@ -294,6 +384,33 @@ impl NoiseTranscoder {
         Ok(self.decryptor.decrypt_single_message(new_data)?)
     }
 
+    /// Constructs pair of decryptors mirroring the current state of the
+    /// transcoder: the first one decrypts frames received from the remote
+    /// peer and the second one – frames sent to it. Used for recording
+    /// decrypted payloads into session captures; since the decryptors contain
+    /// session keys they must not be used for anything except debugging.
+    pub fn mirror(&self) -> (NoiseDecryptor, NoiseDecryptor) {
+        let incoming = NoiseDecryptor {
+            receiving_key: self.decryptor.receiving_key,
+            receiving_chaining_key: self.decryptor.receiving_chaining_key,
+            receiving_nonce: self.decryptor.receiving_nonce,
+            pending_message_length: self.decryptor.pending_message_length,
+            partial_frame: self.decryptor.partial_frame.clone(),
+            read_buffer: self.decryptor.read_buffer.clone(),
+            poisoned: self.decryptor.poisoned,
+        };
+        let outgoing = NoiseDecryptor {
+            receiving_key: self.encryptor.sending_key,
+            receiving_chaining_key: self.encryptor.sending_chaining_key,
+            receiving_nonce: self.encryptor.sending_nonce,
+            pending_message_length: None,
+            partial_frame: None,
+            read_buffer: None,
+            poisoned: false,
+        };
+        (incoming, outgoing)
+    }
+
     fn increment_nonce(
         nonce: &mut u32,
         chaining_key: &mut SymmetricKey,
@@ -323,16 +440,33 @@ impl Encrypt for NoiseTranscoder {
 }
 
 impl Decrypt for NoiseTranscoder {
-    type Error = HandshakeError;
+    type Error = DecryptionError;
+    #[inline]
     fn decrypt(
         &mut self,
         buffer: impl Borrow<[u8]>,
     ) -> Result<Vec<u8>, Self::Error> {
-        match self.decrypt_single_message(Some(buffer.borrow())) {
-            Ok(Some(data)) => Ok(data),
-            Ok(None) => Ok(Vec::new()),
-            Err(e) => Err(HandshakeError::ChaCha20(e)),
-        }
+        self.decryptor.decrypt(buffer)
+    }
+
+    #[inline]
+    fn read_frame(
+        &mut self,
+        reader: &mut dyn RecvFrame,
+    ) -> Result<Vec<u8>, transport::Error> {
+        self.decryptor.read_frame(reader)
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait]
+impl AsyncDecrypt for NoiseTranscoder {
+    #[inline]
+    async fn async_read_frame(
+        &mut self,
+        reader: &mut (dyn AsyncRecvFrame + Send),
+    ) -> Result<Vec<u8>, transport::Error> {
+        self.decryptor.async_read_frame(reader).await
     }
 }
 
@@ -342,10 +476,10 @@ impl Transcode for NoiseTranscoder {
 }
 
 impl Bipolar for NoiseTranscoder {
-    type Left = <Self as Transcode>::Encryptor;
-    type Right = <Self as Transcode>::Decryptor;
+    type Left = <Self as Transcode>::Decryptor;
+    type Right = <Self as Transcode>::Encryptor;
 
-    fn join(encryptor: Self::Left, decryptor: Self::Right) -> Self {
+    fn join(decryptor: Self::Left, encryptor: Self::Right) -> Self {
         Self {
             encryptor,
             decryptor,
@@ -353,7 +487,7 @@ impl Bipolar for NoiseTranscoder {
     }
 
     fn split(self) -> (Self::Left, Self::Right) {
-        (self.encryptor, self.decryptor)
+        (self.decryptor, self.encryptor)
     }
 }
 
@@ -602,6 +736,138 @@ mod tests {
         assert_eq!(connected_peer.decryptor.next(), None);
     }
 
+    #[test]
+    fn test_mirror_inspection() {
+        use crate::lnp::transport::capture::Inspect;
+
+        let (mut connected_peer, mut remote_peer) = setup_peers();
+        let (mut incoming, mut outgoing) = connected_peer.mirror();
+
+        let sent = connected_peer.encrypt_buf(&[1, 2, 3]).unwrap();
+        assert_eq!(outgoing.inspect(&sent[..18]), None);
+        assert_eq!(outgoing.inspect(&sent[18..]), Some(vec![1, 2, 3]));
+
+        let received = remote_peer.encrypt_buf(&[4, 5]).unwrap();
+        assert_eq!(incoming.inspect(&received), Some(vec![4, 5]));
+        assert_eq!(
+            connected_peer
+                .decrypt_single_message(Some(&received))
+                .unwrap(),
+            Some(vec![4, 5])
+        );
+    }
+
+    #[test]
+    fn test_resume_interrupted_frame() {
+        use crate::lnp::transport::{memory, Duplex, SendFrame};
+        use std::time::Duration;
+
+        let (mut connected_peer, mut remote_peer) = setup_peers();
+        let (mut left, mut right) = memory::pair();
+        right.set_timeout(Duration::from_millis(10));
+
+        let encrypted = remote_peer.encrypt_buf(&[1, 2, 3]).unwrap();
+        left.as_sender().send_raw(&encrypted[..20]).unwrap();
+        assert_eq!(
+            connected_peer.decryptor.read_frame(right.as_receiver()),
+            Err(transport::Error::TimedOut)
+        );
+
+        left.as_sender().send_raw(&encrypted[20..]).unwrap();
+        let frame = connected_peer
+            .decryptor
+            .read_frame(right.as_receiver())
+            .unwrap();
+        assert_eq!(frame, encrypted);
+        assert_eq!(connected_peer.decrypt(frame).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_resume_interrupted_tcp_frame() {
+        use crate::lnp::transport::{ftcp, Duplex, SendFrame};
+        use std::time::Duration;
+
+        let (mut connected_peer, mut remote_peer) = setup_peers();
+        let any_port: amplify::internet::InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = ftcp::Listener::bind(any_port).unwrap();
+        let mut sender =
+            ftcp::Connection::connect(listener.local_addr()).unwrap();
+        let mut receiver = listener.accept().unwrap();
+        receiver.set_timeout(Duration::from_millis(10)).unwrap();
+
+        // The frame is split across two timeouts: the first one interrupts
+        // reading the header, the second one - reading the body
+        let encrypted = remote_peer.encrypt_buf(&[1, 2, 3]).unwrap();
+        sender.as_sender().send_raw(&encrypted[..10]).unwrap();
+        assert_eq!(
+            connected_peer.decryptor.read_frame(receiver.as_receiver()),
+            Err(transport::Error::TimedOut)
+        );
+        sender.as_sender().send_raw(&encrypted[10..20]).unwrap();
+        assert_eq!(
+            connected_peer.decryptor.read_frame(receiver.as_receiver()),
+            Err(transport::Error::TimedOut)
+        );
+
+        sender.as_sender().send_raw(&encrypted[20..]).unwrap();
+        let frame = connected_peer
+            .decryptor
+            .read_frame(receiver.as_receiver())
+            .unwrap();
+        assert_eq!(frame, encrypted);
+        assert_eq!(connected_peer.decrypt(frame).unwrap(), vec![1, 2, 3]);
+
+        // Stream stays in sync for the following frames
+        let encrypted = remote_peer.encrypt_buf(&[4, 5]).unwrap();
+        sender.as_sender().send_raw(&encrypted).unwrap();
+        let frame = connected_peer
+            .decryptor
+            .read_frame(receiver.as_receiver())
+            .unwrap();
+        assert_eq!(connected_peer.decrypt(frame).unwrap(), vec![4, 5]);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_resume_cancelled_async_frame() {
+        use crate::lnp::transport::{ftcp, AsyncSendFrame};
+        use std::time::Duration;
+
+        let (mut connected_peer, mut remote_peer) = setup_peers();
+        let any_port: amplify::internet::InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = ftcp::AsyncListener::bind(any_port).await.unwrap();
+        let (mut sender, accepted) = tokio::join!(
+            async { ftcp::async_connect(listener.local_addr()).await.unwrap() },
+            async { listener.accept().await.unwrap() }
+        );
+        let (mut receiver, _) = accepted;
+
+        let encrypted = remote_peer.encrypt_buf(&[1, 2, 3]).unwrap();
+        let header = TAGGED_MESSAGE_LENGTH_HEADER_SIZE;
+        sender.async_send_raw(&encrypted[..header]).await.unwrap();
+        assert!(tokio::time::timeout(
+            Duration::from_millis(10),
+            connected_peer.decryptor.async_read_frame(&mut receiver)
+        )
+        .await
+        .is_err());
+
+        sender.async_send_raw(&encrypted[header..]).await.unwrap();
+        let frame = connected_peer
+            .decryptor
+            .async_read_frame(&mut receiver)
+            .await
+            .unwrap();
+        assert_eq!(frame, encrypted);
+        assert_eq!(connected_peer.decrypt(frame).unwrap(), vec![1, 2, 3]);
+    }
+
     #[test]
     fn max_msg_len_limit_value() {
         assert_eq!(LNP_MSG_MAX_LEN, 65535);
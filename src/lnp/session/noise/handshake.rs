@@ -23,6 +23,9 @@ use super::act::{
 };
 use super::conduit::{NoiseTranscoder, SymmetricKey};
 use super::{chacha, hkdf};
+#[cfg(feature = "async")]
+use crate::lnp::transport::AsyncDuplex;
+use crate::lnp::transport::{self, Duplex};
 
 // Alias type to help differentiate between temporary key and chaining key when
 // passing bytes around
@@ -49,6 +52,12 @@ pub enum HandshakeError {
     ChaCha20(chacha20poly1305::aead::Error),
 }
 
+impl From<HandshakeError> for transport::Error {
+    fn from(_: HandshakeError) -> Self {
+        transport::Error::HandshakeFailure
+    }
+}
+
 pub enum HandshakeState {
     InitiatorStarting(InitiatorStartingState),
     ResponderAwaitingActOne(ResponderAwaitingActOneState),
@@ -101,6 +110,74 @@ impl HandshakeState {
             }
         }
     }
+
+    /// Runs the handshake over the provided `connection` until it completes,
+    /// sending the acts produced by the local side and reading the acts
+    /// expected from the remote peer.
+    ///
+    /// # Returns
+    /// Transcoder for the established session and the static public key of
+    /// the remote node
+    pub fn complete(
+        self,
+        connection: &mut impl Duplex,
+    ) -> Result<(NoiseTranscoder, PublicKey), transport::Error> {
+        let mut state = self;
+        let mut input = vec![];
+        loop {
+            let (act, next) = state.next(&input)?;
+            if let Some(act) = act {
+                connection.as_sender().send_raw(&act)?;
+            }
+            state = match next {
+                HandshakeState::Complete(Some(result)) => return Ok(result),
+                HandshakeState::Complete(None) => {
+                    Err(transport::Error::HandshakeFailure)?
+                }
+                next => next,
+            };
+            input = connection.as_receiver().recv_raw(state.input_len())?;
+        }
+    }
+
+    /// Async version of [`HandshakeState::complete`]
+    #[cfg(feature = "async")]
+    pub async fn async_complete(
+        self,
+        connection: &mut (impl AsyncDuplex + Send),
+    ) -> Result<(NoiseTranscoder, PublicKey), transport::Error> {
+        let mut state = self;
+        let mut input = vec![];
+        loop {
+            let (act, next) = state.next(&input)?;
+            if let Some(act) = act {
+                connection.as_sender().async_send_raw(&act).await?;
+            }
+            state = match next {
+                HandshakeState::Complete(Some(result)) => return Ok(result),
+                HandshakeState::Complete(None) => {
+                    Err(transport::Error::HandshakeFailure)?
+                }
+                next => next,
+            };
+            input = connection
+                .as_receiver()
+                .async_recv_raw(state.input_len())
+                .await?;
+        }
+    }
+
+    /// Number of bytes which must be received from the remote peer before
+    /// proceeding to the next handshake state
+    fn input_len(&self) -> usize {
+        match self {
+            HandshakeState::InitiatorStarting(_) => 0,
+            HandshakeState::ResponderAwaitingActOne(_) => ACT_ONE_LENGTH,
+            HandshakeState::InitiatorAwaitingActTwo(_) => ACT_TWO_LENGTH,
+            HandshakeState::ResponderAwaitingActThree(_) => ACT_THREE_LENGTH,
+            HandshakeState::Complete(_) => 0,
+        }
+    }
 }
 
 // Handshake state of the Initiator prior to generating Act 1
@@ -12,29 +12,46 @@
 // If not, see <https://opensource.org/licenses/MIT>.
 
 use amplify::{internet::InetSocketAddr, Bipolar};
+use bitcoin::secp256k1::{self, rand::thread_rng};
 use core::any::Any;
 
-use super::{Decrypt, Encrypt, Transcode};
+use super::noise::{HandshakeState, NoiseTranscoder};
+use super::Error as SessionError;
+use super::{Decrypt, Encrypt, LocalNode, Transcode};
 use crate::lnp::session::PlainTranscoder;
+#[cfg(not(target_os = "windows"))]
+use crate::lnp::transport::posix;
+#[cfg(feature = "smtp")]
+use crate::lnp::transport::smtp;
+#[cfg(feature = "websockets")]
+use crate::lnp::transport::websocket;
 use crate::lnp::transport::{
-    ftcp, zmqsocket, Duplex, Error, RecvFrame, RoutedFrame, SendFrame,
+    capture, ftcp, http, memory, zmqsocket, Duplex, Error, RecvFrame,
+    RoutedFrame, SendFrame,
 };
+#[cfg(feature = "async")]
+use crate::lnp::{session::AsyncDecrypt, transport::AsyncDuplex};
 
 // Generics prevents us from using session as `&dyn` reference, so we have
 // to avoid `where Self: Input + Output` and generic parameters, unlike with
 // `Transcode`
 pub trait Session {
-    fn recv_raw_message(&mut self) -> Result<Vec<u8>, Error>;
-    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, Error>;
-    fn recv_routed_message(&mut self) -> Result<RoutedFrame, Error>;
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, SessionError>;
+    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, SessionError>;
+    fn recv_routed_message(&mut self) -> Result<RoutedFrame, SessionError>;
     fn send_routed_message(
         &mut self,
         source: &[u8],
         route: &[u8],
         dest: &[u8],
         raw: &[u8],
-    ) -> Result<usize, Error>;
+    ) -> Result<usize, SessionError>;
     fn into_any(self: Box<Self>) -> Box<dyn Any>;
+    /// Splits the session into independent input and output halves, which
+    /// may be used from different threads
+    fn split(
+        self: Box<Self>,
+    ) -> (Box<dyn Input + Send>, Box<dyn Output + Send>);
 }
 
 pub trait Split {
@@ -42,8 +59,8 @@ pub trait Split {
 }
 
 pub trait Input {
-    fn recv_raw_message(&mut self) -> Result<Vec<u8>, Error>;
-    fn recv_routed_message(&mut self) -> Result<RoutedFrame, Error> {
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, SessionError>;
+    fn recv_routed_message(&mut self) -> Result<RoutedFrame, SessionError> {
         // We panic here because this is a program architecture design
         // error and developer must be notified about it; the program using
         // this pattern can't work
@@ -52,14 +69,14 @@ pub trait Input {
 }
 
 pub trait Output {
-    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, Error>;
+    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, SessionError>;
     fn send_routed_message(
         &mut self,
         source: &[u8],
         route: &[u8],
         dest: &[u8],
         raw: &[u8],
-    ) -> Result<usize, Error> {
+    ) -> Result<usize, SessionError> {
         // We panic here because this is a program architecture design
         // error and developer must be notified about it; the program using
         // this pattern can't work
@@ -101,30 +118,33 @@ where
 impl<T, C> Session for Raw<T, C>
 where
     T: Transcode + 'static,
-    T::Left: Decrypt,
-    T::Right: Encrypt,
+    T::Left: Decrypt + Send + 'static,
+    T::Right: Encrypt + Send + 'static,
     C: Duplex + Bipolar + 'static,
-    C::Left: RecvFrame,
-    C::Right: SendFrame,
-    Error: From<T::Error> + From<<T::Left as Decrypt>::Error>,
+    C::Left: RecvFrame + Send + 'static,
+    C::Right: SendFrame + Send + 'static,
 {
     #[inline]
-    fn recv_raw_message(&mut self) -> Result<Vec<u8>, Error> {
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, SessionError> {
         let reader = self.connection.as_receiver();
-        Ok(self.transcoder.decrypt(reader.recv_frame()?)?)
+        let frame = self.transcoder.read_frame(reader)?;
+        self.transcoder.decrypt(frame).map_err(Into::into)
     }
 
     #[inline]
-    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, Error> {
+    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, SessionError> {
         let writer = self.connection.as_sender();
         Ok(writer.send_frame(&self.transcoder.encrypt(raw))?)
     }
 
     #[inline]
-    fn recv_routed_message(&mut self) -> Result<RoutedFrame, Error> {
+    fn recv_routed_message(&mut self) -> Result<RoutedFrame, SessionError> {
         let reader = self.connection.as_receiver();
         let mut routed_frame = reader.recv_routed()?;
-        routed_frame.msg = self.transcoder.decrypt(routed_frame.msg)?;
+        routed_frame.msg = self
+            .transcoder
+            .decrypt(routed_frame.msg)
+            .map_err(Into::<SessionError>::into)?;
         Ok(routed_frame)
     }
 
@@ -135,7 +155,7 @@ where
         route: &[u8],
         dest: &[u8],
         raw: &[u8],
-    ) -> Result<usize, Error> {
+    ) -> Result<usize, SessionError> {
         let writer = self.connection.as_sender();
         Ok(writer.send_routed(
             source,
@@ -149,6 +169,13 @@ where
     fn into_any(self: Box<Self>) -> Box<dyn Any> {
         self
     }
+
+    #[inline]
+    fn split(
+        self: Box<Self>,
+    ) -> (Box<dyn Input + Send>, Box<dyn Output + Send>) {
+        Split::split(*self)
+    }
 }
 
 impl<T, C> Split for Raw<T, C>
@@ -159,7 +186,6 @@ where
     C: Duplex + Bipolar,
     C::Left: RecvFrame + Send + 'static,
     C::Right: SendFrame + Send + 'static,
-    Error: From<T::Error> + From<<T::Left as Decrypt>::Error>,
 {
     #[inline]
     fn split(self) -> (Box<dyn Input + Send>, Box<dyn Output + Send>) {
@@ -192,14 +218,447 @@ impl Raw<PlainTranscoder, ftcp::Connection> {
         })
     }
 
+    /// Accepts the first incoming connection on `socket_addr`. The listening
+    /// socket is closed afterwards; use [`Raw::accept_ftcp_unencrypted_from`]
+    /// to serve multiple peers on the same port.
     pub fn accept_ftcp_unencrypted(
         socket_addr: InetSocketAddr,
+    ) -> Result<Self, Error> {
+        let (connection, _) = ftcp::Connection::accept(socket_addr)?;
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection,
+        })
+    }
+
+    /// Accepts next incoming connection from the `listener`
+    pub fn accept_ftcp_unencrypted_from(
+        listener: &ftcp::Listener,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection: listener.accept()?,
+        })
+    }
+}
+
+impl Raw<NoiseTranscoder, ftcp::Connection> {
+    /// Connects to the remote node with `node_id` and runs BOLT-8 noise
+    /// handshake as an initiator, using keys from the `local` node
+    pub fn connect_ftcp_encrypted(
+        node_id: secp256k1::PublicKey,
+        socket_addr: InetSocketAddr,
+        local: &LocalNode,
+    ) -> Result<Self, Error> {
+        Self::initiator_handshake(
+            ftcp::Connection::connect(socket_addr)?,
+            node_id,
+            local,
+        )
+    }
+
+    /// Connects to the remote node with `node_id` through SOCKS5 `proxy` (like
+    /// Tor) and runs BOLT-8 noise handshake as an initiator, using keys from
+    /// the `local` node
+    pub fn connect_ftcp_encrypted_via(
+        node_id: secp256k1::PublicKey,
+        socket_addr: InetSocketAddr,
+        proxy: std::net::SocketAddr,
+        local: &LocalNode,
+    ) -> Result<Self, Error> {
+        Self::initiator_handshake(
+            ftcp::Connection::connect_via(socket_addr, proxy)?,
+            node_id,
+            local,
+        )
+    }
+
+    /// Accepts the first incoming connection on `socket_addr` and runs BOLT-8
+    /// noise handshake as a responder, using keys from the `local` node. The
+    /// listening socket is closed afterwards; use
+    /// [`Raw::accept_ftcp_encrypted_from`] to serve multiple peers on the
+    /// same port. Returns the session together with the node id of the
+    /// remote peer.
+    pub fn accept_ftcp_encrypted(
+        socket_addr: InetSocketAddr,
+        local: &LocalNode,
+    ) -> Result<(Self, secp256k1::PublicKey), Error> {
+        let (connection, _) = ftcp::Connection::accept(socket_addr)?;
+        Self::responder_handshake(connection, local)
+    }
+
+    /// Accepts next incoming connection from the `listener` and runs BOLT-8
+    /// noise handshake as a responder, using keys from the `local` node.
+    /// Returns the session together with the node id of the remote peer.
+    pub fn accept_ftcp_encrypted_from(
+        listener: &ftcp::Listener,
+        local: &LocalNode,
+    ) -> Result<(Self, secp256k1::PublicKey), Error> {
+        Self::responder_handshake(listener.accept()?, local)
+    }
+
+    /// Returns address of the remote peer
+    #[inline]
+    pub fn remote_addr(&self) -> InetSocketAddr {
+        self.connection.remote_addr()
+    }
+}
+
+impl<C> Raw<NoiseTranscoder, C>
+where
+    C: Duplex + Bipolar,
+    C::Left: RecvFrame,
+    C::Right: SendFrame,
+{
+    fn initiator_handshake(
+        mut connection: C,
+        node_id: secp256k1::PublicKey,
+        local: &LocalNode,
+    ) -> Result<Self, Error> {
+        let (transcoder, _) = HandshakeState::new_initiator(
+            &local.private_key(),
+            &node_id,
+            &ephemeral_key(),
+        )
+        .complete(&mut connection)?;
+        Ok(Self {
+            transcoder,
+            connection,
+        })
+    }
+
+    fn responder_handshake(
+        mut connection: C,
+        local: &LocalNode,
+    ) -> Result<(Self, secp256k1::PublicKey), Error> {
+        let (transcoder, remote) = HandshakeState::new_responder(
+            &local.private_key(),
+            &ephemeral_key(),
+        )
+        .complete(&mut connection)?;
+        Ok((
+            Self {
+                transcoder,
+                connection,
+            },
+            remote,
+        ))
+    }
+}
+
+/// Generates ephemeral key for BOLT-8 noise handshake, which must be fresh
+/// for each handshake
+fn ephemeral_key() -> secp256k1::SecretKey {
+    secp256k1::SecretKey::new(&mut thread_rng())
+}
+
+#[cfg(feature = "websockets")]
+impl Raw<PlainTranscoder, websocket::Connection> {
+    pub fn connect_websocket_unencrypted(
+        socket_addr: InetSocketAddr,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection: websocket::Connection::connect(socket_addr)?,
+        })
+    }
+
+    pub fn accept_websocket_unencrypted(
+        socket_addr: InetSocketAddr,
+    ) -> Result<Self, Error> {
+        let (connection, _) = websocket::Connection::accept(socket_addr)?;
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection,
+        })
+    }
+}
+
+#[cfg(feature = "websockets")]
+impl Raw<NoiseTranscoder, websocket::Connection> {
+    /// Connects to the remote node with `node_id` over websocket and runs
+    /// BOLT-8 noise handshake as an initiator, using keys from the `local`
+    /// node
+    pub fn connect_websocket_encrypted(
+        node_id: secp256k1::PublicKey,
+        socket_addr: InetSocketAddr,
+        local: &LocalNode,
+    ) -> Result<Self, Error> {
+        Self::initiator_handshake(
+            websocket::Connection::connect(socket_addr)?,
+            node_id,
+            local,
+        )
+    }
+
+    /// Accepts the first incoming websocket connection on `socket_addr` and
+    /// runs BOLT-8 noise handshake as a responder, using keys from the
+    /// `local` node. The listening socket is closed afterwards. Returns the
+    /// session together with the node id of the remote peer.
+    pub fn accept_websocket_encrypted(
+        socket_addr: InetSocketAddr,
+        local: &LocalNode,
+    ) -> Result<(Self, secp256k1::PublicKey), Error> {
+        let (connection, _) = websocket::Connection::accept(socket_addr)?;
+        Self::responder_handshake(connection, local)
+    }
+
+    /// Accepts next incoming websocket connection from the TCP `listener`
+    /// and runs BOLT-8 noise handshake as a responder, using keys from the
+    /// `local` node. Returns the session together with the node id of the
+    /// remote peer.
+    pub fn accept_websocket_encrypted_from(
+        listener: &ftcp::Listener,
+        local: &LocalNode,
+    ) -> Result<(Self, secp256k1::PublicKey), Error> {
+        Self::responder_handshake(
+            websocket::Connection::accept_from(listener)?,
+            local,
+        )
+    }
+
+    /// Returns address of the remote peer
+    #[inline]
+    pub fn remote_addr(&self) -> InetSocketAddr {
+        self.connection.remote_addr()
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Raw<PlainTranscoder, posix::Connection> {
+    pub fn connect_posix_unencrypted(
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection: posix::Connection::connect(path)?,
+        })
+    }
+
+    /// Accepts the first incoming connection on the socket file at `path`,
+    /// which is removed afterwards; use
+    /// [`Raw::accept_posix_unencrypted_from`] to serve multiple clients.
+    pub fn accept_posix_unencrypted(
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Self, Error> {
+        let (connection, _) = posix::Connection::accept(path)?;
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection,
+        })
+    }
+
+    /// Accepts next incoming connection from the `listener`
+    pub fn accept_posix_unencrypted_from(
+        listener: &posix::Listener,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection: listener.accept()?,
+        })
+    }
+}
+
+impl Raw<PlainTranscoder, http::Connection> {
+    /// Connects to the remote HTTP server, sending each message as a body of
+    /// HTTP POST request encoded with `encoding`
+    pub fn connect_http_unencrypted(
+        socket_addr: InetSocketAddr,
+        encoding: http::Encoding,
     ) -> Result<Self, Error> {
         Ok(Self {
             transcoder: PlainTranscoder,
-            connection: ftcp::Connection::accept(socket_addr)?,
+            connection: http::Connection::connect(socket_addr, encoding)?,
         })
     }
+
+    /// Accepts the first incoming HTTP connection on `socket_addr`,
+    /// receiving messages as HTTP POST request bodies and sending messages as
+    /// responses encoded with `encoding`. The listening socket is closed
+    /// afterwards.
+    pub fn accept_http_unencrypted(
+        socket_addr: InetSocketAddr,
+        encoding: http::Encoding,
+    ) -> Result<Self, Error> {
+        let (connection, _) = http::Connection::accept(socket_addr, encoding)?;
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection,
+        })
+    }
+
+    /// Accepts next incoming HTTP connection from the TCP `listener`
+    pub fn accept_http_unencrypted_from(
+        listener: &ftcp::Listener,
+        encoding: http::Encoding,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection: http::Connection::accept_from(listener, encoding)?,
+        })
+    }
+
+    /// Returns address of the remote peer
+    #[inline]
+    pub fn remote_addr(&self) -> InetSocketAddr {
+        self.connection.remote_addr()
+    }
+}
+
+#[cfg(feature = "smtp")]
+impl<M> Raw<PlainTranscoder, smtp::Connection<M>>
+where
+    M: smtp::Mailbox + Send + 'static,
+{
+    /// Constructs session sending messages through the SMTP `relay` and
+    /// receiving them from the `mailbox`
+    pub fn with_smtp_unencrypted(relay: smtp::Relay, mailbox: M) -> Self {
+        Self {
+            transcoder: PlainTranscoder,
+            connection: smtp::Connection::with(relay, mailbox),
+        }
+    }
+}
+
+#[cfg(feature = "smtp")]
+impl<M> Raw<NoiseTranscoder, smtp::Connection<M>>
+where
+    M: smtp::Mailbox + Send + 'static,
+{
+    /// Runs BOLT-8 noise handshake with the remote node with `node_id` as an
+    /// initiator over SMTP, using keys from the `local` node. Since mail is
+    /// delivered with high latency, the handshake may take a long time.
+    pub fn connect_smtp_encrypted(
+        node_id: secp256k1::PublicKey,
+        relay: smtp::Relay,
+        mailbox: M,
+        local: &LocalNode,
+    ) -> Result<Self, Error> {
+        Self::initiator_handshake(
+            smtp::Connection::with(relay, mailbox),
+            node_id,
+            local,
+        )
+    }
+
+    /// Waits for BOLT-8 noise handshake initiated by a remote node over SMTP
+    /// and completes it as a responder, using keys from the `local` node.
+    /// Returns the session together with the node id of the remote peer.
+    pub fn accept_smtp_encrypted(
+        relay: smtp::Relay,
+        mailbox: M,
+        local: &LocalNode,
+    ) -> Result<(Self, secp256k1::PublicKey), Error> {
+        Self::responder_handshake(smtp::Connection::with(relay, mailbox), local)
+    }
+}
+
+impl Raw<PlainTranscoder, memory::Connection> {
+    /// Constructs unencrypted session over one side of the in-memory
+    /// connection pair
+    pub fn with_memory_unencrypted(connection: memory::Connection) -> Self {
+        Self {
+            transcoder: PlainTranscoder,
+            connection,
+        }
+    }
+}
+
+impl Raw<NoiseTranscoder, memory::Connection> {
+    /// Runs BOLT-8 noise handshake with the remote node with `node_id` as an
+    /// initiator over one side of the in-memory connection pair, using keys
+    /// from the `local` node
+    pub fn connect_memory_encrypted(
+        connection: memory::Connection,
+        node_id: secp256k1::PublicKey,
+        local: &LocalNode,
+    ) -> Result<Self, Error> {
+        Self::initiator_handshake(connection, node_id, local)
+    }
+
+    /// Completes BOLT-8 noise handshake as a responder over one side of the
+    /// in-memory connection pair, using keys from the `local` node. Returns
+    /// the session together with the node id of the remote peer.
+    pub fn accept_memory_encrypted(
+        connection: memory::Connection,
+        local: &LocalNode,
+    ) -> Result<(Self, secp256k1::PublicKey), Error> {
+        Self::responder_handshake(connection, local)
+    }
+}
+
+impl<C> Raw<PlainTranscoder, capture::Recorder<C>>
+where
+    C: Bipolar,
+    C::Left: RecvFrame + Send + 'static,
+    C::Right: SendFrame + Send + 'static,
+{
+    /// Constructs unencrypted session over the `connection` to the remote
+    /// `peer`, recording all frames together with their payloads into
+    /// `capture`
+    pub fn with_capture_unencrypted(
+        connection: C,
+        capture: capture::Capture,
+        peer: Option<secp256k1::PublicKey>,
+    ) -> Self {
+        let mut connection = capture::Recorder::with(connection, capture, peer);
+        connection.set_inspectors(
+            Box::new(capture::PlainInspector::default()),
+            Box::new(capture::PlainInspector::default()),
+        );
+        Self {
+            transcoder: PlainTranscoder,
+            connection,
+        }
+    }
+}
+
+impl<C> Raw<NoiseTranscoder, capture::Recorder<C>>
+where
+    C: Bipolar,
+    C::Left: RecvFrame + Send + 'static,
+    C::Right: SendFrame + Send + 'static,
+{
+    /// Runs BOLT-8 noise handshake with the remote node with `node_id` as an
+    /// initiator over the `connection`, using keys from the `local` node.
+    /// All frames, including handshake acts, are recorded into `capture`;
+    /// frames sent after the handshake are recorded with decrypted payloads.
+    pub fn connect_capture_encrypted(
+        connection: C,
+        capture: capture::Capture,
+        node_id: secp256k1::PublicKey,
+        local: &LocalNode,
+    ) -> Result<Self, Error> {
+        let connection =
+            capture::Recorder::with(connection, capture, Some(node_id));
+        let mut session =
+            Self::initiator_handshake(connection, node_id, local)?;
+        session.inspect_payloads();
+        Ok(session)
+    }
+
+    /// Completes BOLT-8 noise handshake as a responder over the `connection`,
+    /// using keys from the `local` node and recording all frames into
+    /// `capture` like [`Raw::connect_capture_encrypted`]. Returns the session
+    /// together with the node id of the remote peer.
+    pub fn accept_capture_encrypted(
+        connection: C,
+        capture: capture::Capture,
+        local: &LocalNode,
+    ) -> Result<(Self, secp256k1::PublicKey), Error> {
+        let connection = capture::Recorder::with(connection, capture, None);
+        let (mut session, remote) =
+            Self::responder_handshake(connection, local)?;
+        session.connection.set_peer(Some(remote));
+        session.inspect_payloads();
+        Ok((session, remote))
+    }
+
+    fn inspect_payloads(&mut self) {
+        let (incoming, outgoing) = self.transcoder.mirror();
+        self.connection
+            .set_inspectors(Box::new(incoming), Box::new(outgoing));
+    }
 }
 
 impl Raw<PlainTranscoder, zmqsocket::Connection> {
@@ -217,6 +676,24 @@ impl Raw<PlainTranscoder, zmqsocket::Connection> {
         })
     }
 
+    /// Constructs ZMQ session secured with CURVE encryption and
+    /// authentication according to the `curve` configuration. LNP framing
+    /// is not applied on top of ZMQ, so the transcoder remains plain.
+    pub fn with_zmq_curve(
+        zmq_type: zmqsocket::ZmqType,
+        remote: &zmqsocket::ZmqSocketAddr,
+        local: Option<zmqsocket::ZmqSocketAddr>,
+        identity: Option<&[u8]>,
+        curve: &zmqsocket::Curve,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection: zmqsocket::Connection::with_curve(
+                zmq_type, remote, local, identity, curve,
+            )?,
+        })
+    }
+
     pub fn from_zmq_socket_unencrypted(
         zmq_type: zmqsocket::ZmqType,
         socket: zmq::Socket,
@@ -241,19 +718,152 @@ where
     }
 }
 
+/// Async version of [`Session`] trait
+#[cfg(feature = "async")]
+#[async_trait]
+pub trait AsyncSession {
+    async fn async_recv_raw_message(&mut self)
+        -> Result<Vec<u8>, SessionError>;
+    async fn async_send_raw_message(
+        &mut self,
+        raw: &[u8],
+    ) -> Result<usize, SessionError>;
+}
+
+/// Async version of [`Raw`] session, which does not require a dedicated
+/// thread per connection
+#[cfg(feature = "async")]
+pub struct AsyncRaw<T, C>
+where
+    T: Transcode + AsyncDecrypt,
+    C: AsyncDuplex,
+{
+    pub(self) transcoder: T,
+    pub(self) connection: C,
+}
+
+#[cfg(feature = "async")]
+#[async_trait]
+impl<T, C> AsyncSession for AsyncRaw<T, C>
+where
+    T: Transcode + AsyncDecrypt + Send,
+    C: AsyncDuplex + Send,
+{
+    #[inline]
+    async fn async_recv_raw_message(
+        &mut self,
+    ) -> Result<Vec<u8>, SessionError> {
+        let reader = self.connection.as_receiver();
+        let frame = self.transcoder.async_read_frame(reader).await?;
+        self.transcoder.decrypt(frame).map_err(Into::into)
+    }
+
+    #[inline]
+    async fn async_send_raw_message(
+        &mut self,
+        raw: &[u8],
+    ) -> Result<usize, SessionError> {
+        let frame = self.transcoder.encrypt(raw);
+        Ok(self.connection.as_sender().async_send_frame(&frame).await?)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T> AsyncRaw<T, tokio::net::TcpStream>
+where
+    T: Transcode + AsyncDecrypt,
+{
+    /// Returns address of the remote peer
+    pub fn remote_addr(&self) -> Result<InetSocketAddr, Error> {
+        Ok(self.connection.peer_addr()?.into())
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncRaw<PlainTranscoder, tokio::net::TcpStream> {
+    pub async fn connect_ftcp_unencrypted(
+        socket_addr: InetSocketAddr,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection: ftcp::async_connect(socket_addr).await?,
+        })
+    }
+
+    /// Accepts next incoming connection from the `listener`
+    pub async fn accept_ftcp_unencrypted_from(
+        listener: &ftcp::AsyncListener,
+    ) -> Result<Self, Error> {
+        let (connection, _) = listener.accept().await?;
+        Ok(Self {
+            transcoder: PlainTranscoder,
+            connection,
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncRaw<NoiseTranscoder, tokio::net::TcpStream> {
+    /// Connects to the remote node with `node_id` and runs BOLT-8 noise
+    /// handshake as an initiator, using keys from the `local` node
+    pub async fn connect_ftcp_encrypted(
+        node_id: secp256k1::PublicKey,
+        socket_addr: InetSocketAddr,
+        local: &LocalNode,
+    ) -> Result<Self, Error> {
+        let mut connection = ftcp::async_connect(socket_addr).await?;
+        let (transcoder, _) = HandshakeState::new_initiator(
+            &local.private_key(),
+            &node_id,
+            &ephemeral_key(),
+        )
+        .async_complete(&mut connection)
+        .await?;
+        Ok(Self {
+            transcoder,
+            connection,
+        })
+    }
+
+    /// Accepts next incoming connection from the `listener` and runs BOLT-8
+    /// noise handshake as a responder, using keys from the `local` node.
+    /// Returns the session together with the node id of the remote peer.
+    pub async fn accept_ftcp_encrypted_from(
+        listener: &ftcp::AsyncListener,
+        local: &LocalNode,
+    ) -> Result<(Self, secp256k1::PublicKey), Error> {
+        let (mut connection, _) = listener.accept().await?;
+        let (transcoder, remote) = HandshakeState::new_responder(
+            &local.private_key(),
+            &ephemeral_key(),
+        )
+        .async_complete(&mut connection)
+        .await?;
+        Ok((
+            Self {
+                transcoder,
+                connection,
+            },
+            remote,
+        ))
+    }
+}
+
 impl<T, C> Input for RawInput<T, C>
 where
     T: Decrypt,
     C: RecvFrame,
-    // TODO: (new) Use session-level error type
-    Error: From<T::Error>,
 {
-    fn recv_raw_message(&mut self) -> Result<Vec<u8>, Error> {
-        Ok(self.decryptor.decrypt(self.input.recv_frame()?)?)
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, SessionError> {
+        let frame = self.decryptor.read_frame(&mut self.input)?;
+        self.decryptor.decrypt(frame).map_err(Into::into)
     }
-    fn recv_routed_message(&mut self) -> Result<RoutedFrame, Error> {
+    fn recv_routed_message(&mut self) -> Result<RoutedFrame, SessionError> {
         let mut routed_frame = self.input.recv_routed()?;
-        routed_frame.msg = self.decryptor.decrypt(routed_frame.msg)?;
+        routed_frame.msg = self
+            .decryptor
+            .decrypt(routed_frame.msg)
+            .map_err(Into::<SessionError>::into)?;
         Ok(routed_frame)
     }
 }
@@ -263,7 +873,7 @@ where
     T: Encrypt,
     C: SendFrame,
 {
-    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, Error> {
+    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, SessionError> {
         Ok(self.output.send_frame(&self.encryptor.encrypt(raw))?)
     }
     fn send_routed_message(
@@ -272,7 +882,7 @@ where
         route: &[u8],
         dest: &[u8],
         raw: &[u8],
-    ) -> Result<usize, Error> {
+    ) -> Result<usize, SessionError> {
         let encrypted = self.encryptor.encrypt(raw);
         Ok(self.output.send_routed(source, route, dest, &encrypted)?)
     }
@@ -281,6 +891,7 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::lnp::test_helpers::local_node;
 
     #[test]
     fn test_zmq_no_encryption() {
@@ -308,4 +919,355 @@ mod test {
         rx.send_raw_message(msg).unwrap();
         assert_eq!(tx.recv_raw_message().unwrap(), msg);
     }
+
+    #[test]
+    fn test_zmq_curve_encryption() {
+        // ZMQ binds the socket by itself, so we look up a free port first
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let locator = zmqsocket::ZmqSocketAddr::Tcp(port);
+        let server = zmqsocket::CurveKeys::generate().unwrap();
+        let client = zmqsocket::CurveKeys::generate().unwrap();
+        let stranger = zmqsocket::CurveKeys::generate().unwrap();
+        let mut allowlist = std::collections::BTreeSet::new();
+        allowlist.insert(client.public_key);
+
+        let mut rx = Raw::with_zmq_curve(
+            zmqsocket::ZmqType::Rep,
+            &locator,
+            None,
+            None,
+            &zmqsocket::Curve::Server {
+                keys: server.clone(),
+                allowlist: Some(allowlist),
+            },
+        )
+        .unwrap();
+        let mut tx = Raw::with_zmq_curve(
+            zmqsocket::ZmqType::Req,
+            &locator,
+            None,
+            None,
+            &zmqsocket::Curve::Client {
+                keys: client,
+                server_key: server.public_key,
+            },
+        )
+        .unwrap();
+
+        let msg = b"Some message";
+        tx.send_raw_message(msg).unwrap();
+        assert_eq!(rx.recv_raw_message().unwrap(), msg);
+        rx.send_raw_message(msg).unwrap();
+        assert_eq!(tx.recv_raw_message().unwrap(), msg);
+
+        // Clients with keys not in the allowlist must be rejected
+        let mut tx = Raw::with_zmq_curve(
+            zmqsocket::ZmqType::Req,
+            &locator,
+            None,
+            None,
+            &zmqsocket::Curve::Client {
+                keys: stranger,
+                server_key: server.public_key,
+            },
+        )
+        .unwrap();
+        tx.connection.as_socket().set_rcvtimeo(200).unwrap();
+        tx.send_raw_message(msg).unwrap();
+        assert!(tx.recv_raw_message().is_err());
+
+        // PUSH/PULL local sockets can't be secured with the same
+        // configuration
+        assert_eq!(
+            Raw::with_zmq_curve(
+                zmqsocket::ZmqType::Push,
+                &zmqsocket::ZmqSocketAddr::Inproc(s!("test-curve-push")),
+                Some(zmqsocket::ZmqSocketAddr::Inproc(s!("test-curve-pull"))),
+                None,
+                &zmqsocket::Curve::Client {
+                    keys: server.clone(),
+                    server_key: server.public_key,
+                },
+            )
+            .err(),
+            Some(Error::CurveNotApplicable)
+        );
+    }
+
+    #[test]
+    fn test_ftcp_noise_encryption() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = ftcp::Listener::bind(any_port).unwrap();
+        let socket_addr = listener.local_addr();
+        let responder = local_node(0x21);
+        let initiator = local_node(0x11);
+        let node_id = responder.node_id();
+        let remote_id = initiator.node_id();
+
+        let rx = std::thread::spawn(move || {
+            let (mut session, remote) =
+                Raw::accept_ftcp_encrypted_from(&listener, &responder).unwrap();
+            assert_eq!(remote, remote_id);
+            let msg = session.recv_raw_message().unwrap();
+            session.send_raw_message(&msg).unwrap();
+        });
+
+        let mut tx =
+            Raw::connect_ftcp_encrypted(node_id, socket_addr, &initiator)
+                .unwrap();
+        let msg = b"Some message";
+        tx.send_raw_message(msg).unwrap();
+        assert_eq!(tx.recv_raw_message().unwrap(), msg);
+        rx.join().unwrap();
+    }
+
+    #[test]
+    fn test_ftcp_listener_many_peers() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = ftcp::Listener::bind(any_port).unwrap();
+        let socket_addr = listener.local_addr();
+        let responder = local_node(0x31);
+        let node_id = responder.node_id();
+
+        let peers = (1u8..=2)
+            .map(|seed| {
+                std::thread::spawn(move || {
+                    let initiator = local_node(seed);
+                    let mut tx = Raw::connect_ftcp_encrypted(
+                        node_id,
+                        socket_addr,
+                        &initiator,
+                    )
+                    .unwrap();
+                    tx.send_raw_message(&[seed]).unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut received = (0..2)
+            .map(|_| {
+                let (mut session, _) =
+                    Raw::accept_ftcp_encrypted_from(&listener, &responder)
+                        .unwrap();
+                assert_eq!(session.remote_addr().address, socket_addr.address);
+                session.recv_raw_message().unwrap()
+            })
+            .collect::<Vec<_>>();
+        received.sort();
+        assert_eq!(received, vec![vec![1u8], vec![2u8]]);
+        peers.into_iter().for_each(|peer| peer.join().unwrap());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_posix_no_encryption() {
+        let path = std::env::temp_dir()
+            .join(format!("lnpbp-session-{}.sock", std::process::id()));
+        let listener = posix::Listener::bind(&path).unwrap();
+
+        let rx = std::thread::spawn(move || {
+            let mut session =
+                Raw::accept_posix_unencrypted_from(&listener).unwrap();
+            let msg = session.recv_raw_message().unwrap();
+            session.send_raw_message(&msg).unwrap();
+        });
+
+        let mut tx = Raw::connect_posix_unencrypted(&path).unwrap();
+        let msg = b"Some message";
+        tx.send_raw_message(msg).unwrap();
+        assert_eq!(tx.recv_raw_message().unwrap(), msg);
+        rx.join().unwrap();
+    }
+
+    #[test]
+    fn test_http_no_encryption() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = ftcp::Listener::bind(any_port).unwrap();
+        let socket_addr = listener.local_addr();
+
+        let rx = std::thread::spawn(move || {
+            let mut session = Raw::accept_http_unencrypted_from(
+                &listener,
+                http::Encoding::Binary,
+            )
+            .unwrap();
+            let msg = session.recv_raw_message().unwrap();
+            session.send_raw_message(&msg).unwrap();
+        });
+
+        let mut tx =
+            Raw::connect_http_unencrypted(socket_addr, http::Encoding::Hex)
+                .unwrap();
+        let msg = b"Some message";
+        tx.send_raw_message(msg).unwrap();
+        assert_eq!(tx.recv_raw_message().unwrap(), msg);
+        rx.join().unwrap();
+    }
+
+    #[test]
+    fn test_memory_noise_encryption() {
+        let responder = local_node(0x21);
+        let initiator = local_node(0x11);
+        let node_id = responder.node_id();
+        let remote_id = initiator.node_id();
+        let (left, right) = memory::pair();
+
+        let rx = std::thread::spawn(move || {
+            let (mut session, remote) =
+                Raw::accept_memory_encrypted(right, &responder).unwrap();
+            assert_eq!(remote, remote_id);
+            let msg = session.recv_raw_message().unwrap();
+            session.send_raw_message(&msg).unwrap();
+        });
+
+        let mut tx =
+            Raw::connect_memory_encrypted(left, node_id, &initiator).unwrap();
+        let msg = b"Some message";
+        tx.send_raw_message(msg).unwrap();
+        assert_eq!(tx.recv_raw_message().unwrap(), msg);
+        rx.join().unwrap();
+    }
+
+    #[test]
+    fn test_capture_noise_encryption() {
+        let responder = local_node(0x21);
+        let initiator = local_node(0x11);
+        let node_id = responder.node_id();
+        let path = std::env::temp_dir()
+            .join(format!("lnp-session-capture-{}", std::process::id()));
+        let (left, right) = memory::pair();
+
+        let rx = std::thread::spawn(move || {
+            let (mut session, _) =
+                Raw::accept_memory_encrypted(right, &responder).unwrap();
+            let msg = session.recv_raw_message().unwrap();
+            session.send_raw_message(&msg).unwrap();
+        });
+
+        let capture = capture::Capture::create(&path).unwrap();
+        let mut tx =
+            Raw::connect_capture_encrypted(left, capture, node_id, &initiator)
+                .unwrap();
+        let msg = b"Some message";
+        tx.send_raw_message(msg).unwrap();
+        assert_eq!(tx.recv_raw_message().unwrap(), msg);
+        rx.join().unwrap();
+
+        let payloads = capture::Reader::open(&path)
+            .unwrap()
+            .map(Result::unwrap)
+            .inspect(|record| assert_eq!(record.peer, Some(node_id)))
+            .filter_map(|record| record.payload.map(|p| (record.direction, p)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            payloads,
+            vec![
+                (capture::Direction::Sent, msg.to_vec()),
+                (capture::Direction::Received, msg.to_vec())
+            ]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "websockets")]
+    #[test]
+    fn test_websocket_noise_encryption() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = ftcp::Listener::bind(any_port).unwrap();
+        let socket_addr = listener.local_addr();
+        let responder = local_node(0x41);
+        let initiator = local_node(0x51);
+        let node_id = responder.node_id();
+
+        let rx = std::thread::spawn(move || {
+            let (mut session, _) =
+                Raw::accept_websocket_encrypted_from(&listener, &responder)
+                    .unwrap();
+            let msg = session.recv_raw_message().unwrap();
+            session.send_raw_message(&msg).unwrap();
+        });
+
+        let mut tx =
+            Raw::connect_websocket_encrypted(node_id, socket_addr, &initiator)
+                .unwrap();
+        let msg = b"Some message";
+        tx.send_raw_message(msg).unwrap();
+        assert_eq!(tx.recv_raw_message().unwrap(), msg);
+        rx.join().unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_ftcp_sessions() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = ftcp::AsyncListener::bind(any_port).await.unwrap();
+        let socket_addr = listener.local_addr();
+        let responder = local_node(0x41);
+        let initiator = local_node(0x51);
+        let msg = b"Some message";
+
+        tokio::join!(
+            async {
+                let (mut session, _) =
+                    AsyncRaw::accept_ftcp_encrypted_from(&listener, &responder)
+                        .await
+                        .unwrap();
+                let received = session.async_recv_raw_message().await.unwrap();
+                session.async_send_raw_message(&received).await.unwrap();
+            },
+            async {
+                let mut session = AsyncRaw::connect_ftcp_encrypted(
+                    responder.node_id(),
+                    socket_addr,
+                    &initiator,
+                )
+                .await
+                .unwrap();
+                session.async_send_raw_message(msg).await.unwrap();
+                assert_eq!(
+                    session.async_recv_raw_message().await.unwrap(),
+                    msg
+                );
+            }
+        );
+
+        tokio::join!(
+            async {
+                let mut session =
+                    AsyncRaw::accept_ftcp_unencrypted_from(&listener)
+                        .await
+                        .unwrap();
+                let received = session.async_recv_raw_message().await.unwrap();
+                assert_eq!(received, msg);
+            },
+            async {
+                let mut session =
+                    AsyncRaw::connect_ftcp_unencrypted(socket_addr)
+                        .await
+                        .unwrap();
+                assert_eq!(
+                    session.remote_addr().unwrap().port,
+                    socket_addr.port
+                );
+                session.async_send_raw_message(msg).await.unwrap();
+            }
+        );
+    }
 }
@@ -14,8 +14,11 @@
 use amplify::Bipolar;
 use std::borrow::Borrow;
 
+use super::Error as SessionError;
+#[cfg(feature = "async")]
+use crate::lnp::transport::AsyncRecvFrame;
 use crate::lnp::transport::{
-    Error, FRAME_PREFIX_SIZE, FRAME_SUFFIX_SIZE, MAX_FRAME_SIZE,
+    Error, RecvFrame, FRAME_PREFIX_SIZE, FRAME_SUFFIX_SIZE, MAX_FRAME_SIZE,
 };
 #[cfg(feature = "lightning")]
 use lightning::ln::peers::{
@@ -31,12 +34,40 @@ pub trait Encrypt {
 }
 
 pub trait Decrypt {
-    type Error: ::std::error::Error;
+    /// Decryption error, which must be representable as a session-level
+    /// error
+    type Error: ::std::error::Error + Into<SessionError>;
 
     fn decrypt(
         &mut self,
         buffer: impl Borrow<[u8]>,
     ) -> Result<Vec<u8>, Self::Error>;
+
+    /// Reads a single encrypted frame from the `reader`, which can be later
+    /// processed with [`Decrypt::decrypt`]. Default implementation relies on
+    /// the plain frame length prefix read by [`RecvFrame::recv_frame`];
+    /// transcoders encrypting the length prefix must re-implement it.
+    fn read_frame(
+        &mut self,
+        reader: &mut dyn RecvFrame,
+    ) -> Result<Vec<u8>, Error> {
+        reader.recv_frame()
+    }
+}
+
+/// Async version of [`Decrypt::read_frame`], required for transcoders used
+/// with async sessions
+#[cfg(feature = "async")]
+#[async_trait]
+pub trait AsyncDecrypt: Decrypt + Send {
+    /// Async version of [`Decrypt::read_frame`]; pls refer to it for the
+    /// function documentation
+    async fn async_read_frame(
+        &mut self,
+        reader: &mut (dyn AsyncRecvFrame + Send),
+    ) -> Result<Vec<u8>, Error> {
+        reader.async_recv_frame().await
+    }
 }
 
 pub trait Transcode: Bipolar + Encrypt + Decrypt {
@@ -50,6 +81,12 @@ pub trait Transcode: Bipolar + Encrypt + Decrypt {
 #[display(Debug)]
 pub struct DecryptionError;
 
+impl From<DecryptionError> for Error {
+    fn from(_: DecryptionError) -> Self {
+        Error::DecryptionFailure
+    }
+}
+
 #[cfg(feature = "lightning")]
 impl Encrypt for Encryptor {
     fn encrypt(&mut self, buffer: impl Borrow<[u8]>) -> Vec<u8> {
@@ -167,6 +204,9 @@ impl Decrypt for PlainTranscoder {
     }
 }
 
+#[cfg(feature = "async")]
+impl AsyncDecrypt for PlainTranscoder {}
+
 impl Transcode for PlainTranscoder {
     type Encryptor = Self;
     type Decryptor = Self;
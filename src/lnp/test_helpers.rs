@@ -0,0 +1,145 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Fixtures shared by the unit tests of LNP modules
+
+use amplify::internet::InetAddr;
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::lnp::message::Init;
+#[cfg(feature = "zmq")]
+use crate::lnp::ZmqType;
+use crate::lnp::{FeatureFlag, Features, LocalNode, Messages};
+use crate::SECP256K1;
+
+/// Number of random samples checked by property tests
+pub const PROPERTY_ROUNDS: usize = 256;
+
+/// Constructs local node with the node key made of repeated `seed` byte and
+/// the ephemeral key made of repeated `seed + 1` byte
+pub fn local_node(seed: u8) -> LocalNode {
+    LocalNode::from_keys(
+        SecretKey::from_slice(&[seed; 32]).unwrap(),
+        SecretKey::from_slice(&[seed + 1; 32]).unwrap(),
+    )
+}
+
+/// Returns public key for the secret key made of repeated `byte`
+pub fn point(byte: u8) -> PublicKey {
+    let secret = SecretKey::from_slice(&[byte; 32]).unwrap();
+    PublicKey::from_secret_key(&SECP256K1, &secret)
+}
+
+/// Returns optional (not required) local feature flag
+pub fn feature_flag() -> FeatureFlag {
+    FeatureFlag {
+        context: none!(),
+        global: false,
+        required: false,
+    }
+}
+
+/// Returns features affecting commitment transaction structure, supporting
+/// `option_static_remotekey` and `option_anchor_outputs` as requested
+pub fn commitment_features(
+    static_remotekey: bool,
+    anchor_outputs: bool,
+) -> Features {
+    Features {
+        option_static_remotekey: Some(feature_flag())
+            .filter(|_| static_remotekey),
+        option_anchor_outputs: Some(feature_flag()).filter(|_| anchor_outputs),
+        ..none!()
+    }
+}
+
+/// Constructs `init` message with the given local features
+pub fn init(local_features: Features) -> Messages {
+    Messages::Init(Init {
+        global_features: none!(),
+        local_features,
+        assets: none!(),
+        unknown_tlvs: none!(),
+    })
+}
+
+/// Returns random public key
+pub fn random_pubkey() -> PublicKey {
+    PublicKey::from_secret_key(&SECP256K1, &SecretKey::new(&mut thread_rng()))
+}
+
+/// Returns random IPv4 or IPv6 address
+pub fn random_ip() -> IpAddr {
+    let mut rng = thread_rng();
+    if rng.next_u32() % 2 == 0 {
+        IpAddr::V4(Ipv4Addr::from(rng.next_u32()))
+    } else {
+        let ip = (rng.next_u64() as u128) << 64 | rng.next_u64() as u128;
+        IpAddr::V6(Ipv6Addr::from(ip))
+    }
+}
+
+/// Returns random IP or (with `tor` feature) Tor v3 onion address
+pub fn random_inet_addr() -> InetAddr {
+    #[cfg(feature = "tor")]
+    {
+        if thread_rng().next_u32() % 3 == 0 {
+            let onion = torut::onion::TorSecretKeyV3::generate()
+                .public()
+                .get_onion_address();
+            return onion
+                .get_address_without_dot_onion()
+                .parse()
+                .expect("onion address is always parsable");
+        }
+    }
+    random_ip().into()
+}
+
+/// Returns random port number
+pub fn random_port() -> u16 {
+    thread_rng().next_u32() as u16
+}
+
+/// Returns random port number or `None`
+pub fn random_opt_port() -> Option<u16> {
+    Some(random_port()).filter(|_| thread_rng().next_u32() % 4 != 0)
+}
+
+/// Returns random absolute or relative file path, consisting of
+/// alphanumeric segments
+pub fn random_path() -> String {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789_-";
+    let mut rng = thread_rng();
+    let segments = (0..1 + rng.next_u32() % 4)
+        .map(|_| {
+            (0..1 + rng.next_u32() % 12)
+                .map(|_| CHARS[rng.next_u32() as usize % CHARS.len()] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    match rng.next_u32() % 3 {
+        0 => format!("/{}", segments),
+        1 => format!("./{}", segments),
+        _ => segments,
+    }
+}
+
+/// Returns random ZMQ socket type
+#[cfg(feature = "zmq")]
+pub fn random_zmq_type() -> ZmqType {
+    ZmqType::ALL[thread_rng().next_u32() as usize % ZmqType::ALL.len()]
+}
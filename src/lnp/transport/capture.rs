@@ -0,0 +1,718 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Frame capture and replay, used for debugging peer sessions after the fact.
+//!
+//! [`Recorder`] wraps any bipolar connection and writes every sent and
+//! received frame into a capture file, one [`Record`] per line:
+//!
+//! ```text
+//! <RFC3339 timestamp> <in|out> <peer id|-> <frame hex> <payload hex|-> \
+//!     [<hop hex> <source hex> <destination hex>]
+//! ```
+//!
+//! Decrypted payloads are present when the recorder has [`Inspect`]
+//! implementations for both directions, which are provided by the session
+//! layer once the transcoder is known. Captures are read back with
+//! [`Reader`], which may replay them into [`Unmarshaller`] to reproduce the
+//! session offline.
+
+use amplify::Bipolar;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::secp256k1;
+use chrono::{DateTime, Utc};
+use core::fmt::{self, Debug, Display, Formatter};
+use core::str::FromStr;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Lines, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{
+    Duplex, Error, RecvFrame, RoutedFrame, SendFrame, FRAME_PREFIX_SIZE,
+    FRAME_SUFFIX_SIZE,
+};
+use crate::lnp::presentation::payload::TypedEnum;
+use crate::lnp::presentation::{self, Unmarshall, Unmarshaller};
+
+/// Direction of the captured frame
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display)]
+pub enum Direction {
+    /// Frame received from the remote peer
+    #[display("in")]
+    Received,
+
+    /// Frame sent to the remote peer
+    #[display("out")]
+    Sent,
+}
+
+impl FromStr for Direction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "in" => Ok(Direction::Received),
+            "out" => Ok(Direction::Sent),
+            _ => Err(Error::FrameBroken("invalid capture record direction")),
+        }
+    }
+}
+
+/// Routing information of the frames sent or received by multipeer sockets
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Route {
+    /// Previous hop (for received frames) or next hop (for sent frames)
+    pub hop: Vec<u8>,
+    /// Originator of the frame
+    pub src: Vec<u8>,
+    /// Destination of the frame
+    pub dst: Vec<u8>,
+}
+
+/// Single frame stored in the capture file
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Record {
+    /// Time at which the frame was sent or received
+    pub timestamp: DateTime<Utc>,
+
+    /// Whether the frame was sent or received
+    pub direction: Direction,
+
+    /// Node id of the remote peer, if known
+    pub peer: Option<secp256k1::PublicKey>,
+
+    /// Routing information for the frames of multipeer sockets
+    pub route: Option<Route>,
+
+    /// Frame data as they were sent or received by the transport
+    pub frame: Vec<u8>,
+
+    /// Decrypted message payload; present only if the frame completes a
+    /// message and the recorder knows how to decrypt it
+    pub payload: Option<Vec<u8>>,
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.timestamp.to_rfc3339(),
+            self.direction,
+            self.peer
+                .map(|peer| peer.to_string())
+                .unwrap_or_else(|| s!("-")),
+            self.frame.to_hex(),
+            self.payload
+                .as_ref()
+                .map(|payload| payload.to_hex())
+                .unwrap_or_else(|| s!("-"))
+        )?;
+        if let Some(route) = &self.route {
+            write!(
+                f,
+                " {} {} {}",
+                route.hop.to_hex(),
+                route.src.to_hex(),
+                route.dst.to_hex()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Record {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERR: Error = Error::FrameBroken("invalid capture record");
+
+        // Empty byte strings are encoded as empty hex strings, so we split
+        // over each single space
+        let fields = s.trim_end().split(' ').collect::<Vec<_>>();
+        if fields.len() != 5 && fields.len() != 8 {
+            Err(ERR)?
+        }
+        let hex = |s: &str| Vec::<u8>::from_hex(s).map_err(|_| ERR);
+        Ok(Record {
+            timestamp: DateTime::parse_from_rfc3339(fields[0])
+                .map_err(|_| ERR)?
+                .with_timezone(&Utc),
+            direction: fields[1].parse()?,
+            peer: match fields[2] {
+                "-" => None,
+                peer => Some(peer.parse().map_err(|_| ERR)?),
+            },
+            frame: hex(fields[3])?,
+            payload: match fields[4] {
+                "-" => None,
+                payload => Some(hex(payload)?),
+            },
+            route: if fields.len() == 8 {
+                Some(Route {
+                    hop: hex(fields[5])?,
+                    src: hex(fields[6])?,
+                    dst: hex(fields[7])?,
+                })
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// Extracts decrypted message payloads out of the stream of frame data going
+/// in a single direction. Implementations must buffer data, since a single
+/// message may be received with several [`RecvFrame::recv_raw`] calls.
+pub trait Inspect {
+    /// Consumes next portion of the frame data, returning decrypted payload
+    /// if it completes a message
+    fn inspect(&mut self, data: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Payload inspector for the unencrypted frames produced by plain transcoder
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct PlainInspector {
+    buffer: Vec<u8>,
+}
+
+impl Inspect for PlainInspector {
+    fn inspect(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        self.buffer.extend(data);
+        if self.buffer.len() < 2 {
+            return None;
+        }
+        let len = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+        let frame_len = len + FRAME_PREFIX_SIZE + FRAME_SUFFIX_SIZE;
+        if self.buffer.len() < frame_len {
+            return None;
+        }
+        let payload =
+            self.buffer[FRAME_PREFIX_SIZE..FRAME_PREFIX_SIZE + len].to_vec();
+        self.buffer.drain(..frame_len);
+        Some(payload)
+    }
+}
+
+/// Capture file shared by both halves of a [`Recorder`]
+#[derive(Clone, Debug)]
+pub struct Capture {
+    file: Arc<Mutex<File>>,
+    failures: Arc<AtomicUsize>,
+}
+
+impl Capture {
+    /// Creates new capture file at `path`, truncating existing one
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self {
+            file: Arc::new(Mutex::new(File::create(path)?)),
+            failures: none!(),
+        })
+    }
+
+    /// Opens capture file at `path` for appending new records, creating it if
+    /// it does not exist
+    pub fn append(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            failures: none!(),
+        })
+    }
+
+    /// Writes record to the capture file
+    pub fn write(&self, record: &Record) -> Result<(), Error> {
+        // We panic here because this is a program architecture design
+        // error: the mutex may be poisoned only if another thread has
+        // panicked while writing a record
+        let mut file = self.file.lock().expect("Capture file lock poisoned");
+        file.write_all(format!("{}\n", record).as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Returns number of records which [`Recorder`] has failed to write.
+    /// Such failures do not affect frames passing through the recorder, so
+    /// the capture file may be incomplete when this number is non-zero.
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+/// Receiving half of the [`Recorder`]
+pub struct Receiver<R>
+where
+    R: RecvFrame,
+{
+    input: R,
+    capture: Capture,
+    peer: Option<secp256k1::PublicKey>,
+    inspector: Option<Box<dyn Inspect + Send>>,
+}
+
+/// Sending half of the [`Recorder`]
+pub struct Sender<S>
+where
+    S: SendFrame,
+{
+    output: S,
+    capture: Capture,
+    peer: Option<secp256k1::PublicKey>,
+    inspector: Option<Box<dyn Inspect + Send>>,
+}
+
+/// Connection decorator recording all frames passing through the wrapped
+/// connection into the capture file
+pub struct Recorder<C>
+where
+    C: Bipolar,
+    C::Left: RecvFrame,
+    C::Right: SendFrame,
+{
+    receiver: Receiver<C::Left>,
+    sender: Sender<C::Right>,
+}
+
+impl<R> Debug for Receiver<R>
+where
+    R: RecvFrame + Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("input", &self.input)
+            .field("capture", &self.capture)
+            .field("peer", &self.peer)
+            .field("inspector", &self.inspector.is_some())
+            .finish()
+    }
+}
+
+impl<S> Debug for Sender<S>
+where
+    S: SendFrame + Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("output", &self.output)
+            .field("capture", &self.capture)
+            .field("peer", &self.peer)
+            .field("inspector", &self.inspector.is_some())
+            .finish()
+    }
+}
+
+impl<C> Debug for Recorder<C>
+where
+    C: Bipolar,
+    C::Left: RecvFrame + Debug,
+    C::Right: SendFrame + Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("receiver", &self.receiver)
+            .field("sender", &self.sender)
+            .finish()
+    }
+}
+
+impl<C> Recorder<C>
+where
+    C: Bipolar,
+    C::Left: RecvFrame,
+    C::Right: SendFrame,
+{
+    /// Wraps `connection` to the remote node with node id `peer` (if known),
+    /// writing frames to `capture`
+    pub fn with(
+        connection: C,
+        capture: Capture,
+        peer: Option<secp256k1::PublicKey>,
+    ) -> Self {
+        let (input, output) = connection.split();
+        Self {
+            receiver: Receiver {
+                input,
+                capture: capture.clone(),
+                peer,
+                inspector: None,
+            },
+            sender: Sender {
+                output,
+                capture,
+                peer,
+                inspector: None,
+            },
+        }
+    }
+
+    /// Sets inspectors for extracting decrypted payloads from the
+    /// `incoming` and `outgoing` frames
+    pub fn set_inspectors(
+        &mut self,
+        incoming: Box<dyn Inspect + Send>,
+        outgoing: Box<dyn Inspect + Send>,
+    ) {
+        self.receiver.inspector = Some(incoming);
+        self.sender.inspector = Some(outgoing);
+    }
+
+    /// Sets node id of the remote `peer`, which may become known only after
+    /// the handshake
+    pub fn set_peer(&mut self, peer: Option<secp256k1::PublicKey>) {
+        self.receiver.peer = peer;
+        self.sender.peer = peer;
+    }
+
+    /// Unwraps the original connection
+    pub fn into_inner(self) -> C {
+        C::join(self.receiver.input, self.sender.output)
+    }
+}
+
+fn record(
+    capture: &Capture,
+    inspector: &mut Option<Box<dyn Inspect + Send>>,
+    direction: Direction,
+    peer: Option<secp256k1::PublicKey>,
+    route: Option<Route>,
+    frame: &[u8],
+) {
+    // Frame data are already taken from (or given to) the connection, so
+    // failing here would lose them; we count the failure instead
+    let result = capture.write(&Record {
+        timestamp: Utc::now(),
+        direction,
+        peer,
+        route,
+        frame: frame.to_vec(),
+        payload: inspector
+            .as_mut()
+            .and_then(|inspector| inspector.inspect(frame)),
+    });
+    if result.is_err() {
+        capture.failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<R> Receiver<R>
+where
+    R: RecvFrame,
+{
+    fn record(&mut self, route: Option<Route>, frame: &[u8]) {
+        record(
+            &self.capture,
+            &mut self.inspector,
+            Direction::Received,
+            self.peer,
+            route,
+            frame,
+        )
+    }
+}
+
+impl<S> Sender<S>
+where
+    S: SendFrame,
+{
+    fn record(&mut self, route: Option<Route>, frame: &[u8]) {
+        record(
+            &self.capture,
+            &mut self.inspector,
+            Direction::Sent,
+            self.peer,
+            route,
+            frame,
+        )
+    }
+}
+
+impl<R> RecvFrame for Receiver<R>
+where
+    R: RecvFrame,
+{
+    fn recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        let frame = self.input.recv_frame()?;
+        self.record(None, &frame);
+        Ok(frame)
+    }
+
+    fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        let data = self.input.recv_raw(len)?;
+        self.record(None, &data);
+        Ok(data)
+    }
+
+    fn recv_routed(&mut self) -> Result<RoutedFrame, Error> {
+        let routed = self.input.recv_routed()?;
+        let route = Route {
+            hop: routed.hop.clone(),
+            src: routed.src.clone(),
+            dst: routed.dst.clone(),
+        };
+        self.record(Some(route), &routed.msg);
+        Ok(routed)
+    }
+}
+
+impl<S> SendFrame for Sender<S>
+where
+    S: SendFrame,
+{
+    fn send_frame(&mut self, frame: &[u8]) -> Result<usize, Error> {
+        let len = self.output.send_frame(frame)?;
+        self.record(None, frame);
+        Ok(len)
+    }
+
+    fn send_raw(&mut self, raw_frame: &[u8]) -> Result<usize, Error> {
+        let len = self.output.send_raw(raw_frame)?;
+        self.record(None, raw_frame);
+        Ok(len)
+    }
+
+    fn send_routed(
+        &mut self,
+        source: &[u8],
+        route: &[u8],
+        dest: &[u8],
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        let len = self.output.send_routed(source, route, dest, data)?;
+        let route = Route {
+            hop: route.to_vec(),
+            src: source.to_vec(),
+            dst: dest.to_vec(),
+        };
+        self.record(Some(route), data);
+        Ok(len)
+    }
+}
+
+impl<C> Duplex for Recorder<C>
+where
+    C: Bipolar,
+    C::Left: RecvFrame + Send + 'static,
+    C::Right: SendFrame + Send + 'static,
+{
+    #[inline]
+    fn as_receiver(&mut self) -> &mut dyn RecvFrame {
+        &mut self.receiver
+    }
+
+    #[inline]
+    fn as_sender(&mut self) -> &mut dyn SendFrame {
+        &mut self.sender
+    }
+
+    #[inline]
+    fn split(self) -> (Box<dyn RecvFrame + Send>, Box<dyn SendFrame + Send>) {
+        (Box::new(self.receiver), Box::new(self.sender))
+    }
+}
+
+impl<C> Bipolar for Recorder<C>
+where
+    C: Bipolar,
+    C::Left: RecvFrame,
+    C::Right: SendFrame,
+{
+    type Left = Receiver<C::Left>;
+    type Right = Sender<C::Right>;
+
+    fn join(left: Self::Left, right: Self::Right) -> Self {
+        Self {
+            receiver: left,
+            sender: right,
+        }
+    }
+
+    fn split(self) -> (Self::Left, Self::Right) {
+        (self.receiver, self.sender)
+    }
+}
+
+/// Reader iterating over records of the capture file
+#[derive(Debug)]
+pub struct Reader {
+    lines: Lines<BufReader<File>>,
+}
+
+impl Reader {
+    /// Opens capture file at `path` for reading
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self {
+            lines: BufReader::new(File::open(path)?).lines(),
+        })
+    }
+
+    /// Replays the capture, unmarshalling all recorded message payloads with
+    /// the provided `unmarshaller`. Records without decrypted payload (like
+    /// noise handshake acts or message length headers) are skipped.
+    pub fn replay<'a, T>(
+        self,
+        unmarshaller: &'a Unmarshaller<T>,
+    ) -> impl Iterator<Item = Result<(Record, Arc<T>), presentation::Error>> + 'a
+    where
+        T: TypedEnum + 'a,
+    {
+        self.filter_map(move |record| {
+            let record = match record {
+                Ok(record) => record,
+                Err(err) => return Some(Err(err.into())),
+            };
+            let message = unmarshaller.unmarshall(record.payload.as_ref()?);
+            Some(message.map(|message| (record, message)))
+        })
+    }
+}
+
+impl Iterator for Reader {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.lines.next()? {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => return Some(line.parse()),
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lnp::application::message::{Messages, Ping};
+    use crate::lnp::presentation::Encode;
+    use crate::lnp::test_helpers::point;
+    use crate::lnp::transport::memory;
+    use crate::lnp::LNPWP_UNMARSHALLER;
+
+    fn plain_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u16).to_be_bytes().to_vec();
+        frame.extend(&[0u8; FRAME_PREFIX_SIZE - 2]);
+        frame.extend(payload);
+        frame.extend(&[0u8; FRAME_SUFFIX_SIZE]);
+        frame
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let peer = point(0x21);
+        let record = Record {
+            timestamp: Utc::now(),
+            direction: Direction::Sent,
+            peer: Some(peer),
+            route: Some(Route {
+                hop: vec![0x01],
+                src: vec![],
+                dst: vec![0x02, 0x03],
+            }),
+            frame: vec![0xde, 0xad],
+            payload: None,
+        };
+        assert_eq!(record.to_string().parse::<Record>().unwrap(), record);
+
+        let record = Record {
+            peer: None,
+            route: None,
+            payload: Some(vec![]),
+            ..record
+        };
+        assert_eq!(record.to_string().parse::<Record>().unwrap(), record);
+        assert!("some garbage".parse::<Record>().is_err());
+    }
+
+    #[test]
+    fn test_plain_inspector() {
+        let frame = plain_frame(b"message");
+        let mut inspector = PlainInspector::default();
+        assert_eq!(inspector.inspect(&frame[..FRAME_PREFIX_SIZE]), None);
+        assert_eq!(
+            inspector.inspect(&frame[FRAME_PREFIX_SIZE..]),
+            Some(b"message".to_vec())
+        );
+        assert_eq!(inspector.inspect(&plain_frame(b"")), Some(vec![]));
+    }
+
+    #[test]
+    fn test_capture_replay() {
+        let path = std::env::temp_dir()
+            .join(format!("lnp-capture-test-{}", std::process::id()));
+        let capture = Capture::create(&path).unwrap();
+        let (left, mut right) = memory::pair();
+        let mut recorder = Recorder::with(left, capture, None);
+        recorder.set_inspectors(
+            Box::new(PlainInspector::default()),
+            Box::new(PlainInspector::default()),
+        );
+
+        let ping = Messages::Ping(Ping {
+            pong_size: 4,
+            ignored: vec![0x00; 2],
+        })
+        .encode()
+        .unwrap();
+        recorder
+            .as_sender()
+            .send_frame(&plain_frame(&ping))
+            .unwrap();
+        right.as_receiver().recv_frame().unwrap();
+        right.as_sender().send_frame(&plain_frame(&[0xff])).unwrap();
+        recorder.as_receiver().recv_raw(FRAME_PREFIX_SIZE).unwrap();
+        recorder
+            .as_receiver()
+            .recv_raw(1 + FRAME_SUFFIX_SIZE)
+            .unwrap();
+
+        let records = Reader::open(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(records[0].payload, Some(ping));
+        assert_eq!(records[1].direction, Direction::Received);
+        assert_eq!(records[1].payload, None);
+        assert_eq!(records[2].payload, Some(vec![0xff]));
+
+        let mut replay =
+            Reader::open(&path).unwrap().replay(&LNPWP_UNMARSHALLER);
+        match &*replay.next().unwrap().unwrap().1 {
+            Messages::Ping(ping) => assert_eq!(ping.pong_size, 4),
+            other => panic!("unexpected message {}", other),
+        }
+        // Single-byte payload can't be parsed since it has no two-byte type id
+        assert!(replay.next().unwrap().is_err());
+        assert!(replay.next().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_capture_failures() {
+        // Writes to `/dev/full` always fail with "no space left on device"
+        let capture = Capture::append("/dev/full").unwrap();
+        let (left, mut right) = memory::pair();
+        let mut recorder = Recorder::with(left, capture.clone(), None);
+
+        let frame = plain_frame(&[0x01, 0x02]);
+        recorder.as_sender().send_frame(&frame).unwrap();
+        assert_eq!(right.as_receiver().recv_frame().unwrap(), frame);
+        right.as_sender().send_frame(&frame).unwrap();
+        assert_eq!(recorder.as_receiver().recv_frame().unwrap(), frame);
+        assert_eq!(capture.failures(), 2);
+    }
+}
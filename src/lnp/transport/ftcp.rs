@@ -18,9 +18,11 @@ use amplify::internet::InetSocketAddr;
 use amplify::Bipolar;
 use core::convert::TryFrom;
 use core::time::Duration;
-use std::io::{Read, Write};
+use std::io::{self, ErrorKind, Read, Write};
 use std::net::SocketAddr;
 
+#[cfg(feature = "async")]
+use super::{AsyncDuplex, AsyncRecvFrame, AsyncSendFrame};
 use super::{Duplex, Error, RecvFrame, SendFrame};
 
 /// Wraps TcpStream
@@ -29,7 +31,7 @@ use super::{Duplex, Error, RecvFrame, SendFrame};
 /// as Bipolar, for a foreign type.
 #[derive(Debug)]
 pub struct Connection {
-    pub(self) stream: std::net::TcpStream,
+    pub(self) receiver: Receiver,
     pub(self) remote_addr: InetSocketAddr,
 }
 
@@ -39,7 +41,7 @@ impl Connection {
         remote_addr: InetSocketAddr,
     ) -> Self {
         Self {
-            stream,
+            receiver: Receiver::with(stream),
             remote_addr,
         }
     }
@@ -55,45 +57,181 @@ impl Connection {
         }
     }
 
-    // TODO: (v0.2) Transform into bind method + special Listener object with
-    //       accept method
-    pub fn accept(inet_addr: InetSocketAddr) -> Result<Self, Error> {
+    /// Connects to the `inet_addr` through SOCKS5 `proxy`. This is the only
+    /// way of connecting to Tor onion addresses, for which `proxy` must be
+    /// a Tor SOCKS port (usually `127.0.0.1:9050`).
+    pub fn connect_via(
+        inet_addr: InetSocketAddr,
+        proxy: SocketAddr,
+    ) -> Result<Self, Error> {
+        let stream = super::socks5::connect(proxy, inet_addr)?;
+        // NB: This is how we handle ping-pong cycles
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        Ok(Self::with(stream, inet_addr))
+    }
+
+    /// Binds new [`Listener`] to the `inet_addr` and accepts the first
+    /// incoming connection. Returns the connection together with the
+    /// listener, which may be used to accept further connections; the socket
+    /// is released once the listener is dropped.
+    pub fn accept(
+        inet_addr: InetSocketAddr,
+    ) -> Result<(Self, Listener), Error> {
+        let listener = Listener::bind(inet_addr)?;
+        Ok((listener.accept()?, listener))
+    }
+
+    /// Returns address of the remote peer
+    #[inline]
+    pub fn remote_addr(&self) -> InetSocketAddr {
+        self.remote_addr
+    }
+
+    /// Sets timeout for receiving data, after which [`Error::TimedOut`] is
+    /// returned. Connections are created with 30 seconds timeout, which is
+    /// used for the ping-pong cycles.
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.receiver.stream.set_read_timeout(Some(timeout))?;
+        Ok(())
+    }
+}
+
+/// Receiving half of the framed TCP [`Connection`].
+///
+/// Bytes read before the read timeout has expired are kept by the receiver,
+/// so the next call to [`RecvFrame::recv_frame`] or [`RecvFrame::recv_raw`]
+/// (which must request the same data) resumes reading instead of losing
+/// them and getting out of sync with the stream.
+#[derive(Debug)]
+pub struct Receiver {
+    pub(self) stream: std::net::TcpStream,
+    /// Bytes of the frame or raw data which reading was interrupted
+    pub(self) buffer: Vec<u8>,
+}
+
+impl Receiver {
+    fn with(stream: std::net::TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: vec![],
+        }
+    }
+
+    /// Reads from the stream until the buffer contains `len` bytes, never
+    /// reading beyond them
+    fn fill(&mut self, len: usize) -> Result<(), Error> {
+        while self.buffer.len() < len {
+            let mut chunk = vec![0u8; len - self.buffer.len()];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => Err(io::Error::from(ErrorKind::UnexpectedEof))?,
+                Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => Err(err)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RecvFrame for Receiver {
+    fn recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        self.fill(2)?;
+        let len = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+        self.fill(len + super::FRAME_PREFIX_SIZE + super::FRAME_SUFFIX_SIZE)?;
+        Ok(std::mem::take(&mut self.buffer))
+    }
+
+    fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        self.fill(len)?;
+        Ok(std::mem::take(&mut self.buffer))
+    }
+}
+
+/// Framed TCP listener: binds to a socket once and accepts incoming framed
+/// TCP [`Connection`]s repeatedly
+#[derive(Debug)]
+pub struct Listener {
+    pub(self) listener: std::net::TcpListener,
+    pub(self) local_addr: InetSocketAddr,
+}
+
+impl Listener {
+    /// Binds to the `inet_addr`. If the port is set to zero, it is assigned
+    /// by the OS and can be retrieved with [`Listener::local_addr`]
+    pub fn bind(inet_addr: InetSocketAddr) -> Result<Self, Error> {
         if let Ok(socket_addr) = SocketAddr::try_from(inet_addr) {
             let listener = std::net::TcpListener::bind(socket_addr)?;
-            let (stream, remote_addr) = listener.accept()?;
-            // NB: This is how we handle ping-pong cycles
-            stream.set_read_timeout(Some(Duration::from_secs(30)))?;
-            Ok(Self::with(stream, remote_addr.into()))
+            let local_addr = listener.local_addr()?.into();
+            Ok(Self {
+                listener,
+                local_addr,
+            })
         } else {
             Err(Error::TorNotSupportedYet)
         }
     }
+
+    /// Blocks until a new incoming connection is accepted
+    pub fn accept(&self) -> Result<Connection, Error> {
+        let (stream, remote_addr) = self.listener.accept()?;
+        // NB: This is how we handle ping-pong cycles
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        Ok(Connection::with(stream, remote_addr.into()))
+    }
+
+    /// Returns an iterator over incoming connections, blocking on each
+    /// iteration until a new connection is accepted
+    #[inline]
+    pub fn incoming(&self) -> Incoming {
+        Incoming { listener: self }
+    }
+
+    /// Returns local address the listener is bound to
+    #[inline]
+    pub fn local_addr(&self) -> InetSocketAddr {
+        self.local_addr
+    }
+}
+
+/// Iterator over incoming connections of the [`Listener`], which never
+/// returns `None`
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a Listener,
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = Result<Connection, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept())
+    }
 }
 
 impl Duplex for Connection {
     #[inline]
     fn as_receiver(&mut self) -> &mut dyn RecvFrame {
-        &mut self.stream
+        &mut self.receiver
     }
 
     #[inline]
     fn as_sender(&mut self) -> &mut dyn SendFrame {
-        &mut self.stream
+        &mut self.receiver.stream
     }
 
     #[inline]
     fn split(self) -> (Box<dyn RecvFrame + Send>, Box<dyn SendFrame + Send>) {
-        (
-            Box::new(
-                self.stream.try_clone().expect("Error cloning TCP socket"),
-            ),
-            Box::new(self.stream),
-        )
+        let stream = self
+            .receiver
+            .stream
+            .try_clone()
+            .expect("Error cloning TCP socket");
+        (Box::new(self.receiver), Box::new(stream))
     }
 }
 
 impl Bipolar for Connection {
-    type Left = std::net::TcpStream;
+    type Left = Receiver;
     type Right = std::net::TcpStream;
 
     fn join(left: Self::Left, right: Self::Right) -> Self {
@@ -104,69 +242,394 @@ impl Bipolar for Connection {
 
         #[cfg(not(target_os = "windows"))]
         assert_eq!(
-            left.as_raw_fd(),
+            left.stream.as_raw_fd(),
             right.as_raw_fd(),
             "Two independent TCP sockets can't be joined"
         );
         #[cfg(target_os = "windows")]
         assert_eq!(
-            left.as_raw_socket(),
+            left.stream.as_raw_socket(),
             right.as_raw_socket(),
             "Two independent TCP sockets can't be joined"
         );
+        let remote_addr = left
+            .stream
+            .peer_addr()
+            .map(InetSocketAddr::from)
+            .unwrap_or_default();
         Self {
-            stream: left,
-            // TODO: (v1) Replace with remote address, wbich will require
-            //       creation of TcpSocket wrapper type
-            remote_addr: Default::default(),
+            receiver: left,
+            remote_addr,
         }
     }
 
     fn split(self) -> (Self::Left, Self::Right) {
-        (
-            self.stream.try_clone().expect("TcpStream cloning failed"),
-            self.stream,
-        )
+        let stream = self
+            .receiver
+            .stream
+            .try_clone()
+            .expect("TcpStream cloning failed");
+        (self.receiver, stream)
     }
 }
 
 impl RecvFrame for std::net::TcpStream {
+    #[inline]
     fn recv_frame(&mut self) -> Result<Vec<u8>, Error> {
-        let mut len_buf = [0u8; 2];
-        self.read_exact(&mut len_buf)?;
-        let len = u16::from_be_bytes(len_buf) as usize;
-        let mut buf: Vec<u8> = vec![
-            0u8;
-            len + super::FRAME_PREFIX_SIZE
-                + super::FRAME_SUFFIX_SIZE
-        ];
-        buf[0..2].copy_from_slice(&len_buf);
-        self.read_exact(&mut buf[2..])?;
-        Ok(buf)
+        recv_frame(self)
     }
 
+    #[inline]
     fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
-        let mut buf: Vec<u8> = vec![0u8; len];
-        self.read_exact(&mut buf)?;
-        Ok(buf)
+        recv_raw(self, len)
     }
 }
 
 impl SendFrame for std::net::TcpStream {
+    #[inline]
     fn send_frame(&mut self, data: &[u8]) -> Result<usize, Error> {
-        let len = data.len();
-        if len > super::MAX_FRAME_SIZE {
-            return Err(Error::OversizedFrame(len));
-        }
-        self.write_all(data)?;
-        Ok(len)
+        send_frame(self, data)
     }
 
+    #[inline]
     fn send_raw(&mut self, data: &[u8]) -> Result<usize, Error> {
-        self.write_all(data)?;
-        Ok(data.len())
+        send_raw(self, data)
+    }
+}
+
+/// Reads a single frame from the byte stream, which is prefixed with its
+/// 2-byte payload length
+pub(super) fn recv_frame(reader: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let mut len_buf = [0u8; 2];
+    reader.read_exact(&mut len_buf)?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+    let mut buf: Vec<u8> =
+        vec![0u8; len + super::FRAME_PREFIX_SIZE + super::FRAME_SUFFIX_SIZE];
+    buf[0..2].copy_from_slice(&len_buf);
+    reader.read_exact(&mut buf[2..])?;
+    Ok(buf)
+}
+
+pub(super) fn recv_raw(
+    reader: &mut impl Read,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut buf: Vec<u8> = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub(super) fn send_frame(
+    writer: &mut impl Write,
+    data: &[u8],
+) -> Result<usize, Error> {
+    let len = data.len();
+    if len > super::MAX_FRAME_SIZE {
+        return Err(Error::OversizedFrame(len));
     }
+    send_raw(writer, data)
+}
+
+pub(super) fn send_raw(
+    writer: &mut impl Write,
+    data: &[u8],
+) -> Result<usize, Error> {
+    writer.write_all(data)?;
+    Ok(data.len())
 }
 
-// TODO: (v1) Do Async... implementations for FTCP based on
-//       tokio::net::TcpStream
+/// Async version of [`Listener`] based on [`tokio::net::TcpListener`]
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct AsyncListener {
+    pub(self) listener: tokio::net::TcpListener,
+    pub(self) local_addr: InetSocketAddr,
+}
+
+#[cfg(feature = "async")]
+impl AsyncListener {
+    /// Binds to the `inet_addr`. If the port is set to zero, it is assigned
+    /// by the OS and can be retrieved with [`AsyncListener::local_addr`]
+    pub async fn bind(inet_addr: InetSocketAddr) -> Result<Self, Error> {
+        if let Ok(socket_addr) = SocketAddr::try_from(inet_addr) {
+            let listener = tokio::net::TcpListener::bind(socket_addr).await?;
+            let local_addr = listener.local_addr()?.into();
+            Ok(Self {
+                listener,
+                local_addr,
+            })
+        } else {
+            Err(Error::TorNotSupportedYet)
+        }
+    }
+
+    /// Waits until a new incoming connection is accepted. Returns the
+    /// connection stream together with the address of the remote peer.
+    pub async fn accept(
+        &self,
+    ) -> Result<(tokio::net::TcpStream, InetSocketAddr), Error> {
+        let (stream, remote_addr) = self.listener.accept().await?;
+        Ok((stream, remote_addr.into()))
+    }
+
+    /// Returns local address the listener is bound to
+    #[inline]
+    pub fn local_addr(&self) -> InetSocketAddr {
+        self.local_addr
+    }
+}
+
+/// Async version of [`Connection::connect`]
+#[cfg(feature = "async")]
+pub async fn async_connect(
+    inet_addr: InetSocketAddr,
+) -> Result<tokio::net::TcpStream, Error> {
+    if let Ok(socket_addr) = SocketAddr::try_from(inet_addr) {
+        Ok(tokio::net::TcpStream::connect(socket_addr).await?)
+    } else {
+        Err(Error::TorNotSupportedYet)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncDuplex for tokio::net::TcpStream {
+    #[inline]
+    fn as_receiver(&mut self) -> &mut (dyn AsyncRecvFrame + Send) {
+        self
+    }
+
+    #[inline]
+    fn as_sender(&mut self) -> &mut (dyn AsyncSendFrame + Send) {
+        self
+    }
+
+    #[inline]
+    fn split(
+        self,
+    ) -> (
+        Box<dyn AsyncRecvFrame + Send>,
+        Box<dyn AsyncSendFrame + Send>,
+    ) {
+        let (input, output) = self.into_split();
+        (Box::new(input), Box::new(output))
+    }
+}
+
+#[cfg(feature = "async")]
+async fn async_recv_frame<R>(reader: &mut R) -> Result<Vec<u8>, Error>
+where
+    R: tokio::io::AsyncRead + Unpin + Send,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut len_buf = [0u8; 2];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u16::from_be_bytes(len_buf) as usize;
+    let mut buf: Vec<u8> =
+        vec![0u8; len + super::FRAME_PREFIX_SIZE + super::FRAME_SUFFIX_SIZE];
+    buf[0..2].copy_from_slice(&len_buf);
+    reader.read_exact(&mut buf[2..]).await?;
+    Ok(buf)
+}
+
+#[cfg(feature = "async")]
+async fn async_recv_raw<R>(reader: &mut R, len: usize) -> Result<Vec<u8>, Error>
+where
+    R: tokio::io::AsyncRead + Unpin + Send,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut buf: Vec<u8> = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(feature = "async")]
+async fn async_send_raw<W>(writer: &mut W, data: &[u8]) -> Result<usize, Error>
+where
+    W: tokio::io::AsyncWrite + Unpin + Send,
+{
+    use tokio::io::AsyncWriteExt;
+
+    writer.write_all(data).await?;
+    Ok(data.len())
+}
+
+#[cfg(feature = "async")]
+#[async_trait]
+impl AsyncRecvFrame for tokio::net::TcpStream {
+    #[inline]
+    async fn async_recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        async_recv_frame(self).await
+    }
+
+    #[inline]
+    async fn async_recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        async_recv_raw(self, len).await
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait]
+impl AsyncRecvFrame for tokio::net::tcp::OwnedReadHalf {
+    #[inline]
+    async fn async_recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        async_recv_frame(self).await
+    }
+
+    #[inline]
+    async fn async_recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        async_recv_raw(self, len).await
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait]
+impl AsyncSendFrame for tokio::net::TcpStream {
+    async fn async_send_frame(&mut self, data: &[u8]) -> Result<usize, Error> {
+        if data.len() > super::MAX_FRAME_SIZE {
+            return Err(Error::OversizedFrame(data.len()));
+        }
+        async_send_raw(self, data).await
+    }
+
+    #[inline]
+    async fn async_send_raw(&mut self, data: &[u8]) -> Result<usize, Error> {
+        async_send_raw(self, data).await
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait]
+impl AsyncSendFrame for tokio::net::tcp::OwnedWriteHalf {
+    async fn async_send_frame(&mut self, data: &[u8]) -> Result<usize, Error> {
+        if data.len() > super::MAX_FRAME_SIZE {
+            return Err(Error::OversizedFrame(data.len()));
+        }
+        async_send_raw(self, data).await
+    }
+
+    #[inline]
+    async fn async_send_raw(&mut self, data: &[u8]) -> Result<usize, Error> {
+        async_send_raw(self, data).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_listener_accepts_many() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = Listener::bind(any_port).unwrap();
+        let local_addr = listener.local_addr();
+        assert_ne!(local_addr.port, 0);
+
+        let clients = std::thread::spawn(move || {
+            (0..3)
+                .map(|_| {
+                    let mut connection =
+                        Connection::connect(local_addr).unwrap();
+                    connection.as_sender().send_raw(b"ping").unwrap();
+                    connection.receiver.stream.local_addr().unwrap().into()
+                })
+                .collect::<Vec<InetSocketAddr>>()
+        });
+
+        let remotes = listener
+            .incoming()
+            .take(3)
+            .map(|connection| {
+                let mut connection = connection.unwrap();
+                assert_eq!(
+                    connection.as_receiver().recv_raw(4).unwrap(),
+                    b"ping"
+                );
+                connection.remote_addr()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(remotes, clients.join().unwrap());
+    }
+
+    #[test]
+    fn test_accept_returns_listener() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        // Finding free port which will be bound by `Connection::accept`
+        let socket_addr = Listener::bind(any_port).unwrap().local_addr();
+
+        let clients = std::thread::spawn(move || {
+            let connection = loop {
+                match Connection::connect(socket_addr) {
+                    Ok(connection) => break connection,
+                    Err(_) => std::thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            (connection, Connection::connect(socket_addr).unwrap())
+        });
+        let (connection, listener) = Connection::accept(socket_addr).unwrap();
+        assert_eq!(listener.local_addr(), socket_addr);
+        let (left, right) = Bipolar::split(connection);
+        let joined = <Connection as Bipolar>::join(left, right);
+        assert_eq!(
+            joined.remote_addr(),
+            joined.receiver.stream.peer_addr().unwrap().into()
+        );
+        let next = listener.accept().unwrap();
+        assert_eq!(
+            next.remote_addr(),
+            next.receiver.stream.peer_addr().unwrap().into()
+        );
+        clients.join().unwrap();
+
+        // Socket is released with the listener
+        drop(listener);
+        Listener::bind(socket_addr).unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_frames() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = AsyncListener::bind(any_port).await.unwrap();
+        let socket_addr = listener.local_addr();
+
+        let mut frame = vec![0x00, 0x04];
+        frame.extend(&[0u8; 16]);
+        frame.extend(b"ping");
+        frame.extend(&[0u8; 16]);
+
+        let (stream, _) = tokio::join!(
+            async {
+                let (mut stream, remote_addr) =
+                    listener.accept().await.unwrap();
+                assert_eq!(remote_addr, stream.peer_addr().unwrap().into());
+                let received = stream.async_recv_frame().await.unwrap();
+                stream.async_send_frame(&received).await.unwrap();
+                stream
+            },
+            async {
+                let mut stream = async_connect(socket_addr).await.unwrap();
+                stream.async_send_frame(&frame).await.unwrap();
+                let (mut input, _) = AsyncDuplex::split(stream);
+                assert_eq!(input.async_recv_frame().await.unwrap(), frame);
+            }
+        );
+        let (_, mut output) = AsyncDuplex::split(stream);
+        assert_eq!(
+            output
+                .async_send_frame(&vec![0u8; super::super::MAX_FRAME_SIZE + 1])
+                .await,
+            Err(Error::OversizedFrame(super::super::MAX_FRAME_SIZE + 1))
+        );
+    }
+}
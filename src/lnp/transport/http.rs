@@ -0,0 +1,639 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! HTTP framing protocol: each LNP frame is transmitted as a body of a single
+//! HTTP POST request (by the client) or HTTP response (by the server) over
+//! a persistent (keep-alive) HTTP/1.1 connection. This allows exposing
+//! request/response APIs (like RPC) through HTTP proxies and load balancers.
+
+use amplify::internet::InetSocketAddr;
+use amplify::Bipolar;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use core::convert::TryFrom;
+use core::time::Duration;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use super::{ftcp, Duplex, Error, RecvFrame, SendFrame};
+
+/// Path used for HTTP POST requests carrying LNP frames
+pub const HTTP_PATH: &str = "/";
+
+/// Maximum length of HTTP header line which will be accepted
+const MAX_HEADER_LINE_LEN: usize = 8 * 1024;
+
+/// Side of the HTTP connection, defining whether requests or responses are
+/// sent and received
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display)]
+#[display(Debug)]
+pub enum Role {
+    /// Client sends frames as HTTP POST requests and receives frames as
+    /// HTTP responses
+    Client,
+
+    /// Server receives frames as HTTP POST requests and sends frames as
+    /// HTTP responses
+    Server,
+}
+
+/// Encoding of the LNP frame data within HTTP message body
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display)]
+#[display(Debug)]
+pub enum Encoding {
+    /// Raw binary data with `application/octet-stream` content type
+    Binary,
+
+    /// Hex-encoded data with `text/plain` content type
+    Hex,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Binary
+    }
+}
+
+impl Encoding {
+    /// Returns HTTP content type used for the encoding
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Binary => "application/octet-stream",
+            Encoding::Hex => "text/plain",
+        }
+    }
+
+    /// Detects encoding from HTTP content type header value. Absent or
+    /// unknown content types are treated as binary data.
+    pub fn from_content_type(content_type: &str) -> Self {
+        match content_type.split(';').next().map(str::trim) {
+            Some(t) if t.eq_ignore_ascii_case("text/plain") => Encoding::Hex,
+            _ => Encoding::Binary,
+        }
+    }
+}
+
+/// Receiving half of the HTTP [`Connection`]. Decodes message body according
+/// to its content type, so both [`Encoding`]s are always accepted.
+#[derive(Debug)]
+pub struct Receiver {
+    pub(self) reader: BufReader<TcpStream>,
+    pub(self) role: Role,
+    /// Data from the last received HTTP message which were not consumed
+    /// yet by [`RecvFrame::recv_raw`]
+    pub(self) buffer: Vec<u8>,
+}
+
+/// Sending half of the HTTP [`Connection`]
+#[derive(Debug)]
+pub struct Sender {
+    pub(self) stream: TcpStream,
+    pub(self) role: Role,
+    pub(self) encoding: Encoding,
+    pub(self) remote_addr: InetSocketAddr,
+}
+
+/// HTTP/1.1 connection carrying LNP frames in message bodies
+#[derive(Debug)]
+pub struct Connection {
+    pub(self) receiver: Receiver,
+    pub(self) sender: Sender,
+}
+
+impl Connection {
+    /// Connects to the HTTP server at `inet_addr` as a client, which will
+    /// encode frames with the given `encoding`
+    pub fn connect(
+        inet_addr: InetSocketAddr,
+        encoding: Encoding,
+    ) -> Result<Self, Error> {
+        let socket_addr = SocketAddr::try_from(inet_addr)
+            .map_err(|_| Error::TorNotSupportedYet)?;
+        let stream = TcpStream::connect(socket_addr)?;
+        // NB: This is how we handle ping-pong cycles
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        Self::with(stream, Role::Client, encoding, inet_addr)
+    }
+
+    /// Binds new TCP [`ftcp::Listener`] to the `inet_addr` and accepts the
+    /// first incoming connection as a server. Returns the connection together
+    /// with the listener, which may be used with [`Connection::accept_from`]
+    /// to accept further connections.
+    pub fn accept(
+        inet_addr: InetSocketAddr,
+        encoding: Encoding,
+    ) -> Result<(Self, ftcp::Listener), Error> {
+        let listener = ftcp::Listener::bind(inet_addr)?;
+        Ok((Self::accept_from(&listener, encoding)?, listener))
+    }
+
+    /// Accepts next incoming TCP connection from the `listener` as a server
+    pub fn accept_from(
+        listener: &ftcp::Listener,
+        encoding: Encoding,
+    ) -> Result<Self, Error> {
+        let connection = listener.accept()?;
+        let remote_addr = connection.remote_addr();
+        let (stream, _) = Bipolar::split(connection);
+        Self::with(stream, Role::Server, encoding, remote_addr)
+    }
+
+    /// Returns address of the remote peer
+    #[inline]
+    pub fn remote_addr(&self) -> InetSocketAddr {
+        self.sender.remote_addr
+    }
+
+    /// Returns side of the HTTP connection
+    #[inline]
+    pub fn role(&self) -> Role {
+        self.sender.role
+    }
+
+    fn with(
+        stream: TcpStream,
+        role: Role,
+        encoding: Encoding,
+        remote_addr: InetSocketAddr,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            receiver: Receiver {
+                reader: BufReader::new(stream.try_clone()?),
+                role,
+                buffer: vec![],
+            },
+            sender: Sender {
+                stream,
+                role,
+                encoding,
+                remote_addr,
+            },
+        })
+    }
+}
+
+impl Receiver {
+    fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        let len = (&mut self.reader)
+            .take(MAX_HEADER_LINE_LEN as u64)
+            .read_line(&mut line)?;
+        if len == 0 {
+            Err(Error::ServiceOffline)?
+        }
+        if !line.ends_with("\r\n") {
+            Err(Error::FrameBroken("invalid HTTP header line"))?
+        }
+        line.truncate(line.len() - 2);
+        Ok(line)
+    }
+
+    /// Reads next HTTP message (request or response, depending on the role)
+    /// and returns its decoded body
+    fn read_message(&mut self) -> Result<Vec<u8>, Error> {
+        let start_line = self.read_line()?;
+        let mut parts = start_line.splitn(3, ' ');
+        match (self.role, parts.next(), parts.next(), parts.next()) {
+            (Role::Server, Some("POST"), Some(_), Some(version))
+            | (Role::Client, Some(version), Some(_), _)
+                if !version.starts_with("HTTP/1.") =>
+            {
+                Err(Error::FrameBroken("unsupported HTTP version"))?
+            }
+            (Role::Server, Some("POST"), Some(_), Some(_)) => {}
+            (Role::Server, ..) => {
+                Err(Error::FrameBroken("HTTP request must use POST method"))?
+            }
+            (Role::Client, Some(_), Some(status), _)
+                if status.starts_with('2') => {}
+            (Role::Client, ..) => {
+                Err(Error::FrameBroken("unsuccessful HTTP response status"))?
+            }
+        }
+
+        let mut content_len = None;
+        let mut chunked = false;
+        let mut encoding = Encoding::Binary;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            let mut header = line.splitn(2, ':');
+            let name = header.next().unwrap_or_default().trim();
+            let value = header.next().unwrap_or_default().trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_len = Some(value.parse::<usize>().map_err(|_| {
+                    Error::FrameBroken("invalid HTTP content length")
+                })?);
+            } else if name.eq_ignore_ascii_case("content-type") {
+                encoding = Encoding::from_content_type(value);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                if !value.eq_ignore_ascii_case("chunked") {
+                    Err(Error::FrameBroken(
+                        "HTTP transfer encodings other than chunked are not \
+                         supported",
+                    ))?
+                }
+                chunked = true;
+            }
+        }
+        let max_len = match encoding {
+            Encoding::Binary => super::MAX_FRAME_SIZE,
+            Encoding::Hex => super::MAX_FRAME_SIZE * 2,
+        };
+
+        // NB: Chunked transfer encoding overrides content length
+        let body = if chunked {
+            self.read_chunked(max_len)?
+        } else {
+            let len = content_len
+                .ok_or(Error::FrameBroken("HTTP content length is required"))?;
+            if len > max_len {
+                return Err(Error::OversizedFrame(len));
+            }
+            let mut body = vec![0u8; len];
+            self.reader.read_exact(&mut body)?;
+            body
+        };
+        match encoding {
+            Encoding::Binary => Ok(body),
+            Encoding::Hex => String::from_utf8(body)
+                .ok()
+                .and_then(|s| Vec::<u8>::from_hex(s.trim()).ok())
+                .ok_or(Error::FrameBroken("invalid hex data in HTTP body")),
+        }
+    }
+
+    /// Reads message body with chunked transfer encoding, which can't exceed
+    /// `max_len` bytes in total
+    fn read_chunked(&mut self, max_len: usize) -> Result<Vec<u8>, Error> {
+        let mut body = vec![];
+        loop {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| Error::FrameBroken("invalid HTTP chunk size"))?;
+            if size == 0 {
+                break;
+            }
+            let len = body.len().saturating_add(size);
+            if len > max_len {
+                return Err(Error::OversizedFrame(len));
+            }
+            body.resize(len, 0);
+            let start = len - size;
+            self.reader.read_exact(&mut body[start..])?;
+            if !self.read_line()?.is_empty() {
+                Err(Error::FrameBroken("HTTP chunk is not terminated"))?
+            }
+        }
+        // Trailer headers are ignored
+        while !self.read_line()?.is_empty() {}
+        Ok(body)
+    }
+}
+
+impl RecvFrame for Receiver {
+    /// Returns the body of the next HTTP message. If the previous message
+    /// was partially consumed by [`RecvFrame::recv_raw`], returns its
+    /// remaining part instead.
+    fn recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        if !self.buffer.is_empty() {
+            return Ok(self.buffer.split_off(0));
+        }
+        self.read_message()
+    }
+
+    fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        while self.buffer.len() < len {
+            let data = self.read_message()?;
+            self.buffer.extend(data);
+        }
+        let rest = self.buffer.split_off(len);
+        Ok(core::mem::replace(&mut self.buffer, rest))
+    }
+}
+
+impl SendFrame for Sender {
+    fn send_frame(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let len = data.len();
+        if len > super::MAX_FRAME_SIZE {
+            return Err(Error::OversizedFrame(len));
+        }
+        self.send_raw(data)
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let body = match self.encoding {
+            Encoding::Binary => data.to_vec(),
+            Encoding::Hex => data.to_hex().into_bytes(),
+        };
+        let start_line = match self.role {
+            Role::Client => format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\n",
+                HTTP_PATH, self.remote_addr
+            ),
+            Role::Server => s!("HTTP/1.1 200 OK\r\n"),
+        };
+        let mut message = format!(
+            "{}Content-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            start_line,
+            self.encoding.content_type(),
+            body.len()
+        )
+        .into_bytes();
+        message.extend(body);
+        self.stream.write_all(&message)?;
+        Ok(data.len())
+    }
+}
+
+impl Duplex for Connection {
+    #[inline]
+    fn as_receiver(&mut self) -> &mut dyn RecvFrame {
+        &mut self.receiver
+    }
+
+    #[inline]
+    fn as_sender(&mut self) -> &mut dyn SendFrame {
+        &mut self.sender
+    }
+
+    #[inline]
+    fn split(self) -> (Box<dyn RecvFrame + Send>, Box<dyn SendFrame + Send>) {
+        (Box::new(self.receiver), Box::new(self.sender))
+    }
+}
+
+impl Bipolar for Connection {
+    type Left = Receiver;
+    type Right = Sender;
+
+    /// Joins connection halves. The side of the joined connection is defined
+    /// by its receiving half, so the sending half follows the same [`Role`].
+    fn join(left: Self::Left, mut right: Self::Right) -> Self {
+        right.role = left.role;
+        Self {
+            receiver: left,
+            sender: right,
+        }
+    }
+
+    fn split(self) -> (Self::Left, Self::Right) {
+        (self.receiver, self.sender)
+    }
+}
+
+impl Sender {
+    /// Sends HTTP `400 Bad Request` response, which is used by the server to
+    /// reject malformed requests before closing the connection
+    fn send_bad_request(&mut self) -> Result<(), Error> {
+        self.stream.write_all(
+            b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\
+              Connection: close\r\n\r\n",
+        )?;
+        Ok(())
+    }
+}
+
+/// Serves incoming HTTP connections from the `listener`, calling `handler`
+/// for each of the received requests and sending back the returned data as
+/// a response. Each connection is served by its own thread until it gets
+/// closed by the client or fails, so keep-alive clients do not block each
+/// other. Errors, which terminate the connection before the client has
+/// closed it, are reported to `on_error` together with the client address;
+/// malformed requests are answered with `400 Bad Request` status before the
+/// connection is closed. Returns only if the listener fails to accept a new
+/// connection.
+pub fn serve<H, E>(
+    listener: &ftcp::Listener,
+    encoding: Encoding,
+    handler: H,
+    on_error: E,
+) -> Result<(), Error>
+where
+    H: Fn(Vec<u8>) -> Vec<u8> + Send + Sync + 'static,
+    E: Fn(InetSocketAddr, Error) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let on_error = Arc::new(on_error);
+    loop {
+        let connection = Connection::accept_from(listener, encoding)?;
+        let handler = handler.clone();
+        let on_error = on_error.clone();
+        thread::spawn(move || {
+            let remote_addr = connection.remote_addr();
+            if let Err(err) = serve_connection(connection, &*handler) {
+                on_error(remote_addr, err);
+            }
+        });
+    }
+}
+
+/// Serves requests from a single connection until it gets closed by the
+/// client (which is not an error) or fails
+fn serve_connection<H>(
+    mut connection: Connection,
+    handler: &H,
+) -> Result<(), Error>
+where
+    H: Fn(Vec<u8>) -> Vec<u8>,
+{
+    loop {
+        let request = match connection.receiver.recv_frame() {
+            Ok(request) => request,
+            Err(Error::ServiceOffline) => return Ok(()),
+            Err(err @ Error::FrameBroken(_))
+            | Err(err @ Error::OversizedFrame(_)) => {
+                // Sending failure is not reported since the connection is
+                // already closed due to the request error
+                let _ = connection.sender.send_bad_request();
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        };
+        connection.sender.send_frame(&handler(request))?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn any_port() -> InetSocketAddr {
+        "127.0.0.1:0".parse::<SocketAddr>().unwrap().into()
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            Encoding::from_content_type("text/plain; charset=utf-8"),
+            Encoding::Hex
+        );
+        assert_eq!(
+            Encoding::from_content_type(Encoding::Binary.content_type()),
+            Encoding::Binary
+        );
+        assert_eq!(Encoding::from_content_type(""), Encoding::Binary);
+    }
+
+    #[test]
+    fn test_http_frames() {
+        let listener = ftcp::Listener::bind(any_port()).unwrap();
+        let socket_addr = listener.local_addr();
+
+        let rx = std::thread::spawn(move || {
+            let mut connection =
+                Connection::accept_from(&listener, Encoding::Binary).unwrap();
+            assert_eq!(connection.role(), Role::Server);
+            for _ in 0..2 {
+                let frame = connection.as_receiver().recv_frame().unwrap();
+                connection.as_sender().send_frame(&frame).unwrap();
+            }
+        });
+
+        let mut tx = Connection::connect(socket_addr, Encoding::Hex).unwrap();
+        for frame in &[&b"Some frame"[..], &b""[..]] {
+            tx.as_sender().send_frame(frame).unwrap();
+            assert_eq!(&tx.as_receiver().recv_frame().unwrap()[..], *frame);
+        }
+        rx.join().unwrap();
+    }
+
+    #[test]
+    fn test_http_serve() {
+        let listener = ftcp::Listener::bind(any_port()).unwrap();
+        let socket_addr = listener.local_addr();
+        let (errors, failures) = std::sync::mpsc::channel();
+        let errors = std::sync::Mutex::new(errors);
+        std::thread::spawn(move || {
+            serve(
+                &listener,
+                Encoding::Binary,
+                |mut request| {
+                    request.reverse();
+                    request
+                },
+                move |_, err| errors.lock().unwrap().send(err).unwrap(),
+            )
+        });
+
+        // Keep-alive clients are served concurrently
+        let mut clients = (0..2)
+            .map(|_| {
+                Connection::connect(socket_addr, Encoding::Binary).unwrap()
+            })
+            .collect::<Vec<_>>();
+        for tx in clients.iter_mut().rev() {
+            tx.as_sender().send_frame(b"abc").unwrap();
+            assert_eq!(tx.as_receiver().recv_frame().unwrap(), b"cba");
+        }
+        drop(clients);
+
+        let mut stream =
+            TcpStream::connect(SocketAddr::try_from(socket_addr).unwrap())
+                .unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).unwrap();
+        assert_eq!(response, "HTTP/1.1 400 Bad Request\r\n");
+        assert_eq!(
+            failures.recv().unwrap(),
+            Error::FrameBroken("HTTP request must use POST method")
+        );
+    }
+
+    #[test]
+    fn test_http_chunked() {
+        let listener = ftcp::Listener::bind(any_port()).unwrap();
+        let socket_addr = listener.local_addr();
+
+        let rx = std::thread::spawn(move || {
+            let mut connection =
+                Connection::accept_from(&listener, Encoding::Binary).unwrap();
+            assert_eq!(
+                connection.as_receiver().recv_frame().unwrap(),
+                b"\x01\x02\x03"
+            );
+            assert_eq!(
+                connection.as_receiver().recv_frame(),
+                Err(Error::OversizedFrame(super::super::MAX_FRAME_SIZE + 1))
+            );
+        });
+
+        let mut stream =
+            TcpStream::connect(SocketAddr::try_from(socket_addr).unwrap())
+                .unwrap();
+        stream
+            .write_all(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  2;ext=1\r\n\x01\x02\r\n1\r\n\x03\r\n0\r\n\
+                  Trailer: value\r\n\r\n",
+            )
+            .unwrap();
+        stream
+            .write_all(
+                format!(
+                    "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     {:x}\r\n",
+                    super::super::MAX_FRAME_SIZE + 1
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        rx.join().unwrap();
+    }
+
+    #[test]
+    fn test_http_foreign_request() {
+        let listener = ftcp::Listener::bind(any_port()).unwrap();
+        let socket_addr = listener.local_addr();
+
+        let rx = std::thread::spawn(move || {
+            let mut connection =
+                Connection::accept_from(&listener, Encoding::Hex).unwrap();
+            assert_eq!(
+                connection.as_receiver().recv_frame().unwrap(),
+                b"\x01\x02"
+            );
+            connection.as_sender().send_frame(b"\xff").unwrap();
+            assert_eq!(
+                connection.as_receiver().recv_frame(),
+                Err(Error::FrameBroken("HTTP request must use POST method"))
+            );
+        });
+
+        let mut stream =
+            TcpStream::connect(SocketAddr::try_from(socket_addr).unwrap())
+                .unwrap();
+        stream
+            .write_all(
+                b"POST /rpc HTTP/1.1\r\nHost: localhost\r\n\
+                  content-type: text/plain\r\ncontent-length: 4\r\n\r\n0102",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut response = String::new();
+        while response != "\r\n" {
+            response.clear();
+            reader.read_line(&mut response).unwrap();
+        }
+        let mut body = [0u8; 2];
+        reader.read_exact(&mut body).unwrap();
+        assert_eq!(&body, b"ff");
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        rx.join().unwrap();
+    }
+}
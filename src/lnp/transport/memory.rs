@@ -0,0 +1,303 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! In-memory loopback transport: a pair of connected duplexes within the same
+//! process, using the same framing and frame size limits as [`super::ftcp`].
+//! Intended for testing upper layers without opening sockets; each direction
+//! may inject latency, dropped and truncated frames according to [`Faults`].
+
+use amplify::Bipolar;
+use core::time::Duration;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Instant;
+
+use super::{Duplex, Error, RecvFrame, SendFrame};
+
+/// Default timeout for receiving data, matching the one of framed TCP
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Faults injected into a single direction of the in-memory connection.
+///
+/// Frames are numbered from zero in the order they are sent by
+/// [`SendFrame::send_frame`] or [`SendFrame::send_raw`] calls; faults are
+/// applied deterministically according to these numbers.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Faults {
+    /// Delay before each sent frame becomes available to the receiver
+    pub latency: Duration,
+
+    /// Numbers of frames which are silently dropped
+    pub dropped: BTreeSet<usize>,
+
+    /// Numbers of frames which are truncated to the given length
+    pub truncated: BTreeMap<usize, usize>,
+}
+
+impl Faults {
+    /// Adds `latency` to each of the sent frames
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Drops frame with number `no`
+    pub fn dropping(mut self, no: usize) -> Self {
+        self.dropped.insert(no);
+        self
+    }
+
+    /// Truncates frame with number `no` to `len` bytes
+    pub fn truncating(mut self, no: usize, len: usize) -> Self {
+        self.truncated.insert(no, len);
+        self
+    }
+}
+
+/// Receiving half of the in-memory [`Connection`]
+#[derive(Debug)]
+pub struct Receiver {
+    pub(self) channel: mpsc::Receiver<(Instant, Vec<u8>)>,
+    pub(self) buffer: Vec<u8>,
+    pub(self) timeout: Duration,
+}
+
+/// Sending half of the in-memory [`Connection`]
+#[derive(Debug)]
+pub struct Sender {
+    pub(self) channel: mpsc::Sender<(Instant, Vec<u8>)>,
+    pub(self) faults: Faults,
+    pub(self) count: usize,
+}
+
+/// In-memory connection, which is always created as a part of the connected
+/// pair with [`pair`] or [`pair_with`]
+#[derive(Debug)]
+pub struct Connection {
+    pub(self) receiver: Receiver,
+    pub(self) sender: Sender,
+}
+
+impl Connection {
+    /// Sets timeout for receiving data, after which [`Error::TimedOut`] is
+    /// returned
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.receiver.timeout = timeout;
+    }
+}
+
+/// Constructs pair of connected in-memory duplexes without faults
+pub fn pair() -> (Connection, Connection) {
+    pair_with(Faults::default(), Faults::default())
+}
+
+/// Constructs pair of connected in-memory duplexes, injecting `faults` into
+/// frames sent from the first connection to the second one and
+/// `reverse_faults` into frames sent in the opposite direction
+pub fn pair_with(
+    faults: Faults,
+    reverse_faults: Faults,
+) -> (Connection, Connection) {
+    let (tx1, rx1) = mpsc::channel();
+    let (tx2, rx2) = mpsc::channel();
+    (
+        Connection::with(rx2, tx1, faults),
+        Connection::with(rx1, tx2, reverse_faults),
+    )
+}
+
+impl Connection {
+    fn with(
+        receiver: mpsc::Receiver<(Instant, Vec<u8>)>,
+        sender: mpsc::Sender<(Instant, Vec<u8>)>,
+        faults: Faults,
+    ) -> Self {
+        Self {
+            receiver: Receiver {
+                channel: receiver,
+                buffer: vec![],
+                timeout: DEFAULT_TIMEOUT,
+            },
+            sender: Sender {
+                channel: sender,
+                faults,
+                count: 0,
+            },
+        }
+    }
+}
+
+impl Receiver {
+    /// Blocks until the buffer contains at least `len` bytes
+    fn fill(&mut self, len: usize) -> Result<(), Error> {
+        let deadline = Instant::now() + self.timeout;
+        while self.buffer.len() < len {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let (available_at, data) = match self.channel.recv_timeout(timeout)
+            {
+                Ok(item) => item,
+                Err(RecvTimeoutError::Timeout) => Err(Error::TimedOut)?,
+                Err(RecvTimeoutError::Disconnected) => {
+                    Err(Error::SocketIo(ErrorKind::UnexpectedEof))?
+                }
+            };
+            let now = Instant::now();
+            if available_at > now {
+                std::thread::sleep(available_at - now);
+            }
+            self.buffer.extend(data);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        let rest = self.buffer.split_off(len);
+        core::mem::replace(&mut self.buffer, rest)
+    }
+}
+
+impl RecvFrame for Receiver {
+    fn recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        self.fill(2)?;
+        let len = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+        let len = len + super::FRAME_PREFIX_SIZE + super::FRAME_SUFFIX_SIZE;
+        self.fill(len)?;
+        Ok(self.take(len))
+    }
+
+    fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        self.fill(len)?;
+        Ok(self.take(len))
+    }
+}
+
+impl SendFrame for Sender {
+    fn send_frame(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let len = data.len();
+        if len > super::MAX_FRAME_SIZE {
+            return Err(Error::OversizedFrame(len));
+        }
+        self.send_raw(data)
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let no = self.count;
+        self.count += 1;
+        if self.faults.dropped.contains(&no) {
+            return Ok(data.len());
+        }
+        let mut frame = data.to_vec();
+        if let Some(len) = self.faults.truncated.get(&no) {
+            frame.truncate(*len);
+        }
+        self.channel
+            .send((Instant::now() + self.faults.latency, frame))
+            .map_err(|_| Error::SocketIo(ErrorKind::BrokenPipe))?;
+        Ok(data.len())
+    }
+}
+
+impl Duplex for Connection {
+    #[inline]
+    fn as_receiver(&mut self) -> &mut dyn RecvFrame {
+        &mut self.receiver
+    }
+
+    #[inline]
+    fn as_sender(&mut self) -> &mut dyn SendFrame {
+        &mut self.sender
+    }
+
+    #[inline]
+    fn split(self) -> (Box<dyn RecvFrame + Send>, Box<dyn SendFrame + Send>) {
+        (Box::new(self.receiver), Box::new(self.sender))
+    }
+}
+
+impl Bipolar for Connection {
+    type Left = Receiver;
+    type Right = Sender;
+
+    fn join(left: Self::Left, right: Self::Right) -> Self {
+        Self {
+            receiver: left,
+            sender: right,
+        }
+    }
+
+    fn split(self) -> (Self::Left, Self::Right) {
+        (self.receiver, self.sender)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u16).to_be_bytes().to_vec();
+        frame.extend(&[0u8; super::super::FRAME_PREFIX_SIZE - 2]);
+        frame.extend(payload);
+        frame.extend(&[0u8; super::super::FRAME_SUFFIX_SIZE]);
+        frame
+    }
+
+    #[test]
+    fn test_memory_frames() {
+        let (mut a, mut b) = pair();
+        a.as_sender().send_frame(&frame(b"ping")).unwrap();
+        b.as_sender().send_raw(b"pong").unwrap();
+        assert_eq!(b.as_receiver().recv_frame().unwrap(), frame(b"ping"));
+        assert_eq!(a.as_receiver().recv_raw(2).unwrap(), b"po");
+        assert_eq!(a.as_receiver().recv_raw(2).unwrap(), b"ng");
+        assert_eq!(
+            a.as_sender()
+                .send_frame(&[0u8; super::super::MAX_FRAME_SIZE + 1]),
+            Err(Error::OversizedFrame(super::super::MAX_FRAME_SIZE + 1))
+        );
+        drop(a);
+        assert_eq!(
+            b.as_receiver().recv_frame(),
+            Err(Error::SocketIo(ErrorKind::UnexpectedEof))
+        );
+        assert_eq!(
+            b.as_sender().send_frame(b"ping"),
+            Err(Error::SocketIo(ErrorKind::BrokenPipe))
+        );
+    }
+
+    #[test]
+    fn test_memory_faults() {
+        let latency = Duration::from_millis(20);
+        let faults = Faults::default()
+            .with_latency(latency)
+            .dropping(1)
+            .truncating(2, 20);
+        let (mut a, mut b) = pair_with(faults, Faults::default());
+        b.set_timeout(Duration::from_millis(100));
+
+        let start = Instant::now();
+        for payload in &[&b"first"[..], b"second", b"third", b"fourth"] {
+            a.as_sender().send_frame(&frame(payload)).unwrap();
+        }
+        assert_eq!(b.as_receiver().recv_frame().unwrap(), frame(b"first"));
+        assert!(start.elapsed() >= latency);
+        // Second frame is dropped and the third one is truncated, so the
+        // stream gets desynchronized, as it happens with TCP
+        let broken = b.as_receiver().recv_raw(20).unwrap();
+        assert_eq!(broken, frame(b"third")[..20].to_vec());
+        assert_eq!(b.as_receiver().recv_frame().unwrap(), frame(b"fourth"));
+        assert_eq!(b.as_receiver().recv_frame(), Err(Error::TimedOut));
+    }
+}
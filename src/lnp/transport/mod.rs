@@ -20,8 +20,19 @@
 //! integrates with ZMQ such that the upper level can abstract for a particular
 //! transport protocol used.
 
+pub mod capture;
 pub mod ftcp;
+pub mod http;
+pub mod memory;
+#[cfg(not(target_os = "windows"))]
+pub mod posix;
+#[cfg(feature = "smtp")]
+pub mod smtp;
 pub mod socket_addr;
+pub mod socks5;
+#[cfg(feature = "tor")]
+pub mod tor;
+#[cfg(feature = "websockets")]
 pub mod websocket;
 #[cfg(feature = "zmq")]
 pub mod zmqsocket;
@@ -58,6 +69,12 @@ pub enum Error {
     #[cfg(feature = "zmq")]
     Zmq(zmqsocket::Error),
 
+    /// ZMQ CURVE security can't be applied to PUSH/PULL connections, which
+    /// use an additional local socket, or to the sockets which are already
+    /// bound or connected
+    #[cfg(feature = "zmq")]
+    CurveNotApplicable,
+
     /// service is offline or not responding
     ServiceOffline,
 
@@ -79,9 +96,41 @@ pub enum Error {
     /// frame payload length is not equal to the actual frame payload provided
     InvalidLength,
 
-    /// connections over Tor protocol are not yet supported
+    /// noise or websocket handshake with the remote peer has failed
+    HandshakeFailure,
+
+    /// unable to decrypt the received frame: the frame is either corrupted
+    /// or encrypted with a different key
+    DecryptionFailure,
+
+    /// connections to Tor onion addresses require SOCKS5 proxy, which was
+    /// not provided
     TorNotSupportedYet,
 
+    /// SOCKS5 proxy error: {0}
+    #[from]
+    Socks5(socks5::Error),
+
+    /// SMTP relay has replied with error status {0}
+    Smtp(u16),
+
+    /// SMTP transport requires relay and mailbox configuration, so it can't be
+    /// used with a remote socket address only
+    SmtpConfigRequired,
+
+    /// HTTP transport is used only for request/response RPC APIs and can't
+    /// carry BOLT-8 authenticated and encrypted sessions with remote nodes
+    HttpNodeNotSupported,
+
+    /// Tor control port has replied with error status {0}
+    #[cfg(feature = "tor")]
+    TorControl(u16),
+
+    /// Tor control port has sent a reply which does not follow the control
+    /// protocol or lacks the requested data
+    #[cfg(feature = "tor")]
+    TorControlInvalidReply,
+
     /// read or write attempt exceeded socket timeout
     TimedOut,
 }
@@ -253,6 +302,19 @@ pub trait SendFrame {
     }
 }
 
+/// Async version of [`Duplex`] trait
+#[cfg(feature = "async")]
+pub trait AsyncDuplex {
+    fn as_receiver(&mut self) -> &mut (dyn AsyncRecvFrame + Send);
+    fn as_sender(&mut self) -> &mut (dyn AsyncSendFrame + Send);
+    fn split(
+        self,
+    ) -> (
+        Box<dyn AsyncRecvFrame + Send>,
+        Box<dyn AsyncSendFrame + Send>,
+    );
+}
+
 /// Async version of [`RecvFrame`] trait
 #[cfg(feature = "async")]
 #[async_trait]
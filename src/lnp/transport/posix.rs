@@ -0,0 +1,345 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Framed POSIX (Unix domain) socket protocol: reads & writes frames
+//! (corresponding to LNP messages) from a local Unix socket stream. Used for
+//! communications between local processes, which access is protected with
+//! file system permissions of the socket file.
+
+use amplify::Bipolar;
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use super::{ftcp, Duplex, Error, RecvFrame, SendFrame};
+
+/// Wraps UnixStream
+///
+/// We need this wrapper structure since we can't implement foreign traits, such
+/// as Bipolar, for a foreign type.
+#[derive(Debug)]
+pub struct Connection {
+    pub(self) stream: UnixStream,
+}
+
+impl Connection {
+    #[inline]
+    pub fn with(stream: UnixStream) -> Self {
+        Self { stream }
+    }
+
+    /// Connects to the socket file at `path`
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self::with(UnixStream::connect(path)?))
+    }
+
+    /// Binds new [`Listener`] to the socket file at `path` and accepts the
+    /// first incoming connection. Returns the connection together with the
+    /// listener, which may be used to accept further connections; the socket
+    /// file is removed once the listener is dropped.
+    pub fn accept(path: impl AsRef<Path>) -> Result<(Self, Listener), Error> {
+        let listener = Listener::bind(path)?;
+        Ok((listener.accept()?, listener))
+    }
+}
+
+/// Framed POSIX socket listener: binds to a socket file once and accepts
+/// incoming [`Connection`]s repeatedly. The socket file is removed when the
+/// listener is dropped.
+#[derive(Debug)]
+pub struct Listener {
+    pub(self) listener: UnixListener,
+    pub(self) path: PathBuf,
+}
+
+impl Listener {
+    /// Binds to the socket file at `path`. If the file is a socket left from
+    /// a listener which is not running anymore, it gets replaced; any other
+    /// existing file results in [`ErrorKind::AddrInUse`] error.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        remove_stale(&path)?;
+        let listener = UnixListener::bind(&path)?;
+        Ok(Self { listener, path })
+    }
+
+    /// Binds to the socket file at `path` with file access `mode` (like
+    /// `0o600`) restricting which users may connect to the socket. The socket
+    /// is bound and gets its mode inside a private temporary directory, and
+    /// only then is linked to the `path`, so it never becomes accessible
+    /// with the default permissions.
+    pub fn bind_with_mode(
+        path: impl AsRef<Path>,
+        mode: u32,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let file_name = path
+            .file_name()
+            .ok_or(Error::SocketIo(ErrorKind::InvalidInput))?;
+        let mut private_dir = path.clone();
+        private_dir.set_file_name(format!(
+            ".{}.{}",
+            file_name.to_string_lossy(),
+            std::process::id()
+        ));
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&private_dir)?;
+        let private_path = private_dir.join("socket");
+
+        let result = UnixListener::bind(&private_path)
+            .and_then(|listener| {
+                std::fs::set_permissions(
+                    &private_path,
+                    std::fs::Permissions::from_mode(mode),
+                )?;
+                Ok(listener)
+            })
+            .map_err(Error::from)
+            .and_then(|listener| {
+                remove_stale(&path)?;
+                // NB: Unlike rename, hard link never replaces existing file
+                std::fs::hard_link(&private_path, &path)?;
+                Ok(listener)
+            });
+        let _ = std::fs::remove_file(&private_path);
+        let _ = std::fs::remove_dir(&private_dir);
+        Ok(Self {
+            listener: result?,
+            path,
+        })
+    }
+
+    /// Blocks until a new incoming connection is accepted
+    pub fn accept(&self) -> Result<Connection, Error> {
+        let (stream, _) = self.listener.accept()?;
+        Ok(Connection::with(stream))
+    }
+
+    /// Returns an iterator over incoming connections, blocking on each
+    /// iteration until a new connection is accepted
+    #[inline]
+    pub fn incoming(&self) -> Incoming {
+        Incoming { listener: self }
+    }
+
+    /// Returns path to the socket file the listener is bound to
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Removes socket file at `path` if it is left from a listener which is not
+/// running anymore. Fails with [`ErrorKind::AddrInUse`] if the file is not a
+/// socket or is still in use.
+fn remove_stale(path: &Path) -> Result<(), Error> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => Err(err)?,
+        Ok(metadata) => metadata,
+    };
+    if !metadata.file_type().is_socket() {
+        Err(Error::SocketIo(ErrorKind::AddrInUse))?
+    }
+    match UnixStream::connect(path) {
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        _ => Err(Error::SocketIo(ErrorKind::AddrInUse)),
+    }
+}
+
+/// Iterator over incoming connections of the [`Listener`], which never
+/// returns `None`
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a Listener,
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = Result<Connection, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept())
+    }
+}
+
+impl Duplex for Connection {
+    #[inline]
+    fn as_receiver(&mut self) -> &mut dyn RecvFrame {
+        &mut self.stream
+    }
+
+    #[inline]
+    fn as_sender(&mut self) -> &mut dyn SendFrame {
+        &mut self.stream
+    }
+
+    #[inline]
+    fn split(self) -> (Box<dyn RecvFrame + Send>, Box<dyn SendFrame + Send>) {
+        (
+            Box::new(
+                self.stream.try_clone().expect("Error cloning POSIX socket"),
+            ),
+            Box::new(self.stream),
+        )
+    }
+}
+
+impl Bipolar for Connection {
+    type Left = UnixStream;
+    type Right = UnixStream;
+
+    fn join(left: Self::Left, right: Self::Right) -> Self {
+        assert_eq!(
+            left.as_raw_fd(),
+            right.as_raw_fd(),
+            "Two independent POSIX sockets can't be joined"
+        );
+        Self { stream: left }
+    }
+
+    fn split(self) -> (Self::Left, Self::Right) {
+        (
+            self.stream.try_clone().expect("UnixStream cloning failed"),
+            self.stream,
+        )
+    }
+}
+
+impl RecvFrame for UnixStream {
+    #[inline]
+    fn recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        ftcp::recv_frame(self)
+    }
+
+    #[inline]
+    fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        ftcp::recv_raw(self, len)
+    }
+}
+
+impl SendFrame for UnixStream {
+    #[inline]
+    fn send_frame(&mut self, data: &[u8]) -> Result<usize, Error> {
+        ftcp::send_frame(self, data)
+    }
+
+    #[inline]
+    fn send_raw(&mut self, data: &[u8]) -> Result<usize, Error> {
+        ftcp::send_raw(self, data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "lnpbp-{}-{}.sock",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_posix_frames() {
+        let path = socket_path("frames");
+        let listener = Listener::bind_with_mode(&path, 0o600).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let client_path = path.clone();
+        let client = std::thread::spawn(move || {
+            (0u8..2)
+                .map(|no| {
+                    let mut connection =
+                        Connection::connect(&client_path).unwrap();
+                    let frame = [0u8, 1, no];
+                    connection.as_sender().send_raw(&frame).unwrap();
+                    connection.as_receiver().recv_raw(3).unwrap()
+                })
+                .collect::<Vec<_>>()
+        });
+
+        for connection in listener.incoming().take(2) {
+            let mut connection = connection.unwrap();
+            let frame = connection.as_receiver().recv_raw(3).unwrap();
+            connection.as_sender().send_raw(&frame).unwrap();
+        }
+        assert_eq!(client.join().unwrap(), vec![vec![0, 1, 0], vec![0, 1, 1]]);
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_posix_stale_socket() {
+        let path = socket_path("stale");
+        let stale = UnixListener::bind(&path).unwrap();
+        drop(stale);
+        assert!(path.exists());
+        let listener = Listener::bind(&path).unwrap();
+        assert_eq!(listener.path(), path.as_path());
+        assert_eq!(
+            Listener::bind(&path).unwrap_err(),
+            Error::SocketIo(ErrorKind::AddrInUse)
+        );
+    }
+
+    #[test]
+    fn test_posix_non_socket_file() {
+        let path = socket_path("file");
+        std::fs::write(&path, b"data").unwrap();
+        assert_eq!(
+            Listener::bind(&path).unwrap_err(),
+            Error::SocketIo(ErrorKind::AddrInUse)
+        );
+        assert_eq!(
+            Listener::bind_with_mode(&path, 0o600).unwrap_err(),
+            Error::SocketIo(ErrorKind::AddrInUse)
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_posix_accept_returns_listener() {
+        let path = socket_path("accept");
+        let client_path = path.clone();
+        let client = std::thread::spawn(move || loop {
+            if let Ok(connection) = Connection::connect(&client_path) {
+                break connection;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        });
+        let (_connection, listener) = Connection::accept(&path).unwrap();
+        let _client = client.join().unwrap();
+        assert_eq!(listener.path(), path.as_path());
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,949 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! SMTP store-and-forward framing protocol for ultra-low bandwidth
+//! non-real-time connections.
+//!
+//! Each LNP frame is split into one or more parts, each of which is packed
+//! into a base64-encoded MIME attachment of a separate e-mail sent through
+//! the configured SMTP relay. Inbound e-mails are pulled from a [`Mailbox`]
+//! (like [`Maildir`]); since mail delivery is neither ordered nor reliable,
+//! frames are reassembled from their parts, deduplicated and ordered by the
+//! [`Receiver`] before they are returned by [`RecvFrame`] methods. The
+//! amount of data kept by the [`Receiver`] is bounded by [`MAX_SESSIONS`],
+//! [`MAX_PENDING_FRAMES`] and [`MAX_PARTS`] limits.
+
+use amplify::Bipolar;
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use core::time::Duration;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use super::{Duplex, Error, RecvFrame, SendFrame};
+
+/// Default maximum size of frame data packed into a single e-mail
+pub const DEFAULT_CHUNK_SIZE: usize = 0x4000;
+
+/// Header containing identifier of the sending side, used to distinguish
+/// frame sequences from different senders (or sender restarts)
+pub const HEADER_SESSION: &str = "X-LNP-Session";
+
+/// Header containing sequence number of the frame within the session
+pub const HEADER_SEQUENCE: &str = "X-LNP-Sequence";
+
+/// Header containing part number and total number of parts of the frame,
+/// formatted as `<no>/<count>`
+pub const HEADER_PART: &str = "X-LNP-Part";
+
+/// Maximum number of parts a single frame may be split into
+pub const MAX_PARTS: usize = 256;
+
+/// Maximum number of sending sessions tracked by the [`Receiver`]; when a
+/// new session appears, the least recently active one is forgotten
+pub const MAX_SESSIONS: usize = 16;
+
+/// Maximum number of frames of a single session which may be kept by the
+/// [`Receiver`] while they are reassembled or waiting to be received. Parts
+/// of frames with sequence numbers beyond this window are ignored.
+pub const MAX_PENDING_FRAMES: u64 = 16;
+
+/// Length of lines of base64-encoded attachments
+const BASE64_LINE_LEN: usize = 76;
+
+/// SMTP relay configuration used for sending frames
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Relay {
+    /// Address of the SMTP relay
+    pub addr: SocketAddr,
+
+    /// Host name of the local machine presented to the relay in `EHLO`
+    pub hostname: String,
+
+    /// E-mail address of the local node
+    pub from: String,
+
+    /// E-mail address of the remote node
+    pub to: String,
+
+    /// Maximum size of frame data packed into a single e-mail; larger frames
+    /// are split into multiple e-mails
+    pub chunk_size: usize,
+}
+
+impl Relay {
+    /// Constructs relay configuration with the default chunk size
+    pub fn with(
+        addr: SocketAddr,
+        hostname: impl ToString,
+        from: impl ToString,
+        to: impl ToString,
+    ) -> Self {
+        Self {
+            addr,
+            hostname: hostname.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+/// Source of inbound e-mails
+pub trait Mailbox {
+    /// Returns raw (RFC 5322) e-mails received since the last call, marking
+    /// them as read such that they will not be returned again
+    fn fetch(&mut self) -> Result<Vec<Vec<u8>>, Error>;
+}
+
+/// Maildir mailbox: e-mails are read from `new` subdirectory and moved to
+/// `cur` subdirectory marked as seen
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Maildir {
+    path: PathBuf,
+}
+
+impl Maildir {
+    /// Opens Maildir at `path`, creating its structure if it does not exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        for dir in &["new", "cur", "tmp"] {
+            std::fs::create_dir_all(path.join(dir))?;
+        }
+        Ok(Self { path })
+    }
+
+    /// Returns path to the Maildir
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Delivers raw e-mail `data` into the Maildir, following Maildir
+    /// delivery protocol (writing into `tmp` and moving to `new`)
+    pub fn deliver(&self, data: &[u8]) -> Result<(), Error> {
+        let name = format!(
+            "{}.{:016x}.lnp",
+            chrono::Utc::now().timestamp_nanos(),
+            thread_rng().next_u64()
+        );
+        let tmp = self.path.join("tmp").join(&name);
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(tmp, self.path.join("new").join(name))?;
+        Ok(())
+    }
+}
+
+impl Mailbox for Maildir {
+    fn fetch(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut entries = std::fs::read_dir(self.path.join("new"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        let mut mails = vec![];
+        for entry in entries {
+            mails.push(std::fs::read(entry.path())?);
+            let mut name = entry.file_name();
+            name.push(":2,S");
+            std::fs::rename(entry.path(), self.path.join("cur").join(name))?;
+        }
+        Ok(mails)
+    }
+}
+
+/// Sending half of the SMTP [`Connection`], which sends each frame as one or
+/// more e-mails through the SMTP relay
+#[derive(Debug)]
+pub struct Sender {
+    pub(self) relay: Relay,
+    pub(self) session: String,
+    pub(self) sequence: u64,
+}
+
+impl Sender {
+    /// Constructs sender with a new random session identifier
+    pub fn with(relay: Relay) -> Self {
+        Self {
+            relay,
+            session: format!("{:016x}", thread_rng().next_u64()),
+            sequence: 0,
+        }
+    }
+
+    /// Returns session identifier used to mark e-mails sent
+    #[inline]
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// Packs frame `data` into e-mails, which are returned without sending.
+    /// Chunk size is increased if needed, so that the frame is never split
+    /// into more than [`MAX_PARTS`] parts.
+    fn compose(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let sequence = self.sequence;
+        self.sequence += 1;
+        let chunk_size = self
+            .relay
+            .chunk_size
+            .max((data.len() + MAX_PARTS - 1) / MAX_PARTS)
+            .max(1);
+        let chunks = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(chunk_size).collect()
+        };
+        let count = chunks.len();
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(no, chunk)| {
+                compose_mail(
+                    &self.relay,
+                    &self.session,
+                    sequence,
+                    (no + 1, count),
+                    chunk,
+                )
+            })
+            .collect()
+    }
+}
+
+impl SendFrame for Sender {
+    fn send_frame(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let len = data.len();
+        if len > super::MAX_FRAME_SIZE {
+            return Err(Error::OversizedFrame(len));
+        }
+        self.send_raw(data)
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> Result<usize, Error> {
+        for mail in self.compose(data) {
+            send_mail(&self.relay, &mail)?;
+        }
+        Ok(data.len())
+    }
+}
+
+/// Frame which parts are being collected
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+struct PartialFrame {
+    parts: Vec<Option<Vec<u8>>>,
+    /// Total size of the collected parts
+    len: usize,
+}
+
+impl PartialFrame {
+    fn new(count: usize) -> Self {
+        Self {
+            parts: vec![None; count],
+            len: 0,
+        }
+    }
+
+    /// Adds part data unless the part is already present or the frame would
+    /// exceed [`super::MAX_FRAME_SIZE`]
+    fn add(&mut self, no: usize, data: Vec<u8>) {
+        let len = self.len + data.len();
+        if self.parts[no - 1].is_some() || len > super::MAX_FRAME_SIZE {
+            return;
+        }
+        self.len = len;
+        self.parts[no - 1] = Some(data);
+    }
+
+    fn is_complete(&self) -> bool {
+        self.parts.iter().all(Option::is_some)
+    }
+
+    fn assemble(self) -> Vec<u8> {
+        self.parts.into_iter().flatten().flatten().collect()
+    }
+}
+
+/// Reassembly state for frames of a single sending session
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+struct SessionFrames {
+    next_sequence: u64,
+    pending: BTreeMap<u64, PartialFrame>,
+    /// Reassembled frames which are not received yet
+    ready: VecDeque<Vec<u8>>,
+    /// Moment of the last session activity, measured in processed parts
+    active: u64,
+}
+
+/// Receiving half of the SMTP [`Connection`], which pulls e-mails from the
+/// [`Mailbox`] and returns frames reassembled from them. Frames of the same
+/// sending session are returned in order; frames of different sessions are
+/// never interleaved while the current session has frames ready.
+#[derive(Debug)]
+pub struct Receiver<M>
+where
+    M: Mailbox,
+{
+    pub(self) mailbox: M,
+    pub(self) sessions: HashMap<String, SessionFrames>,
+    /// Session which frames are being received
+    pub(self) current: Option<String>,
+    /// Counter of the processed parts, used to track session activity
+    pub(self) processed: u64,
+    /// Data from the last returned frame which were not consumed yet by
+    /// [`RecvFrame::recv_raw`]
+    pub(self) buffer: Vec<u8>,
+    pub(self) poll_interval: Duration,
+    pub(self) timeout: Option<Duration>,
+}
+
+impl<M> Receiver<M>
+where
+    M: Mailbox,
+{
+    /// Constructs receiver polling the `mailbox` each second and blocking
+    /// until the next frame is received
+    pub fn with(mailbox: M) -> Self {
+        Self {
+            mailbox,
+            sessions: empty!(),
+            current: None,
+            processed: 0,
+            buffer: vec![],
+            poll_interval: Duration::from_secs(1),
+            timeout: None,
+        }
+    }
+
+    /// Sets interval for polling the mailbox
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.poll_interval = interval;
+    }
+
+    /// Sets timeout after which [`RecvFrame`] methods return
+    /// [`Error::TimedOut`] if no frame was received; `None` means blocking
+    /// forever
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Fetches new e-mails from the mailbox and processes them, without
+    /// blocking. Returns number of frames which are ready to be received.
+    pub fn poll(&mut self) -> Result<usize, Error> {
+        for mail in self.mailbox.fetch()? {
+            // E-mails not related to LNP or broken in transit are ignored
+            if let Some(part) = parse_mail(&mail) {
+                self.process(part);
+            }
+        }
+        Ok(self
+            .sessions
+            .values()
+            .map(|session| session.ready.len())
+            .sum())
+    }
+
+    fn process(&mut self, part: MailPart) {
+        if !self.sessions.contains_key(&part.session)
+            && self.sessions.len() >= MAX_SESSIONS
+        {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, session)| session.active)
+                .map(|(id, _)| id.clone())
+                .expect("sessions are checked to be non-empty");
+            self.sessions.remove(&oldest);
+            if self.current.as_ref() == Some(&oldest) {
+                self.current = None;
+            }
+        }
+        self.processed += 1;
+        let session = self.sessions.entry(part.session).or_default();
+        session.active = self.processed;
+
+        let received = session.next_sequence - session.ready.len() as u64;
+        if part.sequence < session.next_sequence {
+            // Duplicate of already reassembled frame
+            return;
+        }
+        if part.sequence - received >= MAX_PENDING_FRAMES {
+            // Frame is too far ahead of the frames being received
+            return;
+        }
+        let frame = session
+            .pending
+            .entry(part.sequence)
+            .or_insert_with(|| PartialFrame::new(part.count));
+        if frame.parts.len() != part.count {
+            // Inconsistent part count, which can't be a valid part
+            return;
+        }
+        frame.add(part.no, part.data);
+
+        while session
+            .pending
+            .get(&session.next_sequence)
+            .map(PartialFrame::is_complete)
+            .unwrap_or_default()
+        {
+            let frame = session
+                .pending
+                .remove(&session.next_sequence)
+                .expect("frame presence is checked in the loop condition");
+            session.ready.push_back(frame.assemble());
+            session.next_sequence += 1;
+        }
+    }
+
+    /// Returns next ready frame of the current session. If the current
+    /// session has no frames ready, switches to the least recently active
+    /// session which has them.
+    fn pop_ready(&mut self) -> Option<Vec<u8>> {
+        if self
+            .current
+            .as_ref()
+            .and_then(|id| self.sessions.get(id))
+            .map(|session| session.ready.is_empty())
+            .unwrap_or(true)
+        {
+            self.current = self
+                .sessions
+                .iter()
+                .filter(|(_, session)| !session.ready.is_empty())
+                .min_by_key(|(_, session)| session.active)
+                .map(|(id, _)| id.clone());
+        }
+        let id = self.current.as_ref()?;
+        self.sessions.get_mut(id)?.ready.pop_front()
+    }
+
+    /// Blocks until a next frame is ready, polling mailbox
+    fn next_frame(&mut self) -> Result<Vec<u8>, Error> {
+        let start = Instant::now();
+        loop {
+            if let Some(frame) = self.pop_ready() {
+                return Ok(frame);
+            }
+            if self.poll()? > 0 {
+                continue;
+            }
+            if let Some(timeout) = self.timeout {
+                if start.elapsed() >= timeout {
+                    Err(Error::TimedOut)?
+                }
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+impl<M> RecvFrame for Receiver<M>
+where
+    M: Mailbox,
+{
+    /// Returns the next reassembled frame. If the previous frame was
+    /// partially consumed by [`RecvFrame::recv_raw`], returns its remaining
+    /// part instead.
+    fn recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        if !self.buffer.is_empty() {
+            return Ok(self.buffer.split_off(0));
+        }
+        self.next_frame()
+    }
+
+    fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        while self.buffer.len() < len {
+            let data = self.next_frame()?;
+            self.buffer.extend(data);
+        }
+        let rest = self.buffer.split_off(len);
+        Ok(core::mem::replace(&mut self.buffer, rest))
+    }
+}
+
+/// SMTP store-and-forward connection, consisting of the SMTP relay sender
+/// and the mailbox receiver
+#[derive(Debug)]
+pub struct Connection<M = Maildir>
+where
+    M: Mailbox,
+{
+    pub(self) receiver: Receiver<M>,
+    pub(self) sender: Sender,
+}
+
+impl<M> Connection<M>
+where
+    M: Mailbox,
+{
+    /// Constructs connection sending frames through the `relay` and
+    /// receiving them from the `mailbox`
+    pub fn with(relay: Relay, mailbox: M) -> Self {
+        Self {
+            receiver: Receiver::with(mailbox),
+            sender: Sender::with(relay),
+        }
+    }
+}
+
+impl<M> Duplex for Connection<M>
+where
+    M: Mailbox + Send + 'static,
+{
+    #[inline]
+    fn as_receiver(&mut self) -> &mut dyn RecvFrame {
+        &mut self.receiver
+    }
+
+    #[inline]
+    fn as_sender(&mut self) -> &mut dyn SendFrame {
+        &mut self.sender
+    }
+
+    #[inline]
+    fn split(self) -> (Box<dyn RecvFrame + Send>, Box<dyn SendFrame + Send>) {
+        (Box::new(self.receiver), Box::new(self.sender))
+    }
+}
+
+impl<M> Bipolar for Connection<M>
+where
+    M: Mailbox,
+{
+    type Left = Receiver<M>;
+    type Right = Sender;
+
+    fn join(left: Self::Left, right: Self::Right) -> Self {
+        Self {
+            receiver: left,
+            sender: right,
+        }
+    }
+
+    fn split(self) -> (Self::Left, Self::Right) {
+        (self.receiver, self.sender)
+    }
+}
+
+/// Part of the frame extracted from an e-mail
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct MailPart {
+    session: String,
+    sequence: u64,
+    no: usize,
+    count: usize,
+    data: Vec<u8>,
+}
+
+fn compose_mail(
+    relay: &Relay,
+    session: &str,
+    sequence: u64,
+    (no, count): (usize, usize),
+    data: &[u8],
+) -> Vec<u8> {
+    let boundary = format!("lnp-{}-{}-{}", session, sequence, no);
+    let mut mail = format!(
+        "From: <{from}>\r\n\
+         To: <{to}>\r\n\
+         Date: {date}\r\n\
+         Subject: LNP frame {seq} part {no}/{count}\r\n\
+         Message-ID: <{session}.{seq}.{no}@{host}>\r\n\
+         MIME-Version: 1.0\r\n\
+         {h_session}: {session}\r\n\
+         {h_sequence}: {seq}\r\n\
+         {h_part}: {no}/{count}\r\n\
+         Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: application/octet-stream\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         Content-Disposition: attachment; \
+         filename=\"lnp-{seq}-{no}.bin\"\r\n\
+         \r\n",
+        from = relay.from,
+        to = relay.to,
+        date = chrono::Utc::now().to_rfc2822(),
+        seq = sequence,
+        no = no,
+        count = count,
+        session = session,
+        host = relay.hostname,
+        h_session = HEADER_SESSION,
+        h_sequence = HEADER_SEQUENCE,
+        h_part = HEADER_PART,
+        boundary = boundary,
+    );
+    let encoded = base64::encode(data);
+    for line in encoded.as_bytes().chunks(BASE64_LINE_LEN) {
+        mail.push_str(
+            core::str::from_utf8(line).expect("base64 is always ASCII"),
+        );
+        mail.push_str("\r\n");
+    }
+    mail.push_str(&format!("--{}--\r\n", boundary));
+    mail.into_bytes()
+}
+
+fn parse_mail(mail: &[u8]) -> Option<MailPart> {
+    let text = core::str::from_utf8(mail).ok()?;
+    let mut lines = text.lines();
+
+    // Parsing headers, unfolding multi-line ones
+    let mut headers: Vec<(String, String)> = vec![];
+    for line in &mut lines {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            let (_, value) = headers.last_mut()?;
+            value.push(' ');
+            value.push_str(line.trim());
+            continue;
+        }
+        let mut pair = line.splitn(2, ':');
+        let name = pair.next()?.trim().to_owned();
+        let value = pair.next()?.trim().to_owned();
+        headers.push((name, value));
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+
+    let session = header(HEADER_SESSION)?.to_owned();
+    let sequence = header(HEADER_SEQUENCE)?.parse().ok()?;
+    let mut part = header(HEADER_PART)?.splitn(2, '/');
+    let no: usize = part.next()?.trim().parse().ok()?;
+    let count: usize = part.next()?.trim().parse().ok()?;
+    if no == 0 || no > count || count > MAX_PARTS {
+        return None;
+    }
+    let boundary = header("Content-Type")?.split(';').map(str::trim).find_map(
+        |param| {
+            if param.len() > 9 && param[..9].eq_ignore_ascii_case("boundary=") {
+                Some(param[9..].trim_matches('"'))
+            } else {
+                None
+            }
+        },
+    )?;
+    let delimiter = format!("--{}", boundary);
+
+    // Searching for the attachment and skipping its headers
+    lines.find(|line| *line == delimiter)?;
+    let mut base64_encoded = false;
+    for line in &mut lines {
+        if line.is_empty() {
+            break;
+        }
+        let lower = line.to_ascii_lowercase();
+        if lower.starts_with("content-transfer-encoding:")
+            && lower.contains("base64")
+        {
+            base64_encoded = true;
+        }
+    }
+    if !base64_encoded {
+        return None;
+    }
+    let encoded = lines
+        .take_while(|line| !line.starts_with(&delimiter))
+        .map(str::trim)
+        .collect::<String>();
+    if encoded.len() > super::MAX_FRAME_SIZE / 3 * 4 + 4 {
+        return None;
+    }
+    let data = base64::decode(encoded).ok()?;
+
+    Some(MailPart {
+        session,
+        sequence,
+        no,
+        count,
+        data,
+    })
+}
+
+/// Reads SMTP reply, which may span multiple lines, returning its status code
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Result<u16, Error> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            Err(Error::ServiceOffline)?
+        }
+        if line.len() < 4 || !line.is_char_boundary(4) {
+            Err(Error::FrameBroken("invalid SMTP reply"))?
+        }
+        let code = line[..3]
+            .parse()
+            .map_err(|_| Error::FrameBroken("invalid SMTP reply"))?;
+        if &line[3..4] != "-" {
+            return Ok(code);
+        }
+    }
+}
+
+fn smtp_command(
+    reader: &mut BufReader<TcpStream>,
+    command: &str,
+    expected: &[u16],
+) -> Result<(), Error> {
+    reader
+        .get_mut()
+        .write_all(format!("{}\r\n", command).as_bytes())?;
+    expect_reply(reader, expected)
+}
+
+fn expect_reply(
+    reader: &mut BufReader<TcpStream>,
+    expected: &[u16],
+) -> Result<(), Error> {
+    let code = read_reply(reader)?;
+    if !expected.contains(&code) {
+        Err(Error::Smtp(code))?
+    }
+    Ok(())
+}
+
+/// Sends raw e-mail through the SMTP relay
+fn send_mail(relay: &Relay, mail: &[u8]) -> Result<(), Error> {
+    let stream = TcpStream::connect(relay.addr)?;
+    // NB: This is how we handle non-responding relays
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(stream);
+    expect_reply(&mut reader, &[220])?;
+    smtp_command(&mut reader, &format!("EHLO {}", relay.hostname), &[250])?;
+    smtp_command(&mut reader, &format!("MAIL FROM:<{}>", relay.from), &[250])?;
+    smtp_command(&mut reader, &format!("RCPT TO:<{}>", relay.to), &[250, 251])?;
+    smtp_command(&mut reader, "DATA", &[354])?;
+
+    // Dot-stuffing lines starting with a dot
+    let mut data = Vec::with_capacity(mail.len() + 5);
+    let mut lines = mail.split(|byte| *byte == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        if line.is_empty() && lines.peek().is_none() {
+            break;
+        }
+        if line.first() == Some(&b'.') {
+            data.push(b'.');
+        }
+        data.extend(line);
+        data.push(b'\n');
+    }
+    data.extend(b".\r\n");
+    reader.get_mut().write_all(&data)?;
+    expect_reply(&mut reader, &[250])?;
+    smtp_command(&mut reader, "QUIT", &[221])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn maildir(name: &str) -> Maildir {
+        let path = std::env::temp_dir().join(format!(
+            "lnpbp-maildir-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        Maildir::open(path).unwrap()
+    }
+
+    /// Runs SMTP stand-in accepting `count` e-mails and delivering them into
+    /// the Maildir
+    fn smtp_stand_in(
+        maildir: Maildir,
+        count: usize,
+    ) -> (SocketAddr, std::thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            for stream in listener.incoming().take(count) {
+                let mut reader = BufReader::new(stream.unwrap());
+                reader.get_mut().write_all(b"220 stand-in\r\n").unwrap();
+                let mut mail = vec![];
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let reply: &[u8] = match &line[..4] {
+                        "EHLO" => b"250-stand-in\r\n250 8BITMIME\r\n",
+                        "MAIL" | "RCPT" => b"250 OK\r\n",
+                        "DATA" => b"354 Go ahead\r\n",
+                        "QUIT" => {
+                            reader.get_mut().write_all(b"221 Bye\r\n").unwrap();
+                            break;
+                        }
+                        _ => unreachable!(),
+                    };
+                    reader.get_mut().write_all(reply).unwrap();
+                    if &line[..4] != "DATA" {
+                        continue;
+                    }
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        let line = line.strip_prefix('.').unwrap_or(&line);
+                        mail.extend(line.as_bytes());
+                    }
+                    reader.get_mut().write_all(b"250 Queued\r\n").unwrap();
+                }
+                maildir.deliver(&mail).unwrap();
+            }
+        });
+        (addr, handle)
+    }
+
+    fn relay(addr: SocketAddr, chunk_size: usize) -> Relay {
+        let mut relay =
+            Relay::with(addr, "localhost", "alice@localhost", "bob@localhost");
+        relay.chunk_size = chunk_size;
+        relay
+    }
+
+    #[test]
+    fn test_mail_roundtrip() {
+        let mut sender =
+            Sender::with(relay("127.0.0.1:25".parse().unwrap(), 4));
+        let data = b"frame data split in six".to_vec();
+        let mails = sender.compose(&data);
+        assert_eq!(mails.len(), 6);
+        let parts = mails
+            .iter()
+            .map(|mail| parse_mail(mail).unwrap())
+            .collect::<Vec<_>>();
+        assert!(parts.iter().all(|part| part.sequence == 0
+            && part.count == 6
+            && part.session == sender.session()));
+        assert_eq!(
+            parts
+                .into_iter()
+                .flat_map(|part| part.data)
+                .collect::<Vec<_>>(),
+            data
+        );
+        assert_eq!(parse_mail(b"Subject: unrelated\r\n\r\nHello"), None);
+    }
+
+    #[test]
+    fn test_reassembly_and_deduplication() {
+        let mailbox = maildir("reassembly");
+        let mut sender =
+            Sender::with(relay("127.0.0.1:25".parse().unwrap(), 2));
+        let first = sender.compose(b"abc");
+        let second = sender.compose(b"");
+        let third = sender.compose(b"xyz");
+
+        let mut receiver = Receiver::with(mailbox.clone());
+        receiver.set_timeout(Some(Duration::from_millis(10)));
+        receiver.set_poll_interval(Duration::from_millis(1));
+        // Out of order and duplicated delivery
+        for mail in
+            [&third[1], &first[1], &second[0], &third[0], &first[1]].iter()
+        {
+            mailbox.deliver(mail).unwrap();
+        }
+        assert_eq!(receiver.poll().unwrap(), 0);
+        assert_eq!(receiver.recv_frame(), Err(Error::TimedOut));
+        mailbox.deliver(&first[0]).unwrap();
+        mailbox.deliver(&first[0]).unwrap();
+        assert_eq!(receiver.recv_frame().unwrap(), b"abc");
+        assert_eq!(receiver.recv_frame().unwrap(), b"");
+        assert_eq!(receiver.recv_raw(2).unwrap(), b"xy");
+        assert_eq!(receiver.recv_raw(1).unwrap(), b"z");
+        assert_eq!(receiver.recv_frame(), Err(Error::TimedOut));
+    }
+
+    #[test]
+    fn test_receiver_bounds() {
+        let part = |session: usize, sequence: u64, no, count, data| MailPart {
+            session: format!("{:016x}", session),
+            sequence,
+            no,
+            count,
+            data,
+        };
+        let mut receiver = Receiver::with(maildir("bounds"));
+        receiver.set_timeout(Some(Duration::from_millis(10)));
+        receiver.set_poll_interval(Duration::from_millis(1));
+
+        // Frames far ahead of the received ones are ignored
+        receiver.process(part(0, MAX_PENDING_FRAMES, 1, 1, vec![0]));
+        receiver.process(part(0, core::u64::MAX, 1, 1, vec![0]));
+        assert!(receiver.sessions[&part(0, 0, 1, 1, vec![]).session]
+            .pending
+            .is_empty());
+
+        // Reassembled frame can't exceed maximum frame size
+        receiver.process(part(
+            0,
+            0,
+            1,
+            2,
+            vec![0; super::super::MAX_FRAME_SIZE],
+        ));
+        receiver.process(part(0, 0, 2, 2, vec![0]));
+        assert_eq!(receiver.poll().unwrap(), 0);
+
+        // The least recently active session is forgotten
+        for session in 1..=MAX_SESSIONS {
+            receiver.process(part(session, 0, 1, 1, vec![session as u8]));
+        }
+        receiver.process(part(1, 1, 1, 1, vec![0xFF]));
+        assert_eq!(receiver.sessions.len(), MAX_SESSIONS);
+        assert_eq!(receiver.poll().unwrap(), MAX_SESSIONS + 1);
+
+        // Sessions are received starting from the least recently active one
+        // and frames of the same session are not interleaved with others
+        for session in 2..=MAX_SESSIONS {
+            assert_eq!(receiver.recv_frame().unwrap(), vec![session as u8]);
+        }
+        assert_eq!(receiver.recv_frame().unwrap(), vec![1]);
+        receiver.process(part(2, 1, 1, 1, vec![2]));
+        assert_eq!(receiver.recv_frame().unwrap(), vec![0xFF]);
+        assert_eq!(receiver.recv_frame().unwrap(), vec![2]);
+        assert_eq!(receiver.recv_frame(), Err(Error::TimedOut));
+    }
+
+    #[test]
+    fn test_smtp_connection() {
+        let inbox = maildir("connection");
+        let (addr, stand_in) = smtp_stand_in(inbox.clone(), 2);
+        let mut connection = Connection::with(relay(addr, 16), inbox);
+        let frame = [0x55u8; 20];
+        connection.as_sender().send_frame(&frame).unwrap();
+        stand_in.join().unwrap();
+        assert_eq!(connection.as_receiver().recv_frame().unwrap(), frame);
+    }
+
+    #[test]
+    fn test_smtp_relay_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stand_in = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"554 No service\r\n").unwrap();
+        });
+        let mut sender = Sender::with(relay(addr, 16));
+        assert_eq!(sender.send_frame(b"frame"), Err(Error::Smtp(554)));
+        stand_in.join().unwrap();
+    }
+}
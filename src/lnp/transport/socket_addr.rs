@@ -17,8 +17,36 @@
 //! public key (for that purpose you need to use session-level address
 //! structures like [`NodeLocator`](lnp::NodeLocator) and
 //! [`NodeAddress`](lnp::NodeAddr)).
+//!
+//! # URL grammar
+//! Remote socket addresses ([`RemoteSocketAddr`]):
+//! * `lnp://<inet>:<port>` – framed TCP
+//! * `lnpz://<ip>:<port>/?api=<api>[&socket=<type>]` – ZMQ over TCP
+//! * `lnph://<inet>:<port>` – HTTP
+//! * `lnpws://<inet>:<port>` – Websocket; `ws://<inet>[:<port>]` is also
+//!   accepted when parsing
+//! * `lnpm://<inet>:<port>` – SMTP relay
+//!
+//! Local socket addresses ([`LocalSocketAddr`]):
+//! * `lnp:<path>` – POSIX socket
+//! * `lnpz:<path>?api=<api>[&socket=<type>]` – ZMQ IPC socket
+//! * `lnpz://<ip>:<port>/?api=<api>[&socket=<type>]` – unencrypted ZMQ over
+//!   TCP
+//! * `lnpz:?api=<api>[&socket=<type>]#<name>` – ZMQ inproc socket; since it
+//!   requires runtime ZMQ context, it is only produced by formatting and is
+//!   never parsed
+//!
+//! Here `<inet>` is IPv4, IPv6 (in square brackets) or Tor onion address
+//! (accepted with or without `.onion` suffix and formatted without it),
+//! `<ip>` is IPv4 or IPv6 address, `<api>` is one of `p2p`, `rpc`, `sub` or
+//! `esb` and `<type>` is the ZMQ socket type name given by
+//! [`zmqsocket::ZmqType::socket_name`]. The socket type may be omitted if it
+//! is the default for the API (see [`zmqsocket::ZmqType::default_for_api`]),
+//! and it is always omitted when formatting the default socket type. No other
+//! query parameters are allowed.
 
 use amplify::internet::{InetAddr, InetSocketAddr, NoOnionSupportError};
+use core::fmt::{self, Display, Formatter};
 #[cfg(feature = "url")]
 use std::convert::{TryFrom, TryInto};
 use std::net::{IpAddr, SocketAddr};
@@ -27,7 +55,7 @@ use std::str::FromStr;
 use url::{self, Url};
 
 #[cfg(feature = "zmq")]
-use super::zmqsocket;
+use super::zmqsocket::{self, ZmqType};
 use crate::lnp::{AddrError, UrlString};
 use bitcoin_hashes::core::cmp::Ordering;
 
@@ -62,7 +90,7 @@ pub enum FramingProtocol {
     Http,
 
     /// Binary LNP data send over Websocket connection
-    #[cfg(feature = "websocket")]
+    #[cfg(feature = "websockets")]
     #[display("Websocket", alt = "ws")]
     Websocket,
 
@@ -84,7 +112,7 @@ impl FromStr for FramingProtocol {
             #[cfg(feature = "zmq")]
             "zmtp" | "zmq" => Ok(FramingProtocol::Zmtp),
             "http" | "https" => Ok(FramingProtocol::Http),
-            #[cfg(feature = "websocket")]
+            #[cfg(feature = "websockets")]
             "ws" | "wss" | "websocket" => Ok(FramingProtocol::Websocket),
             "smtp" => Ok(FramingProtocol::Smtp),
             other => Err(AddrError::UnknownProtocol(other.to_owned())),
@@ -108,23 +136,54 @@ impl FromStr for FramingProtocol {
     Ord,
     Hash,
     Debug,
-    Display,
     StrictEncode,
     StrictDecode,
 )]
 #[lnpbp_crate(crate)]
 pub enum LocalSocketAddr {
-    /// Microservices connected using ZeroMQ protocol locally
+    /// Microservices connected using ZeroMQ protocol locally with the given
+    /// ZMQ socket type
+    ///
+    /// NB: Socket type is strict-encoded after the socket address, so the
+    ///     data encoded before the socket type was added can't be decoded
     #[cfg(feature = "zmq")]
-    #[display("{0}", alt = "lnpz://{0}")]
-    Zmq(zmqsocket::ZmqSocketAddr),
+    Zmq(zmqsocket::ZmqSocketAddr, ZmqType),
 
     /// Local node operating as a separate **process** or **threads** connected
     /// with unencrypted POSIX file I/O (like in c-lightning)
-    #[display("{0}", alt = "lnp:{0}")]
     Posix(String),
 }
 
+impl Display for LocalSocketAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "zmq")]
+            LocalSocketAddr::Zmq(addr, _) if !f.alternate() => {
+                Display::fmt(addr, f)
+            }
+            #[cfg(feature = "zmq")]
+            LocalSocketAddr::Zmq(
+                zmqsocket::ZmqSocketAddr::Inproc(name),
+                zmq_type,
+            ) => write!(f, "lnpz:?{}#{}", zmq_type.url_query(), name),
+            #[cfg(feature = "zmq")]
+            LocalSocketAddr::Zmq(
+                zmqsocket::ZmqSocketAddr::Ipc(path),
+                zmq_type,
+            ) => write!(f, "lnpz:{}?{}", path, zmq_type.url_query()),
+            #[cfg(feature = "zmq")]
+            LocalSocketAddr::Zmq(
+                zmqsocket::ZmqSocketAddr::Tcp(addr),
+                zmq_type,
+            ) => write!(f, "lnpz://{}/?{}", addr, zmq_type.url_query()),
+            LocalSocketAddr::Posix(path) if f.alternate() => {
+                write!(f, "lnp:{}", path)
+            }
+            LocalSocketAddr::Posix(path) => f.write_str(path),
+        }
+    }
+}
+
 /// Represents a connection to a generic remote peer operating with LNP protocol
 #[cfg_attr(feature = "serde", serde_as(as = "DisplayFromStr"))]
 #[cfg_attr(
@@ -133,40 +192,64 @@ pub enum LocalSocketAddr {
     serde(crate = "serde_crate")
 )]
 #[derive(
-    Clone, Copy, PartialEq, Eq, Hash, Debug, Display, StrictEncode, StrictDecode,
+    Clone, Copy, PartialEq, Eq, Hash, Debug, StrictEncode, StrictDecode,
 )]
 #[lnpbp_crate(crate)]
 #[non_exhaustive]
 pub enum RemoteSocketAddr {
     /// Framed TCP socket connection, that may be served either over plain IP,
     /// IPSec or Tor v2 and v3
-    #[display("{0}", alt = "lnp://{0}")]
     Ftcp(InetSocketAddr),
 
-    /// Microservices connected using ZeroMQ protocol remotely. Can be used
-    /// only with TCP-based ZMQ; for other types use [`LocalAddr::Zmq`]
+    /// Microservices connected using ZeroMQ protocol remotely with the given
+    /// ZMQ socket type. Can be used only with TCP-based ZMQ; for other types
+    /// use [`LocalSocketAddr::Zmq`]
+    ///
+    /// NB: Socket type is strict-encoded after the socket address, so the
+    ///     data encoded before the socket type was added can't be decoded
     #[cfg(feature = "zmq")]
-    #[display("{0}", alt = "lnpz://{0}")]
-    Zmq(SocketAddr),
+    Zmq(SocketAddr, ZmqType),
 
-    /// End-to-end encryption over web connection: think of this as LN protocol
-    /// streamed over HTTP
-    #[display("{0}", alt = "lnph://{0}")]
+    /// HTTP connection, which is used only for request/response RPC APIs
+    /// and can't carry encrypted sessions with remote nodes
     Http(InetSocketAddr),
 
     /// End-to-end ecnruption over web connection: think of this as LN protocol
     /// streamed over Websocket
-    #[cfg(feature = "websocket")]
-    #[display("{0}", alt = "lnpws://{0}")]
+    #[cfg(feature = "websockets")]
     Websocket(InetSocketAddr),
 
     /// SMTP connection: asynchronous end-to-end-over SMTP information transfer
     /// which is useful for ultra-low bandwidth non-real-time connections like
     /// satellite networks
-    #[display("{0}", alt = "lnpm://{0}")]
     Smtp(InetSocketAddr),
 }
 
+impl Display for RemoteSocketAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            f.write_str(self.url_scheme())?;
+        }
+        match self {
+            #[cfg(feature = "zmq")]
+            RemoteSocketAddr::Zmq(addr, _) if !f.alternate() => {
+                write!(f, "{}", addr)
+            }
+            #[cfg(feature = "zmq")]
+            RemoteSocketAddr::Zmq(addr, zmq_type) => {
+                write!(f, "{}/?{}", addr, zmq_type.url_query())
+            }
+            RemoteSocketAddr::Ftcp(inet) => f.write_str(&url_host_port(inet)),
+            RemoteSocketAddr::Http(inet) => f.write_str(&url_host_port(inet)),
+            #[cfg(feature = "websockets")]
+            RemoteSocketAddr::Websocket(inet) => {
+                f.write_str(&url_host_port(inet))
+            }
+            RemoteSocketAddr::Smtp(inet) => f.write_str(&url_host_port(inet)),
+        }
+    }
+}
+
 // Fake implementation required to use node addresses with StrictEncode
 // BTreeMaps
 impl PartialOrd for RemoteSocketAddr {
@@ -187,18 +270,24 @@ impl RemoteSocketAddr {
         Self::with_socket_addr(proto, addr)
     }
 
+    /// Constructs remote socket address for the given framing protocol. ZMQ
+    /// sockets are assumed to be RPC clients ([`ZmqType::Req`]); for other
+    /// socket types [`RemoteSocketAddr::Zmq`] must be constructed directly.
     pub fn with_socket_addr(proto: FramingProtocol, addr: SocketAddr) -> Self {
         match proto {
             FramingProtocol::FramedRaw => Self::Ftcp(addr.into()),
             #[cfg(feature = "zmq")]
-            FramingProtocol::Zmtp => Self::Zmq(addr.into()),
+            FramingProtocol::Zmtp => Self::Zmq(addr, ZmqType::Req),
             FramingProtocol::Http => Self::Http(addr.into()),
-            #[cfg(feature = "websocket")]
+            #[cfg(feature = "websockets")]
             FramingProtocol::Websocket => Self::Websocket(addr.into()),
             FramingProtocol::Smtp => Self::Smtp(addr.into()),
         }
     }
 
+    /// Constructs remote socket address for the given framing protocol. ZMQ
+    /// sockets are assumed to be RPC clients ([`ZmqType::Req`]), like with
+    /// [`RemoteSocketAddr::with_socket_addr`].
     pub fn with_inet_addr(
         proto: FramingProtocol,
         addr: InetSocketAddr,
@@ -206,9 +295,9 @@ impl RemoteSocketAddr {
         Ok(match proto {
             FramingProtocol::FramedRaw => Self::Ftcp(addr),
             #[cfg(feature = "zmq")]
-            FramingProtocol::Zmtp => Self::Zmq(addr.try_into()?),
+            FramingProtocol::Zmtp => Self::Zmq(addr.try_into()?, ZmqType::Req),
             FramingProtocol::Http => Self::Http(addr),
-            #[cfg(feature = "websocket")]
+            #[cfg(feature = "websockets")]
             FramingProtocol::Websocket => Self::Websocket(addr),
             FramingProtocol::Smtp => Self::Smtp(addr),
         })
@@ -218,9 +307,9 @@ impl RemoteSocketAddr {
         match self {
             RemoteSocketAddr::Ftcp(_) => FramingProtocol::FramedRaw,
             #[cfg(feature = "zmq")]
-            RemoteSocketAddr::Zmq(_) => FramingProtocol::Zmtp,
+            RemoteSocketAddr::Zmq(..) => FramingProtocol::Zmtp,
             RemoteSocketAddr::Http(_) => FramingProtocol::Http,
-            #[cfg(feature = "websocket")]
+            #[cfg(feature = "websockets")]
             RemoteSocketAddr::Websocket(_) => FramingProtocol::Websocket,
             RemoteSocketAddr::Smtp(_) => FramingProtocol::Smtp,
         }
@@ -232,9 +321,9 @@ impl From<RemoteSocketAddr> for InetSocketAddr {
         match rsa {
             RemoteSocketAddr::Ftcp(inet) => inet,
             #[cfg(feature = "zmq")]
-            RemoteSocketAddr::Zmq(sa) => sa.into(),
+            RemoteSocketAddr::Zmq(sa, _) => sa.into(),
             RemoteSocketAddr::Http(inet) => inet,
-            #[cfg(feature = "websocket")]
+            #[cfg(feature = "websockets")]
             RemoteSocketAddr::Websocket(inet) => inet,
             RemoteSocketAddr::Smtp(inet) => inet,
         }
@@ -263,11 +352,11 @@ impl UrlString for LocalSocketAddr {
     fn url_scheme(&self) -> &'static str {
         match self {
             #[cfg(feature = "zmq")]
-            LocalSocketAddr::Zmq(zmqsocket::ZmqSocketAddr::Tcp(..)) => {
+            LocalSocketAddr::Zmq(zmqsocket::ZmqSocketAddr::Tcp(..), _) => {
                 "lnpz://"
             }
             #[cfg(feature = "zmq")]
-            LocalSocketAddr::Zmq(_) => "lnpz:",
+            LocalSocketAddr::Zmq(..) => "lnpz:",
             LocalSocketAddr::Posix(_) => "lnp:",
         }
     }
@@ -281,11 +370,11 @@ impl UrlString for RemoteSocketAddr {
     fn url_scheme(&self) -> &'static str {
         match self {
             #[cfg(feature = "zmq")]
-            RemoteSocketAddr::Zmq(_) => "lnpz://",
+            RemoteSocketAddr::Zmq(..) => "lnpz://",
             RemoteSocketAddr::Ftcp(_) => "lnp://",
             RemoteSocketAddr::Smtp(_) => "lnpm://",
             RemoteSocketAddr::Http(_) => "lnph://",
-            #[cfg(feature = "websocket")]
+            #[cfg(feature = "websockets")]
             RemoteSocketAddr::Websocket(_) => "lnpws://",
         }
     }
@@ -295,26 +384,43 @@ impl UrlString for RemoteSocketAddr {
     }
 }
 
+#[cfg(feature = "url")]
 impl TryFrom<Url> for LocalSocketAddr {
     type Error = AddrError;
 
     fn try_from(url: Url) -> Result<Self, Self::Error> {
         Ok(match url.scheme() {
             "lnp" => {
-                if url.host().is_some() {
+                if url.has_host() {
                     Err(AddrError::UnexpectedHost)?
                 } else if url.has_authority() {
                     Err(AddrError::UnexpectedAuthority)?
                 } else if url.port().is_some() {
                     Err(AddrError::UnexpectedPort)?
                 }
+                check_url_query(&url, &[])?;
                 LocalSocketAddr::Posix(url.path().to_owned())
             }
             #[cfg(feature = "zmq")]
             "lnpz" => {
-                LocalSocketAddr::Zmq(zmqsocket::ZmqSocketAddr::try_from(url)?)
+                check_url_query(&url, &["api", "socket"])?;
+                let zmq_type = ZmqType::from_url_query(&url)?;
+                let addr = if url.has_host() {
+                    if !url.username().is_empty() {
+                        Err(AddrError::UnexpectedAuthority)?
+                    }
+                    zmqsocket::ZmqSocketAddr::Tcp(SocketAddr::new(
+                        url_ip_addr(&url)?,
+                        url.port().ok_or(AddrError::PortRequired)?,
+                    ))
+                } else if url.path().is_empty() {
+                    Err(AddrError::ZmqContextRequired)?
+                } else {
+                    zmqsocket::ZmqSocketAddr::Ipc(url.path().to_owned())
+                };
+                LocalSocketAddr::Zmq(addr, zmq_type)
             }
-            "lnph" | "lnpws" | "lnpm" => {
+            "lnph" | "lnpws" | "ws" | "lnpm" => {
                 Err(AddrError::Unsupported("for local socket address"))?
             }
             other => Err(AddrError::UnknownUrlScheme(other.to_owned()))?,
@@ -327,19 +433,218 @@ impl TryFrom<Url> for RemoteSocketAddr {
     type Error = AddrError;
 
     fn try_from(url: Url) -> Result<Self, Self::Error> {
-        let host = url.host_str().ok_or(AddrError::HostRequired)?.to_owned();
-        let inet_addr = host.parse::<InetAddr>()?;
-        let port = url.port().ok_or(AddrError::PortRequired)?;
+        if !url.username().is_empty() {
+            Err(AddrError::UnexpectedAuthority)?
+        }
+        let allowed_query: &[&str] = match url.scheme() {
+            "lnpz" => &["api", "socket"],
+            _ => &[],
+        };
+        check_url_query(&url, allowed_query)?;
+        let inet_addr = url_inet_addr(&url)?;
+        let port =
+            url.port_or_known_default().ok_or(AddrError::PortRequired)?;
         let inet_socket_addr = InetSocketAddr::new(inet_addr, port);
         Ok(match url.scheme() {
             "lnp" => RemoteSocketAddr::Ftcp(inet_socket_addr),
             #[cfg(feature = "zmq")]
-            "lnpz" => RemoteSocketAddr::Zmq(inet_socket_addr.try_into()?),
+            "lnpz" => RemoteSocketAddr::Zmq(
+                inet_socket_addr.try_into()?,
+                ZmqType::from_url_query(&url)?,
+            ),
             "lnph" => RemoteSocketAddr::Http(inet_socket_addr),
-            #[cfg(feature = "websocket")]
-            "lnpws" => RemoteSocketAddr::Websocket(inet_socket_addr),
+            #[cfg(feature = "websockets")]
+            "lnpws" | "ws" => RemoteSocketAddr::Websocket(inet_socket_addr),
             "lnpm" => RemoteSocketAddr::Smtp(inet_socket_addr),
             other => Err(AddrError::UnknownUrlScheme(other.to_owned()))?,
         })
     }
 }
+
+/// Formats host part of the URL, putting IPv6 addresses into square brackets
+/// and removing `.onion` suffix from Tor addresses
+pub(crate) fn url_host(addr: &InetAddr) -> String {
+    match addr {
+        InetAddr::IPv6(ip) => format!("[{}]", ip),
+        addr => addr.to_string().trim_end_matches(".onion").to_owned(),
+    }
+}
+
+/// Formats host and port parts of the URL
+pub(crate) fn url_host_port(addr: &InetSocketAddr) -> String {
+    format!("{}:{}", url_host(&addr.address), addr.port)
+}
+
+/// Parses host part of the URL as IP or Tor onion address, which may have
+/// `.onion` suffix. DNS names are not supported.
+#[cfg(feature = "url")]
+pub(crate) fn url_inet_addr(url: &Url) -> Result<InetAddr, AddrError> {
+    Ok(match url.host().ok_or(AddrError::HostRequired)? {
+        url::Host::Ipv4(ip) => IpAddr::V4(ip).into(),
+        url::Host::Ipv6(ip) => IpAddr::V6(ip).into(),
+        url::Host::Domain(domain) => {
+            domain.trim_end_matches(".onion").parse()?
+        }
+    })
+}
+
+/// Parses host part of the URL as IP address
+#[cfg(feature = "url")]
+pub(crate) fn url_ip_addr(url: &Url) -> Result<IpAddr, AddrError> {
+    Ok(match url.host().ok_or(AddrError::HostRequired)? {
+        url::Host::Ipv4(ip) => IpAddr::V4(ip),
+        url::Host::Ipv6(ip) => IpAddr::V6(ip),
+        url::Host::Domain(domain) => domain.parse()?,
+    })
+}
+
+/// Checks that the URL query does not contain parameters other than
+/// `allowed`
+#[cfg(feature = "url")]
+pub(crate) fn check_url_query(
+    url: &Url,
+    allowed: &[&str],
+) -> Result<(), AddrError> {
+    match url
+        .query_pairs()
+        .find(|(key, _)| !allowed.contains(&key.as_ref()))
+    {
+        Some((key, _)) => Err(AddrError::UnexpectedQuery(key.into_owned())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(all(test, feature = "url"))]
+mod test {
+    use super::*;
+    #[cfg(feature = "zmq")]
+    use crate::lnp::test_helpers::random_zmq_type;
+    use crate::lnp::test_helpers::{
+        random_inet_addr, random_ip, random_path, random_port, PROPERTY_ROUNDS,
+    };
+
+    fn random_remote_addrs() -> Vec<RemoteSocketAddr> {
+        let inet = || InetSocketAddr::new(random_inet_addr(), random_port());
+        vec![
+            RemoteSocketAddr::Ftcp(inet()),
+            RemoteSocketAddr::Http(inet()),
+            RemoteSocketAddr::Smtp(inet()),
+            #[cfg(feature = "websockets")]
+            RemoteSocketAddr::Websocket(inet()),
+            #[cfg(feature = "zmq")]
+            RemoteSocketAddr::Zmq(
+                SocketAddr::new(random_ip(), random_port()),
+                random_zmq_type(),
+            ),
+        ]
+    }
+
+    fn random_local_addrs() -> Vec<LocalSocketAddr> {
+        vec![
+            LocalSocketAddr::Posix(random_path()),
+            #[cfg(feature = "zmq")]
+            LocalSocketAddr::Zmq(
+                zmqsocket::ZmqSocketAddr::Ipc(random_path()),
+                random_zmq_type(),
+            ),
+            #[cfg(feature = "zmq")]
+            LocalSocketAddr::Zmq(
+                zmqsocket::ZmqSocketAddr::Tcp(SocketAddr::new(
+                    random_ip(),
+                    random_port(),
+                )),
+                random_zmq_type(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_remote_url_roundtrip() {
+        for addr in (0..PROPERTY_ROUNDS).flat_map(|_| random_remote_addrs()) {
+            let url = addr.to_url_string();
+            assert!(url.starts_with(addr.url_scheme()));
+            assert_eq!(RemoteSocketAddr::from_str(&url), Ok(addr), "{}", url);
+            assert_eq!(
+                RemoteSocketAddr::try_from(Url::parse(&url).unwrap()),
+                Ok(addr)
+            );
+        }
+    }
+
+    #[test]
+    fn test_local_url_roundtrip() {
+        for addr in (0..PROPERTY_ROUNDS).flat_map(|_| random_local_addrs()) {
+            let url = addr.to_url_string();
+            assert!(url.starts_with(addr.url_scheme()));
+            assert_eq!(
+                LocalSocketAddr::from_str(&url),
+                Ok(addr.clone()),
+                "{}",
+                url
+            );
+        }
+
+        #[cfg(feature = "zmq")]
+        for zmq_type in &ZmqType::ALL {
+            let inproc = LocalSocketAddr::Zmq(
+                zmqsocket::ZmqSocketAddr::Inproc(s!("socket1")),
+                *zmq_type,
+            );
+            assert_eq!(
+                LocalSocketAddr::from_str(&inproc.to_url_string()),
+                Err(AddrError::ZmqContextRequired)
+            );
+        }
+    }
+
+    #[test]
+    fn test_strict_url_checks() {
+        assert_eq!(
+            RemoteSocketAddr::from_str("lnp://127.0.0.1"),
+            Err(AddrError::PortRequired)
+        );
+        assert_eq!(
+            RemoteSocketAddr::from_str("lnp://127.0.0.1:9735/?api=rpc"),
+            Err(AddrError::UnexpectedQuery(s!("api")))
+        );
+        assert_eq!(
+            RemoteSocketAddr::from_str("lnp://user@127.0.0.1:9735"),
+            Err(AddrError::UnexpectedAuthority)
+        );
+        assert_eq!(
+            LocalSocketAddr::from_str("lnp:/tmp/lnp.sock?api=rpc"),
+            Err(AddrError::UnexpectedQuery(s!("api")))
+        );
+
+        #[cfg(feature = "zmq")]
+        {
+            assert_eq!(
+                RemoteSocketAddr::from_str("lnpz://127.0.0.1:9735"),
+                Err(AddrError::ZmqTypeRequired)
+            );
+            assert_eq!(
+                RemoteSocketAddr::from_str("lnpz://127.0.0.1:9735/?api=foo"),
+                Err(AddrError::InvalidZmqType(s!("foo")))
+            );
+            assert_eq!(
+                RemoteSocketAddr::from_str(
+                    "lnpz://127.0.0.1:9735/?api=rpc&socket=push"
+                ),
+                Err(AddrError::InvalidZmqType(s!("push")))
+            );
+            assert_eq!(
+                RemoteSocketAddr::from_str(
+                    "lnpz://127.0.0.1:9735/?api=rpc&socket=rep"
+                ),
+                Ok(RemoteSocketAddr::Zmq(
+                    "127.0.0.1:9735".parse().unwrap(),
+                    ZmqType::Rep
+                ))
+            );
+            assert_eq!(
+                LocalSocketAddr::from_str("lnpz:/tmp/lnp.sock?api=rpc&key=0"),
+                Err(AddrError::UnexpectedQuery(s!("key")))
+            );
+        }
+    }
+}
@@ -0,0 +1,278 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! SOCKS5 proxy client (RFC 1928) used to establish TCP connections through
+//! proxies, including connections to Tor onion addresses through a locally
+//! running Tor daemon
+
+use amplify::internet::InetSocketAddr;
+use core::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use super::Error as TransportError;
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_NONE: u8 = 0x00;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 proxy errors
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum Error {
+    /// proxy uses unsupported SOCKS protocol version {0}
+    UnsupportedVersion(u8),
+
+    /// proxy requires authentication, which is not supported
+    AuthenticationRequired,
+
+    /// proxy reply has an unknown address type {0}
+    UnknownAddressType(u8),
+
+    /// general SOCKS server failure
+    GeneralFailure,
+
+    /// connection is not allowed by the proxy ruleset
+    NotAllowed,
+
+    /// network is unreachable from the proxy
+    NetworkUnreachable,
+
+    /// remote host is unreachable from the proxy
+    HostUnreachable,
+
+    /// connection is refused by the remote host
+    ConnectionRefused,
+
+    /// TTL expired while connecting through the proxy
+    TtlExpired,
+
+    /// proxy does not support connect command
+    CommandNotSupported,
+
+    /// proxy does not support the type of the remote address
+    AddressTypeNotSupported,
+
+    /// proxy replied with unknown status code {0}
+    UnknownReply(u8),
+}
+
+impl Error {
+    fn from_reply(code: u8) -> Self {
+        match code {
+            0x01 => Error::GeneralFailure,
+            0x02 => Error::NotAllowed,
+            0x03 => Error::NetworkUnreachable,
+            0x04 => Error::HostUnreachable,
+            0x05 => Error::ConnectionRefused,
+            0x06 => Error::TtlExpired,
+            0x07 => Error::CommandNotSupported,
+            0x08 => Error::AddressTypeNotSupported,
+            other => Error::UnknownReply(other),
+        }
+    }
+}
+
+/// Connects to the `target` through SOCKS5 proxy listening on `proxy`
+/// address. Tor onion addresses are passed to the proxy as domain names and
+/// require `tor` feature.
+pub fn connect(
+    proxy: SocketAddr,
+    target: InetSocketAddr,
+) -> Result<TcpStream, TransportError> {
+    let mut stream = TcpStream::connect(proxy)?;
+    handshake(&mut stream, target)?;
+    Ok(stream)
+}
+
+/// Runs SOCKS5 connection negotiation with the proxy over the `stream`,
+/// requesting connection to the `target`
+pub fn handshake(
+    stream: &mut (impl Read + Write),
+    target: InetSocketAddr,
+) -> Result<(), TransportError> {
+    stream.write_all(&[SOCKS_VERSION, 1, AUTH_NONE])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    match reply {
+        [SOCKS_VERSION, AUTH_NONE] => {}
+        [SOCKS_VERSION, _] => Err(Error::AuthenticationRequired)?,
+        [version, _] => Err(Error::UnsupportedVersion(version))?,
+    }
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    request.extend(encode_addr(target)?);
+    request.extend(&target.port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        Err(Error::UnsupportedVersion(reply[0]))?
+    }
+    if reply[1] != 0x00 {
+        Err(Error::from_reply(reply[1]))?
+    }
+    // Skipping bound address and port, which are not used
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        other => Err(Error::UnknownAddressType(other))?,
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn encode_addr(target: InetSocketAddr) -> Result<Vec<u8>, TransportError> {
+    let mut data = vec![];
+    match SocketAddr::try_from(target) {
+        Ok(SocketAddr::V4(addr)) => {
+            data.push(ATYP_IPV4);
+            data.extend(&addr.ip().octets());
+        }
+        Ok(SocketAddr::V6(addr)) => {
+            data.push(ATYP_IPV6);
+            data.extend(&addr.ip().octets());
+        }
+        Err(_) => {
+            let host = onion_host(target)?;
+            data.push(ATYP_DOMAIN);
+            data.push(host.len() as u8);
+            data.extend(host.as_bytes());
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(feature = "tor")]
+fn onion_host(target: InetSocketAddr) -> Result<String, TransportError> {
+    if let Some(onion) = target.address.to_onion() {
+        Ok(format!("{}.onion", onion.get_address_without_dot_onion()))
+    } else if let Some(onion) = target.address.to_onion_v2() {
+        Ok(format!("{}.onion", onion.get_address_without_dot_onion()))
+    } else {
+        Err(TransportError::TorNotSupportedYet)
+    }
+}
+
+#[cfg(not(feature = "tor"))]
+fn onion_host(_: InetSocketAddr) -> Result<String, TransportError> {
+    Err(TransportError::TorNotSupportedYet)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Runs SOCKS5 stand-in accepting a single connection, which returns
+    /// address request data and echoes a single byte after the negotiation
+    fn socks5_stand_in(
+        reply_code: u8,
+    ) -> (SocketAddr, std::thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).unwrap();
+
+            let mut header = [0u8; 4];
+            stream.read_exact(&mut header).unwrap();
+            assert_eq!(header[..3], [5, 1, 0]);
+            let mut request = vec![header[3]];
+            let len = match header[3] {
+                ATYP_IPV4 => 4,
+                ATYP_IPV6 => 16,
+                _ => {
+                    let mut len = [0u8; 1];
+                    stream.read_exact(&mut len).unwrap();
+                    request.push(len[0]);
+                    len[0] as usize
+                }
+            };
+            let mut addr = vec![0u8; len + 2];
+            stream.read_exact(&mut addr).unwrap();
+            request.extend(addr);
+
+            stream
+                .write_all(&[5, reply_code, 0, 1, 127, 0, 0, 1, 0, 0])
+                .unwrap();
+            if reply_code == 0 {
+                let mut byte = [0u8; 1];
+                stream.read_exact(&mut byte).unwrap();
+                stream.write_all(&byte).unwrap();
+            }
+            request
+        });
+        (proxy, handle)
+    }
+
+    #[test]
+    fn test_socks5_ip() {
+        let (proxy, stand_in) = socks5_stand_in(0);
+        let target: InetSocketAddr =
+            "10.0.0.1:9735".parse::<SocketAddr>().unwrap().into();
+        let mut stream = connect(proxy, target).unwrap();
+        stream.write_all(&[0x42]).unwrap();
+        let mut echo = [0u8; 1];
+        stream.read_exact(&mut echo).unwrap();
+        assert_eq!(echo, [0x42]);
+        assert_eq!(
+            stand_in.join().unwrap(),
+            vec![ATYP_IPV4, 10, 0, 0, 1, 0x26, 0x07]
+        );
+    }
+
+    #[test]
+    fn test_socks5_failure() {
+        let (proxy, stand_in) = socks5_stand_in(0x05);
+        let target: InetSocketAddr =
+            "[::1]:9735".parse::<SocketAddr>().unwrap().into();
+        assert_eq!(
+            connect(proxy, target).unwrap_err(),
+            TransportError::Socks5(Error::ConnectionRefused)
+        );
+        assert_eq!(stand_in.join().unwrap()[0], ATYP_IPV6);
+    }
+
+    #[cfg(feature = "tor")]
+    #[test]
+    fn test_socks5_onion() {
+        use amplify::internet::InetAddr;
+        use std::str::FromStr;
+
+        let (proxy, stand_in) = socks5_stand_in(0);
+        let onion = "32zzibxmqi2ybxpqyggwwuwz7a3lbvtzoloti7cxoevyvijexvgsfeid";
+        let target =
+            InetSocketAddr::new(InetAddr::from_str(onion).unwrap(), 9735);
+        let mut stream = connect(proxy, target).unwrap();
+        stream.write_all(&[0x42]).unwrap();
+        let request = stand_in.join().unwrap();
+        let host = format!("{}.onion", onion);
+        assert_eq!(request[..2], [ATYP_DOMAIN, host.len() as u8]);
+        assert_eq!(&request[2..2 + host.len()], host.as_bytes());
+        assert_eq!(request[2 + host.len()..], [0x26, 0x07]);
+    }
+}
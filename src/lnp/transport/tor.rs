@@ -0,0 +1,271 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Tor control port client publishing hidden services, such that a node
+//! listening on a local TCP socket can accept inbound connections to its onion
+//! address. Outbound connections to onion addresses are done through the Tor
+//! SOCKS5 proxy, see [`super::socks5`].
+
+use amplify::internet::{InetAddr, InetSocketAddr};
+use bitcoin::hashes::hex::ToHex;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use torut::onion::OnionAddressV3;
+
+use super::Error;
+
+/// Authentication method used with Tor control port
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display(Debug)]
+pub enum TorAuth {
+    /// Control port does not require authentication
+    Null,
+
+    /// Authentication with the password configured with `HashedControlPassword`
+    Password(String),
+
+    /// Authentication with the cookie file created by Tor if
+    /// `CookieAuthentication` is set
+    Cookie(PathBuf),
+}
+
+/// Hidden service published with Tor control port. The service exists until
+/// this structure is dropped or the control connection gets closed.
+#[derive(Debug)]
+pub struct HiddenService {
+    control: BufReader<TcpStream>,
+    onion_addr: OnionAddressV3,
+    port: u16,
+    private_key: String,
+}
+
+impl HiddenService {
+    /// Connects to Tor `control` port and publishes hidden service with the
+    /// virtual onion `port`, which connections are forwarded by Tor to the
+    /// `target` local socket. If `private_key` returned by a previous call to
+    /// [`HiddenService::private_key`] is provided, the same onion address is
+    /// re-used; otherwise a new one is generated.
+    pub fn publish(
+        control: SocketAddr,
+        auth: &TorAuth,
+        port: u16,
+        target: SocketAddr,
+        private_key: Option<&str>,
+    ) -> Result<Self, Error> {
+        let mut control = BufReader::new(TcpStream::connect(control)?);
+        let auth = match auth {
+            TorAuth::Null => s!("AUTHENTICATE"),
+            TorAuth::Password(password) => format!(
+                "AUTHENTICATE \"{}\"",
+                password.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+            TorAuth::Cookie(path) => {
+                format!("AUTHENTICATE {}", std::fs::read(path)?.to_hex())
+            }
+        };
+        command(&mut control, &auth)?;
+
+        let key = private_key.unwrap_or("NEW:ED25519-V3");
+        let reply = command(
+            &mut control,
+            &format!("ADD_ONION {} Port={},{}", key, port, target),
+        )?;
+        let onion_addr = reply
+            .get("ServiceID")
+            .and_then(|id| OnionAddressV3::from_str(id).ok())
+            .ok_or(Error::TorControlInvalidReply)?;
+        let private_key = match private_key {
+            Some(key) => key.to_owned(),
+            None => reply
+                .get("PrivateKey")
+                .cloned()
+                .ok_or(Error::TorControlInvalidReply)?,
+        };
+
+        Ok(Self {
+            control,
+            onion_addr,
+            port,
+            private_key,
+        })
+    }
+
+    /// Returns onion address of the published hidden service
+    #[inline]
+    pub fn onion_addr(&self) -> &OnionAddressV3 {
+        &self.onion_addr
+    }
+
+    /// Returns onion address and port at which the node may be reached
+    pub fn inet_addr(&self) -> InetSocketAddr {
+        let address = InetAddr::from_str(
+            &self.onion_addr.get_address_without_dot_onion(),
+        )
+        .expect("Onion address produced by torut is always valid");
+        InetSocketAddr::new(address, self.port)
+    }
+
+    /// Returns private key of the hidden service, which may be used to
+    /// publish the service with the same onion address again
+    #[inline]
+    pub fn private_key(&self) -> &str {
+        &self.private_key
+    }
+}
+
+impl Drop for HiddenService {
+    fn drop(&mut self) {
+        let _ = command(
+            &mut self.control,
+            &format!(
+                "DEL_ONION {}",
+                self.onion_addr.get_address_without_dot_onion()
+            ),
+        );
+    }
+}
+
+/// Sends command to the Tor control port and returns key-value pairs from
+/// the successful reply
+fn command(
+    control: &mut BufReader<TcpStream>,
+    command: &str,
+) -> Result<HashMap<String, String>, Error> {
+    control
+        .get_mut()
+        .write_all(format!("{}\r\n", command).as_bytes())?;
+    let mut values = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if control.read_line(&mut line)? == 0 {
+            Err(Error::ServiceOffline)?
+        }
+        let line = line.trim_end();
+        if line.len() < 4 || !line.is_char_boundary(4) {
+            Err(Error::TorControlInvalidReply)?
+        }
+        let status = u16::from_str(&line[..3])
+            .map_err(|_| Error::TorControlInvalidReply)?;
+        if status != 250 {
+            Err(Error::TorControl(status))?
+        }
+        let mut pair = line[4..].splitn(2, '=');
+        if let (Some(key), Some(value)) = (pair.next(), pair.next()) {
+            values.insert(key.to_owned(), value.to_owned());
+        }
+        if &line[3..4] == " " {
+            return Ok(values);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    const ONION: &str =
+        "32zzibxmqi2ybxpqyggwwuwz7a3lbvtzoloti7cxoevyvijexvgsfeid";
+
+    /// Runs Tor control port stand-in serving a single connection, which
+    /// returns commands received
+    fn control_stand_in(
+        replies: Vec<String>,
+    ) -> (SocketAddr, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let control = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut commands = vec![];
+            for reply in replies {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                commands.push(line.trim_end().to_owned());
+                reader.get_mut().write_all(reply.as_bytes()).unwrap();
+            }
+            commands
+        });
+        (control, handle)
+    }
+
+    #[test]
+    fn test_publish_hidden_service() {
+        let (control, stand_in) = control_stand_in(vec![
+            s!("250 OK\r\n"),
+            format!(
+                "250-ServiceID={}\r\n250-PrivateKey=ED25519-V3:key\r\n\
+                 250 OK\r\n",
+                ONION
+            ),
+            s!("250 OK\r\n"),
+        ]);
+        let target: SocketAddr = "127.0.0.1:9735".parse().unwrap();
+        let service = HiddenService::publish(
+            control,
+            &TorAuth::Password(s!("pass\"word")),
+            9735,
+            target,
+            None,
+        )
+        .unwrap();
+        assert_eq!(service.private_key(), "ED25519-V3:key");
+        assert_eq!(
+            service.onion_addr(),
+            &OnionAddressV3::from_str(ONION).unwrap()
+        );
+        assert_eq!(service.inet_addr().port, 9735);
+        assert_eq!(
+            service.inet_addr().address.to_onion().as_ref(),
+            Some(service.onion_addr())
+        );
+        drop(service);
+
+        assert_eq!(
+            stand_in.join().unwrap(),
+            vec![
+                s!("AUTHENTICATE \"pass\\\"word\""),
+                s!("ADD_ONION NEW:ED25519-V3 Port=9735,127.0.0.1:9735"),
+                format!("DEL_ONION {}", ONION)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_control_port_failure() {
+        let (control, stand_in) =
+            control_stand_in(vec![s!("515 Authentication failed\r\n")]);
+        let target: SocketAddr = "127.0.0.1:9735".parse().unwrap();
+        assert_eq!(
+            HiddenService::publish(control, &TorAuth::Null, 9735, target, None)
+                .unwrap_err(),
+            Error::TorControl(515)
+        );
+        assert_eq!(stand_in.join().unwrap(), vec![s!("AUTHENTICATE")]);
+    }
+
+    #[test]
+    fn test_control_port_invalid_reply() {
+        let (control, stand_in) = control_stand_in(vec![s!("OK\r\n")]);
+        let target: SocketAddr = "127.0.0.1:9735".parse().unwrap();
+        assert_eq!(
+            HiddenService::publish(control, &TorAuth::Null, 9735, target, None)
+                .unwrap_err(),
+            Error::TorControlInvalidReply
+        );
+        assert_eq!(stand_in.join().unwrap(), vec![s!("AUTHENTICATE")]);
+    }
+}
@@ -10,3 +10,342 @@
 // You should have received a copy of the MIT License
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
+
+//! Websocket framing protocol: each LNP frame is transmitted as a single
+//! binary websocket message over TCP connection
+
+use amplify::internet::InetSocketAddr;
+use amplify::Bipolar;
+use core::convert::TryFrom;
+use core::time::Duration;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use super::{ftcp, Duplex, Error, RecvFrame, SendFrame};
+
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Self {
+        match err {
+            tungstenite::Error::Io(err) => err.into(),
+            tungstenite::Error::ConnectionClosed
+            | tungstenite::Error::AlreadyClosed => Error::ServiceOffline,
+            tungstenite::Error::Capacity(_) => {
+                Error::FrameBroken("websocket message exceeds capacity")
+            }
+            _ => Error::FrameBroken("websocket protocol violation"),
+        }
+    }
+}
+
+/// Websocket used for sending data, which is shared by both connection
+/// halves, such that all writes to the TCP socket are serialized
+type Writer = Arc<Mutex<WebSocket<TcpStream>>>;
+
+/// TCP stream of the receiving websocket half. Reads from the socket
+/// directly, while all writes, including automatic replies to the control
+/// messages, go through the lock of the shared [`Writer`], so they can't
+/// interleave with the frames sent by [`Sender`].
+#[derive(Debug)]
+pub struct ReadStream {
+    pub(self) stream: TcpStream,
+    pub(self) writer: Writer,
+}
+
+impl Read for ReadStream {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for ReadStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut writer = self
+            .writer
+            .lock()
+            .expect("Websocket writer lock is poisoned");
+        writer.get_mut().write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut writer = self
+            .writer
+            .lock()
+            .expect("Websocket writer lock is poisoned");
+        writer.get_mut().flush()
+    }
+}
+
+/// Receiving half of the websocket [`Connection`]
+#[derive(Debug)]
+pub struct Receiver {
+    pub(self) socket: WebSocket<ReadStream>,
+    /// Data from the last received binary message which were not consumed
+    /// yet by [`RecvFrame::recv_raw`]
+    pub(self) buffer: Vec<u8>,
+}
+
+/// Sending half of the websocket [`Connection`]
+#[derive(Debug)]
+pub struct Sender {
+    pub(self) socket: Writer,
+}
+
+/// Websocket connection over TCP stream, which is split into independent
+/// receiving and sending websocket halves sharing the same TCP socket. All
+/// writes to the socket are done through a single lock.
+#[derive(Debug)]
+pub struct Connection {
+    pub(self) receiver: Receiver,
+    pub(self) sender: Sender,
+    pub(self) remote_addr: InetSocketAddr,
+}
+
+impl Connection {
+    /// Connects to the remote websocket server at `inet_addr` and performs
+    /// client websocket handshake
+    pub fn connect(inet_addr: InetSocketAddr) -> Result<Self, Error> {
+        let socket_addr = SocketAddr::try_from(inet_addr)
+            .map_err(|_| Error::TorNotSupportedYet)?;
+        let stream = TcpStream::connect(socket_addr)?;
+        // NB: This is how we handle ping-pong cycles
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        let input = Self::read_stream(stream, Role::Client)?;
+        let writer = input.writer.clone();
+        let (input, _) =
+            tungstenite::client(format!("ws://{}/", socket_addr), input)
+                .map_err(|_| Error::HandshakeFailure)?;
+        Ok(Self::with(input, writer, inet_addr))
+    }
+
+    /// Binds new TCP [`ftcp::Listener`] to the `inet_addr`, accepts the
+    /// first incoming connection and performs server websocket handshake.
+    /// Returns the connection together with the listener, which may be used
+    /// with [`Connection::accept_from`] to accept further connections.
+    pub fn accept(
+        inet_addr: InetSocketAddr,
+    ) -> Result<(Self, ftcp::Listener), Error> {
+        let listener = ftcp::Listener::bind(inet_addr)?;
+        Ok((Self::accept_from(&listener)?, listener))
+    }
+
+    /// Accepts next incoming TCP connection from the `listener` and performs
+    /// server websocket handshake
+    pub fn accept_from(listener: &ftcp::Listener) -> Result<Self, Error> {
+        let connection = listener.accept()?;
+        let remote_addr = connection.remote_addr();
+        let (stream, _) = Bipolar::split(connection);
+        let input = Self::read_stream(stream, Role::Server)?;
+        let writer = input.writer.clone();
+        let input =
+            tungstenite::accept(input).map_err(|_| Error::HandshakeFailure)?;
+        Ok(Self::with(input, writer, remote_addr))
+    }
+
+    /// Returns address of the remote peer
+    #[inline]
+    pub fn remote_addr(&self) -> InetSocketAddr {
+        self.remote_addr
+    }
+
+    /// Constructs reading stream for the TCP socket together with the
+    /// shared websocket writer used for all writes to the same socket
+    fn read_stream(stream: TcpStream, role: Role) -> Result<ReadStream, Error> {
+        let output = stream.try_clone()?;
+        Ok(ReadStream {
+            stream,
+            writer: Arc::new(Mutex::new(WebSocket::from_raw_socket(
+                output, role, None,
+            ))),
+        })
+    }
+
+    fn with(
+        input: WebSocket<ReadStream>,
+        writer: Writer,
+        remote_addr: InetSocketAddr,
+    ) -> Self {
+        Self {
+            receiver: Receiver {
+                socket: input,
+                buffer: vec![],
+            },
+            sender: Sender { socket: writer },
+            remote_addr,
+        }
+    }
+}
+
+impl Receiver {
+    /// Reads next binary websocket message, skipping control messages
+    fn read_message(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            match self.socket.read_message()? {
+                Message::Binary(data) => return Ok(data),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                Message::Text(_) => Err(Error::FrameBroken(
+                    "text websocket messages are not supported",
+                ))?,
+                Message::Close(_) => Err(Error::ServiceOffline)?,
+            }
+        }
+    }
+}
+
+impl RecvFrame for Receiver {
+    /// Returns the data of the next binary message. If the previous message
+    /// was partially consumed by [`RecvFrame::recv_raw`], returns its
+    /// remaining part instead.
+    fn recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        if !self.buffer.is_empty() {
+            return Ok(self.buffer.split_off(0));
+        }
+        self.read_message()
+    }
+
+    fn recv_raw(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        while self.buffer.len() < len {
+            let data = self.read_message()?;
+            self.buffer.extend(data);
+        }
+        let rest = self.buffer.split_off(len);
+        Ok(core::mem::replace(&mut self.buffer, rest))
+    }
+}
+
+impl SendFrame for Sender {
+    fn send_frame(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let len = data.len();
+        if len > super::MAX_FRAME_SIZE {
+            return Err(Error::OversizedFrame(len));
+        }
+        self.send_raw(data)
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> Result<usize, Error> {
+        self.socket
+            .lock()
+            .expect("Websocket writer lock is poisoned")
+            .write_message(Message::Binary(data.to_vec()))?;
+        Ok(data.len())
+    }
+}
+
+impl Duplex for Connection {
+    #[inline]
+    fn as_receiver(&mut self) -> &mut dyn RecvFrame {
+        &mut self.receiver
+    }
+
+    #[inline]
+    fn as_sender(&mut self) -> &mut dyn SendFrame {
+        &mut self.sender
+    }
+
+    #[inline]
+    fn split(self) -> (Box<dyn RecvFrame + Send>, Box<dyn SendFrame + Send>) {
+        (Box::new(self.receiver), Box::new(self.sender))
+    }
+}
+
+impl Bipolar for Connection {
+    type Left = Receiver;
+    type Right = Sender;
+
+    fn join(left: Self::Left, right: Self::Right) -> Self {
+        let remote_addr = left
+            .socket
+            .get_ref()
+            .stream
+            .peer_addr()
+            .map(InetSocketAddr::from)
+            .unwrap_or_default();
+        Self {
+            receiver: left,
+            sender: right,
+            remote_addr,
+        }
+    }
+
+    fn split(self) -> (Self::Left, Self::Right) {
+        (self.receiver, self.sender)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_websocket_frames() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = ftcp::Listener::bind(any_port).unwrap();
+        let socket_addr = listener.local_addr();
+
+        let rx = std::thread::spawn(move || {
+            let mut connection = Connection::accept_from(&listener).unwrap();
+            let frame = connection.as_receiver().recv_frame().unwrap();
+            assert_eq!(connection.as_receiver().recv_raw(2).unwrap(), b"ab");
+            assert_eq!(connection.as_receiver().recv_raw(2).unwrap(), b"cd");
+            connection.as_sender().send_frame(&frame).unwrap();
+        });
+
+        let mut tx = Connection::connect(socket_addr).unwrap();
+        tx.as_sender().send_frame(b"Some frame").unwrap();
+        tx.as_sender().send_raw(b"abc").unwrap();
+        tx.as_sender().send_raw(b"d").unwrap();
+        assert_eq!(tx.as_receiver().recv_frame().unwrap(), b"Some frame");
+        assert_eq!(
+            tx.as_sender()
+                .send_frame(&[0u8; super::super::MAX_FRAME_SIZE + 1]),
+            Err(Error::OversizedFrame(super::super::MAX_FRAME_SIZE + 1))
+        );
+        rx.join().unwrap();
+    }
+
+    #[test]
+    fn test_control_replies_serialized() {
+        let any_port: InetSocketAddr = "127.0.0.1:0"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let listener = ftcp::Listener::bind(any_port).unwrap();
+        let socket_addr = listener.local_addr();
+
+        let server = std::thread::spawn(move || {
+            let connection = Connection::accept_from(&listener).unwrap();
+            let (mut receiver, mut sender) = Duplex::split(connection);
+            // Pong replies to the pings are written while frames are sent
+            // from the other thread
+            let rx = std::thread::spawn(move || {
+                assert_eq!(receiver.recv_frame().unwrap(), b"end");
+            });
+            for no in 0..100u8 {
+                sender.send_frame(&[no; 1000]).unwrap();
+            }
+            rx.join().unwrap();
+        });
+
+        let mut tx = Connection::connect(socket_addr).unwrap();
+        for _ in 0..100 {
+            tx.sender
+                .socket
+                .lock()
+                .unwrap()
+                .write_message(Message::Ping(vec![0xFF; 100]))
+                .unwrap();
+        }
+        tx.as_sender().send_frame(b"end").unwrap();
+        for no in 0..100u8 {
+            assert_eq!(tx.as_receiver().recv_frame().unwrap(), vec![no; 1000]);
+        }
+        server.join().unwrap();
+    }
+}
@@ -16,21 +16,57 @@ use amplify::{Bipolar, Wrapper};
 use core::convert::TryFrom;
 #[cfg(feature = "url")]
 use core::str::FromStr;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::{self, Debug, Display, Formatter};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Mutex;
 #[cfg(feature = "url")]
 use url::Url;
 
+#[cfg(feature = "url")]
+use super::socket_addr;
 use super::{Duplex, RecvFrame, RoutedFrame, SendFrame};
-use crate::lnp::{transport, AddrError, UrlString};
+use crate::lnp::{transport, AddrError, LocalNode, UrlString};
 use bitcoin_hashes::core::cmp::Ordering;
+use bitcoin_hashes::{sha256, Hash, HashEngine};
 
 lazy_static! {
     pub static ref ZMQ_CONTEXT: zmq::Context = zmq::Context::new();
+
+    /// Allowlists of client CURVE public keys per ZAP domain of the server
+    /// sockets
+    static ref ZAP_ALLOWLISTS: Mutex<HashMap<String, BTreeSet<CurveKey>>> =
+        Mutex::new(HashMap::new());
+
+    /// ZAP handler is started once per process for the shared [`ZMQ_CONTEXT`]
+    static ref ZAP_HANDLER: Result<(), Error> = start_zap_handler();
 }
 
+/// Endpoint at which ZMQ looks for the ZAP authentication handler
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+
+/// Counter used to assign each server socket with allowlist a unique ZAP
+/// domain
+static ZAP_DOMAIN_NO: AtomicUsize = AtomicUsize::new(0);
+
+/// Tag used in derivation of CURVE keys from the node private key
+const CURVE_KEY_DERIVATION_TAG: &[u8] = b"LNP ZMQ CURVE";
+
 /// API type for node-to-node communications used by ZeroMQ
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Display)]
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+    Display,
+    FromPrimitive,
+    ToPrimitive,
+)]
 #[repr(u8)]
 #[non_exhaustive]
 pub enum ZmqType {
@@ -89,12 +125,26 @@ pub enum ZmqType {
     RouterConnect = 7,
 }
 
+impl_enum_strict_encoding!(ZmqType);
+
 /// Unknown [`ApiType`] string
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Display, Error)]
 #[display(Debug)]
 pub struct UnknownApiType;
 
 impl ZmqType {
+    /// All known ZMQ API types
+    pub const ALL: [ZmqType; 8] = [
+        ZmqType::Push,
+        ZmqType::Pull,
+        ZmqType::Req,
+        ZmqType::Rep,
+        ZmqType::Pub,
+        ZmqType::Sub,
+        ZmqType::RouterBind,
+        ZmqType::RouterConnect,
+    ];
+
     /// Returns [`zmq::SocketType`] corresponding to the given [`ApiType`]
     pub fn socket_type(&self) -> zmq::SocketType {
         match self {
@@ -119,6 +169,71 @@ impl ZmqType {
             ZmqType::RouterBind | ZmqType::RouterConnect => s!("esb"),
         }
     }
+
+    /// Returns name of the specific socket type that can be used as a part of
+    /// URL query together with [`ZmqType::api_name`]
+    pub fn socket_name(&self) -> &'static str {
+        match self {
+            ZmqType::Pull => "pull",
+            ZmqType::Push => "push",
+            ZmqType::Req => "req",
+            ZmqType::Rep => "rep",
+            ZmqType::Pub => "pub",
+            ZmqType::Sub => "sub",
+            ZmqType::RouterBind => "router-bind",
+            ZmqType::RouterConnect => "router-connect",
+        }
+    }
+
+    /// Returns socket type which is assumed for the API when the URL query
+    /// does not specify socket type explicitly
+    pub fn default_for_api(api: &str) -> Option<ZmqType> {
+        match api {
+            "p2p" => Some(ZmqType::Push),
+            "rpc" => Some(ZmqType::Req),
+            "sub" => Some(ZmqType::Sub),
+            "esb" => Some(ZmqType::RouterBind),
+            _ => None,
+        }
+    }
+
+    /// Returns URL query representing the API type. Socket type is given
+    /// only if it differs from [`ZmqType::default_for_api`], so the query for
+    /// the default socket type is just `api=<api>`.
+    pub fn url_query(&self) -> String {
+        let api = self.api_name();
+        if ZmqType::default_for_api(&api) == Some(*self) {
+            format!("api={}", api)
+        } else {
+            format!("api={}&socket={}", api, self.socket_name())
+        }
+    }
+
+    /// Parses API type from the URL query, consisting of the required `api`
+    /// parameter and optional `socket` parameter, which must match the API
+    #[cfg(feature = "url")]
+    pub fn from_url_query(url: &Url) -> Result<ZmqType, AddrError> {
+        let mut api = None;
+        let mut socket = None;
+        for (key, val) in url.query_pairs() {
+            match key.as_ref() {
+                "api" => api = Some(val.to_ascii_lowercase()),
+                "socket" => socket = Some(val.to_ascii_lowercase()),
+                _ => {}
+            }
+        }
+        let api = api.ok_or(AddrError::ZmqTypeRequired)?;
+        let default = ZmqType::default_for_api(&api)
+            .ok_or_else(|| AddrError::InvalidZmqType(api.clone()))?;
+        match socket {
+            None => Ok(default),
+            Some(socket) => ZmqType::ALL
+                .iter()
+                .find(|ty| ty.api_name() == api && ty.socket_name() == socket)
+                .copied()
+                .ok_or(AddrError::InvalidZmqType(socket)),
+        }
+    }
 }
 
 impl FromStr for ZmqType {
@@ -126,19 +241,13 @@ impl FromStr for ZmqType {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let s = s.to_lowercase();
-        vec![
-            ZmqType::Push,
-            ZmqType::Pull,
-            ZmqType::Req,
-            ZmqType::Rep,
-            ZmqType::Pub,
-            ZmqType::Sub,
-            ZmqType::RouterBind,
-            ZmqType::RouterConnect,
-        ]
-        .into_iter()
-        .find(|api| api.to_string() == s)
-        .ok_or(UnknownApiType)
+        ZmqType::ALL
+            .iter()
+            .find(|api| {
+                api.socket_name() == s || api.to_string().to_lowercase() == s
+            })
+            .copied()
+            .ok_or(UnknownApiType)
     }
 }
 
@@ -152,7 +261,7 @@ impl FromStr for ZmqType {
     serde(crate = "serde_crate", tag = "type")
 )]
 pub enum ZmqSocketAddr {
-    #[display("inproc://{0}", alt = "zmq:{0}")]
+    #[display("inproc://{0}")]
     Inproc(String),
 
     #[display("ipc://{0}", alt = "lnpz:{0}")]
@@ -179,7 +288,7 @@ impl Ord for ZmqSocketAddr {
 impl UrlString for ZmqSocketAddr {
     fn url_scheme(&self) -> &'static str {
         match self {
-            ZmqSocketAddr::Inproc(_) => "zmq:",
+            ZmqSocketAddr::Inproc(_) => "inproc://",
             ZmqSocketAddr::Ipc(_) => "lnpz:",
             ZmqSocketAddr::Tcp(_) => "lnpz://",
         }
@@ -196,6 +305,156 @@ impl ZmqSocketAddr {
     }
 }
 
+/// ZMQ CURVE public or secret key
+pub type CurveKey = [u8; 32];
+
+/// ZMQ CURVE keypair
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CurveKeys {
+    pub public_key: CurveKey,
+    pub secret_key: CurveKey,
+}
+
+impl CurveKeys {
+    /// Generates new random keypair
+    pub fn generate() -> Result<Self, transport::Error> {
+        let keypair = zmq::CurveKeyPair::new()?;
+        Ok(Self {
+            public_key: keypair.public_key,
+            secret_key: keypair.secret_key,
+        })
+    }
+
+    /// Constructs keypair from a configured secret key
+    pub fn with_secret(secret_key: CurveKey) -> Self {
+        let secret = x25519_dalek::StaticSecret::from(secret_key);
+        let public_key = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        Self {
+            public_key,
+            secret_key,
+        }
+    }
+}
+
+/// Deterministically derives CURVE keypair from the node private key, such
+/// that the node keeps the same CURVE identity across restarts
+impl From<&LocalNode> for CurveKeys {
+    fn from(node: &LocalNode) -> Self {
+        let mut engine = sha256::Hash::engine();
+        engine.input(CURVE_KEY_DERIVATION_TAG);
+        engine.input(&node.private_key()[..]);
+        Self::with_secret(sha256::Hash::from_engine(engine).into_inner())
+    }
+}
+
+/// ZMQ CURVE security configuration for a socket. The role in CURVE
+/// handshake does not depend on whether the socket binds or connects.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Curve {
+    /// Server role: the socket authenticates with `keys` and, if `allowlist`
+    /// is given, accepts connections only from clients with the listed
+    /// public keys
+    Server {
+        keys: CurveKeys,
+        allowlist: Option<BTreeSet<CurveKey>>,
+    },
+
+    /// Client role: the socket authenticates with `keys` and accepts only
+    /// the server with `server_key` public key
+    Client {
+        keys: CurveKeys,
+        server_key: CurveKey,
+    },
+}
+
+impl Curve {
+    /// Configures CURVE security for the `socket`. Must be called before the
+    /// socket is bound or connected. Returns ZAP domain assigned to the
+    /// socket if it uses the allowlist; once the socket is closed the domain
+    /// must be released with [`release_zap_domain`].
+    pub fn configure(
+        &self,
+        socket: &zmq::Socket,
+    ) -> Result<Option<String>, transport::Error> {
+        match self {
+            Curve::Server { keys, allowlist } => {
+                socket.set_curve_server(true)?;
+                socket.set_curve_secretkey(&keys.secret_key)?;
+                socket.set_curve_publickey(&keys.public_key)?;
+                let allowlist = match allowlist {
+                    None => return Ok(None),
+                    Some(allowlist) => allowlist.clone(),
+                };
+                (*ZAP_HANDLER)?;
+                let domain = format!(
+                    "lnp-{}",
+                    ZAP_DOMAIN_NO.fetch_add(1, AtomicOrdering::SeqCst)
+                );
+                socket.set_zap_domain(&domain)?;
+                ZAP_ALLOWLISTS
+                    .lock()
+                    .expect("ZAP allowlists lock is poisoned")
+                    .insert(domain.clone(), allowlist);
+                Ok(Some(domain))
+            }
+            Curve::Client { keys, server_key } => {
+                socket.set_curve_serverkey(server_key)?;
+                socket.set_curve_secretkey(&keys.secret_key)?;
+                socket.set_curve_publickey(&keys.public_key)?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Removes allowlist of the closed socket with the given ZAP `domain`
+pub fn release_zap_domain(domain: &str) {
+    if let Ok(mut allowlists) = ZAP_ALLOWLISTS.lock() {
+        allowlists.remove(domain);
+    }
+}
+
+fn start_zap_handler() -> Result<(), Error> {
+    let socket = ZMQ_CONTEXT.socket(zmq::REP)?;
+    socket.bind(ZAP_ENDPOINT)?;
+    std::thread::spawn(move || serve_zap(socket));
+    Ok(())
+}
+
+/// Serves ZAP requests according to RFC 27/ZAP, checking client CURVE public
+/// keys against the allowlist of the server socket ZAP domain
+fn serve_zap(socket: zmq::Socket) {
+    while let Ok(request) = socket.recv_multipart(0) {
+        let empty = vec![];
+        let frame = |no: usize| request.get(no).unwrap_or(&empty);
+        // Frames: version, request id, domain, address, identity, mechanism
+        // and credentials
+        let domain = String::from_utf8_lossy(frame(2));
+        let allowed = match ZAP_ALLOWLISTS
+            .lock()
+            .expect("ZAP allowlists lock is poisoned")
+            .get(domain.as_ref())
+        {
+            // Domain is not ours, so we do not restrict it
+            None => true,
+            Some(allowlist) => {
+                frame(5).as_slice() == b"CURVE"
+                    && frame(6).len() == 32
+                    && allowlist.iter().any(|key| &key[..] == frame(6))
+            }
+        };
+        let (status, text) = if allowed {
+            (&b"200"[..], &b"OK"[..])
+        } else {
+            (&b"400"[..], &b"client key is not allowed"[..])
+        };
+        let reply = [&b"1.0"[..], frame(1), status, text, b"", b""];
+        if socket.send_multipart(&reply, 0).is_err() {
+            break;
+        }
+    }
+}
+
 #[derive(Display)]
 pub enum Carrier {
     #[display(inner)]
@@ -290,10 +549,7 @@ impl TryFrom<Url> for ZmqSocketAddr {
             "lnpz" => {
                 if url.has_authority() {
                     Ok(ZmqSocketAddr::Tcp(SocketAddr::new(
-                        url.host()
-                            .ok_or(AddrError::HostRequired)?
-                            .to_string()
-                            .parse()?,
+                        socket_addr::url_ip_addr(&url)?,
                         url.port().ok_or(AddrError::PortRequired)?,
                     )))
                 } else {
@@ -317,6 +573,7 @@ impl TryFrom<Url> for ZmqSocketAddr {
 pub struct WrappedSocket {
     api_type: ZmqType,
     socket: zmq::Socket,
+    zap_domain: Option<String>,
 }
 
 pub struct Connection {
@@ -331,11 +588,44 @@ impl Connection {
         remote: &ZmqSocketAddr,
         local: Option<ZmqSocketAddr>,
         identity: Option<impl AsRef<[u8]>>,
+    ) -> Result<Self, transport::Error> {
+        Self::setup(api_type, remote, local, identity, None)
+    }
+
+    /// Constructs connection secured with ZMQ CURVE according to the `curve`
+    /// configuration. PUSH/PULL connections are not supported, since their
+    /// `local` socket works in the opposite direction and would require
+    /// CURVE configuration with the reversed roles; the function fails with
+    /// [`transport::Error::CurveNotApplicable`] for them.
+    pub fn with_curve(
+        api_type: ZmqType,
+        remote: &ZmqSocketAddr,
+        local: Option<ZmqSocketAddr>,
+        identity: Option<impl AsRef<[u8]>>,
+        curve: &Curve,
+    ) -> Result<Self, transport::Error> {
+        if let ZmqType::Push | ZmqType::Pull = api_type {
+            Err(transport::Error::CurveNotApplicable)?
+        }
+        Self::setup(api_type, remote, local, identity, Some(curve))
+    }
+
+    fn setup(
+        api_type: ZmqType,
+        remote: &ZmqSocketAddr,
+        local: Option<ZmqSocketAddr>,
+        identity: Option<impl AsRef<[u8]>>,
+        curve: Option<&Curve>,
     ) -> Result<Self, transport::Error> {
         let socket = ZMQ_CONTEXT.socket(api_type.socket_type())?;
         if let Some(identity) = identity {
             socket.set_identity(identity.as_ref())?;
         }
+        let mut input = WrappedSocket::from_zmq_socket(api_type, socket);
+        if let Some(curve) = curve {
+            input.zap_domain = curve.configure(&input.socket)?;
+        }
+        let socket = &input.socket;
         let endpoint = remote.zmq_socket_string();
         match api_type {
             ZmqType::Pull
@@ -366,7 +656,7 @@ impl Connection {
         .map(|s| WrappedSocket::from_zmq_socket(api_type, s));
         Ok(Self {
             api_type,
-            input: WrappedSocket::from_zmq_socket(api_type, socket),
+            input,
             output,
         })
     }
@@ -388,7 +678,11 @@ impl Connection {
 impl WrappedSocket {
     #[inline]
     fn from_zmq_socket(api_type: ZmqType, socket: zmq::Socket) -> Self {
-        Self { api_type, socket }
+        Self {
+            api_type,
+            socket,
+            zap_domain: None,
+        }
     }
 
     #[inline]
@@ -397,6 +691,14 @@ impl WrappedSocket {
     }
 }
 
+impl Drop for WrappedSocket {
+    fn drop(&mut self) {
+        if let Some(domain) = &self.zap_domain {
+            release_zap_domain(domain);
+        }
+    }
+}
+
 impl Duplex for Connection {
     #[inline]
     fn as_receiver(&mut self) -> &mut dyn RecvFrame {
@@ -542,3 +844,45 @@ impl SendFrame for WrappedSocket {
         Ok(data.len())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lnp::test_helpers::local_node;
+
+    #[test]
+    fn test_zmq_type_names() {
+        for zmq_type in &ZmqType::ALL {
+            assert_eq!(
+                ZmqType::from_str(zmq_type.socket_name()),
+                Ok(*zmq_type)
+            );
+            assert_eq!(ZmqType::from_str(&zmq_type.to_string()), Ok(*zmq_type));
+            #[cfg(feature = "url")]
+            {
+                let url =
+                    Url::parse(&format!("lnpz:x?{}", zmq_type.url_query()))
+                        .unwrap();
+                assert_eq!(ZmqType::from_url_query(&url), Ok(*zmq_type));
+            }
+        }
+        assert_eq!(ZmqType::Req.url_query(), "api=rpc");
+        assert_eq!(ZmqType::Rep.url_query(), "api=rpc&socket=rep");
+        assert_eq!(
+            ZmqType::RouterConnect.url_query(),
+            "api=esb&socket=router-connect"
+        );
+        assert_eq!(ZmqType::from_str("dealer"), Err(UnknownApiType));
+    }
+
+    #[test]
+    fn test_curve_keys() {
+        let keys = CurveKeys::generate().unwrap();
+        assert_eq!(CurveKeys::with_secret(keys.secret_key), keys);
+
+        let node = local_node(0x21);
+        let other = local_node(0x11);
+        assert_eq!(CurveKeys::from(&node), CurveKeys::from(&node));
+        assert_ne!(CurveKeys::from(&node), CurveKeys::from(&other));
+    }
+}
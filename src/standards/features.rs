@@ -24,6 +24,8 @@ use std::io;
 use std::ops::{BitAnd, BitOr, BitXor};
 use std::str::FromStr;
 
+#[cfg(feature = "lnp")]
+use crate::lnp::presentation::{self, LightningDecode, LightningEncode};
 use crate::paradigms::strict_encoding::{Error, StrictDecode};
 use crate::strict_encoding::StrictEncode;
 
@@ -253,6 +255,31 @@ impl StrictDecode for FlagVec {
     }
 }
 
+/// Lightning encoding of feature flags follows BOLT-9: it is a `u16`-prefixed
+/// byte array with big-endian byte order, i.e. the last byte holds flags 0-7
+#[cfg(feature = "lnp")]
+impl LightningEncode for FlagVec {
+    fn lightning_encode<E: io::Write>(
+        &self,
+        e: E,
+    ) -> Result<usize, presentation::Error> {
+        let mut bytes = self.shrunk().0;
+        bytes.reverse();
+        bytes.lightning_encode(e)
+    }
+}
+
+#[cfg(feature = "lnp")]
+impl LightningDecode for FlagVec {
+    fn lightning_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, presentation::Error> {
+        let mut bytes = Vec::<u8>::lightning_decode(d)?;
+        bytes.reverse();
+        Ok(Self(bytes))
+    }
+}
+
 impl FlagVec {
     fn bits_to_bytes(bits: FlagNo) -> usize {
         if bits == 0 {
@@ -337,16 +364,11 @@ impl FlagVec {
     /// buffer already was of the smallest possible size
     #[inline]
     pub fn shrink(&mut self) -> bool {
-        let capacity = self.capacity();
-        if capacity == 0 {
-            return false;
-        }
-        let mut top = 1;
-        while top < capacity && !self.is_set(capacity - top) {
-            top += 1;
-        }
-        let top = capacity - top;
-        let used = Self::bits_to_bytes(top);
+        let used = (0..self.capacity())
+            .rev()
+            .find(|flag_no| self.is_set(*flag_no))
+            .map(|flag_no| Self::bits_to_bytes(flag_no + 1))
+            .unwrap_or(0);
         if used < self.0.len() {
             let old = self.0.clone();
             self.0 = vec![0u8; used as usize];
@@ -372,7 +394,7 @@ impl FlagVec {
     /// value exceeds current maximum flag capacity.
     #[inline]
     fn mut_byte_at(&mut self, flag_no: FlagNo) -> &mut u8 {
-        self.enlarge(flag_no);
+        self.enlarge(flag_no + 1);
         &mut self.0[flag_no as usize / 8]
     }
 
@@ -559,6 +581,21 @@ mod test {
         assert_eq!(f1.capacity(), 8);
     }
 
+    #[test]
+    fn test_flag_bounds() {
+        let mut f1 = FlagVec::with_capacity(8);
+        assert_eq!(f1.set(8), false);
+        assert_eq!(f1.capacity(), 16);
+        f1.shrink();
+        assert_eq!(f1.capacity(), 16);
+        assert_eq!(f1.is_set(8), true);
+        f1.unset(8);
+        f1.set(0);
+        f1.shrink();
+        assert_eq!(f1.capacity(), 8);
+        assert_eq!(f1.is_set(0), true);
+    }
+
     #[test]
     fn test_fmt() {
         let mut f1 = FlagVec::from_str("-0-\t#__1 \n--\r+* +!").unwrap();
@@ -641,4 +678,22 @@ mod test {
             FlagVec::from_str("-++--++---+-+--+").unwrap()
         );
     }
+
+    #[test]
+    #[cfg(feature = "lnp")]
+    fn test_lightning_encoding() {
+        let mut f1 = FlagVec::new();
+        f1.set(1);
+        f1.set(9);
+        let data =
+            presentation::lightning_encoding::lightning_encode(&f1).unwrap();
+        assert_eq!(data, vec![0x00, 0x02, 0x02, 0x02]);
+        f1.set(17);
+        let data =
+            presentation::lightning_encoding::lightning_encode(&f1).unwrap();
+        assert_eq!(data, vec![0x00, 0x03, 0x02, 0x02, 0x02]);
+        let f2: FlagVec =
+            presentation::lightning_encoding::lightning_decode(&data).unwrap();
+        assert_eq!(f1, f2);
+    }
 }